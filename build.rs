@@ -1,9 +1,326 @@
-use std::path::PathBuf;
+use readpflash_layout::banks::{self, Bank};
+use std::path::{Path, PathBuf};
+
+/// The addresses of a QEMU machine's flash `bank`, for configs that do
+/// not give them: `pflash-paddr`, `pflash-size` and `pflash0-paddr`.
+fn qemu_flash(bank: Bank) -> (u64, u64, Option<u64>) {
+    let pflash0 = bank.pflash0.map(|paddr| paddr as u64);
+    (bank.paddr as u64, bank.size as u64, pflash0)
+}
+
+/// PCIe ECAM base of the QEMU machine of `arch`, for configs that do not
+/// give `pci-ecam-base`.
+fn qemu_ecam(arch: &str) -> Option<u64> {
+    match arch {
+        "riscv64" => Some(0x3000_0000),
+        "aarch64" => Some(0x40_1000_0000),
+        "x86_64" => Some(0xb000_0000),
+        "loongarch64" => Some(0x2000_0000),
+        _ => None,
+    }
+}
+
+/// The value of `key` in axconfig `text`, an integer or a string.
+fn config_value<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    text.lines().find_map(|line| {
+        let (k, v) = line.split_once('=')?;
+        (k.trim() == key).then(|| v.split('#').next().unwrap_or("").trim().trim_matches('"'))
+    })
+}
+
+/// An axconfig integer: decimal or `0x` hex, `_` allowed.
+fn parse_uint(v: &str) -> Option<u64> {
+    let v = v.trim().replace('_', "");
+    match v.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => v.parse().ok(),
+    }
+}
+
+fn config_uint(text: &str, key: &str) -> Option<u64> {
+    parse_uint(config_value(text, key)?)
+}
+
+/// The `[base, size]` pairs of array `key` in axconfig `text`, which may
+/// span lines.
+fn config_ranges(text: &str, key: &str) -> Option<Vec<(u64, u64)>> {
+    let start = text
+        .lines()
+        .position(|line| line.split_once('=').is_some_and(|(k, _)| k.trim() == key))?;
+    let mut array = String::new();
+    for line in text.lines().skip(start) {
+        array.push_str(line.split('#').next().unwrap_or(""));
+        if array.matches('[').count() == array.matches(']').count() {
+            break;
+        }
+    }
+    let (_, array) = array.split_once('=')?;
+    let inner = array.trim().strip_prefix('[')?.strip_suffix(']')?;
+    inner
+        .split(']')
+        .filter_map(|pair| pair.split_once('[').map(|(_, pair)| pair))
+        .map(|pair| {
+            let (base, size) = pair.split_once(',')?;
+            Some((parse_uint(base)?, parse_uint(size)?))
+        })
+        .collect()
+}
+
+/// Whether `ranges` together cover `[paddr, paddr + size)`.
+fn covered(ranges: &[(u64, u64)], paddr: u64, size: u64) -> bool {
+    let end = paddr + size;
+    let mut at = paddr;
+    while at < end {
+        match ranges
+            .iter()
+            .find(|&&(base, len)| base <= at && at < base + len)
+        {
+            Some(&(base, len)) => at = base + len,
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Fail the build if a bank the app touches is outside the config's
+/// `mmio-ranges`: the kernel maps only those, so the app would fault on
+/// its first read instead of printing anything useful.
+fn check_mapped(config: &str, text: &str, banks: &[(u8, u64)], size: u64) {
+    let Some(ranges) = config_ranges(text, "mmio-ranges") else {
+        panic!("{config}: mmio-ranges is missing or not a list of [base, size] pairs");
+    };
+    for &(unit, paddr) in banks {
+        if !covered(&ranges, paddr, size) {
+            panic!(
+                "{config}: pflash{unit} at {paddr:#x}..{:#x} is not inside mmio-ranges, so the \
+                 kernel would not map it; add [{paddr:#x}, {size:#x}] to mmio-ranges or fix \
+                 the pflash-* keys",
+                paddr + size
+            );
+        }
+    }
+}
+
+/// Write `board.rs` to `OUT_DIR` with the flash banks and PCIe ECAM base
+/// of the board the app is built for, taken from the `[devices]` table of
+/// the axconfig in `AX_CONFIG_PATH`. Builds for another architecture than
+/// the config's (host builds) and configs without the keys get the QEMU
+/// machine's; only a `bare` (no_std) build needs a bank at all.
+///
+/// The app reads the bank at `pflash-paddr`, or with the `bank0` feature
+/// the one at `pflash0-paddr`; `bank1` insists on pflash1. That bank and
+/// the machine's other one must lie in the config's `mmio-ranges`.
+fn write_board(out_dir: &str, arch: &str, bare: bool) {
+    println!("cargo:rerun-if-env-changed=AX_CONFIG_PATH");
+    let config = std::env::var("AX_CONFIG_PATH").ok();
+    let text = config.as_deref().and_then(|path| {
+        println!("cargo:rerun-if-changed={path}");
+        std::fs::read_to_string(path).ok()
+    });
+    let text = text.filter(|text| config_value(text, "arch") == Some(arch));
+    let from_config = text.as_deref().and_then(|text| {
+        let paddr = config_uint(text, "pflash-paddr")?;
+        let size = config_uint(text, "pflash-size")?;
+        Some((paddr, size, config_uint(text, "pflash0-paddr")))
+    });
+    let qemu = banks::for_arch(arch);
+    let (paddr, size, paddr0) = match from_config.or_else(|| qemu.map(qemu_flash)) {
+        Some(bank) => bank,
+        None if !bare => (0, 0, None),
+        None => panic!(
+            "no flash bank for {arch}: set pflash-paddr and pflash-size in the axconfig ({})",
+            config.as_deref().unwrap_or("AX_CONFIG_PATH is not set")
+        ),
+    };
+    let bank0 = std::env::var_os("CARGO_FEATURE_BANK0").is_some();
+    let bank1 = std::env::var_os("CARGO_FEATURE_BANK1").is_some();
+    // pflash-paddr names the bank the image goes in on the QEMU machine:
+    // pflash0 on x86_64, where it is the only one, pflash1 elsewhere.
+    let default = (qemu.map_or(1, |bank| bank.unit), paddr);
+    let (unit, paddr) = match (bank0, bank1, default.0) {
+        (true, true, _) => panic!("the bank0 and bank1 features are exclusive"),
+        (true, false, 1) => match paddr0 {
+            Some(paddr0) => (0, paddr0),
+            None if !bare => (0, 0),
+            None => panic!(
+                "bank0: no pflash0 for {arch}; set pflash0-paddr in the axconfig ({})",
+                config.as_deref().unwrap_or("AX_CONFIG_PATH is not set")
+            ),
+        },
+        (false, true, 0) if bare => panic!("bank1: {arch} has only one flash bank (pflash0)"),
+        (false, true, 0) => (1, 0),
+        (_, _, unit) => (unit, paddr),
+    };
+    // The machine's other bank, which src/bank.rs looks at if this one has
+    // no image.
+    let other = match (unit, paddr0) {
+        (unit, _) if unit != default.0 => Some(default),
+        (1, Some(paddr0)) => Some((0, paddr0)),
+        _ => None,
+    };
+    if let (true, Some(config), Some(text)) = (bare, &config, &text) {
+        let banks: Vec<_> = [Some((unit, paddr)), other].into_iter().flatten().collect();
+        check_mapped(config, text, &banks, size);
+    }
+    // With bank0 the firmware bank is the one read, not a second one.
+    let paddr0 = paddr0.filter(|_| unit != 0);
+    let ecam = text
+        .as_deref()
+        .and_then(|text| config_uint(text, "pci-ecam-base"))
+        .or_else(|| qemu_ecam(arch))
+        .unwrap_or(0);
+    let source = match (&from_config, &config) {
+        (Some(_), Some(path)) => path.as_str(),
+        _ => "the QEMU defaults in readpflash-layout",
+    };
+    let paddr0 = match paddr0 {
+        Some(paddr0) => format!("Some({paddr0:#x})"),
+        None => "None".into(),
+    };
+    let other = match other {
+        Some((unit, paddr)) => format!("Some(({unit}, {paddr:#x}))"),
+        None => "None".into(),
+    };
+    let board = format!(
+        "// Generated by build.rs from {source}.\n\
+         pub const PFLASH_UNIT: u8 = {unit};\n\
+         pub const PFLASH_PADDR: usize = {paddr:#x};\n\
+         pub const PFLASH_SIZE: usize = {size:#x};\n\
+         pub const PFLASH0_PADDR: Option<usize> = {paddr0};\n\
+         pub const PFLASH_OTHER: Option<(u8, usize)> = {other};\n\
+         pub const PCI_ECAM_PADDR: usize = {ecam:#x};\n"
+    );
+    std::fs::write(PathBuf::from(out_dir).join("board.rs"), board).unwrap();
+}
+
+/// A `[[region]]` of a layout file: name, the offset the file fixes (if
+/// any), and length.
+type LayoutRegion = (String, Option<u64>, u64);
+
+/// The `[[region]]` tables of the layout file at `path` (see
+/// `xtask/src/layout.rs`, which owns the format; this reads only what the
+/// app needs). A `file` region is as long as its file.
+fn layout_regions(path: &Path) -> Result<Vec<LayoutRegion>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read it: {e}"))?;
+    let base = path.parent().unwrap_or(Path::new("."));
+    let mut regions = Vec::new();
+    // name, offset, length of the table being read
+    let mut table: Option<(Option<String>, Option<u64>, Option<u64>)> = None;
+    let mut finish = |table: Option<(Option<String>, Option<u64>, Option<u64>)>| match table {
+        None => Ok(()),
+        Some((Some(name), offset, Some(len))) => {
+            regions.push((name, offset, len));
+            Ok(())
+        }
+        Some((name, ..)) => Err(format!(
+            "region {} needs a name and a file or size",
+            name.unwrap_or_default()
+        )),
+    };
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            finish(table.take())?;
+            if line.starts_with("[[region]]") {
+                table = Some((None, None, None));
+            }
+            continue;
+        }
+        let (Some(region), Some((key, value))) = (table.as_mut(), line.split_once('=')) else {
+            continue;
+        };
+        let value = value.trim();
+        let string = || {
+            let rest = value.strip_prefix('"')?;
+            Some(rest[..rest.find('"')?].to_string())
+        };
+        let int = || parse_uint(value.split('#').next().unwrap_or(""));
+        let bad = || format!("line {}: bad {}", n + 1, key.trim());
+        match key.trim() {
+            "name" => region.0 = Some(string().ok_or_else(bad)?),
+            "offset" => region.1 = Some(int().ok_or_else(bad)?),
+            "size" => region.2 = Some(int().ok_or_else(bad)?),
+            "file" => {
+                let file = base.join(string().ok_or_else(bad)?);
+                println!("cargo:rerun-if-changed={}", file.display());
+                let len = std::fs::metadata(&file)
+                    .map_err(|e| format!("line {}: {}: {e}", n + 1, file.display()))?
+                    .len();
+                region.2 = Some(len);
+            }
+            _ => {}
+        }
+    }
+    finish(table)?;
+    Ok(regions)
+}
+
+/// Write `regions.rs` to `OUT_DIR` with the regions of the layout file in
+/// `READPFLASH_LAYOUT` (`cargo xtask run --layout`), for the
+/// `layout-regions` feature: `<NAME>_OFFSET` and `<NAME>_LEN` for each,
+/// named after the region in upper case with `_` for anything but letters
+/// and digits.
+fn write_regions(out_dir: &str) {
+    println!("cargo:rerun-if-env-changed=READPFLASH_LAYOUT");
+    let layout = std::env::var("READPFLASH_LAYOUT")
+        .ok()
+        .filter(|_| std::env::var_os("CARGO_FEATURE_LAYOUT_REGIONS").is_some());
+    let mut out = String::new();
+    let regions = match &layout {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            let regions = layout_regions(Path::new(path))
+                .unwrap_or_else(|e| panic!("layout file {path}: {e}"));
+            out += &format!(
+                "// Generated by build.rs from {path}.\n\
+                 pub const LAYOUT: Option<&str> = Some({path:?});\n"
+            );
+            regions
+        }
+        None => {
+            out += "// Generated by build.rs: built without a layout file.\n\
+                    pub const LAYOUT: Option<&str> = None;\n";
+            Vec::new()
+        }
+    };
+    let offset = |offset: Option<u64>| match offset {
+        Some(offset) => format!("Some({offset:#x})"),
+        None => "None".into(),
+    };
+    out += "pub const REGIONS: &[(&str, Option<usize>, usize)] = &[\n";
+    for (name, at, len) in &regions {
+        out += &format!("    ({name:?}, {}, {len:#x}),\n", offset(*at));
+    }
+    out += "];\n";
+    for (name, at, len) in &regions {
+        let mut ident: String = name
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c.to_ascii_uppercase(),
+                false => '_',
+            })
+            .collect();
+        if ident.starts_with(|c: char| c.is_ascii_digit()) {
+            ident.insert(0, '_');
+        }
+        out += &format!(
+            "pub const {ident}_OFFSET: Option<usize> = {};\n\
+             pub const {ident}_LEN: usize = {len:#x};\n",
+            offset(*at)
+        );
+    }
+    std::fs::write(PathBuf::from(out_dir).join("regions.rs"), out).unwrap();
+}
 
 fn main() {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let target = std::env::var("TARGET").unwrap_or_default();
+    write_board(&out_dir, &arch, target.contains("-none"));
+    write_regions(&out_dir);
+
     // Only apply bare-metal linker settings when targeting a no_std platform.
     // This allows `cargo publish` verification (which builds for the host) to succeed.
-    let target = std::env::var("TARGET").unwrap_or_default();
     if !target.contains("-none") {
         return;
     }
@@ -13,13 +330,11 @@ fn main() {
     // We can locate it relative to OUT_DIR:
     //   OUT_DIR = target/<triple>/<profile>/build/<pkg>-<hash>/out
     //   ../../.. from OUT_DIR = target/<triple>/<profile>/
-    let out_dir = std::env::var("OUT_DIR").unwrap();
     let profile_dir = PathBuf::from(&out_dir).join("../../..");
     let profile_dir = std::fs::canonicalize(&profile_dir)
         .unwrap_or_else(|_| PathBuf::from(&out_dir).join("../../.."));
 
     // Auto-detect the platform from the target architecture
-    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap();
     let platform = match arch.as_str() {
         "riscv64" => "riscv64-qemu-virt",
         "aarch64" => "aarch64-qemu-virt",
@@ -27,7 +342,15 @@ fn main() {
         "loongarch64" => "loongarch64-qemu-virt",
         other => panic!("Unsupported architecture: {other}"),
     };
-    let lds_path = profile_dir.join(format!("linker_{platform}.lds"));
+    // `cargo xtask build/run --link-script` replaces it.
+    println!("cargo:rerun-if-env-changed=READPFLASH_LINK_SCRIPT");
+    let lds_path = match std::env::var("READPFLASH_LINK_SCRIPT") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            PathBuf::from(path)
+        }
+        Err(_) => profile_dir.join(format!("linker_{platform}.lds")),
+    };
 
     println!("cargo:rustc-link-arg=-T{}", lds_path.display());
     println!("cargo:rustc-link-arg=-no-pie");
@@ -0,0 +1,39 @@
+//! Feeds arbitrary bytes to the image header and manifest parser as the
+//! contents of a flash window.
+//!
+//! Truncated headers, absurd offsets and counts, and regions that overlap
+//! each other or run past the window must all come back as errors or as
+//! regions whose data is `None`, never as a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use readpflash_layout::{Header, MANIFEST_ENTRY_SIZE, Manifest, NAME_LEN};
+
+fuzz_target!(|flash: &[u8]| {
+    let Ok(header) = Header::parse(flash) else {
+        return;
+    };
+    if let Some((offset, len)) = header.kernel() {
+        let start = offset as usize;
+        let _ = start
+            .checked_add(len as usize)
+            .and_then(|end| flash.get(start..end));
+    }
+    let Ok(manifest) = Manifest::parse(flash, &header) else {
+        return;
+    };
+    assert_eq!(manifest.len(), header.region_count as usize);
+    assert!(manifest.len() * MANIFEST_ENTRY_SIZE <= flash.len());
+
+    // Entries may overlap, repeat names or point anywhere; each must still
+    // hand out either its exact bytes or nothing.
+    for region in manifest.regions().flatten() {
+        assert!(region.name.len() <= NAME_LEN);
+        if let Some(data) = region.data(flash) {
+            assert_eq!(data.len(), region.len as usize);
+        }
+        let _ = region.writable();
+    }
+});
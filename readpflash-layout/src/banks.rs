@@ -0,0 +1,67 @@
+//! The flash bank the image goes in on each QEMU machine.
+//!
+//! The app reads the bank at `pflash-paddr` in its axconfig
+//! (`configs/<ARCH>.toml`), and xtask attaches the image to the QEMU unit
+//! mapped there, sized as QEMU requires. These are the values for the
+//! default machine of each architecture.
+
+/// Where a machine maps the bank holding the image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bank {
+    /// QEMU pflash unit (`-drive if=pflash,unit=N`).
+    pub unit: u8,
+    /// Physical address of the bank.
+    pub paddr: usize,
+    /// Size of the bank, which the image file must match: fixed on the
+    /// virt machines of riscv64 and aarch64, chosen by the image on the
+    /// others.
+    pub size: usize,
+    /// Physical address of pflash0, the firmware bank, if the machine has
+    /// one besides this bank.
+    pub pflash0: Option<usize>,
+}
+
+/// riscv64 `virt`: pflash1, 32 MiB; pflash0 holds firmware.
+pub const RISCV64: Bank = Bank {
+    unit: 1,
+    paddr: 0x2200_0000,
+    size: 0x200_0000,
+    pflash0: Some(0x2000_0000),
+};
+
+/// aarch64 `virt`: pflash1, 64 MiB; pflash0 holds firmware.
+pub const AARCH64: Bank = Bank {
+    unit: 1,
+    paddr: 0x0400_0000,
+    size: 0x400_0000,
+    pflash0: Some(0),
+};
+
+/// x86_64 `q35`: pflash0, 4 MiB ending at 4 GiB, shared with SeaBIOS.
+pub const X86_64: Bank = Bank {
+    unit: 0,
+    paddr: 0xFFC0_0000,
+    size: 0x40_0000,
+    pflash0: None,
+};
+
+/// loongarch64 `virt`: pflash1, 4 MiB at the start of the VIRT_FLASH
+/// region, which it takes when pflash0 is absent.
+pub const LOONGARCH64: Bank = Bank {
+    unit: 1,
+    paddr: 0x1D00_0000,
+    size: 0x40_0000,
+    pflash0: None,
+};
+
+/// The bank of the architecture named `arch` (`riscv64`, `aarch64`,
+/// `x86_64`, `loongarch64`).
+pub fn for_arch(arch: &str) -> Option<Bank> {
+    match arch {
+        "riscv64" => Some(RISCV64),
+        "aarch64" => Some(AARCH64),
+        "x86_64" => Some(X86_64),
+        "loongarch64" => Some(LOONGARCH64),
+        _ => None,
+    }
+}
@@ -0,0 +1,128 @@
+//! The message catalog: numbered strings in the "messages" region.
+//!
+//! `cargo xtask mkimage --messages <FILE>` writes it and the app's
+//! `messages` feature looks its messages up in it by number. All integers
+//! are little-endian:
+//!
+//! ```text
+//! 0x00  "MSGC"
+//! 0x04  u32 count
+//! 0x08  count entries of u32 id, u32 offset, u32 len, by ascending id
+//! ....  the UTF-8 strings the entries point to, from the region start
+//! ```
+
+use core::fmt;
+
+/// Magic at the start of the messages region.
+pub const MAGIC: &[u8; 4] = b"MSGC";
+/// Size of the catalog header: magic and count.
+pub const HEADER_SIZE: usize = 8;
+/// Size of one entry of the index.
+pub const ENTRY_SIZE: usize = 12;
+
+/// Reasons a catalog cannot be read.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CatalogError {
+    /// The region does not start with `"MSGC"`.
+    BadMagic,
+    /// The index runs past the end of the region.
+    Truncated,
+    /// The string of message `id` runs past the end of the region.
+    OutOfBounds(u32),
+    /// The string of message `id` is not UTF-8.
+    NotUtf8(u32),
+    /// Message `id` is listed after a message with the same or a higher id.
+    Unsorted(u32),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "bad catalog magic"),
+            Self::Truncated => write!(f, "catalog index truncated"),
+            Self::OutOfBounds(id) => write!(f, "message {id} runs past the region"),
+            Self::NotUtf8(id) => write!(f, "message {id} is not UTF-8"),
+            Self::Unsorted(id) => write!(f, "message {id} is out of order or repeated"),
+        }
+    }
+}
+
+fn u32_at(bytes: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(bytes[off..off + 4].try_into().unwrap())
+}
+
+/// A catalog whose every entry has been checked, so lookups cannot fail.
+pub struct Catalog<'a> {
+    region: &'a [u8],
+    index: &'a [u8],
+}
+
+impl<'a> Catalog<'a> {
+    /// Read the catalog in `region`, checking the whole index.
+    pub fn parse(region: &'a [u8]) -> Result<Self, CatalogError> {
+        if region.get(..4) != Some(MAGIC) {
+            return Err(CatalogError::BadMagic);
+        }
+        let count = u32_at(region.get(..HEADER_SIZE).ok_or(CatalogError::Truncated)?, 4);
+        let end = (count as usize)
+            .checked_mul(ENTRY_SIZE)
+            .and_then(|len| len.checked_add(HEADER_SIZE))
+            .ok_or(CatalogError::Truncated)?;
+        let index = region
+            .get(HEADER_SIZE..end)
+            .ok_or(CatalogError::Truncated)?;
+        let catalog = Self { region, index };
+        let mut last = None;
+        for entry in index.chunks_exact(ENTRY_SIZE) {
+            let id = u32_at(entry, 0);
+            if last.is_some_and(|last| id <= last) {
+                return Err(CatalogError::Unsorted(id));
+            }
+            last = Some(id);
+            catalog.text(entry)?;
+        }
+        Ok(catalog)
+    }
+
+    fn text(&self, entry: &[u8]) -> Result<&'a str, CatalogError> {
+        let id = u32_at(entry, 0);
+        let start = u32_at(entry, 4) as usize;
+        let bytes = start
+            .checked_add(u32_at(entry, 8) as usize)
+            .and_then(|end| self.region.get(start..end))
+            .ok_or(CatalogError::OutOfBounds(id))?;
+        core::str::from_utf8(bytes).map_err(|_| CatalogError::NotUtf8(id))
+    }
+
+    /// Number of messages.
+    pub fn len(&self) -> usize {
+        self.index.len() / ENTRY_SIZE
+    }
+
+    /// Whether the catalog holds no messages.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// The text of message `id`, found by binary search of the index.
+    pub fn get(&self, id: u32) -> Option<&'a str> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let entry = &self.index[mid * ENTRY_SIZE..][..ENTRY_SIZE];
+            match u32_at(entry, 0).cmp(&id) {
+                core::cmp::Ordering::Less => low = mid + 1,
+                core::cmp::Ordering::Greater => high = mid,
+                core::cmp::Ordering::Equal => return self.text(entry).ok(),
+            }
+        }
+        None
+    }
+
+    /// Every message with its id, by ascending id.
+    pub fn messages(&self) -> impl Iterator<Item = (u32, &'a str)> + '_ {
+        self.index
+            .chunks_exact(ENTRY_SIZE)
+            .filter_map(|entry| Some((u32_at(entry, 0), self.text(entry).ok()?)))
+    }
+}
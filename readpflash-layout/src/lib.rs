@@ -0,0 +1,516 @@
+//! The PFlash image format and the flash banks it goes in, shared by the
+//! app (`no_std`) and `cargo xtask` so the two cannot drift apart.
+//!
+//! The image starts with a 64-byte header (magic `"PFLA"`), followed by a
+//! manifest (magic `"MNFS"`) with one 64-byte entry per region, tagged with
+//! the [`Kind`] of data it holds. Their integers are in the byte order
+//! recorded in the header byte at [`ENDIAN_OFFSET`]: little-endian unless
+//! the image was created with `--header-endian big`. `xtask/src/image.rs`
+//! writes images and [`Header`] and [`Manifest`] read them.
+//!
+//! An image whose payload is encrypted (`--encrypt`) has a 128-byte header:
+//! the fixed one, then the AES-GCM nonce, tag and key id ([`Sealing`]).
+//!
+//! The header carries a format version. xtask writes [`VERSION`]; the app
+//! reads versions [`MIN_VERSION`] to [`MAX_VERSION`] and refuses others
+//! with an error that says which side to rebuild.
+//!
+//! [`banks`] lists where each QEMU machine maps the bank the image goes in,
+//! and [`catalog`] reads the message catalog an image may hold.
+
+#![no_std]
+
+pub mod banks;
+pub mod catalog;
+
+use core::fmt;
+
+/// Magic at offset 0 of every image.
+pub const MAGIC: &[u8; 4] = b"PFLA";
+/// Magic at the start of the manifest region.
+pub const MANIFEST_MAGIC: &[u8; 4] = b"MNFS";
+/// Image format version xtask writes into the header. Bump it with any
+/// change to the layout an older app would misread.
+pub const VERSION: u16 = 1;
+/// Oldest image format version the app reads.
+pub const MIN_VERSION: u16 = 1;
+/// Newest image format version the app reads: the one it was built with.
+pub const MAX_VERSION: u16 = VERSION;
+/// Size of the fixed header at offset 0. The manifest directly follows the
+/// header, including the encryption fields if there are any.
+pub const HEADER_SIZE: usize = 0x40;
+/// Bytes at the start of the header covered by `Header::header_crc`.
+pub const HEADER_CRC_LEN: usize = 0x20;
+/// Header byte recording the byte order of the header and manifest.
+pub const ENDIAN_OFFSET: usize = 0x24;
+/// [`ENDIAN_OFFSET`] value for little-endian.
+pub const ENDIAN_LITTLE: u8 = 0;
+/// [`ENDIAN_OFFSET`] value for big-endian.
+pub const ENDIAN_BIG: u8 = 1;
+/// Size of the manifest preamble (magic + entry count).
+pub const MANIFEST_PREAMBLE: usize = 8;
+/// Size of one manifest entry.
+pub const MANIFEST_ENTRY_SIZE: usize = 64;
+/// Maximum region name length (NUL-padded in the entry).
+pub const NAME_LEN: usize = 16;
+/// Data regions start on this alignment.
+pub const REGION_ALIGN: usize = 0x1000;
+/// Header flag: a kernel image is embedded (see `Header::kernel`).
+pub const FLAG_KERNEL: u16 = 1 << 0;
+/// Header flag: the payload is a PRNG pattern (see `Header::pattern`).
+pub const FLAG_PATTERN: u16 = 1 << 1;
+/// Header flag: the payload is encrypted (see `Header::sealing`).
+pub const FLAG_ENCRYPTED: u16 = 1 << 2;
+/// Header flag: the header records the flash geometry QEMU was asked to
+/// emulate (see `Header::geometry`).
+pub const FLAG_GEOMETRY: u16 = 1 << 3;
+/// Size of the encryption fields after the fixed header when
+/// [`FLAG_ENCRYPTED`] is set.
+pub const CRYPT_FIELDS_SIZE: usize = 0x40;
+/// Region flag: the guest writes to the region, so its digest only
+/// describes the image as created.
+pub const REGION_WRITABLE: u32 = 1 << 0;
+/// Manifest entry byte holding the region's [`Kind`] tag.
+pub const KIND_OFFSET: usize = 0x1C;
+
+/// Length of an `--encrypt` key (AES-256).
+pub const KEY_LEN: usize = 32;
+/// Key the payload is sealed with when no `--encrypt` key is given, and
+/// the one the app tries without a `key=` on its command line.
+pub const DEV_KEY: [u8; KEY_LEN] = *b"readpflash-dev-key-do-not-use!!!";
+
+/// Magic at the start of the crc region.
+pub const CRC_MAGIC: &[u8; 4] = b"CRCT";
+/// Bytes covered by each entry of the crc region.
+pub const CRC_SECTOR: usize = 512;
+/// Size of the crc region header.
+pub const CRC_HEADER_SIZE: usize = 0x10;
+
+/// Magic at the start of the xip region.
+pub const XIP_MAGIC: &[u8; 4] = b"XIPC";
+/// Size of the stub header in front of the xip code.
+pub const XIP_HEADER_SIZE: usize = 0x10;
+/// Value returned by the xip function.
+pub const XIP_RESULT: u32 = 0x5849_5021;
+
+/// Magic at the start of a crash record in the panics region.
+pub const PANIC_MAGIC: &[u8; 4] = b"PANC";
+/// Bytes per crash record slot.
+pub const PANIC_SLOT: usize = 0x400;
+/// Magic at the start of a results record in the bench region.
+pub const BENCH_MAGIC: &[u8; 4] = b"BNCH";
+/// Bytes per results record slot.
+pub const BENCH_SLOT: usize = 0x40;
+
+/// Sector (LBA) size of the `--partition-table` tables.
+pub const SECTOR: usize = 512;
+/// MBR partition type of the data partition holding the image
+/// (non-filesystem data).
+pub const MBR_DATA_TYPE: u8 = 0xDA;
+/// MBR partition type of a protective MBR, which announces a GPT.
+pub const MBR_PROTECTIVE: u8 = 0xEE;
+/// GPT partition type of the data partition holding the image,
+/// 70666C61-7368-4461-7461-726561647066, in on-disk byte order.
+pub const GPT_DATA_TYPE: [u8; 16] = [
+    0x61, 0x6C, 0x66, 0x70, 0x68, 0x73, 0x61, 0x44, b't', b'a', b'r', b'e', b'a', b'd', b'p', b'f',
+];
+
+/// Reasons an image cannot be parsed.
+#[derive(Debug)]
+pub enum LayoutError {
+    /// The flash window is smaller than the structure being read.
+    Truncated,
+    /// The image does not start with `"PFLA"`.
+    BadMagic,
+    /// The header records a byte order this app does not know.
+    BadEndian(u8),
+    /// The header's format version is newer than [`MAX_VERSION`].
+    NewerVersion(u16),
+    /// The header's format version is older than [`MIN_VERSION`].
+    OlderVersion(u16),
+    /// The manifest does not start with `"MNFS"`.
+    BadManifestMagic,
+    /// The header and manifest disagree on the number of regions.
+    CountMismatch,
+    /// A region name is not valid UTF-8.
+    BadName,
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "image truncated"),
+            Self::BadMagic => write!(f, "bad image magic"),
+            Self::BadEndian(marker) => write!(f, "unknown header byte order {marker:#04x}"),
+            Self::NewerVersion(version) => write!(
+                f,
+                "image format version {version} was written by a newer xtask \
+                 (this app reads up to version {MAX_VERSION}); rebuild the app"
+            ),
+            Self::OlderVersion(version) => write!(
+                f,
+                "image format version {version} was written by an older xtask \
+                 (this app reads version {MIN_VERSION} and later); recreate the image \
+                 with `cargo xtask mkimage`"
+            ),
+            Self::BadManifestMagic => write!(f, "bad manifest magic"),
+            Self::CountMismatch => write!(f, "header and manifest region counts differ"),
+            Self::BadName => write!(f, "region name is not UTF-8"),
+        }
+    }
+}
+
+/// Byte order of the header and manifest integers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// The byte order recorded by the [`ENDIAN_OFFSET`] byte `marker`.
+    fn from_marker(marker: u8) -> Result<Self, LayoutError> {
+        match marker {
+            ENDIAN_LITTLE => Ok(Self::Little),
+            ENDIAN_BIG => Ok(Self::Big),
+            _ => Err(LayoutError::BadEndian(marker)),
+        }
+    }
+
+    fn u16(self, bytes: &[u8], off: usize) -> u16 {
+        let bytes = [bytes[off], bytes[off + 1]];
+        match self {
+            Self::Little => u16::from_le_bytes(bytes),
+            Self::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(self, bytes: &[u8], off: usize) -> u32 {
+        let bytes = bytes[off..off + 4].try_into().unwrap();
+        match self {
+            Self::Little => u32::from_le_bytes(bytes),
+            Self::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn u64(self, bytes: &[u8], off: usize) -> u64 {
+        let bytes = bytes[off..off + 8].try_into().unwrap();
+        match self {
+            Self::Little => u64::from_le_bytes(bytes),
+            Self::Big => u64::from_be_bytes(bytes),
+        }
+    }
+}
+
+/// How an encrypted payload was sealed: the encryption fields of the
+/// header.
+#[derive(Debug)]
+pub struct Sealing {
+    /// AES-GCM nonce.
+    pub nonce: [u8; 12],
+    /// AES-GCM authentication tag.
+    pub tag: [u8; 16],
+    /// First 8 bytes of the SHA-256 of the key.
+    pub key_id: [u8; 8],
+}
+
+/// Geometry of the emulated flash bank, as `cfi.pflash01` takes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlashGeometry {
+    /// Erase block size of the bank, all chips together, in bytes.
+    pub sector_size: u32,
+    /// Bus width of one chip in bytes.
+    pub device_width: u8,
+    /// Number of chips side by side on the bus.
+    pub devices: u8,
+}
+
+impl FlashGeometry {
+    /// Width of the bus in bytes: the chips' widths together.
+    pub fn bank_width(&self) -> usize {
+        usize::from(self.device_width) * usize::from(self.devices)
+    }
+}
+
+impl fmt::Display for FlashGeometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} KiB erase blocks, {} x{} chip(s)",
+            self.sector_size / 1024,
+            self.devices,
+            u32::from(self.device_width) * 8
+        )
+    }
+}
+
+/// The fixed image header.
+#[derive(Debug)]
+pub struct Header {
+    pub endian: Endian,
+    pub version: u16,
+    pub flags: u16,
+    pub header_size: u32,
+    pub image_size: u32,
+    pub manifest_offset: u32,
+    pub region_count: u32,
+    pub kernel_offset: u32,
+    pub kernel_len: u32,
+    /// CRC-32 of the first [`HEADER_CRC_LEN`] bytes.
+    pub header_crc: u32,
+    pub pattern_seed: u64,
+    /// Erase block size of the flash geometry, 0 unless [`FLAG_GEOMETRY`]
+    /// is set.
+    pub sector_size: u32,
+    pub device_width: u8,
+    pub devices: u8,
+    /// The encryption fields, if the payload is encrypted.
+    pub sealing: Option<Sealing>,
+}
+
+impl Header {
+    /// Parse the header at the start of `flash`.
+    pub fn parse(flash: &[u8]) -> Result<Self, LayoutError> {
+        let raw = flash.get(..HEADER_SIZE).ok_or(LayoutError::Truncated)?;
+        if &raw[0..4] != MAGIC {
+            return Err(LayoutError::BadMagic);
+        }
+        let endian = Endian::from_marker(raw[ENDIAN_OFFSET])?;
+        let version = endian.u16(raw, 0x04);
+        if version > MAX_VERSION {
+            return Err(LayoutError::NewerVersion(version));
+        }
+        if version < MIN_VERSION {
+            return Err(LayoutError::OlderVersion(version));
+        }
+        let flags = endian.u16(raw, 0x06);
+        let sealing = match flags & FLAG_ENCRYPTED {
+            0 => None,
+            _ => {
+                let fields = flash
+                    .get(HEADER_SIZE..HEADER_SIZE + CRYPT_FIELDS_SIZE)
+                    .ok_or(LayoutError::Truncated)?;
+                Some(Sealing {
+                    nonce: fields[0x00..0x0C].try_into().unwrap(),
+                    tag: fields[0x10..0x20].try_into().unwrap(),
+                    key_id: fields[0x20..0x28].try_into().unwrap(),
+                })
+            }
+        };
+        Ok(Self {
+            endian,
+            version,
+            flags,
+            header_size: endian.u32(raw, 0x08),
+            image_size: endian.u32(raw, 0x0C),
+            manifest_offset: endian.u32(raw, 0x10),
+            region_count: endian.u32(raw, 0x14),
+            kernel_offset: endian.u32(raw, 0x18),
+            kernel_len: endian.u32(raw, 0x1C),
+            header_crc: endian.u32(raw, 0x20),
+            pattern_seed: endian.u64(raw, 0x28),
+            sector_size: endian.u32(raw, 0x30),
+            device_width: raw[0x34],
+            devices: raw[0x35],
+            sealing,
+        })
+    }
+
+    /// Offset and length of the embedded kernel image, if any.
+    pub fn kernel(&self) -> Option<(u32, u32)> {
+        (self.flags & FLAG_KERNEL != 0).then_some((self.kernel_offset, self.kernel_len))
+    }
+
+    /// Seed of the PRNG stream in the payload region, if it holds one.
+    pub fn pattern(&self) -> Option<u64> {
+        (self.flags & FLAG_PATTERN != 0).then_some(self.pattern_seed)
+    }
+
+    /// The flash geometry QEMU was asked to emulate, if the image records
+    /// one.
+    pub fn geometry(&self) -> Option<FlashGeometry> {
+        (self.flags & FLAG_GEOMETRY != 0).then_some(FlashGeometry {
+            sector_size: self.sector_size,
+            device_width: self.device_width,
+            devices: self.devices,
+        })
+    }
+}
+
+/// What a region holds, recorded in its manifest entry (the byte at
+/// [`KIND_OFFSET`]) so the app can hand it to the right parser. Images
+/// written before the tag existed have zero there, which reads as
+/// [`Kind::Raw`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Bytes with no structure the app knows, or records of its own.
+    Raw,
+    /// UTF-8 text.
+    Text,
+    /// `key=value` lines.
+    Config,
+    /// A flattened device tree.
+    Dtb,
+    /// A filesystem image or archive (cpio, ustar, romfs, ext2, gzip).
+    Archive,
+    /// Code: an ELF file, a raw kernel or firmware image, or the xip stub.
+    Executable,
+    /// A message catalog ([`catalog`]).
+    Catalog,
+}
+
+impl Kind {
+    /// Every kind, in tag order.
+    pub const ALL: [Kind; 7] = [
+        Kind::Raw,
+        Kind::Text,
+        Kind::Config,
+        Kind::Dtb,
+        Kind::Archive,
+        Kind::Executable,
+        Kind::Catalog,
+    ];
+
+    /// The kind with manifest tag `tag`, if this app knows it.
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(tag)).copied()
+    }
+
+    /// The manifest tag of the kind.
+    pub fn tag(self) -> u8 {
+        self as u8
+    }
+
+    /// The kind called `name`, as [`Kind::name`] spells it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// Lower-case name, as `--payload-type` and layout files take it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Raw => "raw",
+            Kind::Text => "text",
+            Kind::Config => "config",
+            Kind::Dtb => "dtb",
+            Kind::Archive => "archive",
+            Kind::Executable => "executable",
+            Kind::Catalog => "catalog",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// One manifest entry describing a region of the image.
+pub struct Region<'a> {
+    pub name: &'a str,
+    pub offset: u32,
+    pub len: u32,
+    pub flags: u32,
+    /// [`Kind`] tag; see [`Region::kind`].
+    pub tag: u8,
+    pub sha256: &'a [u8; 32],
+}
+
+impl<'a> Region<'a> {
+    /// The region's bytes, or `None` if it extends past the end of `flash`.
+    pub fn data<'f>(&self, flash: &'f [u8]) -> Option<&'f [u8]> {
+        let start = self.offset as usize;
+        flash.get(start..start.checked_add(self.len as usize)?)
+    }
+
+    /// Whether the guest modifies the region at runtime.
+    pub fn writable(&self) -> bool {
+        self.flags & REGION_WRITABLE != 0
+    }
+
+    /// What the region holds, or `None` for a tag newer than this app.
+    pub fn kind(&self) -> Option<Kind> {
+        Kind::from_tag(self.tag)
+    }
+}
+
+/// The manifest: a table of named, hashed regions.
+pub struct Manifest<'a> {
+    entries: &'a [u8],
+    endian: Endian,
+}
+
+impl<'a> Manifest<'a> {
+    /// Locate and validate the manifest described by `header`.
+    pub fn parse(flash: &'a [u8], header: &Header) -> Result<Self, LayoutError> {
+        let start = header.manifest_offset as usize;
+        let body = start
+            .checked_add(MANIFEST_PREAMBLE)
+            .ok_or(LayoutError::Truncated)?;
+        let preamble = flash.get(start..body).ok_or(LayoutError::Truncated)?;
+        if &preamble[0..4] != MANIFEST_MAGIC {
+            return Err(LayoutError::BadManifestMagic);
+        }
+        let count = header.endian.u32(preamble, 4);
+        if count != header.region_count {
+            return Err(LayoutError::CountMismatch);
+        }
+        let len = (count as usize)
+            .checked_mul(MANIFEST_ENTRY_SIZE)
+            .ok_or(LayoutError::Truncated)?;
+        let entries = flash
+            .get(body..body.checked_add(len).ok_or(LayoutError::Truncated)?)
+            .ok_or(LayoutError::Truncated)?;
+        Ok(Self {
+            entries,
+            endian: header.endian,
+        })
+    }
+
+    /// Number of regions listed.
+    pub fn len(&self) -> usize {
+        self.entries.len() / MANIFEST_ENTRY_SIZE
+    }
+
+    /// Whether no regions are listed.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over the listed regions.
+    pub fn regions(&self) -> impl Iterator<Item = Result<Region<'a>, LayoutError>> {
+        let endian = self.endian;
+        self.entries
+            .chunks_exact(MANIFEST_ENTRY_SIZE)
+            .map(move |e| {
+                let name = &e[..NAME_LEN];
+                let name_len = name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+                Ok(Region {
+                    name: core::str::from_utf8(&name[..name_len])
+                        .map_err(|_| LayoutError::BadName)?,
+                    offset: endian.u32(e, 0x10),
+                    len: endian.u32(e, 0x14),
+                    flags: endian.u32(e, 0x18),
+                    tag: e[KIND_OFFSET],
+                    sha256: e[0x20..0x40].try_into().unwrap(),
+                })
+            })
+    }
+}
+
+/// The offset, length and writability of the first region of the image in
+/// `flash` that `pick` accepts, skipping entries that do not parse. Copied
+/// out of the manifest, so the caller may go on to change the flash.
+pub fn find_region(
+    flash: &[u8],
+    pick: impl Fn(&Region) -> bool,
+) -> Result<Option<(usize, usize, bool)>, LayoutError> {
+    let header = Header::parse(flash)?;
+    let manifest = Manifest::parse(flash, &header)?;
+    Ok(manifest
+        .regions()
+        .flatten()
+        .find(|r| pick(r))
+        .map(|r| (r.offset as usize, r.len as usize, r.writable())))
+}
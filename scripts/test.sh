@@ -41,6 +41,9 @@ check_format() {
         cargo fmt -- --check
     done
     
+    echo "  Checking configs/*.toml match the template..."
+    cargo xtask gen-configs --check
+    
     echo "✓ Code format check passed for all architectures"
     echo ""
 }
@@ -0,0 +1,57 @@
+//! Finding the bank that holds the image.
+//!
+//! The app is built for one bank (`PFLASH_PADDR`, see `build.rs`) and
+//! `cargo xtask run` attaches the image to the same unit. A `-drive` written
+//! by hand, or a `unit=` that disagrees with the build, puts it on the other
+//! bank instead, and the demos would read firmware bytes or erased flash as
+//! the image. [`select`] looks for an image header in the bank the app is
+//! built for and, if there is none, in the machine's other bank, and reads
+//! whichever has one.
+
+use crate::layout::{Header, MAGIC};
+
+/// Whether the bank of `size` bytes at `va` starts with an image header,
+/// or with a partition table the `partitions` feature reads the image
+/// through.
+fn has_image(va: usize, size: usize) -> bool {
+    let magic = unsafe { (va as *const [u8; 4]).read_volatile() };
+    let bank = unsafe { core::slice::from_raw_parts(va as *const u8, size) };
+    #[cfg(feature = "partitions")]
+    if crate::partition::has_table(bank) {
+        return true;
+    }
+    magic == *MAGIC && Header::parse(bank).is_ok()
+}
+
+/// The bank to read, as (unit, physical address): `built`, the one the app
+/// is built for, if it holds an image, else `other` if that one does, else
+/// `built` again so the image check reports what is there. `va_of` gives the
+/// address to read a bank at, or `None` if it must not be read.
+pub fn select(
+    built: (u8, usize),
+    other: Option<(u8, usize)>,
+    size: usize,
+    va_of: impl Fn(usize) -> Option<usize>,
+) -> (u8, usize) {
+    let found = |(_, paddr): (u8, usize)| va_of(paddr).is_some_and(|va| has_image(va, size));
+    if found(built) {
+        println!("Bank: image found in pflash{} at {:#X}", built.0, built.1);
+        return built;
+    }
+    let Some(other) = other else {
+        return built;
+    };
+    if found(other) {
+        println!(
+            "Bank: no image in pflash{} at {:#X}, but in pflash{} at {:#X}; reading that \
+             (attach the image with unit={} to match the build)",
+            built.0, built.1, other.0, other.1, built.0
+        );
+        return other;
+    }
+    println!(
+        "Bank: no image in pflash{} at {:#X} or pflash{} at {:#X}",
+        built.0, built.1, other.0, other.1
+    );
+    built
+}
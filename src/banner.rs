@@ -0,0 +1,34 @@
+//! Boot banner stored in flash.
+//!
+//! `cargo xtask mkimage --banner <FILE|TEXT>` stores UTF-8 text in the
+//! "banner" region. The app prints it first thing, so a serial log shows
+//! which image variant it came from.
+
+use crate::layout::{Header, Manifest};
+
+/// Print the banner of the image in the bank of `size` bytes at `base`.
+pub fn run(base: usize, size: usize) {
+    let flash = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest
+            .regions()
+            .flatten()
+            .find(|r| r.name == "banner")
+            .and_then(|r| r.data(flash)))
+    });
+    match region {
+        Ok(Some(data)) => match core::str::from_utf8(data) {
+            Ok(text) => {
+                for line in text.lines() {
+                    println!("{line}");
+                }
+            }
+            Err(_) => println!("Banner: the banner region is not UTF-8"),
+        },
+        Ok(None) => {
+            println!("Banner: none (add one with `cargo xtask mkimage --banner <FILE|TEXT>`)")
+        }
+        Err(e) => println!("Banner: cannot read manifest: {e}"),
+    }
+}
@@ -0,0 +1,108 @@
+//! Benchmark results in flash.
+//!
+//! [`record`] appends the figures of a write-combining benchmark run to the
+//! writable "bench" region (`cargo xtask mkimage --bench-region`), so runs
+//! accumulate across boots and can be compared on the host with
+//! `cargo xtask image inspect --bench`. The region holds one record per
+//! 64-byte slot, in the order written:
+//!
+//! ```text
+//! 0x00  magic         "BNCH", programmed last
+//! 0x04  arch          u32  1 riscv64, 2 aarch64, 3 x86_64, 4 loongarch64
+//! 0x08  span          u32  bytes read per pass
+//! 0x0C  passes        u32  passes per mapping
+//! 0x10  device_ns     u64  best pass through the device mapping
+//! 0x18  relaxed_ns    u64  best pass through the relaxed mapping
+//! 0x20  device_mibs   u32
+//! 0x24  relaxed_mibs  u32
+//! 0x28  counter       u64  `cycles::cycles()` when written
+//! ```
+//!
+//! When every slot is taken the region is erased and filling starts over.
+
+use crate::cfi::CfiFlash;
+use crate::cycles::cycles;
+use crate::layout::{BENCH_MAGIC, BENCH_SLOT, Header, Manifest};
+use crate::wcmap::Sample;
+
+const RECORD_SIZE: usize = 0x30;
+
+#[cfg(target_arch = "riscv64")]
+const ARCH: u32 = 1;
+#[cfg(target_arch = "aarch64")]
+const ARCH: u32 = 2;
+#[cfg(target_arch = "x86_64")]
+const ARCH: u32 = 3;
+#[cfg(target_arch = "loongarch64")]
+const ARCH: u32 = 4;
+
+fn encode(sample: &Sample) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+    let mut put = |at: usize, bytes: &[u8]| record[at..at + bytes.len()].copy_from_slice(bytes);
+    put(0x04, &ARCH.to_le_bytes());
+    put(0x08, &(sample.span as u32).to_le_bytes());
+    put(0x0C, &(sample.passes as u32).to_le_bytes());
+    put(0x10, &(sample.device.as_nanos() as u64).to_le_bytes());
+    put(0x18, &(sample.relaxed.as_nanos() as u64).to_le_bytes());
+    put(0x20, &(sample.device_mibs as u32).to_le_bytes());
+    put(0x24, &(sample.relaxed_mibs as u32).to_le_bytes());
+    put(0x28, &cycles().to_le_bytes());
+    record
+}
+
+/// Append `sample` to the bench region of the bank of `size` bytes mapped
+/// at `base`.
+pub fn record(base: usize, size: usize, sample: &Sample) {
+    let flash = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest
+            .regions()
+            .flatten()
+            .find(|r| r.name == "bench" && r.writable())
+            .map(|r| (r.offset as usize, r.len as usize)))
+    });
+    let (offset, len) = match region {
+        Ok(Some((offset, len))) if len >= BENCH_SLOT => (offset, len),
+        Ok(_) => {
+            println!(
+                "Bench record: no writable bench region in the image (create it with `cargo xtask mkimage --bench-region`)"
+            );
+            return;
+        }
+        Err(e) => {
+            println!("Bench record: cannot read manifest: {e}");
+            return;
+        }
+    };
+    let earlier = flash[offset..offset + len]
+        .chunks_exact(BENCH_SLOT)
+        .filter(|slot| slot.starts_with(BENCH_MAGIC))
+        .count();
+    let free = flash[offset..offset + len]
+        .chunks_exact(BENCH_SLOT)
+        .position(|slot| slot[..4] == [0xFF; 4]);
+
+    let record = encode(sample);
+    let written = CfiFlash::probe(base).ok().and_then(|flash| {
+        let slot = match free {
+            Some(index) => offset + index * BENCH_SLOT,
+            None => {
+                flash.erase_range(offset, len).ok()?;
+                offset
+            }
+        };
+        flash.program(slot + 4, &record[4..]).ok()?;
+        flash.program(slot, BENCH_MAGIC).ok()?;
+        Some(slot)
+    });
+    match (written, free) {
+        (Some(slot), Some(_)) => {
+            println!("Bench record: written to flash at {slot:#x} ({earlier} earlier result(s))")
+        }
+        (Some(slot), None) => {
+            println!("Bench record: region full, erased it and wrote at {slot:#x}")
+        }
+        (None, _) => println!("Bench record: could not write to flash"),
+    }
+}
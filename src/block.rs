@@ -0,0 +1,214 @@
+//! Block device view of flash.
+//!
+//! Filesystem drivers that expect a disk read fixed-size sectors through
+//! [`BlockDevice`]. [`FlashBlocks`] serves them from a region of the flash
+//! mapping, so the same driver would work on top of a real disk.
+//! `FlashBlocksMut` adds writes, carried out with the CFI program and erase
+//! commands.
+
+use core::fmt;
+#[cfg(feature = "fs-write")]
+use {
+    crate::cfi::{CfiFlash, FlashError},
+    std::vec,
+    std::vec::Vec,
+};
+
+/// Sector size exposed by [`FlashBlocks`].
+pub const SECTOR_SIZE: usize = 512;
+
+/// Reasons a block cannot be read or written.
+#[derive(Debug)]
+pub enum BlockError {
+    /// The block lies past the end of the device.
+    OutOfRange(u64),
+    /// Programming or erasing failed.
+    #[cfg(feature = "fs-write")]
+    Flash(FlashError),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfRange(lba) => write!(f, "block {lba} out of range"),
+            #[cfg(feature = "fs-write")]
+            Self::Flash(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// A read-only device of fixed-size blocks.
+pub trait BlockDevice {
+    /// Size of every block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device.
+    fn num_blocks(&self) -> u64;
+
+    /// Read block `lba` into `buf`, which is one block long.
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Read `buf.len()` bytes starting at byte `offset`, which need not be
+    /// block-aligned.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let bs = self.block_size();
+        let mut block = [0; SECTOR_SIZE];
+        let block = &mut block[..bs.min(SECTOR_SIZE)];
+        debug_assert_eq!(block.len(), bs, "read_at supports blocks up to SECTOR_SIZE");
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let skip = (pos % bs as u64) as usize;
+            self.read_block(pos / bs as u64, block)?;
+            let n = (bs - skip).min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&block[skip..skip + n]);
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+/// A block device that can also be written.
+#[cfg(feature = "fs-write")]
+pub trait BlockDeviceMut: BlockDevice {
+    /// Write `buf`, which is one block long, to block `lba`. The data may
+    /// stay cached until [`flush`](Self::flush).
+    fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Write all cached data to the medium.
+    fn flush(&mut self) -> Result<(), BlockError>;
+}
+
+/// 512-byte sectors backed by a slice of the flash mapping.
+pub struct FlashBlocks<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> FlashBlocks<'a> {
+    /// Expose `data` as a block device; a partial last sector is dropped.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl BlockDevice for FlashBlocks<'_> {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let start = usize::try_from(lba)
+            .ok()
+            .and_then(|lba| lba.checked_mul(SECTOR_SIZE))
+            .ok_or(BlockError::OutOfRange(lba))?;
+        let sector = self
+            .data
+            .get(start..start + SECTOR_SIZE)
+            .ok_or(BlockError::OutOfRange(lba))?;
+        buf.copy_from_slice(sector);
+        Ok(())
+    }
+}
+
+/// 512-byte sectors of a writable flash region.
+///
+/// Flash is erased a whole erase block at a time, so a sector write loads
+/// the erase block around it into a RAM cache. Flushing erases the block
+/// and programs the cache back, which also preserves the bytes of the block
+/// outside the region. When the new contents only clear bits, the erase is
+/// skipped.
+#[cfg(feature = "fs-write")]
+pub struct FlashBlocksMut<'a> {
+    flash: &'a CfiFlash,
+    /// Offset of the region in the bank.
+    offset: usize,
+    len: usize,
+    cache: Vec<u8>,
+    /// Bank offset of the cached erase block.
+    cached: Option<usize>,
+    dirty: bool,
+}
+
+#[cfg(feature = "fs-write")]
+impl<'a> FlashBlocksMut<'a> {
+    /// Expose the `len` bytes at bank offset `offset` as a block device.
+    pub fn new(flash: &'a CfiFlash, offset: usize, len: usize) -> Self {
+        Self {
+            flash,
+            offset,
+            len,
+            cache: vec![0; flash.erase_size],
+            cached: None,
+            dirty: false,
+        }
+    }
+
+    /// Bank offset of sector `lba`.
+    fn sector(&self, lba: u64) -> Result<usize, BlockError> {
+        usize::try_from(lba)
+            .ok()
+            .and_then(|lba| lba.checked_mul(SECTOR_SIZE))
+            .filter(|&start| start + SECTOR_SIZE <= self.len)
+            .map(|start| self.offset + start)
+            .ok_or(BlockError::OutOfRange(lba))
+    }
+
+    /// Make the erase block at `block` the cached one.
+    fn load(&mut self, block: usize) -> Result<(), BlockError> {
+        if self.cached != Some(block) {
+            self.flush()?;
+            self.flash.read(block, &mut self.cache);
+            self.cached = Some(block);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fs-write")]
+impl BlockDevice for FlashBlocksMut<'_> {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.len / SECTOR_SIZE) as u64
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let pos = self.sector(lba)?;
+        match self.cached {
+            Some(block) if (block..block + self.cache.len()).contains(&pos) => {
+                buf.copy_from_slice(&self.cache[pos - block..pos - block + SECTOR_SIZE]);
+            }
+            _ => self.flash.read(pos, buf),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fs-write")]
+impl BlockDeviceMut for FlashBlocksMut<'_> {
+    fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let pos = self.sector(lba)?;
+        let block = pos - pos % self.flash.erase_size;
+        self.load(block)?;
+        self.cache[pos - block..pos - block + SECTOR_SIZE].copy_from_slice(buf);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        let Some(block) = self.cached.filter(|_| self.dirty) else {
+            return Ok(());
+        };
+        self.flash
+            .rewrite_block(block, &self.cache)
+            .map_err(BlockError::Flash)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
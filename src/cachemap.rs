@@ -0,0 +1,167 @@
+//! Reads through a cacheable alias of the flash window.
+//!
+//! A NOR bank must be read through a device (or at least uncached) mapping
+//! whenever commands are in flight, since a cached line can hold array
+//! data from before a command. In read-array mode, though, the bank reads
+//! like ROM, and firmware that only copies data out could map it cacheable.
+//! This experiment measures what that would buy: it maps a read-only alias
+//! of the start of the bank as normal cacheable memory, at a fixed offset
+//! from the linear map, and compares data and throughput with the device
+//! mapping.
+//!
+//! The mode is guarded. It only runs while the bank holds the image magic,
+//! that is, in read-array mode. The alias has no write permission, so a
+//! stray command write through it faults instead of reaching the chip. It
+//! is unmapped again before any demo issues commands, and the lines it
+//! filled are clean, so nothing is ever written back to the bank. The
+//! caveats of each architecture are printed with the figures.
+
+use crate::cycles::{Span, Stopwatch};
+use crate::layout::MAGIC;
+use std::os::arceos::modules::axhal::mem::{PhysAddr, VirtAddr};
+use std::os::arceos::modules::axhal::paging::MappingFlags;
+use std::os::arceos::modules::axmm::kernel_aspace;
+
+/// Bytes read per pass, from the start of the bank.
+const SPAN: usize = 1024 * 1024;
+/// Passes through each mapping; the fastest one counts.
+const PASSES: usize = 4;
+/// Distance of the alias from the bank's linear-map address; far past
+/// any RAM or MMIO the linear map covers, yet inside the kernel address
+/// space on every architecture here.
+const ALIAS_OFFSET: usize = 1 << 36;
+
+/// What a cacheable alias of a device region means on this architecture.
+#[cfg(target_arch = "aarch64")]
+const CAVEAT: &str = "Normal Write-Back over a Device-nGnRE bank is a mismatched-attribute alias; \
+                      only clean reads through it are safe, and its lines outlive the unmapping \
+                      until evicted";
+#[cfg(target_arch = "riscv64")]
+const CAVEAT: &str = "without Svpbmt the page attributes are ignored and the platform's PMAs decide, \
+                      so both mappings may well behave alike";
+#[cfg(target_arch = "x86_64")]
+const CAVEAT: &str = "the MTRRs mark the flash range uncacheable, which wins over the page's \
+                      write-back type, so the alias is likely uncached as well";
+#[cfg(target_arch = "loongarch64")]
+const CAVEAT: &str = "the bank is reached through a direct-mapped window, not the page table";
+
+/// Read `SPAN` bytes at `va` 64 bits at a time and fold them together.
+fn pass(va: usize) -> (Span, u64) {
+    let watch = Stopwatch::start();
+    let mut sum = 0u64;
+    for off in (0..SPAN).step_by(8) {
+        let word = unsafe { ((va + off) as *const u64).read_volatile() };
+        sum = sum.rotate_left(1) ^ word;
+    }
+    (watch.stop(), sum)
+}
+
+/// The first of [`PASSES`] passes, the fastest, and the value they all
+/// folded to, or `None` if they disagree.
+fn time(va: usize) -> (Span, Span, Option<u64>) {
+    let (first, sum) = pass(va);
+    let mut best = first;
+    let mut agree = true;
+    for _ in 1..PASSES {
+        let (span, again) = pass(va);
+        best = best.min(span);
+        agree &= again == sum;
+    }
+    (first, best, agree.then_some(sum))
+}
+
+fn mib_per_s(span: Span) -> u128 {
+    SPAN as u128 * 1_000_000_000 / span.time.as_nanos().max(1) / (1024 * 1024)
+}
+
+/// Compare reads of the bank at physical address `phys`, mapped as device
+/// memory at `va`, with reads through a cacheable alias of it.
+///
+/// Returns `true` if both read the same data, or if the mode does not
+/// apply here.
+pub fn run(phys: usize, va: usize, size: usize) -> bool {
+    println!("Cached alias: {CAVEAT}");
+    if cfg!(target_arch = "loongarch64") {
+        println!("Cached alias: skipped");
+        return true;
+    }
+    if size < SPAN {
+        println!("Cached alias: skipped (the bank is smaller than {SPAN:#x} bytes)");
+        return true;
+    }
+    // Guard: only while the bank reads as an array.
+    let magic = unsafe { (va as *const [u8; 4]).read_volatile() };
+    if magic != *MAGIC {
+        println!("Cached alias: skipped (the bank is not in read-array mode with an image)");
+        return true;
+    }
+    let alias = va + ALIAS_OFFSET;
+    {
+        let mut aspace = kernel_aspace().lock();
+        if aspace.page_table().query(VirtAddr::from(alias)).is_ok()
+            || aspace
+                .page_table()
+                .query(VirtAddr::from(alias + SPAN - 1))
+                .is_ok()
+        {
+            println!("Cached alias: skipped ({alias:#x} is in use)");
+            return true;
+        }
+        // Read-only, and neither DEVICE nor UNCACHED: normal cacheable.
+        if let Err(e) = aspace.map_linear(
+            VirtAddr::from(alias),
+            PhysAddr::from(phys),
+            SPAN,
+            MappingFlags::READ,
+        ) {
+            println!("Cached alias: skipped (cannot map an alias at {alias:#x}: {e})");
+            return true;
+        }
+    }
+    println!(
+        "Cached alias: reading {} KiB at {va:#x} (device) and {alias:#x} (cacheable, read-only), {PASSES} passes",
+        SPAN / 1024
+    );
+    let (device_first, device_best, device_sum) = time(va);
+    let (cached_first, cached_best, cached_sum) = time(alias);
+    let unmapped = kernel_aspace()
+        .lock()
+        .unmap(VirtAddr::from(alias), SPAN)
+        .is_ok();
+
+    for (label, first, best) in [
+        ("device", device_first, device_best),
+        ("cached", cached_first, cached_best),
+    ] {
+        println!(
+            "  {label:<8} first {:>8} us  best {:>8} us  {:>6} MiB/s  {:>10} cycles",
+            first.time.as_micros(),
+            best.time.as_micros(),
+            mib_per_s(best),
+            best.cycles
+        );
+    }
+    if !unmapped {
+        println!("Cached alias: FAIL (cannot unmap the alias at {alias:#x})");
+        return false;
+    }
+    match (device_sum, cached_sum) {
+        (Some(a), Some(b)) if a == b => {
+            let ratio = device_best.cycles * 100 / cached_best.cycles.max(1);
+            println!(
+                "Cached alias: PASS (same data; cached is {}.{:02}x the device rate)",
+                ratio / 100,
+                ratio % 100
+            );
+            true
+        }
+        (Some(_), Some(_)) => {
+            println!("Cached alias: FAIL (the alias read different data)");
+            false
+        }
+        _ => {
+            println!("Cached alias: FAIL (passes through one mapping read different data)");
+            false
+        }
+    }
+}
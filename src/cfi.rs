@@ -0,0 +1,471 @@
+//! Minimal driver for the Intel/Sharp command set (CFI ID 0001) NOR flash
+//! that QEMU emulates as `cfi.pflash01`.
+//!
+//! The bank is accessed through its MMIO mapping. Plain loads work while
+//! the device is in read-array mode; program and erase commands switch it
+//! to status mode, so every operation here ends by writing `READ_ARRAY`
+//! again. Commands are issued on the full bank width, with the command in
+//! the low byte.
+//!
+//! A bank may be built from several narrower chips side by side: the virt
+//! machines use two 16-bit devices on a 32-bit bus. Each chip answers
+//! queries with its own copy of the data, and its CFI table describes only
+//! itself, so [`CfiFlash::probe`] counts the copies and scales the geometry.
+//!
+//! The bus width is the machine's, [`BANK_WIDTH`], unless the image header
+//! records the geometry QEMU was asked to emulate (`cargo xtask run
+//! --flash-geometry`), whose width [`CfiFlash::probe`] then drives.
+//!
+//! Program and erase poll the status register until the chip is ready, up
+//! to [`CfiFlash::timeouts`], and turn its error bits into a [`FlashError`].
+//! A block erase can also be started, suspended to read other blocks, and
+//! resumed (see [`CfiFlash::erase_start`]).
+
+use crate::layout::{CRYPT_FIELDS_SIZE, HEADER_SIZE, Header};
+use core::fmt;
+use std::time::{Duration, Instant};
+
+/// Bank width the machines configure by default: 1 byte on x86_64 (the
+/// firmware flash of `q35`), 4 bytes on the virt machines.
+#[cfg(target_arch = "x86_64")]
+pub const BANK_WIDTH: usize = 1;
+#[cfg(not(target_arch = "x86_64"))]
+pub const BANK_WIDTH: usize = 4;
+
+const CMD_READ_ARRAY: u8 = 0xFF;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_QUERY: u8 = 0x98;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_PROGRAM: u8 = 0x40;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+/// Also resumes a suspended erase.
+const CMD_CONFIRM: u8 = 0xD0;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_SUSPEND: u8 = 0xB0;
+
+/// Offset of the query command, in bank-width units.
+const QUERY_ADDR: usize = 0x55;
+/// Status register: write state machine ready.
+const STATUS_READY: u8 = 0x80;
+/// Status register: erase suspended.
+const STATUS_ERASE_SUSPENDED: u8 = 0x40;
+/// Status register: erase error.
+const STATUS_ERASE_ERROR: u8 = 0x20;
+/// Status register: block locked.
+const STATUS_LOCKED: u8 = 0x02;
+/// Status register: erase, program, Vpp and block lock errors.
+const STATUS_ERRORS: u8 = 0x3A;
+
+/// Reasons a program or erase operation fails.
+#[derive(Clone, Copy, Debug)]
+pub enum FlashError {
+    /// The chip stayed busy longer than the timeout.
+    Timeout,
+    /// Programming failed; the status register.
+    ProgramFailed(u8),
+    /// Erasing failed; the status register.
+    EraseFailed(u8),
+    /// The block is locked against program and erase.
+    Locked,
+}
+
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "flash operation timed out"),
+            Self::ProgramFailed(status) => write!(f, "program failed (status {status:#04x})"),
+            Self::EraseFailed(status) => write!(f, "erase failed (status {status:#04x})"),
+            Self::Locked => write!(f, "block is locked"),
+        }
+    }
+}
+
+/// How long a single operation may keep the chip busy.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// One bank-width word program.
+    pub program: Duration,
+    /// One block erase.
+    pub erase: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            program: Duration::from_millis(10),
+            erase: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Ten times the maximum times in the CFI table, and at least the
+    /// defaults: emulated and virtualized chips can be slower than the
+    /// datasheet.
+    fn from_geometry(g: &Geometry) -> Self {
+        let default = Self::default();
+        Self {
+            program: default
+                .program
+                .max(Duration::from_micros(u64::from(g.program_us.1) * 10)),
+            erase: default
+                .erase
+                .max(Duration::from_millis(u64::from(g.erase_ms.1) * 10)),
+        }
+    }
+}
+
+/// Kinds of operation [`CfiFlash::finish`] waits for.
+#[derive(Clone, Copy)]
+enum Op {
+    Program,
+    Erase,
+}
+
+/// Codes reported in read-identifier (autoselect) mode.
+pub struct Ident {
+    /// JEDEC manufacturer code.
+    pub manufacturer: u16,
+    pub device: u16,
+}
+
+impl Ident {
+    /// Name of the manufacturer, for the codes QEMU machines use.
+    pub fn vendor(&self) -> &'static str {
+        match self.manufacturer {
+            0x89 => "Intel",
+            0x01 => "AMD/Spansion",
+            0x20 => "ST/Micron",
+            0xC2 => "Macronix",
+            0x00 => "none",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Bank layout and timing from the CFI query table.
+pub struct Geometry {
+    /// Number of chips side by side on the bus.
+    pub interleave: usize,
+    /// Device interface code (0 x8, 1 x16, 2 x8/x16, 5 x16/x32).
+    pub interface: u16,
+    /// Erase block regions of different block sizes.
+    pub erase_regions: usize,
+    /// Blocks in the first erase block region.
+    pub blocks: usize,
+    /// Write buffer size in bytes for the whole bank, 0 if none.
+    pub write_buffer: usize,
+    /// Typical and maximum single word program time in microseconds.
+    pub program_us: (u32, u32),
+    /// Typical and maximum buffer write time in microseconds, 0 if none.
+    pub buffer_us: (u32, u32),
+    /// Typical and maximum block erase time in milliseconds.
+    pub erase_ms: (u32, u32),
+}
+
+/// A block erase that was started and not yet waited for.
+#[must_use = "the erase must be waited for with `erase_wait`"]
+pub struct PendingErase {
+    block: usize,
+}
+
+/// A probed flash bank.
+pub struct CfiFlash {
+    base: usize,
+    /// Bus width in bytes: 1, 2 or 4.
+    pub width: usize,
+    /// Bank size in bytes, from the CFI query table.
+    pub size: usize,
+    /// Erase block size in bytes, from the CFI query table.
+    pub erase_size: usize,
+    pub geometry: Geometry,
+    /// Limits for program and erase; derived from the CFI table and free
+    /// to change.
+    pub timeouts: Timeouts,
+}
+
+/// `byte` in the low lanes of each of `chips` chips sharing a bank word
+/// of `width` bytes.
+fn replicate(byte: u32, chips: usize, width: usize) -> u32 {
+    let lanes = (width * 8 / chips) as u32;
+    (0..chips as u32).fold(0, |word, i| word | byte << (i * lanes))
+}
+
+impl CfiFlash {
+    /// The bus width the image at `base` records, if its header has a
+    /// flash geometry.
+    fn recorded_width(base: usize) -> Option<usize> {
+        // With the encryption fields, which `Header::parse` reads too.
+        let len = HEADER_SIZE + CRYPT_FIELDS_SIZE;
+        let header = unsafe { core::slice::from_raw_parts(base as *const u8, len) };
+        let width = Header::parse(header).ok()?.geometry()?.bank_width();
+        [1, 2, 4].contains(&width).then_some(width)
+    }
+
+    /// Identify the flash mapped at virtual address `base` through its CFI
+    /// query table, on the bus width the image there records or else
+    /// [`BANK_WIDTH`].
+    pub fn probe(base: usize) -> Result<Self, &'static str> {
+        let width = Self::recorded_width(base).unwrap_or(BANK_WIDTH);
+        let mut flash = Self {
+            base,
+            width,
+            size: 0,
+            erase_size: 0,
+            geometry: Geometry {
+                interleave: 1,
+                interface: 0,
+                erase_regions: 0,
+                blocks: 0,
+                write_buffer: 0,
+                program_us: (0, 0),
+                buffer_us: (0, 0),
+                erase_ms: (0, 0),
+            },
+            timeouts: Timeouts::default(),
+        };
+        flash.command(QUERY_ADDR * width, CMD_QUERY);
+        // Every chip returns the 'Q' of "QRY" in its own lanes.
+        let cell = flash.read_cell(0x10 * width);
+        let interleave = [1, 2, 4]
+            .into_iter()
+            .filter(|&n| n <= width)
+            .find(|&n| cell == replicate(u32::from(b'Q'), n, width));
+        let q = |i: usize| flash.read_cell(i * width) as u8;
+        let q16 = |i: usize| u16::from(q(i)) | u16::from(q(i + 1)) << 8;
+        let qry = [q(0x10), q(0x11), q(0x12)];
+        let command_set = q16(0x13);
+        // Typical times are 2^n, maximum times 2^m times the typical ones.
+        let time = |typ: usize, max: usize| match q(typ) {
+            0 => (0, 0),
+            n => (1 << n, 1 << (n + q(max))),
+        };
+        let program_us = time(0x1F, 0x23);
+        let buffer_us = time(0x20, 0x24);
+        let erase_ms = time(0x21, 0x25);
+        let chip_size = 1usize << q(0x27);
+        let interface = q16(0x28);
+        let chip_buffer = match q16(0x2A) {
+            0 => 0,
+            n => 1usize << n,
+        };
+        let erase_regions = usize::from(q(0x2C));
+        let blocks = usize::from(q16(0x2D)) + 1;
+        let chip_erase_size = usize::from(q16(0x2F)) * 256;
+        flash.command(0, CMD_READ_ARRAY);
+        if &qry != b"QRY" {
+            return Err("no CFI query table");
+        }
+        let Some(interleave) = interleave else {
+            return Err("unsupported chip interleave");
+        };
+        if command_set != 1 {
+            return Err("not an Intel/Sharp command set device");
+        }
+        flash.size = chip_size * interleave;
+        flash.erase_size = chip_erase_size * interleave;
+        flash.geometry = Geometry {
+            interleave,
+            interface,
+            erase_regions,
+            blocks,
+            write_buffer: chip_buffer * interleave,
+            program_us,
+            buffer_us,
+            erase_ms,
+        };
+        flash.timeouts = Timeouts::from_geometry(&flash.geometry);
+        Ok(flash)
+    }
+
+    /// Read the manufacturer and device codes in read-identifier mode.
+    ///
+    /// Intel command set chips enter it with a single command; the
+    /// `0xAA`/`0x55` unlock cycles are only needed by the AMD command set.
+    pub fn identify(&self) -> Ident {
+        // Keep one chip's lanes; the others return the same codes.
+        let mask = (1u64 << (self.width * 8 / self.geometry.interleave)) - 1;
+        self.command(0, CMD_READ_ID);
+        let manufacturer = (u64::from(self.read_cell(0)) & mask) as u16;
+        let device = (u64::from(self.read_cell(self.width)) & mask) as u16;
+        self.command(0, CMD_READ_ARRAY);
+        Ident {
+            manufacturer,
+            device,
+        }
+    }
+
+    fn read_cell(&self, off: usize) -> u32 {
+        let addr = self.base + off;
+        unsafe {
+            match self.width {
+                1 => u32::from((addr as *const u8).read_volatile()),
+                2 => u32::from((addr as *const u16).read_volatile()),
+                _ => (addr as *const u32).read_volatile(),
+            }
+        }
+    }
+
+    fn write_cell(&self, off: usize, value: u32) {
+        let addr = self.base + off;
+        unsafe {
+            match self.width {
+                1 => (addr as *mut u8).write_volatile(value as u8),
+                2 => (addr as *mut u16).write_volatile(value as u16),
+                _ => (addr as *mut u32).write_volatile(value),
+            }
+        }
+    }
+
+    fn command(&self, off: usize, cmd: u8) {
+        self.write_cell(off, u32::from(cmd));
+    }
+
+    /// Poll the status register at `off` until the write state machine is
+    /// ready, and return it.
+    fn wait_ready(&self, off: usize, timeout: Duration) -> Result<u8, FlashError> {
+        let start = Instant::now();
+        loop {
+            let status = self.read_cell(off) as u8;
+            if status & STATUS_READY != 0 {
+                return Ok(status);
+            }
+            if start.elapsed() > timeout {
+                self.command(off, CMD_READ_ARRAY);
+                return Err(FlashError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wait for the write state machine, then return to read-array mode.
+    ///
+    /// On failure the status register is cleared and the error bits are
+    /// translated for the kind of operation.
+    fn finish(&self, off: usize, op: Op) -> Result<(), FlashError> {
+        let timeout = match op {
+            Op::Program => self.timeouts.program,
+            Op::Erase => self.timeouts.erase,
+        };
+        let status = self.wait_ready(off, timeout)?;
+        if status & STATUS_ERRORS != 0 {
+            self.command(off, CMD_CLEAR_STATUS);
+        }
+        self.command(off, CMD_READ_ARRAY);
+        if status & STATUS_LOCKED != 0 {
+            Err(FlashError::Locked)
+        } else if status & STATUS_ERRORS == 0 {
+            Ok(())
+        } else if matches!(op, Op::Erase) || status & STATUS_ERASE_ERROR != 0 {
+            Err(FlashError::EraseFailed(status))
+        } else {
+            Err(FlashError::ProgramFailed(status))
+        }
+    }
+
+    /// Copy `buf.len()` bytes starting at `off` into `buf`.
+    pub fn read(&self, off: usize, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = unsafe { ((self.base + off + i) as *const u8).read_volatile() };
+        }
+    }
+
+    /// Program `data` at `off`, one bank-width word at a time.
+    ///
+    /// `off` and `data.len()` must be multiples of the bus width. Programming
+    /// only clears bits, so the target should be erased.
+    pub fn program(&self, off: usize, data: &[u8]) -> Result<(), FlashError> {
+        debug_assert!(off.is_multiple_of(self.width) && data.len().is_multiple_of(self.width));
+        #[cfg(feature = "write-trace")]
+        crate::writetrace::note(off, data.len(), self.erase_size);
+        for (i, word) in data.chunks_exact(self.width).enumerate() {
+            let at = off + i * self.width;
+            let mut value = [0xFF; 4];
+            value[..self.width].copy_from_slice(word);
+            self.command(at, CMD_PROGRAM);
+            self.write_cell(at, u32::from_le_bytes(value));
+            self.finish(at, Op::Program)?;
+        }
+        Ok(())
+    }
+
+    /// Erase the block containing `off` to all ones.
+    pub fn erase(&self, off: usize) -> Result<(), FlashError> {
+        self.erase_wait(self.erase_start(off))
+    }
+
+    /// Start erasing the block containing `off` without waiting for it.
+    ///
+    /// Until [`erase_wait`](Self::erase_wait) returns, reads anywhere in the
+    /// bank return the status register, unless the erase is suspended.
+    pub fn erase_start(&self, off: usize) -> PendingErase {
+        let block = off - off % self.erase_size;
+        #[cfg(feature = "write-trace")]
+        crate::writetrace::note(block, self.erase_size, self.erase_size);
+        self.command(block, CMD_BLOCK_ERASE);
+        self.command(block, CMD_CONFIRM);
+        PendingErase { block }
+    }
+
+    /// Suspend `erase` and return to read-array mode, so other blocks can be
+    /// read.
+    ///
+    /// Returns `false` if the erase had already finished, in which case
+    /// there is nothing to resume.
+    pub fn erase_suspend(&self, erase: &PendingErase) -> Result<bool, FlashError> {
+        self.command(erase.block, CMD_SUSPEND);
+        self.command(erase.block, CMD_READ_STATUS);
+        let status = self.wait_ready(erase.block, self.timeouts.program)?;
+        self.command(erase.block, CMD_READ_ARRAY);
+        Ok(status & STATUS_ERASE_SUSPENDED != 0)
+    }
+
+    /// Resume a suspended `erase`.
+    pub fn erase_resume(&self, erase: &PendingErase) {
+        self.command(erase.block, CMD_CONFIRM);
+    }
+
+    /// Wait for `erase` to finish and return to read-array mode.
+    pub fn erase_wait(&self, erase: PendingErase) -> Result<(), FlashError> {
+        self.command(erase.block, CMD_READ_STATUS);
+        self.finish(erase.block, Op::Erase)
+    }
+
+    /// Make the erase block at `block` hold `data`, which is one erase
+    /// block long.
+    ///
+    /// The block is only erased when some bit has to go from 0 to 1, and
+    /// only the words that differ are programmed. Returns whether it was
+    /// erased and the number of words programmed.
+    pub fn rewrite_block(&self, block: usize, data: &[u8]) -> Result<(bool, usize), FlashError> {
+        debug_assert!(block.is_multiple_of(self.erase_size) && data.len() == self.erase_size);
+        let mut buf = [0; 4];
+        let word = &mut buf[..self.width];
+        let needs_erase = data.chunks_exact(self.width).enumerate().any(|(i, new)| {
+            self.read(block + i * self.width, word);
+            word.iter().zip(new).any(|(old, new)| old & new != *new)
+        });
+        if needs_erase {
+            self.erase(block)?;
+        }
+        let mut words = 0;
+        for (i, new) in data.chunks_exact(self.width).enumerate() {
+            let at = block + i * self.width;
+            self.read(at, word);
+            if *word != *new {
+                self.program(at, new)?;
+                words += 1;
+            }
+        }
+        Ok((needs_erase, words))
+    }
+
+    /// Erase every block overlapping `[off, off + len)`.
+    pub fn erase_range(&self, off: usize, len: usize) -> Result<(), FlashError> {
+        let start = off - off % self.erase_size;
+        for block in (start..off + len).step_by(self.erase_size) {
+            self.erase(block)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+//! cpio (newc) initramfs listing.
+//!
+//! When the payload region holds a newc-format cpio archive (e.g. from
+//! `find . | cpio -o -H newc`, passed with `cargo xtask run --payload`), the
+//! app walks it the way a kernel unpacks a flash-resident initramfs. Every
+//! entry is listed with its mode, size and name, and small text files are
+//! printed. Archives in the `070702` variant also have the checksum of every
+//! regular file checked.
+//!
+//! Each member is a 110-byte ASCII header of thirteen 8-digit hex fields,
+//! the NUL-terminated name, then the data; header plus name and the data are
+//! each padded to 4 bytes. The archive ends with a member named `TRAILER!!!`.
+
+use crate::layout::{Header, Manifest};
+use crate::text::text;
+use core::fmt;
+
+/// Magic of the plain newc format.
+pub const MAGIC_NEWC: &[u8; 6] = b"070701";
+/// Magic of the newc format with a data checksum in the `check` field.
+pub const MAGIC_CRC: &[u8; 6] = b"070702";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Reasons an archive cannot be read.
+#[derive(Debug)]
+pub enum CpioError {
+    /// A header, name or data extends past the end of the region.
+    Truncated(usize),
+    /// A header at this offset does not start with a newc magic.
+    BadMagic(usize),
+    /// A header field at this offset is not hexadecimal.
+    BadField(usize),
+    /// A name is not valid UTF-8.
+    BadName(usize),
+}
+
+impl fmt::Display for CpioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated(off) => write!(f, "member at {off:#x} truncated"),
+            Self::BadMagic(off) => write!(f, "bad magic at {off:#x}"),
+            Self::BadField(off) => write!(f, "bad header field at {off:#x}"),
+            Self::BadName(off) => write!(f, "name of member at {off:#x} is not UTF-8"),
+        }
+    }
+}
+
+/// One archive member.
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    /// The `check` field: the byte sum of the data for `070702` archives.
+    pub check: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    fn kind(&self) -> u32 {
+        self.mode & S_IFMT
+    }
+}
+
+/// `ls -l` style type and permission string, e.g. `-rw-r--r--`.
+struct Mode(u32);
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.0 & S_IFMT {
+            S_IFDIR => 'd',
+            S_IFREG => '-',
+            S_IFLNK => 'l',
+            0o020000 => 'c',
+            0o060000 => 'b',
+            0o010000 => 'p',
+            0o140000 => 's',
+            _ => '?',
+        };
+        write!(f, "{kind}")?;
+        for shift in [6, 3, 0] {
+            let bits = self.0 >> shift;
+            write!(
+                f,
+                "{}{}{}",
+                if bits & 4 != 0 { 'r' } else { '-' },
+                if bits & 2 != 0 { 'w' } else { '-' },
+                if bits & 1 != 0 { 'x' } else { '-' }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `data` starts like a newc archive.
+pub fn is_newc(data: &[u8]) -> bool {
+    data.starts_with(MAGIC_NEWC) || data.starts_with(MAGIC_CRC)
+}
+
+fn hex_field(header: &[u8], index: usize, off: usize) -> Result<u32, CpioError> {
+    let field = &header[6 + 8 * index..6 + 8 * (index + 1)];
+    core::str::from_utf8(field)
+        .ok()
+        .and_then(|s| u32::from_str_radix(s, 16).ok())
+        .ok_or(CpioError::BadField(off))
+}
+
+/// Call `f` for every member of the archive in `data` up to the trailer.
+///
+/// Returns the offset just past the trailer.
+pub fn for_each_entry<'a>(
+    data: &'a [u8],
+    mut f: impl FnMut(Entry<'a>),
+) -> Result<usize, CpioError> {
+    let mut off = 0;
+    loop {
+        let header = data
+            .get(off..off + HEADER_SIZE)
+            .ok_or(CpioError::Truncated(off))?;
+        if !is_newc(header) {
+            return Err(CpioError::BadMagic(off));
+        }
+        let mode = hex_field(header, 1, off)?;
+        let size = hex_field(header, 6, off)? as usize;
+        let name_size = hex_field(header, 11, off)? as usize;
+        let check = hex_field(header, 12, off)?;
+
+        let name_start = off + HEADER_SIZE;
+        let name = data
+            .get(name_start..name_start + name_size)
+            .ok_or(CpioError::Truncated(off))?;
+        let name = name.strip_suffix(&[0]).unwrap_or(name);
+        let name = core::str::from_utf8(name).map_err(|_| CpioError::BadName(off))?;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let body = data
+            .get(data_start..data_start + size)
+            .ok_or(CpioError::Truncated(off))?;
+        let next = (data_start + size).next_multiple_of(4);
+
+        if name == TRAILER {
+            return Ok(next);
+        }
+        f(Entry {
+            name,
+            mode,
+            check,
+            data: body,
+        });
+        off = next;
+    }
+}
+
+/// The payload region of `flash`, or `None` after saying why there is none.
+fn payload_region(flash: &[u8]) -> Option<&[u8]> {
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest.regions().flatten().find(|r| r.name == "payload"))
+    });
+    match region {
+        Ok(Some(region)) => {
+            let payload = region.data(flash);
+            if payload.is_none() {
+                println!("Cpio: FAIL (payload region out of bounds)");
+            }
+            payload
+        }
+        Ok(None) => {
+            println!("Cpio: no payload region in the image");
+            None
+        }
+        Err(e) => {
+            println!("Cpio: cannot read manifest: {e}");
+            None
+        }
+    }
+}
+
+/// List the cpio archive in the payload of `flash`, if it holds one: the
+/// `decrypted` plaintext of an encrypted payload, else the payload region.
+///
+/// Returns `true` if the archive was read up to its trailer and every
+/// checksum matched.
+pub fn run(flash: &[u8], decrypted: Option<&[u8]>) -> bool {
+    let Some(payload) = decrypted.or_else(|| payload_region(flash)) else {
+        return false;
+    };
+    if !is_newc(payload) {
+        println!(
+            "Cpio: payload is not a newc cpio archive (pass one with `cargo xtask run --payload <FILE>`)"
+        );
+        return false;
+    }
+
+    let crc = payload.starts_with(MAGIC_CRC);
+    println!(
+        "Cpio: newc{} archive in the payload region ({} bytes):",
+        if crc { " (with checksums)" } else { "" },
+        payload.len()
+    );
+    let mut count = 0;
+    let mut bad_sums = 0;
+    let result = for_each_entry(payload, |entry| {
+        count += 1;
+        let sum_ok = !crc
+            || entry.kind() != S_IFREG
+            || entry
+                .data
+                .iter()
+                .fold(0u32, |sum, &b| sum.wrapping_add(u32::from(b)))
+                == entry.check;
+        if !sum_ok {
+            bad_sums += 1;
+        }
+        match entry.kind() {
+            S_IFLNK => println!(
+                "  {} {:>8}  {} -> {}",
+                Mode(entry.mode),
+                entry.data.len(),
+                entry.name,
+                core::str::from_utf8(entry.data).unwrap_or("?")
+            ),
+            _ => println!(
+                "  {} {:>8}  {}{}",
+                Mode(entry.mode),
+                entry.data.len(),
+                entry.name,
+                if sum_ok { "" } else { "  (checksum mismatch)" }
+            ),
+        }
+    });
+    let end = match result {
+        Ok(end) => end,
+        Err(e) => {
+            println!("Cpio: FAIL ({e})");
+            return false;
+        }
+    };
+
+    // Print the small text files, as an initramfs' config files would be read.
+    let _ = for_each_entry(payload, |entry| {
+        if entry.kind() != S_IFREG || entry.data.is_empty() {
+            return;
+        }
+        if let Some(text) = text(entry.data) {
+            println!("Cpio: {} ({} bytes):", entry.name, entry.data.len());
+            for line in text.lines() {
+                println!("  | {line}");
+            }
+        }
+    });
+
+    if bad_sums == 0 {
+        println!("Cpio: PASS ({count} entries, trailer ends at {end:#x})");
+        true
+    } else {
+        println!("Cpio: FAIL ({bad_sums} of {count} entries have bad checksums)");
+        false
+    }
+}
@@ -0,0 +1,205 @@
+//! Crash records in flash.
+//!
+//! [`fatal`] writes the message, the caller's location, the stack, frame
+//! and return address registers and the top of the stack to the writable
+//! "panics" region (`cargo xtask mkimage --panic-region`) before panicking,
+//! so the state of a run that died can be read after QEMU has exited with
+//! `cargo xtask image inspect --panics`. The region holds one record per
+//! 1K slot, in the order written:
+//!
+//! ```text
+//! 0x000  magic     "PANC", programmed last
+//! 0x004  arch      u32  1 riscv64, 2 aarch64, 3 x86_64, 4 loongarch64
+//! 0x008  line      u32
+//! 0x00C  column    u32
+//! 0x010  sp        u64
+//! 0x018  fp        u64
+//! 0x020  ra        u64  return address (x86_64: the address of the capture)
+//! 0x028  words     u32  stack words saved
+//! 0x02C  msg_len   u32
+//! 0x030  file      [u8; 64]
+//! 0x070  message   [u8; 256]
+//! 0x170  stack     [u64; 32]  from sp upwards
+//! ```
+//!
+//! The app cannot hook panics raised elsewhere: `#[panic_handler]` is
+//! defined by axruntime, which prints the message and powers off. Fatal
+//! paths in the app call [`fatal`] instead of `panic!`.
+
+use crate::cfi::CfiFlash;
+use crate::layout::{Header, Manifest, PANIC_MAGIC, PANIC_SLOT};
+use core::fmt::{self, Write};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const RECORD_SIZE: usize = 0x270;
+const FILE_LEN: usize = 64;
+const MESSAGE_LEN: usize = 256;
+const STACK_WORDS: usize = 32;
+
+#[cfg(target_arch = "riscv64")]
+const ARCH: u32 = 1;
+#[cfg(target_arch = "aarch64")]
+const ARCH: u32 = 2;
+#[cfg(target_arch = "x86_64")]
+const ARCH: u32 = 3;
+#[cfg(target_arch = "loongarch64")]
+const ARCH: u32 = 4;
+
+/// Bank address and bank offset and length of the panics region, set by
+/// [`init`].
+static BASE: AtomicUsize = AtomicUsize::new(0);
+static OFFSET: AtomicUsize = AtomicUsize::new(0);
+static LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The stack pointer, frame pointer and return address of the caller.
+#[inline(always)]
+fn registers() -> [u64; 3] {
+    let (sp, fp, ra): (usize, usize, usize);
+    unsafe {
+        #[cfg(target_arch = "riscv64")]
+        core::arch::asm!("mv {}, sp", "mv {}, s0", "mv {}, ra", out(reg) sp, out(reg) fp, out(reg) ra);
+        #[cfg(target_arch = "aarch64")]
+        core::arch::asm!("mov {}, sp", "mov {}, x29", "mov {}, x30", out(reg) sp, out(reg) fp, out(reg) ra);
+        #[cfg(target_arch = "x86_64")]
+        core::arch::asm!("mov {}, rsp", "mov {}, rbp", "lea {}, [rip]", out(reg) sp, out(reg) fp, out(reg) ra);
+        #[cfg(target_arch = "loongarch64")]
+        core::arch::asm!("move {}, $sp", "move {}, $fp", "move {}, $ra", out(reg) sp, out(reg) fp, out(reg) ra);
+    }
+    [sp as u64, fp as u64, ra as u64]
+}
+
+/// A message being formatted into a record, truncated at [`MESSAGE_LEN`].
+struct Message<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Message<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Build the record for a crash at `location` with the registers `regs`.
+fn encode(args: fmt::Arguments, location: &Location, regs: [u64; 3]) -> [u8; RECORD_SIZE] {
+    let [sp, fp, ra] = regs;
+    let mut record = [0; RECORD_SIZE];
+    let mut put = |at: usize, bytes: &[u8]| record[at..at + bytes.len()].copy_from_slice(bytes);
+    put(0x04, &ARCH.to_le_bytes());
+    put(0x08, &location.line().to_le_bytes());
+    put(0x0C, &location.column().to_le_bytes());
+    put(0x10, &sp.to_le_bytes());
+    put(0x18, &fp.to_le_bytes());
+    put(0x20, &ra.to_le_bytes());
+    put(0x28, &(STACK_WORDS as u32).to_le_bytes());
+    let file = location.file().as_bytes();
+    put(0x30, &file[file.len().saturating_sub(FILE_LEN)..]);
+    for i in 0..STACK_WORDS {
+        // The words above the stack pointer belong to live frames.
+        let word = unsafe { (sp as *const u64).add(i).read_volatile() };
+        put(0x170 + i * 8, &word.to_le_bytes());
+    }
+    let mut message = Message {
+        buf: &mut record[0x70..0x70 + MESSAGE_LEN],
+        len: 0,
+    };
+    let _ = message.write_fmt(args);
+    let len = message.len as u32;
+    record[0x2C..0x30].copy_from_slice(&len.to_le_bytes());
+    record
+}
+
+/// Bank offset of the first free slot in the region, erasing the region
+/// when it is full.
+fn free_slot(flash: &CfiFlash, offset: usize, len: usize) -> Option<usize> {
+    let mut magic = [0; 4];
+    let slots = (offset..offset + len - PANIC_SLOT + 1).step_by(PANIC_SLOT);
+    for slot in slots {
+        flash.read(slot, &mut magic);
+        if magic == [0xFF; 4] {
+            return Some(slot);
+        }
+    }
+    flash.erase_range(offset, len).ok()?;
+    Some(offset)
+}
+
+/// Record a crash with `args` as the message in flash, then panic with it.
+#[track_caller]
+pub fn fatal(args: fmt::Arguments) -> ! {
+    let regs = registers();
+    let location = Location::caller();
+    let (base, offset, len) = (
+        BASE.load(Ordering::Acquire),
+        OFFSET.load(Ordering::Relaxed),
+        LEN.load(Ordering::Relaxed),
+    );
+    if base != 0 {
+        let record = encode(args, location, regs);
+        // Probing also returns a bank interrupted mid-command to read mode.
+        let written = CfiFlash::probe(base).ok().and_then(|flash| {
+            let slot = free_slot(&flash, offset, len)?;
+            flash.program(slot + 4, &record[4..]).ok()?;
+            flash.program(slot, PANIC_MAGIC).ok()?;
+            Some(slot)
+        });
+        match written {
+            Some(slot) => println!("Crash record: written to flash at {slot:#x}"),
+            None => println!("Crash record: could not write to flash"),
+        }
+    }
+    panic!("{args}");
+}
+
+/// Find the panics region of the bank mapped at `base`, so [`fatal`] can
+/// write to it, and report the records already there.
+pub fn init(base: usize, size: usize) {
+    let flash = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest
+            .regions()
+            .flatten()
+            .find(|r| r.name == "panics" && r.writable())
+            .map(|r| (r.offset as usize, r.len as usize)))
+    });
+    let (offset, len) = match region {
+        Ok(Some((offset, len))) if len >= PANIC_SLOT => (offset, len),
+        Ok(_) => {
+            println!(
+                "Crash record: no writable panics region in the image (create it with `cargo xtask mkimage --panic-region`)"
+            );
+            return;
+        }
+        Err(e) => {
+            println!("Crash record: cannot read manifest: {e}");
+            return;
+        }
+    };
+
+    let records = flash[offset..offset + len]
+        .chunks_exact(PANIC_SLOT)
+        .filter(|slot| slot.starts_with(PANIC_MAGIC));
+    let (count, newest) = records.fold((0, None), |(n, _), slot| (n + 1, Some(slot)));
+    if let Some(slot) = newest {
+        let word = |at: usize| u32::from_le_bytes(slot[at..at + 4].try_into().unwrap());
+        let file = &slot[0x30..0x30 + FILE_LEN];
+        let file = &file[..file.iter().position(|&b| b == 0).unwrap_or(FILE_LEN)];
+        let message = &slot[0x70..0x70 + (word(0x2C) as usize).min(MESSAGE_LEN)];
+        println!(
+            "Crash record: {count} earlier crash(es) in flash, the last at {}:{}: {}",
+            core::str::from_utf8(file).unwrap_or("?"),
+            word(0x08),
+            core::str::from_utf8(message).unwrap_or("<invalid UTF-8>")
+        );
+    } else {
+        println!("Crash record: no earlier crashes in flash");
+    }
+    OFFSET.store(offset, Ordering::Relaxed);
+    LEN.store(len, Ordering::Relaxed);
+    BASE.store(base, Ordering::Release);
+}
@@ -0,0 +1,77 @@
+//! Cycle counts for the timing demos.
+//!
+//! The timer behind `Instant` can be too coarse for the reads being timed:
+//! riscv64's `time` runs at 10 MHz on QEMU's virt board, so a single flash
+//! access reads as zero or one tick, and the demos print microseconds.
+//! [`cycles`] reads the finest free-running counter each architecture lets
+//! the kernel read: `cycle` (`rdcycle`) on riscv64, the TSC on x86_64,
+//! `CNTVCT_EL0` on aarch64 and the stable counter on loongarch64. The last
+//! two are fixed-rate timers rather than cycle counters, but their raw
+//! count still shows differences that round away in microseconds.
+//!
+//! [`Stopwatch`] takes both readings, so every measurement has a wall-clock
+//! time for rates and a count for comparing short spans.
+
+use std::time::{Duration, Instant};
+
+/// The free-running counter described above.
+pub fn cycles() -> u64 {
+    let count: u64;
+    unsafe {
+        #[cfg(target_arch = "riscv64")]
+        core::arch::asm!("rdcycle {}", out(reg) count);
+        #[cfg(target_arch = "aarch64")]
+        core::arch::asm!("mrs {}, cntvct_el0", out(reg) count);
+        #[cfg(target_arch = "x86_64")]
+        {
+            count = core::arch::x86_64::_rdtsc();
+        }
+        #[cfg(target_arch = "loongarch64")]
+        core::arch::asm!("rdtime.d {}, $zero", out(reg) count);
+    }
+    count
+}
+
+/// A measured span: the [`cycles`] it took, then its wall-clock time.
+/// Spans order by their counts, the finer of the two.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub cycles: u64,
+    pub time: Duration,
+}
+
+impl Span {
+    /// The average of `n` spans that add up to this one.
+    #[cfg_attr(not(feature = "shadow"), allow(dead_code))]
+    pub fn per(self, n: u32) -> Span {
+        Span {
+            cycles: self.cycles / u64::from(n.max(1)),
+            time: self.time / n.max(1),
+        }
+    }
+}
+
+/// Both clocks, started together.
+pub struct Stopwatch {
+    instant: Instant,
+    cycles: u64,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        let instant = Instant::now();
+        Stopwatch {
+            instant,
+            cycles: cycles(),
+        }
+    }
+
+    /// The span since [`Stopwatch::start`].
+    pub fn stop(&self) -> Span {
+        let cycles = cycles().wrapping_sub(self.cycles);
+        Span {
+            cycles,
+            time: self.instant.elapsed(),
+        }
+    }
+}
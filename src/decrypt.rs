@@ -0,0 +1,121 @@
+//! Decryption of an encrypted payload.
+//!
+//! `cargo xtask mkimage --encrypt <KEYFILE>` seals the payload region with
+//! AES-256-GCM and keeps the nonce, tag and key id in the header. The app
+//! takes the key from `key=<64 hex digits>` on the kernel command line
+//! (passed by `cargo xtask run --encrypt`), or else uses [`DEV_KEY`], the
+//! development key `run --features decrypt` seals with by default. It
+//! copies the ciphertext into RAM and decrypts it there; the plaintext is
+//! only handed to the other demos once the tag has verified, so altered
+//! flash never reaches them.
+
+use crate::Hex;
+use crate::layout::{DEV_KEY, Header, Manifest};
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+use sha2::{Digest, Sha256};
+use std::vec::Vec;
+
+/// Bytes of the plaintext shown after a successful decryption.
+const PREVIEW: usize = 64;
+
+/// Parse a key given as 64 hex digits.
+fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Decrypt the payload of the image in `flash` with the key in `key_arg`
+/// (the `key=` argument), or [`DEV_KEY`] without one.
+///
+/// Returns the plaintext if the payload is encrypted and its tag verified,
+/// else `None`, so nothing unauthenticated is passed on.
+pub fn run(flash: &[u8], key_arg: Option<&str>) -> Option<Vec<u8>> {
+    let found = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        let payload = manifest
+            .regions()
+            .flatten()
+            .find(|r| r.name == "payload")
+            .and_then(|r| r.data(flash));
+        Ok((header.sealing, payload))
+    });
+    let (sealing, ciphertext) = match found {
+        Ok((Some(sealing), Some(payload))) => (sealing, payload),
+        Ok((None, _)) => {
+            println!(
+                "Decrypt: the payload is not encrypted (create it with `cargo xtask mkimage --encrypt <KEYFILE>`)"
+            );
+            return None;
+        }
+        Ok((Some(_), None)) => {
+            println!("Decrypt: FAIL (no payload region within the bank)");
+            return None;
+        }
+        Err(e) => {
+            println!("Decrypt: FAIL (cannot read manifest: {e})");
+            return None;
+        }
+    };
+
+    let (key, source) = match key_arg {
+        Some(hex) => match parse_key(hex) {
+            Some(key) => (key, "the kernel command line"),
+            None => {
+                println!("Decrypt: FAIL (key= on the command line is not 64 hex digits)");
+                return None;
+            }
+        },
+        None => (DEV_KEY, "the built-in development key"),
+    };
+    let digest = Sha256::digest(key);
+    let key_id = &digest[..8];
+    println!(
+        "Decrypt: AES-256-GCM payload of {} bytes, key id {}, key from {source}",
+        ciphertext.len(),
+        Hex(&sealing.key_id)
+    );
+    if key_id != sealing.key_id {
+        println!(
+            "Decrypt: FAIL (the payload was sealed with another key; this key's id is {})",
+            Hex(key_id)
+        );
+        return None;
+    }
+
+    let mut data = ciphertext.to_vec();
+    let cipher = Aes256Gcm::new_from_slice(&key).expect("AES-256 key length");
+    let result = cipher.decrypt_in_place_detached(
+        Nonce::from_slice(&sealing.nonce),
+        b"",
+        &mut data,
+        Tag::from_slice(&sealing.tag),
+    );
+    if result.is_err() {
+        println!(
+            "Decrypt: FAIL (authentication tag mismatch: the payload or its header was altered)"
+        );
+        return None;
+    }
+    let shown = &data[..data.len().min(PREVIEW)];
+    match core::str::from_utf8(shown) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && c != '\n') => {
+            println!("Decrypt: plaintext starts {:?}", text);
+        }
+        _ => println!(
+            "Decrypt: plaintext starts {}",
+            Hex(&shown[..shown.len().min(16)])
+        ),
+    }
+    println!(
+        "Decrypt: PASS (tag verified, {} bytes of plaintext)",
+        data.len()
+    );
+    Some(data)
+}
@@ -0,0 +1,215 @@
+//! Region dispatch by type.
+//!
+//! Every manifest entry carries a type tag ([`Kind`]), set by `cargo xtask
+//! mkimage` from `--payload-type`, a layout file's `type`, or what the
+//! region holds. This walks the manifest and hands each region to the
+//! handler registered for its type in [`HANDLERS`], which checks that the
+//! contents are what the tag says and prints a one-line summary. A new
+//! payload type needs a [`Kind`] in readpflash-layout and one handler
+//! here; the main flow does not change.
+
+use crate::layout::catalog::{Catalog, CatalogError};
+use crate::layout::{Header, Kind, Manifest};
+use std::string::String;
+use std::vec::Vec;
+
+/// A handler: the summary of a region's bytes, or why they are not of the
+/// handler's type.
+type Handler = fn(&[u8]) -> Result<String, &'static str>;
+
+/// The handler of each type.
+const HANDLERS: [(Kind, Handler); 7] = [
+    (Kind::Raw, raw),
+    (Kind::Text, text),
+    (Kind::Config, config),
+    (Kind::Dtb, dtb),
+    (Kind::Archive, archive),
+    (Kind::Executable, executable),
+    (Kind::Catalog, catalog),
+];
+
+/// Characters of text shown in a summary.
+const PREVIEW: usize = 40;
+
+/// `line`, cut to [`PREVIEW`] characters.
+fn preview(line: &str) -> &str {
+    line.char_indices()
+        .nth(PREVIEW)
+        .map_or(line, |(end, _)| &line[..end])
+}
+
+fn raw(data: &[u8]) -> Result<String, &'static str> {
+    if data.iter().all(|&b| b == 0xFF) {
+        return Ok(std::format!("{:#x} bytes, erased", data.len()));
+    }
+    let head: Vec<_> = data
+        .iter()
+        .take(8)
+        .map(|b| std::format!("{b:02x}"))
+        .collect();
+    Ok(std::format!(
+        "{:#x} bytes, starting {}",
+        data.len(),
+        head.join(" ")
+    ))
+}
+
+fn text(data: &[u8]) -> Result<String, &'static str> {
+    let text = core::str::from_utf8(data).map_err(|_| "not UTF-8")?;
+    let first = text.lines().next().unwrap_or_default();
+    Ok(std::format!(
+        "{} line(s): \"{}\"",
+        text.lines().count(),
+        preview(first).escape_debug()
+    ))
+}
+
+fn config(data: &[u8]) -> Result<String, &'static str> {
+    let text = core::str::from_utf8(data).map_err(|_| "not UTF-8")?;
+    let mut keys = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, _)) if !key.trim().is_empty() => keys.push(key.trim()),
+            _ => return Err("a line is not key=value"),
+        }
+    }
+    Ok(std::format!("{} key(s): {}", keys.len(), keys.join(", ")))
+}
+
+fn dtb(data: &[u8]) -> Result<String, &'static str> {
+    let be = |off: usize| {
+        data.get(off..off + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+    };
+    if be(0) != Some(0xd00d_feed) {
+        return Err("no device tree magic");
+    }
+    let size = be(4).ok_or("truncated header")? as usize;
+    if size > data.len() {
+        return Err("totalsize runs past the region");
+    }
+    let version = be(20).ok_or("truncated header")?;
+    Ok(std::format!(
+        "device tree version {version}, {size:#x} bytes"
+    ))
+}
+
+fn archive(data: &[u8]) -> Result<String, &'static str> {
+    let at = |offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+    let format = if at(0, b"070701") || at(0, b"070702") {
+        "cpio (newc)"
+    } else if at(257, b"ustar") {
+        "ustar"
+    } else if at(0, b"-rom1fs-") {
+        "romfs"
+    } else if at(1080, &[0x53, 0xEF]) {
+        "ext2"
+    } else if at(0, &[0x1F, 0x8B]) {
+        "gzip"
+    } else {
+        return Err("no archive or filesystem magic");
+    };
+    Ok(std::format!("{format}, {:#x} bytes", data.len()))
+}
+
+fn catalog(data: &[u8]) -> Result<String, &'static str> {
+    let catalog = Catalog::parse(data).map_err(|e| match e {
+        CatalogError::BadMagic => "no catalog magic",
+        CatalogError::Truncated => "index truncated",
+        CatalogError::OutOfBounds(_) => "a message runs past the region",
+        CatalogError::NotUtf8(_) => "a message is not UTF-8",
+        CatalogError::Unsorted(_) => "ids out of order or repeated",
+    })?;
+    let mut ids = catalog.messages().map(|(id, _)| id);
+    match (ids.next(), ids.last()) {
+        (Some(first), last) => Ok(std::format!(
+            "{} message(s), ids {first} to {}",
+            catalog.len(),
+            last.unwrap_or(first)
+        )),
+        (None, _) => Ok("no messages".into()),
+    }
+}
+
+fn executable(data: &[u8]) -> Result<String, &'static str> {
+    if data.starts_with(crate::layout::XIP_MAGIC) {
+        return Ok(std::format!("xip stub, {:#x} bytes", data.len()));
+    }
+    if !data.starts_with(b"\x7FELF") {
+        // Raw kernel and firmware images have no header to check.
+        return Ok(std::format!("raw code, {:#x} bytes", data.len()));
+    }
+    let (class, little) = match (data.get(4), data.get(5)) {
+        (Some(&class @ (1 | 2)), Some(&order @ (1 | 2))) => (class, order == 1),
+        _ => return Err("bad ELF identification"),
+    };
+    let field = |off: usize, len: usize| {
+        let bytes = data.get(off..off + len)?;
+        let fold = |acc: u64, &b: &u8| acc << 8 | u64::from(b);
+        Some(match little {
+            true => bytes.iter().rev().fold(0, fold),
+            false => bytes.iter().fold(0, fold),
+        })
+    };
+    let machine = match field(18, 2).ok_or("truncated ELF header")? {
+        0x3E => "x86_64",
+        0xB7 => "aarch64",
+        0xF3 => "riscv",
+        0x102 => "loongarch",
+        _ => "other",
+    };
+    let entry = match class {
+        1 => field(24, 4),
+        _ => field(24, 8),
+    }
+    .ok_or("truncated ELF header")?;
+    Ok(std::format!(
+        "ELF{}, {machine}, entry {entry:#x}",
+        if class == 1 { 32 } else { 64 }
+    ))
+}
+
+/// Hand every region of the image in `flash` to the handler for its type.
+/// Returns `true` if each region's contents match its tag; a tag this app
+/// does not know is skipped.
+pub fn run(flash: &[u8]) -> bool {
+    let manifest = match Header::parse(flash).and_then(|header| Manifest::parse(flash, &header)) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("Region dispatch: FAIL (cannot read the manifest: {e})");
+            return false;
+        }
+    };
+    println!("Region dispatch ({} regions):", manifest.len());
+    let mut ok = true;
+    for region in manifest.regions() {
+        let Ok(region) = region else {
+            continue;
+        };
+        let name = region.name;
+        let Some(kind) = region.kind() else {
+            println!(
+                "  {name:<16} tag {:<6} skipped (unknown to this app)",
+                region.tag
+            );
+            continue;
+        };
+        let Some(data) = region.data(flash) else {
+            println!("  {name:<16} {kind:<10} FAIL (runs past the end of the bank)");
+            ok = false;
+            continue;
+        };
+        let (_, handler) = HANDLERS.iter().find(|(k, _)| *k == kind).unwrap();
+        match handler(data) {
+            Ok(summary) => println!("  {name:<16} {kind:<10} {summary}"),
+            Err(e) => {
+                println!("  {name:<16} {kind:<10} FAIL ({e})");
+                ok = false;
+            }
+        }
+    }
+    ok
+}
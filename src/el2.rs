@@ -0,0 +1,39 @@
+//! Exception level report (aarch64).
+//!
+//! `cargo xtask run --arch aarch64 --el2` starts the virt machine with
+//! `virtualization=on`, so QEMU enters the kernel at EL2. ArceOS normally
+//! drops to EL1 during boot and maps the flash bank through the EL1
+//! translation regime as usual. A kernel built to stay at EL2 maps it
+//! through TTBR0_EL2 instead. This prints which of the two the app got
+//! before the first flash read, so a fault there is attributed to the
+//! right regime, and says when EL2 was not available at all.
+
+/// The current exception level (`CurrentEL[3:2]`).
+fn current_el() -> u64 {
+    let el: u64;
+    unsafe { core::arch::asm!("mrs {}, CurrentEL", out(reg) el) };
+    (el >> 2) & 0b11
+}
+
+/// Whether the CPU implements EL2 (`ID_AA64PFR0_EL1.EL2`, bits 11:8).
+fn el2_implemented() -> bool {
+    let pfr0: u64;
+    unsafe { core::arch::asm!("mrs {}, ID_AA64PFR0_EL1", out(reg) pfr0) };
+    (pfr0 >> 8) & 0xf != 0
+}
+
+/// Print the exception level the app runs at and how it got there.
+pub fn run() {
+    match (current_el(), el2_implemented()) {
+        (2, _) => println!(
+            "Exception level: EL2 (the kernel stayed at EL2; flash is mapped through TTBR0_EL2)"
+        ),
+        (1, true) => println!(
+            "Exception level: EL1 (EL2 is implemented; the kernel dropped from EL2 at boot; flash is mapped through the EL1 regime)"
+        ),
+        (1, false) => println!(
+            "Exception level: EL1 (the machine has no EL2; run with `cargo xtask run --el2` for virtualization=on)"
+        ),
+        (el, _) => println!("Exception level: EL{el}"),
+    }
+}
@@ -0,0 +1,152 @@
+//! Errors that stop the app.
+//!
+//! The checks before the demos (the mapping, the magic, the header) return a
+//! [`PflashError`] instead of dereferencing a bad pointer or unwrapping, and
+//! the demos' PASS/FAIL results become one when they fail. `main` prints
+//! the first error as
+//!
+//! ```text
+//! PFlash error <CODE>: <message>
+//! ```
+//!
+//! and exits with [`PflashError::exit_code`]. QEMU exits 0 however the
+//! guest stopped, so `cargo xtask run` finds that line in the serial output
+//! and exits with the code itself.
+
+use crate::layout::{LayoutError, MAX_VERSION, MIN_VERSION};
+use core::fmt;
+
+/// Reasons the app stops.
+#[derive(Debug)]
+pub enum PflashError {
+    /// The bank is not mapped, or not onto itself, at its virtual address.
+    Unmapped {
+        paddr: usize,
+        va: usize,
+        size: usize,
+        fault: MapFault,
+    },
+    /// The bank does not start with the image magic.
+    BadMagic([u8; 4]),
+    /// The header does not match the CRC-32 stored in it.
+    BadCrc { stored: u32, computed: u32 },
+    /// The image format version is outside what this app reads.
+    UnsupportedVersion(u16),
+    /// The header or manifest extends past the end of the bank.
+    OutOfBounds,
+    /// The header or manifest is malformed in another way.
+    BadLayout(LayoutError),
+    /// A demo that checks the image or the bank failed.
+    CheckFailed(&'static str),
+    /// A demo that programs or erases the bank failed.
+    WriteFailed(&'static str),
+}
+
+/// Where the mapping of a bank goes wrong.
+#[derive(Debug)]
+pub enum MapFault {
+    /// The byte at this offset into the bank has no page.
+    Missing(usize),
+    /// The byte at `offset` translates to `to`, not into the bank.
+    Elsewhere { offset: usize, to: usize },
+    /// The `map-info` or `mem-regions` listing printed before the error
+    /// shows where.
+    Listed,
+}
+
+impl PflashError {
+    /// Exit status of a run that stopped on this error. The codes start at
+    /// 10, clear of xtask's own 1 and the timeout's 124.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Unmapped { .. } => 10,
+            Self::BadMagic(_) => 11,
+            Self::BadCrc { .. } => 12,
+            Self::UnsupportedVersion(_) => 13,
+            Self::OutOfBounds => 14,
+            Self::BadLayout(_) => 15,
+            Self::CheckFailed(_) => 16,
+            Self::WriteFailed(_) => 17,
+        }
+    }
+}
+
+impl From<LayoutError> for PflashError {
+    fn from(e: LayoutError) -> Self {
+        match e {
+            LayoutError::NewerVersion(version) | LayoutError::OlderVersion(version) => {
+                Self::UnsupportedVersion(version)
+            }
+            LayoutError::Truncated => Self::OutOfBounds,
+            e => Self::BadLayout(e),
+        }
+    }
+}
+
+impl fmt::Display for PflashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unmapped {
+                paddr,
+                va,
+                size,
+                fault,
+            } => {
+                write!(
+                    f,
+                    "the bank [{paddr:#X}, +{size:#X}] is not mapped onto itself at {va:#X}: "
+                )?;
+                match *fault {
+                    MapFault::Missing(0) => write!(
+                        f,
+                        "nothing maps it; add [{paddr:#x}, {size:#x}] to the mmio-ranges \
+                         of the axconfig"
+                    ),
+                    MapFault::Missing(offset) => write!(
+                        f,
+                        "{:#X} (physical {:#X}) has no page; the mmio-ranges entry ends \
+                         there, before the bank does, so widen it or lower pflash-size",
+                        va + offset,
+                        paddr + offset
+                    ),
+                    MapFault::Elsewhere { offset, to } => write!(
+                        f,
+                        "{:#X} translates to physical {to:#X}, not {:#X}; check \
+                         phys-virt-offset and the mmio-ranges of the axconfig",
+                        va + offset,
+                        paddr + offset
+                    ),
+                    MapFault::Listed => write!(f, "see the mapping listed above"),
+                }
+            }
+            Self::BadMagic(magic) => write!(
+                f,
+                "the bank starts with \"{}\", not \"PFLA\"; create the image with \
+                 `cargo xtask mkimage`",
+                magic.escape_ascii()
+            ),
+            Self::BadCrc { stored, computed } => write!(
+                f,
+                "header CRC mismatch (stored {stored:#010x}, computed {computed:#010x})"
+            ),
+            Self::UnsupportedVersion(version) if *version > MAX_VERSION => write!(
+                f,
+                "image format version {version} was written by a newer xtask \
+                 (this app reads up to version {MAX_VERSION}); rebuild the app"
+            ),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "image format version {version} was written by an older xtask \
+                 (this app reads version {MIN_VERSION} and later); recreate the image \
+                 with `cargo xtask mkimage`"
+            ),
+            Self::OutOfBounds => write!(
+                f,
+                "the image header or manifest extends past the end of the bank"
+            ),
+            Self::BadLayout(e) => write!(f, "cannot read the image: {e}"),
+            Self::CheckFailed(demo) => write!(f, "the {demo} check failed"),
+            Self::WriteFailed(demo) => write!(f, "the {demo} demo failed to update the bank"),
+        }
+    }
+}
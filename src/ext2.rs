@@ -0,0 +1,403 @@
+//! Read-only ext2 driver.
+//!
+//! `cargo xtask run --ext2 <DIR>` builds an ext2 image of a directory with
+//! `mke2fs -d` and stores it as the fs region. The app mounts it through the
+//! flash [`BlockDevice`] adapter and walks the whole tree: superblock, group
+//! descriptors, inode tables, direct and indirect block maps, and linked-list
+//! directories (hashed directories stay readable that way too). All integers
+//! are little-endian.
+
+use crate::block::{BlockDevice, BlockError, FlashBlocks};
+use crate::layout::{Header, Manifest};
+use crate::text::{PREVIEW_LEN, preview};
+use core::fmt;
+use std::vec;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT2_MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+/// Incompatible features this driver understands: directory entries with a
+/// file type byte.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Number of block pointers in an inode: 12 direct, then single, double and
+/// triple indirect.
+const DIRECT_BLOCKS: u64 = 12;
+/// Symlink targets shorter than this are stored in the block pointers.
+const FAST_SYMLINK_MAX: u64 = 60;
+/// Deepest directory level printed.
+const MAX_DEPTH: usize = 8;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+
+/// Reasons the filesystem cannot be read.
+#[derive(Debug)]
+pub enum Ext2Error {
+    Block(BlockError),
+    /// No ext2 superblock magic.
+    BadMagic,
+    /// The image needs a feature this driver does not implement.
+    Unsupported(&'static str),
+    /// A structure points outside the filesystem.
+    Corrupt(&'static str),
+}
+
+impl From<BlockError> for Ext2Error {
+    fn from(e: BlockError) -> Self {
+        Self::Block(e)
+    }
+}
+
+impl fmt::Display for Ext2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Block(e) => write!(f, "{e}"),
+            Self::BadMagic => write!(f, "no ext2 superblock"),
+            Self::Unsupported(what) => write!(f, "unsupported: {what}"),
+            Self::Corrupt(what) => write!(f, "corrupt {what}"),
+        }
+    }
+}
+
+fn le_u16(bytes: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([bytes[off], bytes[off + 1]])
+}
+
+fn le_u32(bytes: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(bytes[off..off + 4].try_into().unwrap())
+}
+
+/// The fields of an on-disk inode this driver uses.
+pub struct Inode {
+    pub mode: u16,
+    pub size: u64,
+    /// 512-byte sectors allocated to the inode.
+    sectors: u32,
+    block: [u32; 15],
+}
+
+impl Inode {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    fn kind(&self) -> u16 {
+        self.mode & S_IFMT
+    }
+}
+
+/// A mounted filesystem.
+pub struct Ext2<D> {
+    dev: D,
+    pub block_size: usize,
+    pub blocks: u32,
+    pub inodes: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    /// First block of the group descriptor table.
+    gdt_block: u64,
+    volume: [u8; 16],
+}
+
+impl<D: BlockDevice> Ext2<D> {
+    /// Read and check the superblock.
+    pub fn mount(dev: D) -> Result<Self, Ext2Error> {
+        let mut sb = [0; 1024];
+        dev.read_at(SUPERBLOCK_OFFSET, &mut sb)?;
+        if le_u16(&sb, 56) != EXT2_MAGIC {
+            return Err(Ext2Error::BadMagic);
+        }
+        let log_block_size = le_u32(&sb, 24);
+        if log_block_size > 2 {
+            return Err(Ext2Error::Unsupported("block size above 4K"));
+        }
+        let incompat = if le_u32(&sb, 76) >= 1 {
+            le_u32(&sb, 96)
+        } else {
+            0
+        };
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(Ext2Error::Unsupported(
+                "incompatible features (extents, 64bit, journal replay...)",
+            ));
+        }
+        let inode_size = if le_u32(&sb, 76) >= 1 {
+            le_u16(&sb, 88) as usize
+        } else {
+            128
+        };
+        let inodes_per_group = le_u32(&sb, 40);
+        if inodes_per_group == 0 || inode_size < 128 {
+            return Err(Ext2Error::Corrupt("superblock"));
+        }
+        Ok(Self {
+            dev,
+            block_size: 1024 << log_block_size,
+            blocks: le_u32(&sb, 4),
+            inodes: le_u32(&sb, 0),
+            inodes_per_group,
+            inode_size,
+            gdt_block: u64::from(le_u32(&sb, 20)) + 1,
+            volume: sb[120..136].try_into().unwrap(),
+        })
+    }
+
+    /// Volume label.
+    pub fn volume(&self) -> &str {
+        let len = self.volume.iter().position(|&b| b == 0).unwrap_or(16);
+        core::str::from_utf8(&self.volume[..len]).unwrap_or("?")
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), Ext2Error> {
+        if block >= self.blocks {
+            return Err(Ext2Error::Corrupt("block pointer"));
+        }
+        let offset = u64::from(block) * self.block_size as u64;
+        Ok(self.dev.read_at(offset, &mut buf[..self.block_size])?)
+    }
+
+    fn read_u32(&self, block: u32, index: u64) -> Result<u32, Ext2Error> {
+        if block >= self.blocks {
+            return Err(Ext2Error::Corrupt("indirect block pointer"));
+        }
+        let mut word = [0; 4];
+        let offset = u64::from(block) * self.block_size as u64 + index * 4;
+        self.dev.read_at(offset, &mut word)?;
+        Ok(u32::from_le_bytes(word))
+    }
+
+    /// Read inode `ino` (numbered from 1).
+    pub fn inode(&self, ino: u32) -> Result<Inode, Ext2Error> {
+        if ino == 0 || ino > self.inodes {
+            return Err(Ext2Error::Corrupt("inode number"));
+        }
+        let group = u64::from((ino - 1) / self.inodes_per_group);
+        let index = u64::from((ino - 1) % self.inodes_per_group);
+        let mut desc = [0; 32];
+        self.dev.read_at(
+            self.gdt_block * self.block_size as u64 + group * 32,
+            &mut desc,
+        )?;
+        let table = u64::from(le_u32(&desc, 8));
+        let mut raw = [0; 128];
+        self.dev.read_at(
+            table * self.block_size as u64 + index * self.inode_size as u64,
+            &mut raw,
+        )?;
+        let mode = le_u16(&raw, 0);
+        let mut size = u64::from(le_u32(&raw, 4));
+        if mode & S_IFMT == S_IFREG {
+            size |= u64::from(le_u32(&raw, 108)) << 32;
+        }
+        let mut block = [0; 15];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = le_u32(&raw, 40 + 4 * i);
+        }
+        Ok(Inode {
+            mode,
+            size,
+            sectors: le_u32(&raw, 28),
+            block,
+        })
+    }
+
+    /// Physical block holding logical block `n` of `inode` (0 for a hole).
+    fn map(&self, inode: &Inode, n: u64) -> Result<u32, Ext2Error> {
+        let per = (self.block_size / 4) as u64;
+        if n < DIRECT_BLOCKS {
+            return Ok(inode.block[n as usize]);
+        }
+        // Walk down the single, double or triple indirect tree.
+        let mut n = n - DIRECT_BLOCKS;
+        let mut span = per;
+        for level in 0..3 {
+            if n < span {
+                let mut block = inode.block[12 + level];
+                for depth in (0..=level as u32).rev() {
+                    if block == 0 {
+                        return Ok(0);
+                    }
+                    block = self.read_u32(block, n / per.pow(depth) % per)?;
+                }
+                return Ok(block);
+            }
+            n -= span;
+            span *= per;
+        }
+        Err(Ext2Error::Corrupt("file size"))
+    }
+
+    /// Read up to `buf.len()` bytes of `inode` from `offset`; returns the
+    /// number of bytes read.
+    pub fn read(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Ext2Error> {
+        let end = inode.size.min(offset + buf.len() as u64);
+        let bs = self.block_size as u64;
+        let mut pos = offset;
+        let mut block = vec![0; self.block_size];
+        while pos < end {
+            let skip = (pos % bs) as usize;
+            let n = ((bs - pos % bs).min(end - pos)) as usize;
+            let out = &mut buf[(pos - offset) as usize..][..n];
+            match self.map(inode, pos / bs)? {
+                0 => out.fill(0),
+                phys => {
+                    self.read_block(phys, &mut block)?;
+                    out.copy_from_slice(&block[skip..skip + n]);
+                }
+            }
+            pos += n as u64;
+        }
+        Ok((end.saturating_sub(offset)) as usize)
+    }
+
+    /// Read the target of a symlink into `buf`; returns its length.
+    pub fn read_link(&self, inode: &Inode, buf: &mut [u8]) -> Result<usize, Ext2Error> {
+        if inode.size < FAST_SYMLINK_MAX && inode.sectors == 0 {
+            let len = (inode.size as usize).min(buf.len());
+            for (i, b) in buf[..len].iter_mut().enumerate() {
+                *b = inode.block[i / 4].to_le_bytes()[i % 4];
+            }
+            return Ok(len);
+        }
+        self.read(inode, 0, buf)
+    }
+
+    /// Call `f(inode, name)` for every entry of directory `dir`.
+    pub fn for_each_entry(
+        &self,
+        dir: &Inode,
+        mut f: impl FnMut(u32, &str) -> Result<(), Ext2Error>,
+    ) -> Result<(), Ext2Error> {
+        let mut block = vec![0; self.block_size];
+        let blocks = dir.size.div_ceil(self.block_size as u64);
+        for n in 0..blocks {
+            let len = self.read(dir, n * self.block_size as u64, &mut block)?;
+            let mut off = 0;
+            while off + 8 <= len {
+                let ino = le_u32(&block, off);
+                let rec_len = le_u16(&block, off + 4) as usize;
+                let name_len = block[off + 6] as usize;
+                if rec_len < 8 || off + rec_len > len || 8 + name_len > rec_len {
+                    return Err(Ext2Error::Corrupt("directory entry"));
+                }
+                if ino != 0 {
+                    let name = core::str::from_utf8(&block[off + 8..off + 8 + name_len])
+                        .unwrap_or("<non-UTF-8 name>");
+                    f(ino, name)?;
+                }
+                off += rec_len;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print the entries of directory `dir`, recursing into subdirectories.
+/// Returns the number of entries printed.
+fn print_tree<D: BlockDevice>(fs: &Ext2<D>, dir: &Inode, depth: usize) -> Result<usize, Ext2Error> {
+    let mut count = 0;
+    fs.for_each_entry(dir, |ino, name| {
+        if name == "." || name == ".." {
+            return Ok(());
+        }
+        count += 1;
+        let inode = fs.inode(ino)?;
+        let indent = 2 * (depth + 1);
+        match inode.kind() {
+            S_IFDIR => {
+                println!("{:indent$}{name}/ (inode {ino})", "");
+                if depth + 1 < MAX_DEPTH {
+                    count += print_tree(fs, &inode, depth + 1)?;
+                }
+            }
+            S_IFLNK => {
+                let mut target = [0; 256];
+                let len = fs.read_link(&inode, &mut target)?;
+                let target = core::str::from_utf8(&target[..len]).unwrap_or("?");
+                println!("{:indent$}{name} -> {target}", "");
+            }
+            S_IFREG => {
+                let mut head = [0; PREVIEW_LEN];
+                let len = fs.read(&inode, 0, &mut head)?;
+                match preview(&head[..len]) {
+                    Some(text) => println!(
+                        "{:indent$}{name} ({} bytes): {text:?}{}",
+                        "",
+                        inode.size,
+                        if (text.len() as u64) < inode.size {
+                            "..."
+                        } else {
+                            ""
+                        }
+                    ),
+                    None => println!("{:indent$}{name} ({} bytes)", "", inode.size),
+                }
+            }
+            _ => println!("{:indent$}{name} (mode {:o})", "", inode.mode),
+        }
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+/// Mount the ext2 image in the fs region of `flash` and list its tree.
+///
+/// Returns `true` if the whole tree could be read.
+pub fn run(flash: &[u8]) -> bool {
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest.regions().flatten().find(|r| r.name == "fs"))
+    });
+    let data = match region {
+        Ok(Some(region)) => match region.data(flash) {
+            Some(data) => data,
+            None => {
+                println!("Ext2: FAIL (fs region out of bounds)");
+                return false;
+            }
+        },
+        Ok(None) => {
+            println!(
+                "Ext2: no fs region in the image (create it with `cargo xtask mkimage --ext2 <DIR>`)"
+            );
+            return false;
+        }
+        Err(e) => {
+            println!("Ext2: cannot read manifest: {e}");
+            return false;
+        }
+    };
+
+    let dev = FlashBlocks::new(data);
+    let sectors = dev.num_blocks();
+    let fs = match Ext2::mount(dev) {
+        Ok(fs) => fs,
+        Err(e) => {
+            println!("Ext2: FAIL (cannot mount: {e})");
+            return false;
+        }
+    };
+    println!(
+        "Ext2: mounted {:?} from {sectors} flash sectors: {} blocks of {} bytes, {} inodes",
+        fs.volume(),
+        fs.blocks,
+        fs.block_size,
+        fs.inodes
+    );
+    let result = fs.inode(ROOT_INODE).and_then(|root| match root.is_dir() {
+        true => print_tree(&fs, &root, 0),
+        false => Err(Ext2Error::Corrupt("root inode")),
+    });
+    match result {
+        Ok(count) => {
+            println!("Ext2: PASS ({count} entries)");
+            true
+        }
+        Err(e) => {
+            println!("Ext2: FAIL ({e})");
+            false
+        }
+    }
+}
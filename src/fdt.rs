@@ -0,0 +1,183 @@
+//! Minimal flattened device tree reader.
+//!
+//! Just enough to list the flash nodes QEMU puts directly under the root
+//! node, so the app can tell which banks non-secure software may access
+//! before touching them, and to read the kernel command line. All FDT
+//! integers are big-endian.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Largest number of `reg` entries kept per node.
+const MAX_REGS: usize = 4;
+
+fn be_u32(bytes: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(off..off + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Read a `cells`-wide big-endian number.
+fn be_cells(bytes: &[u8], cells: u32) -> u64 {
+    bytes
+        .chunks_exact(4)
+        .take(cells as usize)
+        .fold(0, |acc, c| {
+            (acc << 32) | u64::from(u32::from_be_bytes(c.try_into().unwrap()))
+        })
+}
+
+/// A string property value without its NUL terminator.
+fn prop_str(value: &[u8]) -> Option<&str> {
+    let end = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+    core::str::from_utf8(&value[..end]).ok()
+}
+
+/// A flash node found under the root node.
+pub struct FlashNode<'a> {
+    /// Node name, e.g. `flash@0` or `secflash@0`.
+    pub name: &'a str,
+    regs: [(u64, u64); MAX_REGS],
+    reg_count: usize,
+    /// `status` property, if present.
+    pub status: Option<&'a str>,
+    /// `secure-status` property, if present.
+    pub secure_status: Option<&'a str>,
+}
+
+impl<'a> FlashNode<'a> {
+    /// `(base, size)` pairs from the `reg` property.
+    pub fn regs(&self) -> &[(u64, u64)] {
+        &self.regs[..self.reg_count]
+    }
+
+    /// Whether non-secure software may use the node.
+    pub fn non_secure(&self) -> bool {
+        matches!(self.status, None | Some("okay") | Some("ok"))
+    }
+}
+
+/// Total size of the blob at `ptr`, or `None` if it is not a device tree.
+///
+/// # Safety
+///
+/// `ptr` must be readable for the 8-byte FDT header.
+pub unsafe fn total_size(ptr: *const u8) -> Option<usize> {
+    let header = unsafe { core::slice::from_raw_parts(ptr, 8) };
+    if be_u32(header, 0)? != FDT_MAGIC {
+        return None;
+    }
+    be_u32(header, 4).map(|n| n as usize)
+}
+
+/// Call `f` for every `flash@`/`secflash@` node directly under the root.
+///
+/// Returns `None` if the blob is malformed.
+pub fn for_each_flash_node<'a>(fdt: &'a [u8], mut f: impl FnMut(FlashNode<'a>)) -> Option<()> {
+    let structs = be_u32(fdt, 8)? as usize;
+    let strings = be_u32(fdt, 12)? as usize;
+    let (mut addr_cells, mut size_cells) = (2, 1);
+    let mut depth = 0usize;
+    let mut node: Option<FlashNode<'a>> = None;
+    let mut off = structs;
+    loop {
+        let token = be_u32(fdt, off)?;
+        off += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let len = fdt.get(off..)?.iter().position(|&b| b == 0)?;
+                let name = core::str::from_utf8(&fdt[off..off + len]).ok()?;
+                off = (off + len + 1).next_multiple_of(4);
+                depth += 1;
+                if depth == 2 && (name.starts_with("flash@") || name.starts_with("secflash@")) {
+                    node = Some(FlashNode {
+                        name,
+                        regs: [(0, 0); MAX_REGS],
+                        reg_count: 0,
+                        status: None,
+                        secure_status: None,
+                    });
+                }
+            }
+            FDT_END_NODE => {
+                if depth == 2
+                    && let Some(done) = node.take()
+                {
+                    f(done);
+                }
+                depth = depth.checked_sub(1)?;
+            }
+            FDT_PROP => {
+                let len = be_u32(fdt, off)? as usize;
+                let name_off = be_u32(fdt, off + 4)? as usize;
+                let value = fdt.get(off + 8..off + 8 + len)?;
+                off = (off + 8 + len).next_multiple_of(4);
+                let name = prop_str(fdt.get(strings + name_off..)?)?;
+                match (depth, name, node.as_mut()) {
+                    (1, "#address-cells", _) => addr_cells = be_u32(value, 0)?,
+                    (1, "#size-cells", _) => size_cells = be_u32(value, 0)?,
+                    (2, "reg", Some(node)) => {
+                        let entry = 4 * (addr_cells + size_cells) as usize;
+                        for chunk in value.chunks_exact(entry).take(MAX_REGS) {
+                            let (base, size) = chunk.split_at(4 * addr_cells as usize);
+                            node.regs[node.reg_count] =
+                                (be_cells(base, addr_cells), be_cells(size, size_cells));
+                            node.reg_count += 1;
+                        }
+                    }
+                    (2, "status", Some(node)) => node.status = prop_str(value),
+                    (2, "secure-status", Some(node)) => node.secure_status = prop_str(value),
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => return Some(()),
+            _ => return None,
+        }
+    }
+}
+
+/// The `bootargs` property of `/chosen` (QEMU's `-append`).
+///
+/// Returns `None` if there is none or the blob is malformed.
+pub fn chosen_bootargs(fdt: &[u8]) -> Option<&str> {
+    let structs = be_u32(fdt, 8)? as usize;
+    let strings = be_u32(fdt, 12)? as usize;
+    let mut depth = 0usize;
+    let mut in_chosen = false;
+    let mut off = structs;
+    loop {
+        let token = be_u32(fdt, off)?;
+        off += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let len = fdt.get(off..)?.iter().position(|&b| b == 0)?;
+                let name = &fdt[off..off + len];
+                off = (off + len + 1).next_multiple_of(4);
+                depth += 1;
+                if depth == 2 {
+                    in_chosen = name == b"chosen";
+                }
+            }
+            FDT_END_NODE => depth = depth.checked_sub(1)?,
+            FDT_PROP => {
+                let len = be_u32(fdt, off)? as usize;
+                let name_off = be_u32(fdt, off + 4)? as usize;
+                let value = fdt.get(off + 8..off + 8 + len)?;
+                off = (off + 8 + len).next_multiple_of(4);
+                if in_chosen
+                    && depth == 2
+                    && prop_str(fdt.get(strings + name_off..)?)? == "bootargs"
+                {
+                    return prop_str(value);
+                }
+            }
+            FDT_NOP => {}
+            _ => return None,
+        }
+    }
+}
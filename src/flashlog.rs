@@ -0,0 +1,303 @@
+//! Console log kept in a flash ring buffer.
+//!
+//! With the `flash-log` feature every line the app prints is also appended
+//! to the writable "log" region (`cargo xtask mkimage --log-ring`), so the
+//! output of a run that crashed or hung can be read on the next boot. The
+//! region is used as a ring of erase blocks:
+//!
+//! ```text
+//! block   0x00  magic  "LOGB"
+//!         0x04  seq    u32, one more than the previous block's
+//!         0x10  entries, each 4-byte aligned:
+//!               len u16 (0xFFFF: free), boot u16, len bytes of text
+//! ```
+//!
+//! When a block is full the oldest one is erased and reused. Each entry is
+//! tagged with the boot it was written in; at start-up [`start`] prints the
+//! entries of the previous boot before mirroring begins.
+//!
+//! Only the app's own output is mirrored: axlog's logger belongs to
+//! axruntime, which installs it before `main` runs.
+
+use crate::cfi::{CfiFlash, FlashError};
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const BLOCK_MAGIC: &[u8; 4] = b"LOGB";
+const BLOCK_HEADER: usize = 0x10;
+const ENTRY_HEADER: usize = 4;
+/// `len` of a free entry slot.
+const FREE: u16 = 0xFFFF;
+/// Longest line kept; longer ones are truncated.
+pub const MAX_LINE: usize = 256;
+
+/// Append-only log over a ring of erase blocks.
+pub struct FlashLog {
+    flash: CfiFlash,
+    /// Bank offset of the first block.
+    start: usize,
+    blocks: usize,
+    /// Block being appended to, its sequence number and the next free byte
+    /// in it.
+    head: usize,
+    seq: u32,
+    pos: usize,
+    /// Number of this boot.
+    pub boot: u16,
+}
+
+impl FlashLog {
+    fn block(&self, index: usize) -> usize {
+        self.start + index * self.flash.erase_size
+    }
+
+    /// Sequence number of block `index`, or `None` if it is not a log block.
+    fn seq(&self, index: usize) -> Option<u32> {
+        let mut header = [0; 8];
+        self.flash.read(self.block(index), &mut header);
+        header
+            .starts_with(BLOCK_MAGIC)
+            .then(|| u32::from_le_bytes(header[4..].try_into().unwrap()))
+    }
+
+    /// Call `f` with the boot, offset and length of every entry in block
+    /// `index`, and return the offset of its first free slot.
+    fn scan(&self, index: usize, mut f: impl FnMut(u16, usize, usize)) -> usize {
+        let end = self.block(index) + self.flash.erase_size;
+        let mut at = self.block(index) + BLOCK_HEADER;
+        let mut header = [0; ENTRY_HEADER];
+        while at + ENTRY_HEADER <= end {
+            self.flash.read(at, &mut header);
+            let len = u16::from_le_bytes([header[0], header[1]]);
+            let next = at + ENTRY_HEADER + usize::from(len).next_multiple_of(4);
+            if len == FREE || next > end {
+                break;
+            }
+            f(
+                u16::from_le_bytes([header[2], header[3]]),
+                at + ENTRY_HEADER,
+                len.into(),
+            );
+            at = next;
+        }
+        at
+    }
+
+    /// Block indices holding log blocks, oldest first.
+    fn order(&self) -> impl Iterator<Item = usize> + '_ {
+        (1..=self.blocks)
+            .map(|i| (self.head + i) % self.blocks)
+            .filter(|&i| self.seq(i).is_some())
+    }
+
+    /// Whether the `len` bytes at `off` are erased.
+    fn erased(&self, off: usize, len: usize) -> bool {
+        let mut chunk = [0; 64];
+        (off..off + len).step_by(chunk.len()).all(|at| {
+            let n = chunk.len().min(off + len - at);
+            self.flash.read(at, &mut chunk[..n]);
+            chunk[..n].iter().all(|&b| b == 0xFF)
+        })
+    }
+
+    /// Erase block `index` and start it as sequence number `seq`.
+    fn open(&mut self, index: usize, seq: u32) -> Result<(), FlashError> {
+        let block = self.block(index);
+        self.flash.erase(block)?;
+        let mut header = [0; 8];
+        header[..4].copy_from_slice(BLOCK_MAGIC);
+        header[4..].copy_from_slice(&seq.to_le_bytes());
+        self.flash.program(block, &header)?;
+        (self.head, self.seq, self.pos) = (index, seq, block + BLOCK_HEADER);
+        Ok(())
+    }
+
+    /// Find the end of the log in the `len` bytes at bank offset `offset`.
+    pub fn mount(flash: CfiFlash, offset: usize, len: usize) -> Result<Self, FlashError> {
+        let start = offset.next_multiple_of(flash.erase_size);
+        let blocks = (offset + len).saturating_sub(start) / flash.erase_size;
+        let mut log = Self {
+            flash,
+            start,
+            blocks,
+            head: 0,
+            seq: 0,
+            pos: 0,
+            boot: 1,
+        };
+        let newest = (0..blocks)
+            .filter_map(|i| log.seq(i).map(|seq| (seq, i)))
+            .max();
+        let Some((seq, head)) = newest else {
+            log.open(0, 1)?;
+            return Ok(log);
+        };
+        (log.head, log.seq) = (head, seq);
+        let mut last = 0;
+        for index in log.order() {
+            log.scan(index, |boot, _, _| last = boot);
+        }
+        log.boot = last.wrapping_add(1);
+        log.pos = log.scan(head, |_, _, _| {});
+        // A torn append leaves bytes after the last entry; start afresh.
+        let end = log.block(head) + log.flash.erase_size;
+        if !log.erased(log.pos, end - log.pos) {
+            log.open((head + 1) % blocks, seq + 1)?;
+        }
+        Ok(log)
+    }
+
+    /// Append `text` as an entry of this boot.
+    pub fn append(&mut self, text: &[u8]) -> Result<(), FlashError> {
+        let max = self.flash.erase_size - BLOCK_HEADER - ENTRY_HEADER;
+        let text = &text[..text.len().min(max).min(usize::from(FREE - 1))];
+        let size = ENTRY_HEADER + text.len().next_multiple_of(4);
+        if self.pos + size > self.block(self.head) + self.flash.erase_size {
+            self.open((self.head + 1) % self.blocks, self.seq + 1)?;
+        }
+        // Text first, so the length marks the entry as written.
+        let mut word = [0xFF; 4];
+        for (i, chunk) in text.chunks(4).enumerate() {
+            word[..chunk.len()].copy_from_slice(chunk);
+            word[chunk.len()..].fill(0xFF);
+            self.flash.program(self.pos + ENTRY_HEADER + i * 4, &word)?;
+        }
+        word[..2].copy_from_slice(&(text.len() as u16).to_le_bytes());
+        word[2..].copy_from_slice(&self.boot.to_le_bytes());
+        self.flash.program(self.pos, &word)?;
+        self.pos += size;
+        Ok(())
+    }
+
+    /// Print every entry of boot `boot`, oldest first. Returns the count.
+    pub fn dump(&self, boot: u16) -> usize {
+        let mut count = 0;
+        let mut line = [0; MAX_LINE];
+        for index in self.order() {
+            self.scan(index, |b, at, len| {
+                if b != boot {
+                    return;
+                }
+                let line = &mut line[..len.min(MAX_LINE)];
+                self.flash.read(at, line);
+                match core::str::from_utf8(line) {
+                    Ok(text) => println!("  | {text}"),
+                    Err(_) => println!("  | <{len} bytes of binary data>"),
+                }
+                count += 1;
+            });
+        }
+        count
+    }
+}
+
+/// A line being formatted, truncated at [`MAX_LINE`] bytes.
+struct Line {
+    buf: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(MAX_LINE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// The log that console lines are mirrored to, once [`start`] has run.
+struct Sink {
+    busy: AtomicBool,
+    log: UnsafeCell<Option<FlashLog>>,
+}
+
+// Only accessed while holding `busy`.
+unsafe impl Sync for Sink {}
+
+static SINK: Sink = Sink {
+    busy: AtomicBool::new(true),
+    log: UnsafeCell::new(None),
+};
+
+/// Append a formatted line to the flash log, if it is running.
+///
+/// Lines printed while another is being written (from another CPU) are
+/// dropped rather than interleaved.
+pub fn record(args: fmt::Arguments) {
+    if SINK.busy.swap(true, Ordering::Acquire) {
+        return;
+    }
+    // SAFETY: `busy` was clear, so nothing else holds the log.
+    let log = unsafe { &mut *SINK.log.get() };
+    if let Some(flash_log) = log {
+        let mut line = Line {
+            buf: [0; MAX_LINE],
+            len: 0,
+        };
+        let _ = line.write_fmt(args);
+        if let Err(e) = flash_log.append(&line.buf[..line.len]) {
+            *log = None;
+            println!("Flash log: stopped ({e})");
+        }
+    }
+    SINK.busy.store(false, Ordering::Release);
+}
+
+/// Mount the log region of the bank mapped at `base`, print the previous
+/// boot's lines and start mirroring console output to it.
+pub fn start(base: usize, size: usize) {
+    let (offset, len) = match crate::writable_region(base, size, Some("log")) {
+        Ok(Some(region)) => region,
+        Ok(None) => {
+            println!(
+                "Flash log: no log region in the image (create it with `cargo xtask mkimage --log-ring`)"
+            );
+            return;
+        }
+        Err(e) => {
+            println!("Flash log: disabled ({e})");
+            return;
+        }
+    };
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => {
+            println!("Flash log: disabled (flash probe: {e})");
+            return;
+        }
+    };
+    let whole = (offset + len).saturating_sub(offset.next_multiple_of(flash.erase_size));
+    if whole / flash.erase_size < 2 {
+        println!("Flash log: disabled (log region holds fewer than two erase blocks)");
+        return;
+    }
+    let log = match FlashLog::mount(flash, offset, len) {
+        Ok(log) => log,
+        Err(e) => {
+            println!("Flash log: disabled ({e})");
+            return;
+        }
+    };
+
+    let previous = log.boot.wrapping_sub(1);
+    if previous == 0 {
+        println!("Flash log: empty, this is boot 1");
+    } else {
+        println!("Flash log: boot {}, output of boot {previous}:", log.boot);
+        let lines = log.dump(previous);
+        println!("Flash log: end of boot {previous} ({lines} line(s))");
+    }
+    // SAFETY: `busy` starts set, so `record` does not touch the log yet.
+    unsafe { *SINK.log.get() = Some(log) };
+    SINK.busy.store(false, Ordering::Release);
+}
+
+/// Stop mirroring console output, so that what is printed from here on
+/// leaves the bank as it is. Only call it once other CPUs are done
+/// printing. Used before the write-trace report.
+#[cfg(feature = "write-trace")]
+pub fn stop() {
+    SINK.busy.store(true, Ordering::Release);
+}
@@ -0,0 +1,104 @@
+//! Reading a large span of flash onto the heap and hashing it there.
+//!
+//! The other demos hash regions in place, through the flash mapping. This
+//! one copies the first megabytes of the bank into a single `Vec`, so a run
+//! also exercises the kernel allocator with one large allocation and long
+//! copies out of MMIO. It then checks the copy against flash and hashes
+//! every read-only manifest region inside the span from the copy, which
+//! must give the digests the manifest holds.
+
+use crate::cycles::Stopwatch;
+use crate::layout::{Header, Manifest};
+use sha2::{Digest, Sha256};
+use std::vec::Vec;
+
+/// MiB copied when the command line gives no `hash-mib=<MIB>`.
+pub const DEFAULT_MIB: usize = 4;
+
+/// Copy the first `mib` MiB of `flash` (all of it if smaller) onto the
+/// heap and check the manifest digests against the copy.
+///
+/// Returns `true` if the allocation succeeded, the copy reads as flash and
+/// every read-only region inside the span matches its digest.
+pub fn run(flash: &[u8], mib: Option<&str>) -> bool {
+    let mib = match mib.map(str::parse::<usize>) {
+        None => DEFAULT_MIB,
+        Some(Ok(mib)) if mib > 0 => mib,
+        Some(_) => {
+            println!("Heap hash: FAIL (hash-mib must be a number of MiB above 0)");
+            return false;
+        }
+    };
+    let len = mib.saturating_mul(1 << 20).min(flash.len());
+    let span = &flash[..len];
+
+    let mut copy = Vec::new();
+    if copy.try_reserve_exact(len).is_err() {
+        println!(
+            "Heap hash: FAIL (cannot allocate {len:#x} bytes; give the guest more RAM \
+             with --mem or hash a smaller span)"
+        );
+        return false;
+    }
+    let watch = Stopwatch::start();
+    copy.extend_from_slice(span);
+    let copied = watch.stop();
+    println!(
+        "Heap hash: copied {len:#x} bytes of flash to the heap at {:p} in {} us, {} cycles",
+        copy.as_ptr(),
+        copied.time.as_micros(),
+        copied.cycles
+    );
+    if copy != span {
+        println!("Heap hash: FAIL (the copy differs from flash)");
+        return false;
+    }
+
+    let watch = Stopwatch::start();
+    let digest = Sha256::digest(&copy);
+    let hashed = watch.stop();
+    println!(
+        "  sha256 of the span {} ({} us)",
+        crate::Hex(&digest),
+        hashed.time.as_micros()
+    );
+
+    let manifest = Header::parse(&copy).and_then(|header| Manifest::parse(&copy, &header));
+    let manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("Heap hash: FAIL (cannot read the manifest from the copy: {e})");
+            return false;
+        }
+    };
+    let (mut checked, mut failed) = (0, 0);
+    for region in manifest.regions().flatten() {
+        let result = match region.data(&copy) {
+            _ if region.writable() => "SKIP (writable)",
+            Some(data) if Sha256::digest(data).as_slice() == region.sha256 => "PASS",
+            Some(_) => "FAIL (digest mismatch)",
+            None => "SKIP (past the span)",
+        };
+        if result == "PASS" {
+            checked += 1;
+        } else if result.starts_with("FAIL") {
+            failed += 1;
+        }
+        println!(
+            "  {:<16} {:#010x}  {:<10}  {result}",
+            region.name, region.offset, region.len
+        );
+    }
+    drop(copy);
+
+    if failed > 0 {
+        println!("Heap hash: FAIL ({failed} region(s) differ from the manifest)");
+        false
+    } else if checked == 0 {
+        println!("Heap hash: FAIL (no read-only region lies within the first {mib} MiB)");
+        false
+    } else {
+        println!("Heap hash: PASS ({checked} region(s) hashed from the heap, buffer freed)");
+        true
+    }
+}
@@ -0,0 +1,164 @@
+//! Huge pages against 4K pages for the flash window.
+//!
+//! ArceOS maps the `mmio-ranges` with the largest pages their alignment
+//! allows, so a bank on a 2M boundary normally sits in 2M pages (an
+//! aarch64 level-2 block, an Sv39 megapage, an x86_64 PDE with PS set).
+//! This experiment remaps the start of the bank twice: first with one
+//! mapping per 4K page, which forces 4K entries, then with a single
+//! mapping the page table may cover with 2M pages. After each it reads
+//! back the page size actually installed, checks that the span reads the
+//! same as before, and times a pass that touches one word per 4K page, so
+//! that every access needs a different 4K translation but only one per 2M.
+//!
+//! The bank is left in the single mapping, as the kernel had it. Under
+//! QEMU's TCG the guest's page size barely changes the cost of an access
+//! (its software TLB holds target pages either way); the figures are
+//! meant for hardware.
+
+use crate::cycles::{Span, Stopwatch};
+use std::os::arceos::modules::axhal::mem::{PhysAddr, VirtAddr};
+use std::os::arceos::modules::axhal::paging::{MappingFlags, PageSize};
+use std::os::arceos::modules::axmm::kernel_aspace;
+
+const SMALL: usize = 0x1000;
+const HUGE: usize = 0x20_0000;
+/// Most of the bank remapped and timed: two huge pages.
+const SPAN: usize = 2 * HUGE;
+/// Timed passes per mapping; the fastest one counts.
+const PASSES: usize = 8;
+
+/// One word of every 4K page in the `span` bytes at `va`, folded together.
+fn touch(va: usize, span: usize) -> (Span, u64) {
+    let watch = Stopwatch::start();
+    let mut sum = 0u64;
+    for off in (0..span).step_by(SMALL) {
+        let word = unsafe { ((va + off) as *const u64).read_volatile() };
+        sum = sum.rotate_left(1) ^ word;
+    }
+    (watch.stop(), sum)
+}
+
+/// The fastest of [`PASSES`] passes, and whether they all read `want`.
+fn time(va: usize, span: usize, want: u64) -> (Span, bool) {
+    let mut best: Option<Span> = None;
+    let mut same = true;
+    for _ in 0..PASSES {
+        let (span, sum) = touch(va, span);
+        best = Some(best.map_or(span, |best| best.min(span)));
+        same &= sum == want;
+    }
+    (best.unwrap(), same)
+}
+
+/// Map the `span` bytes at `va` onto `pa` afresh, with `chunk` bytes per
+/// mapping, and return the page size the page table used at `va`.
+fn remap(
+    va: usize,
+    pa: usize,
+    span: usize,
+    chunk: usize,
+    flags: MappingFlags,
+) -> Result<PageSize, &'static str> {
+    let mut aspace = kernel_aspace().lock();
+    aspace
+        .unmap(VirtAddr::from(va), span)
+        .map_err(|_| "cannot unmap the span")?;
+    for off in (0..span).step_by(chunk) {
+        aspace
+            .map_linear(
+                VirtAddr::from(va + off),
+                PhysAddr::from(pa + off),
+                chunk,
+                flags,
+            )
+            .map_err(|_| "cannot map the span")?;
+    }
+    match aspace.page_table().query(VirtAddr::from(va)) {
+        Ok((got, _, page)) if got.as_usize() == pa => Ok(page),
+        _ => Err("the new mapping does not translate to the bank"),
+    }
+}
+
+fn page_name(page: PageSize) -> &'static str {
+    match usize::from(page) {
+        p if p >= 1 << 30 => "1G",
+        p if p >= HUGE => "2M",
+        _ => "4K",
+    }
+}
+
+/// Compare 4K and huge-page mappings of the start of the bank of `size`
+/// bytes at physical address `phys`, mapped at `va`.
+///
+/// Returns `true` if both mappings read what the kernel's mapping read.
+pub fn run(phys: usize, va: usize, size: usize) -> bool {
+    if cfg!(target_arch = "loongarch64") {
+        println!(
+            "Huge pages: not on loongarch64, which maps the bank through a direct-mapped window"
+        );
+        return true;
+    }
+    let span = SPAN.min(size);
+    if !va.is_multiple_of(HUGE) || !phys.is_multiple_of(HUGE) || span < HUGE {
+        println!(
+            "Huge pages: skipped (the bank at {phys:#x} is not on a 2M boundary or is smaller than 2M)"
+        );
+        return true;
+    }
+    let flags = match kernel_aspace()
+        .lock()
+        .page_table()
+        .query(VirtAddr::from(va))
+    {
+        Ok((_, flags, _)) => flags,
+        Err(e) => {
+            println!("Huge pages: FAIL (bank is not mapped: {e:?})");
+            return false;
+        }
+    };
+    let (_, want) = touch(va, span);
+    println!(
+        "Huge pages: touching one word per 4K page of the first {} KiB, best of {PASSES} passes",
+        span / 1024
+    );
+
+    let mut ok = true;
+    let mut rows = [None; 2];
+    for (row, chunk) in rows.iter_mut().zip([SMALL, span]) {
+        match remap(va, phys, span, chunk, flags) {
+            Ok(page) => {
+                let (best, same) = time(va, span, want);
+                println!(
+                    "  {:<3} pages  {:>8} ns  {:>10} cycles{}",
+                    page_name(page),
+                    best.time.as_nanos(),
+                    best.cycles,
+                    if same { "" } else { "  (different data)" }
+                );
+                ok &= same;
+                *row = Some((page, best));
+            }
+            Err(e) => {
+                println!("Huge pages: FAIL ({e})");
+                return false;
+            }
+        }
+    }
+    match rows {
+        [Some((small, small_time)), Some((huge, huge_time))] if ok => {
+            let ratio = small_time.cycles * 100 / huge_time.cycles.max(1);
+            println!(
+                "Huge pages: PASS (same data; {} pages take {}.{:02}x the time of {} pages)",
+                page_name(small),
+                ratio / 100,
+                ratio % 100,
+                page_name(huge)
+            );
+            true
+        }
+        _ => {
+            println!("Huge pages: FAIL (a remapped span read different data)");
+            false
+        }
+    }
+}
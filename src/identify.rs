@@ -0,0 +1,108 @@
+//! Flash device identification.
+//!
+//! Probes the bank the way a flash driver does at boot: the manufacturer
+//! and device codes from read-identifier mode, then the layout and timing
+//! from the CFI query table. QEMU's `cfi.pflash01` reports the codes the
+//! machine configures (Intel `0x89`/`0x18` on the virt machines, zeros on
+//! x86_64). The bank size computed from the table is checked against the
+//! size of the bank the app maps, and the chips and erase blocks against
+//! the flash geometry the image header records, if it has one.
+
+use crate::cfi::CfiFlash;
+use crate::layout::{FlashGeometry, Header};
+
+/// How the probed `flash` differs from `expected`, if it does.
+fn mismatch(flash: &CfiFlash, expected: &FlashGeometry) -> Option<std::string::String> {
+    let chips = flash.geometry.interleave;
+    let chip_bits = flash.width * 8 / chips;
+    let expected_bits = usize::from(expected.device_width) * 8;
+    if chips != usize::from(expected.devices) || chip_bits != expected_bits {
+        return Some(std::format!(
+            "{chips} x{chip_bits} chip(s) answer, the image expects {} x{expected_bits}",
+            expected.devices
+        ));
+    }
+    if flash.erase_size != expected.sector_size as usize {
+        return Some(std::format!(
+            "the erase blocks are {} KiB, the image expects {} KiB",
+            flash.erase_size / 1024,
+            expected.sector_size / 1024
+        ));
+    }
+    None
+}
+
+/// Identify the flash bank mapped at `base`, which holds `image`, and
+/// print its codes and geometry.
+///
+/// Returns `true` if the CFI table describes a bank of the image's size
+/// and the geometry the image records.
+pub fn run(base: usize, image: &[u8]) -> bool {
+    let size = image.len();
+    let expected = Header::parse(image)
+        .ok()
+        .and_then(|header| header.geometry());
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => {
+            println!("Identify: FAIL (flash probe: {e})");
+            return false;
+        }
+    };
+    let id = flash.identify();
+    let g = &flash.geometry;
+    println!(
+        "Identify: manufacturer {:#06x} ({}), device {:#06x}",
+        id.manufacturer,
+        id.vendor(),
+        id.device
+    );
+    println!(
+        "Identify: {} x{} chip(s) on a {}-bit bus, interface code {:#x}",
+        g.interleave,
+        flash.width * 8 / g.interleave,
+        flash.width * 8,
+        g.interface
+    );
+    println!(
+        "Identify: {} KiB in {} erase region(s), {} blocks of {} KiB, {}-byte write buffer",
+        flash.size / 1024,
+        g.erase_regions,
+        g.blocks,
+        flash.erase_size / 1024,
+        g.write_buffer
+    );
+    println!(
+        "Identify: typical/max word program {}/{} us, buffer write {}/{} us, block erase {}/{} ms",
+        g.program_us.0, g.program_us.1, g.buffer_us.0, g.buffer_us.1, g.erase_ms.0, g.erase_ms.1
+    );
+    println!(
+        "Identify: driver timeouts {} us per word program, {} ms per block erase",
+        flash.timeouts.program.as_micros(),
+        flash.timeouts.erase.as_millis()
+    );
+    if let Some(expected) = &expected {
+        println!("Identify: the image expects {expected}");
+        if let Some(why) = mismatch(&flash, expected) {
+            println!(
+                "Identify: FAIL ({why}; QEMU did not emulate the --flash-geometry the image \
+                 was built for)"
+            );
+            return false;
+        }
+    }
+    if flash.size == size {
+        println!(
+            "Identify: PASS (CFI geometry matches the {} KiB bank)",
+            size / 1024
+        );
+        true
+    } else {
+        println!(
+            "Identify: FAIL (CFI table describes {} KiB, the bank is {} KiB)",
+            flash.size / 1024,
+            size / 1024
+        );
+        false
+    }
+}
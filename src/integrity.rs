@@ -0,0 +1,342 @@
+//! Per-sector integrity checking.
+//!
+//! The manifest digests tell whether a region changed; the "crc" region
+//! (`cargo xtask mkimage --crc`) pinpoints where. It stores a CRC-32 for
+//! every 512-byte sector from the payload on, and [`CheckedFlash`] checks
+//! them on every read. A bad sector is reported with its offset and region,
+//! and can be quarantined so later reads fail straight away instead of
+//! returning data known to be bad.
+//!
+//! Sectors of writable regions change at run time and are not checked.
+//! `cargo xtask image corrupt` flips bits in an image to try this out.
+
+#[cfg(any(feature = "report", feature = "json-report"))]
+use crate::layout::Region;
+use crate::layout::{CRC_HEADER_SIZE, CRC_MAGIC, Header, Manifest};
+use core::fmt;
+use std::vec;
+use std::vec::Vec;
+
+/// Bad sectors listed by the demo.
+const REPORT_MAX: usize = 8;
+
+/// CRC-32 (IEEE 802.3) lookup table.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & 0u32.wrapping_sub(crc & 1));
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE 802.3), a byte at a time.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Fold `data` into a running CRC-32, for data that arrives in pieces.
+/// Start from `!0`; the CRC is the complement of the final value.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &b| {
+        CRC_TABLE[usize::from(crc as u8 ^ b)] ^ (crc >> 8)
+    })
+}
+
+/// Reasons a read through [`CheckedFlash`] fails.
+#[derive(Debug)]
+pub enum IntegrityError {
+    /// The crc region is malformed.
+    BadTable,
+    /// The sector at this offset does not match its CRC.
+    Corrupt(usize),
+    /// The sector at this offset was found corrupt earlier.
+    Quarantined(usize),
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadTable => write!(f, "malformed crc region"),
+            Self::Corrupt(off) => write!(f, "sector at {off:#x} fails its CRC"),
+            Self::Quarantined(off) => write!(f, "sector at {off:#x} is quarantined"),
+        }
+    }
+}
+
+/// The CRC table stored in the crc region.
+pub struct CrcTable<'a> {
+    pub sector_size: usize,
+    /// Image offset of the first sector.
+    pub start: usize,
+    crcs: &'a [u8],
+}
+
+impl<'a> CrcTable<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, IntegrityError> {
+        let word = |off: usize| {
+            data.get(off..off + 4)
+                .map(|w| u32::from_le_bytes(w.try_into().unwrap()) as usize)
+                .ok_or(IntegrityError::BadTable)
+        };
+        if data.get(..4) != Some(CRC_MAGIC.as_slice()) {
+            return Err(IntegrityError::BadTable);
+        }
+        let sector_size = word(4)?;
+        let start = word(8)?;
+        let count = word(12)?;
+        let crcs = data
+            .get(CRC_HEADER_SIZE..CRC_HEADER_SIZE + 4 * count)
+            .ok_or(IntegrityError::BadTable)?;
+        if sector_size == 0 {
+            return Err(IntegrityError::BadTable);
+        }
+        Ok(Self {
+            sector_size,
+            start,
+            crcs,
+        })
+    }
+
+    /// Number of sectors covered.
+    pub fn sectors(&self) -> usize {
+        self.crcs.len() / 4
+    }
+
+    /// Stored CRC of sector `index`.
+    pub fn crc(&self, index: usize) -> u32 {
+        u32::from_le_bytes(self.crcs[4 * index..4 * index + 4].try_into().unwrap())
+    }
+}
+
+/// CRC state of one region, for the region tables.
+#[cfg(any(feature = "report", feature = "json-report"))]
+pub enum CrcStatus {
+    /// The image has no (valid) crc region.
+    NoTable,
+    /// Writable regions change at run time and are not covered.
+    Writable,
+    /// The region lies outside the sectors the table covers.
+    NotCovered,
+    /// `bad` of `sectors` covered sectors fail their CRC.
+    Checked { sectors: usize, bad: usize },
+}
+
+#[cfg(any(feature = "report", feature = "json-report"))]
+impl fmt::Display for CrcStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoTable => write!(f, "-"),
+            Self::Writable => write!(f, "writable, not covered"),
+            Self::NotCovered => write!(f, "not covered"),
+            Self::Checked { sectors, bad: 0 } => write!(f, "ok ({sectors} sectors)"),
+            Self::Checked { sectors, bad } => write!(f, "BAD ({bad} of {sectors} sectors)"),
+        }
+    }
+}
+
+/// Check the sectors of `table` that overlap `region`.
+#[cfg(any(feature = "report", feature = "json-report"))]
+pub fn crc_status(flash: &[u8], table: Option<&CrcTable>, region: &Region) -> CrcStatus {
+    let Some(table) = table else {
+        return CrcStatus::NoTable;
+    };
+    if region.writable() {
+        return CrcStatus::Writable;
+    }
+    let start = region.offset as usize;
+    let end = start.saturating_add(region.len as usize);
+    if end <= table.start || region.len == 0 {
+        return CrcStatus::NotCovered;
+    }
+    let first = start.saturating_sub(table.start) / table.sector_size;
+    let last = (end - table.start)
+        .div_ceil(table.sector_size)
+        .min(table.sectors());
+    if first >= last {
+        return CrcStatus::NotCovered;
+    }
+    let bad = (first..last)
+        .filter(|&index| {
+            let off = table.start + index * table.sector_size;
+            let sector = flash.get(off..(off + table.sector_size).min(flash.len()));
+            sector.is_none_or(|sector| crc32(sector) != table.crc(index))
+        })
+        .count();
+    CrcStatus::Checked {
+        sectors: last - first,
+        bad,
+    }
+}
+
+/// Reads of the flash image that check every covered sector they touch.
+pub struct CheckedFlash<'a> {
+    flash: &'a [u8],
+    table: CrcTable<'a>,
+    /// Image ranges that are not checked (writable regions).
+    skip: Vec<(usize, usize)>,
+    /// Sectors found corrupt, if quarantining is on.
+    quarantine: Option<Vec<bool>>,
+}
+
+impl<'a> CheckedFlash<'a> {
+    /// Check reads of `flash` against `table`, except in the `skip` ranges.
+    /// With `quarantine`, a sector that fails once is refused from then on.
+    pub fn new(
+        flash: &'a [u8],
+        table: CrcTable<'a>,
+        skip: Vec<(usize, usize)>,
+        quarantine: bool,
+    ) -> Self {
+        let quarantine = quarantine.then(|| vec![false; table.sectors()]);
+        Self {
+            flash,
+            table,
+            skip,
+            quarantine,
+        }
+    }
+
+    /// Check sector `index` of the table.
+    fn check(&mut self, index: usize) -> Result<(), IntegrityError> {
+        let start = self.table.start + index * self.table.sector_size;
+        let end = (start + self.table.sector_size).min(self.flash.len());
+        if self.skip.iter().any(|&(s, e)| start < e && s < end) {
+            return Ok(());
+        }
+        if self.quarantine.as_ref().is_some_and(|q| q[index]) {
+            return Err(IntegrityError::Quarantined(start));
+        }
+        let sector = self.flash.get(start..end).ok_or(IntegrityError::BadTable)?;
+        if crc32(sector) == self.table.crc(index) {
+            return Ok(());
+        }
+        if let Some(q) = &mut self.quarantine {
+            q[index] = true;
+        }
+        Err(IntegrityError::Corrupt(start))
+    }
+
+    /// Copy `buf.len()` bytes at `off` into `buf` after checking the sectors
+    /// they lie in. Bytes outside the table are copied unchecked.
+    pub fn read(&mut self, off: usize, buf: &mut [u8]) -> Result<(), IntegrityError> {
+        let end = off + buf.len();
+        let first = off.saturating_sub(self.table.start) / self.table.sector_size;
+        let last = end
+            .saturating_sub(self.table.start)
+            .div_ceil(self.table.sector_size)
+            .min(self.table.sectors());
+        for index in first..last {
+            self.check(index)?;
+        }
+        buf.copy_from_slice(&self.flash[off..end]);
+        Ok(())
+    }
+}
+
+/// Check every sector covered by the crc region of `flash`, then read each
+/// read-only region through a quarantining [`CheckedFlash`].
+///
+/// Returns `true` if no sector is corrupt.
+pub fn run(flash: &[u8]) -> bool {
+    let manifest = Header::parse(flash).and_then(|header| Manifest::parse(flash, &header));
+    let manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("Integrity: cannot read manifest: {e}");
+            return false;
+        }
+    };
+    let Some(data) = manifest
+        .regions()
+        .flatten()
+        .find(|r| r.name == "crc")
+        .and_then(|r| r.data(flash))
+    else {
+        println!(
+            "Integrity: no crc region in the image (create it with `cargo xtask mkimage --crc`)"
+        );
+        return false;
+    };
+    let table = match CrcTable::parse(data) {
+        Ok(table) => table,
+        Err(e) => {
+            println!("Integrity: FAIL ({e})");
+            return false;
+        }
+    };
+    let skip: Vec<_> = manifest
+        .regions()
+        .flatten()
+        .filter(|r| r.writable())
+        .map(|r| (r.offset as usize, r.offset as usize + r.len as usize))
+        .collect();
+    let region_of = |off: usize| {
+        manifest
+            .regions()
+            .flatten()
+            .find(|r| (r.offset as usize..r.offset as usize + r.len as usize).contains(&off))
+            .map_or("no region", |r| r.name)
+    };
+    println!(
+        "Integrity: {} sectors of {} bytes from {:#x}, {} writable range(s) skipped",
+        table.sectors(),
+        table.sector_size,
+        table.start,
+        skip.len()
+    );
+
+    let mut checked = CheckedFlash::new(flash, table, skip, true);
+    let count = checked.table.sectors();
+    let mut bad = 0;
+    for index in 0..count {
+        match checked.check(index) {
+            Ok(()) => {}
+            Err(IntegrityError::Corrupt(off)) => {
+                bad += 1;
+                if bad <= REPORT_MAX {
+                    println!(
+                        "  sector {off:#x}..{:#x} in {}: CRC mismatch",
+                        off + checked.table.sector_size,
+                        region_of(off)
+                    );
+                }
+            }
+            Err(e) => println!("  {e}"),
+        }
+    }
+    if bad > REPORT_MAX {
+        println!("  ... and {} more", bad - REPORT_MAX);
+    }
+
+    // Bad sectors are now quarantined: reads that touch them are refused.
+    let mut buf = [0; 512];
+    for region in manifest.regions().flatten().filter(|r| !r.writable()) {
+        // Widened before adding, and cut off at the end of the bank, so a
+        // damaged manifest entry cannot take the read past it.
+        let start = region.offset as usize;
+        let end = (start + region.len as usize).min(flash.len());
+        for off in (start..end).step_by(buf.len()) {
+            let n = buf.len().min(end - off);
+            if let Err(e) = checked.read(off, &mut buf[..n]) {
+                println!("Integrity: reading {} refused: {e}", region.name);
+                break;
+            }
+        }
+    }
+
+    if bad == 0 {
+        println!("Integrity: PASS (all {count} sectors match their CRC)");
+        true
+    } else {
+        println!("Integrity: FAIL ({bad} of {count} sectors corrupt, quarantined)");
+        false
+    }
+}
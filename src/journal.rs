@@ -0,0 +1,378 @@
+//! Journaling filesystem demo.
+//!
+//! `cargo xtask run --journal` reserves an erased, writable "journal" region
+//! in the image and attaches the bank read-write. The region holds a tiny
+//! filesystem of [`FILE_COUNT`] fixed-size files, split into three areas of
+//! equal size:
+//!
+//! ```text
+//! area 0  journal       append-only records, one per file write
+//! area 1  checkpoint A  full copy of all files
+//! area 2  checkpoint B  full copy of all files
+//! ```
+//!
+//! Every record and checkpoint carries a sequence number and a CRC-32, and
+//! ends with a commit word that is programmed in a separate, final step. An
+//! entry without it was torn by a crash and is ignored. Mounting loads the
+//! newest valid checkpoint and replays the committed records written after
+//! it. A checkpoint is written to the older area and committed before the
+//! journal is erased, so a crash at any point leaves a consistent state.
+
+use crate::cfi::{CfiFlash, FlashError};
+use crate::integrity::crc32;
+
+/// Number of files.
+pub const FILE_COUNT: usize = 4;
+/// Size of every file.
+pub const FILE_SIZE: usize = 64;
+
+const RECORD_MAGIC: &[u8; 4] = b"JREC";
+const CHECKPOINT_MAGIC: &[u8; 4] = b"JFSC";
+/// Marks a record or checkpoint as complete.
+const COMMIT: u32 = 0x5449_4D43;
+/// Value of a never-programmed word.
+const ERASED: u32 = 0xFFFF_FFFF;
+
+/// Record: magic, seq, file, data, crc, commit.
+const RECORD_SIZE: usize = 12 + FILE_SIZE + 8;
+/// Checkpoint: magic, seq, all files, crc, commit.
+const CHECKPOINT_SIZE: usize = 8 + FILE_COUNT * FILE_SIZE + 8;
+
+/// Reasons the filesystem cannot be used.
+#[derive(Debug)]
+pub enum JournalError {
+    /// The region is not three erase blocks' worth of aligned areas.
+    BadRegion,
+    /// A file index is out of range.
+    NoSuchFile,
+    /// A program or erase operation failed.
+    Flash(FlashError),
+}
+
+impl From<FlashError> for JournalError {
+    fn from(e: FlashError) -> Self {
+        Self::Flash(e)
+    }
+}
+
+impl core::fmt::Display for JournalError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::BadRegion => write!(f, "region is not three aligned erase blocks"),
+            Self::NoSuchFile => write!(f, "no such file"),
+            Self::Flash(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+fn le_u32(bytes: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(bytes[off..off + 4].try_into().unwrap())
+}
+
+/// What mounting found on flash.
+pub struct MountInfo {
+    /// Checkpoint area the state was loaded from (0 = A, 1 = B), if any.
+    pub checkpoint: Option<usize>,
+    /// Committed records applied on top of the checkpoint.
+    pub replayed: usize,
+    /// Records without a valid commit that were skipped.
+    pub torn: usize,
+}
+
+/// A mounted filesystem.
+pub struct Journal<'a> {
+    flash: &'a CfiFlash,
+    /// Offset of the region in the bank.
+    base: usize,
+    /// Size of each of the three areas.
+    area: usize,
+    /// Sequence number of the last committed write.
+    seq: u32,
+    /// Area holding the newest checkpoint.
+    current: usize,
+    /// Offset of the next free record slot in the journal.
+    append: usize,
+    /// The journal holds something other than records and must be
+    /// checkpointed before the next write.
+    dirty: bool,
+    files: [[u8; FILE_SIZE]; FILE_COUNT],
+}
+
+impl<'a> Journal<'a> {
+    /// Mount the filesystem in the `len` bytes at `base`, formatting it if
+    /// neither checkpoint is valid.
+    pub fn mount(
+        flash: &'a CfiFlash,
+        base: usize,
+        len: usize,
+    ) -> Result<(Self, MountInfo), JournalError> {
+        let area = len / 3;
+        if area == 0
+            || !area.is_multiple_of(flash.erase_size)
+            || !base.is_multiple_of(flash.erase_size)
+        {
+            return Err(JournalError::BadRegion);
+        }
+        let mut fs = Self {
+            flash,
+            base,
+            area,
+            seq: 0,
+            current: 0,
+            append: 0,
+            dirty: false,
+            files: [[0; FILE_SIZE]; FILE_COUNT],
+        };
+        let mut info = MountInfo {
+            checkpoint: None,
+            replayed: 0,
+            torn: 0,
+        };
+
+        for idx in 0..2 {
+            let mut buf = [0; CHECKPOINT_SIZE];
+            flash.read(fs.checkpoint_offset(idx), &mut buf);
+            let body = CHECKPOINT_SIZE - 8;
+            let valid = &buf[0..4] == CHECKPOINT_MAGIC
+                && le_u32(&buf, body) == crc32(&buf[..body])
+                && le_u32(&buf, body + 4) == COMMIT;
+            let seq = le_u32(&buf, 4);
+            if valid && (info.checkpoint.is_none() || seq > fs.seq) {
+                info.checkpoint = Some(idx);
+                fs.seq = seq;
+                fs.current = idx;
+                for (file, data) in fs
+                    .files
+                    .iter_mut()
+                    .zip(buf[8..body].chunks_exact(FILE_SIZE))
+                {
+                    file.copy_from_slice(data);
+                }
+            }
+        }
+        if info.checkpoint.is_none() {
+            // Fresh (erased) or unrecognizable: start empty.
+            fs.current = 1;
+            fs.checkpoint()?;
+            return Ok((fs, info));
+        }
+
+        while fs.append + RECORD_SIZE <= area {
+            let mut buf = [0; RECORD_SIZE];
+            flash.read(base + fs.append, &mut buf);
+            if le_u32(&buf, 0) == ERASED {
+                break;
+            }
+            if &buf[0..4] != RECORD_MAGIC {
+                fs.dirty = true;
+                break;
+            }
+            fs.append += RECORD_SIZE;
+            let body = RECORD_SIZE - 8;
+            let seq = le_u32(&buf, 4);
+            let file = le_u32(&buf, 8) as usize;
+            if le_u32(&buf, body + 4) != COMMIT
+                || le_u32(&buf, body) != crc32(&buf[..body])
+                || file >= FILE_COUNT
+            {
+                info.torn += 1;
+            } else if seq > fs.seq {
+                fs.files[file].copy_from_slice(&buf[12..body]);
+                fs.seq = seq;
+                info.replayed += 1;
+            }
+        }
+        Ok((fs, info))
+    }
+
+    fn checkpoint_offset(&self, idx: usize) -> usize {
+        self.base + (1 + idx) * self.area
+    }
+
+    /// Sequence number of the last committed write.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Contents of file `idx`.
+    pub fn file(&self, idx: usize) -> Option<&[u8; FILE_SIZE]> {
+        self.files.get(idx)
+    }
+
+    fn record(&self, file: usize, data: &[u8]) -> [u8; RECORD_SIZE] {
+        let mut rec = [0xFF; RECORD_SIZE];
+        let body = RECORD_SIZE - 8;
+        rec[0..4].copy_from_slice(RECORD_MAGIC);
+        rec[4..8].copy_from_slice(&(self.seq + 1).to_le_bytes());
+        rec[8..12].copy_from_slice(&(file as u32).to_le_bytes());
+        rec[12..body].fill(0);
+        rec[12..12 + data.len()].copy_from_slice(data);
+        let crc = crc32(&rec[..body]);
+        rec[body..body + 4].copy_from_slice(&crc.to_le_bytes());
+        rec
+    }
+
+    /// Append a record replacing file `file` with `data` (zero-padded),
+    /// leaving out the commit word unless `commit` is set.
+    fn append(&mut self, file: usize, data: &[u8], commit: bool) -> Result<(), JournalError> {
+        if file >= FILE_COUNT || data.len() > FILE_SIZE {
+            return Err(JournalError::NoSuchFile);
+        }
+        if self.dirty || self.append + RECORD_SIZE > self.area {
+            self.checkpoint()?;
+        }
+        let rec = self.record(file, data);
+        let at = self.base + self.append;
+        self.flash.program(at, &rec[..RECORD_SIZE - 4])?;
+        self.append += RECORD_SIZE;
+        if commit {
+            self.flash
+                .program(at + RECORD_SIZE - 4, &COMMIT.to_le_bytes())?;
+            self.seq += 1;
+            self.files[file].copy_from_slice(&rec[12..12 + FILE_SIZE]);
+        }
+        Ok(())
+    }
+
+    /// Replace the contents of file `file` with `data` (zero-padded).
+    pub fn write(&mut self, file: usize, data: &[u8]) -> Result<(), JournalError> {
+        self.append(file, data, true)
+    }
+
+    /// Write a record for `file` but stop before its commit word, as if
+    /// power was lost in between. The in-memory state is unchanged.
+    pub fn write_torn(&mut self, file: usize, data: &[u8]) -> Result<(), JournalError> {
+        self.append(file, data, false)
+    }
+
+    /// Write all files to the older checkpoint area, then empty the journal.
+    pub fn checkpoint(&mut self) -> Result<(), JournalError> {
+        let idx = 1 - self.current;
+        let at = self.checkpoint_offset(idx);
+        let mut buf = [0; CHECKPOINT_SIZE];
+        let body = CHECKPOINT_SIZE - 8;
+        buf[0..4].copy_from_slice(CHECKPOINT_MAGIC);
+        buf[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        for (data, file) in buf[8..body].chunks_exact_mut(FILE_SIZE).zip(&self.files) {
+            data.copy_from_slice(file);
+        }
+        let crc = crc32(&buf[..body]);
+        buf[body..body + 4].copy_from_slice(&crc.to_le_bytes());
+
+        self.flash.erase_range(at, self.area)?;
+        self.flash.program(at, &buf[..body + 4])?;
+        self.flash.program(at + body + 4, &COMMIT.to_le_bytes())?;
+        self.current = idx;
+        // Records up to `seq` are now stale, so a crash before or during
+        // this erase is harmless.
+        self.flash.erase_range(self.base, self.area)?;
+        self.append = 0;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Printable prefix of a file: up to the first NUL or non-ASCII byte.
+fn text(file: &[u8]) -> &str {
+    let end = file
+        .iter()
+        .position(|&b| b == 0 || !b.is_ascii_graphic() && b != b' ')
+        .unwrap_or(file.len());
+    core::str::from_utf8(&file[..end]).unwrap_or("")
+}
+
+/// File 0 holds a little-endian boot counter.
+fn boot_count(fs: &Journal) -> u32 {
+    u32::from_le_bytes(fs.file(0).unwrap()[..4].try_into().unwrap())
+}
+
+fn report(fs: &Journal, info: &MountInfo) {
+    match info.checkpoint {
+        Some(idx) => println!(
+            "Journal: mounted checkpoint {}, replayed {} records, skipped {} torn, seq {}",
+            ["A", "B"][idx],
+            info.replayed,
+            info.torn,
+            fs.seq()
+        ),
+        None => println!("Journal: no valid checkpoint, formatted"),
+    }
+    println!("  file 0: boot count {}", boot_count(fs));
+    for idx in 1..FILE_COUNT {
+        println!("  file {idx}: {:?}", text(fs.file(idx).unwrap()));
+    }
+}
+
+/// Mount the filesystem in the "journal" region of the bank mapped at
+/// `base` (`size` bytes) and exercise writes, a torn write and replay.
+///
+/// Returns `true` if every step behaved as expected.
+pub fn run(base: usize, size: usize) -> bool {
+    let (offset, len) = match crate::writable_region(base, size, Some("journal")) {
+        Ok(Some(region)) => region,
+        Ok(None) => {
+            println!(
+                "Journal: no journal region in the image (create it with `cargo xtask mkimage --journal`)"
+            );
+            return false;
+        }
+        Err(e) => {
+            println!("Journal: FAIL ({e})");
+            return false;
+        }
+    };
+
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => {
+            println!("Journal: FAIL (flash probe: {e})");
+            return false;
+        }
+    };
+    println!(
+        "Journal: CFI flash of {} KiB, {} KiB erase blocks; region at {offset:#x} ({} KiB)",
+        flash.size / 1024,
+        flash.erase_size / 1024,
+        len / 1024
+    );
+
+    let result = (|| -> Result<bool, JournalError> {
+        let (mut fs, info) = Journal::mount(&flash, offset, len)?;
+        report(&fs, &info);
+
+        let boots = boot_count(&fs) + 1;
+        fs.write(0, &boots.to_le_bytes())?;
+        println!("Journal: boot count {boots} (seq {})", fs.seq());
+
+        let before = *fs.file(1).unwrap();
+        fs.write_torn(1, b"torn write")?;
+        println!("Journal: wrote file 1 without committing, remounting");
+        let (fs2, info) = Journal::mount(&flash, offset, len)?;
+        report(&fs2, &info);
+        let torn_ok = info.torn > 0 && fs2.file(1) == Some(&before) && fs2.seq() == fs.seq();
+
+        let mut fs = fs2;
+        fs.write(1, b"committed after a torn write")?;
+        fs.checkpoint()?;
+        println!("Journal: committed file 1 and checkpointed, remounting");
+        let (fs2, info) = Journal::mount(&flash, offset, len)?;
+        report(&fs2, &info);
+        let commit_ok = fs2.seq() == fs.seq() && fs2.file(1) == fs.file(1) && info.replayed == 0;
+        Ok(torn_ok && commit_ok)
+    })();
+
+    match result {
+        Ok(true) => {
+            println!("Journal: PASS (torn write ignored, committed state survived remount)");
+            true
+        }
+        Ok(false) => {
+            println!("Journal: FAIL (remounted state differs from what was committed)");
+            false
+        }
+        Err(e) => {
+            println!("Journal: FAIL ({e})");
+            false
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Machine-readable results on one line.
+//!
+//! Everything else the app prints is meant for people. With the
+//! `json-report` feature it ends with one more line, [`PREFIX`] followed by
+//! a JSON object, for host tools to parse instead of matching that prose:
+//!
+//! ```text
+//! PFlash JSON: {"arch":"riscv64","paddr":34603008,"size":33554432,"magic_ok":true,
+//!   "crc_ok":true,"version":1,"regions":[{"name":"payload","offset":4096,"len":59,
+//!   "writable":false,"crc":"ok"}],"bench":null,"error":null}
+//! ```
+//!
+//! (on one line). `crc_ok` is the header CRC and, with a crc region, every
+//! sector it covers; `regions[].crc` is `"ok"`, `"bad"` (also for a region
+//! past the end of the bank) or `null` for one the table does not cover.
+//! `bench` holds the figures of the `write-combining` demo, and `error` the
+//! code and message of the error the run stopped on. Values the app did not
+//! get to are `null`.
+
+use crate::error::PflashError;
+use crate::integrity::{CrcStatus, CrcTable, crc_status, crc32};
+use crate::layout::{HEADER_CRC_LEN, Header, MAGIC, Manifest};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::string::String;
+
+/// Start of the report line.
+pub const PREFIX: &str = "PFlash JSON: ";
+
+#[cfg(target_arch = "riscv64")]
+const ARCH: &str = "riscv64";
+#[cfg(target_arch = "aarch64")]
+const ARCH: &str = "aarch64";
+#[cfg(target_arch = "x86_64")]
+const ARCH: &str = "x86_64";
+#[cfg(target_arch = "loongarch64")]
+const ARCH: &str = "loongarch64";
+
+/// The image as the demos read it: physical and virtual address and size,
+/// set by [`locate`]. A size of 0 means the run stopped before.
+static PADDR: AtomicUsize = AtomicUsize::new(0);
+static VA: AtomicUsize = AtomicUsize::new(0);
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Figures of the write-combining benchmark, set by [`bench`].
+static BENCH: AtomicBool = AtomicBool::new(false);
+static BENCH_FIGURES: [AtomicU64; 6] = [const { AtomicU64::new(0) }; 6];
+
+/// Record where the image is, once it has been checked to be mapped.
+pub fn locate(paddr: usize, va: usize, size: usize) {
+    PADDR.store(paddr, Ordering::Relaxed);
+    VA.store(va, Ordering::Relaxed);
+    SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Record the figures of the write-combining benchmark.
+#[cfg(feature = "write-combining")]
+pub fn bench(sample: &crate::wcmap::Sample) {
+    let figures = [
+        sample.span as u64,
+        sample.passes as u64,
+        sample.device.as_nanos() as u64,
+        sample.relaxed.as_nanos() as u64,
+        sample.device_mibs,
+        sample.relaxed_mibs,
+    ];
+    for (slot, value) in BENCH_FIGURES.iter().zip(figures) {
+        slot.store(value, Ordering::Relaxed);
+    }
+    BENCH.store(true, Ordering::Relaxed);
+}
+
+/// `s` as a JSON string.
+fn string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// The `magic_ok` to `regions` fields for the image in `flash`.
+fn image(out: &mut String, flash: &[u8]) {
+    let header = Header::parse(flash).ok();
+    let manifest = header
+        .as_ref()
+        .and_then(|header| Manifest::parse(flash, header).ok());
+    let table = manifest.as_ref().and_then(|manifest| {
+        manifest
+            .regions()
+            .flatten()
+            .find(|r| r.name == "crc")
+            .and_then(|r| r.data(flash))
+            .and_then(|data| CrcTable::parse(data).ok())
+    });
+    let _ = write!(out, "\"magic_ok\":{},", flash.starts_with(MAGIC));
+    let mut crc_ok = header
+        .as_ref()
+        .map(|header| crc32(&flash[..HEADER_CRC_LEN]) == header.header_crc);
+    let mut regions = String::new();
+    for region in manifest.iter().flat_map(|m| m.regions()).flatten() {
+        let crc = match region
+            .data(flash)
+            .map(|_| crc_status(flash, table.as_ref(), &region))
+        {
+            Some(CrcStatus::Checked { bad: 0, .. }) => "\"ok\"",
+            Some(CrcStatus::Checked { .. }) | None => {
+                crc_ok = Some(false);
+                "\"bad\""
+            }
+            Some(_) => "null",
+        };
+        if !regions.is_empty() {
+            regions.push(',');
+        }
+        regions.push_str("{\"name\":");
+        string(&mut regions, region.name);
+        let _ = write!(
+            regions,
+            ",\"offset\":{},\"len\":{},\"writable\":{},\"crc\":{crc}}}",
+            region.offset,
+            region.len,
+            region.writable()
+        );
+    }
+    match crc_ok {
+        Some(ok) => {
+            let _ = write!(out, "\"crc_ok\":{ok},");
+        }
+        None => out.push_str("\"crc_ok\":null,"),
+    }
+    match &header {
+        Some(header) => {
+            let _ = write!(out, "\"version\":{},", header.version);
+        }
+        None => out.push_str("\"version\":null,"),
+    }
+    if manifest.is_some() {
+        let _ = write!(out, "\"regions\":[{regions}],");
+    } else {
+        out.push_str("\"regions\":null,");
+    }
+}
+
+/// Print the report line for a run that ended with `result`.
+pub fn emit(result: &Result<(), PflashError>) {
+    let mut out = String::new();
+    let _ = write!(out, "{{\"arch\":\"{ARCH}\",");
+    let (paddr, va, size) = (
+        PADDR.load(Ordering::Relaxed),
+        VA.load(Ordering::Relaxed),
+        SIZE.load(Ordering::Relaxed),
+    );
+    if size > 0 {
+        let _ = write!(out, "\"paddr\":{paddr},\"size\":{size},");
+        image(&mut out, unsafe {
+            core::slice::from_raw_parts(va as *const u8, size)
+        });
+    } else {
+        out.push_str(
+            "\"paddr\":null,\"size\":null,\"magic_ok\":null,\"crc_ok\":null,\
+             \"version\":null,\"regions\":null,",
+        );
+    }
+    if BENCH.load(Ordering::Relaxed) {
+        let [
+            span,
+            passes,
+            device_ns,
+            relaxed_ns,
+            device_mibs,
+            relaxed_mibs,
+        ] = BENCH_FIGURES
+            .each_ref()
+            .map(|slot| slot.load(Ordering::Relaxed));
+        let _ = write!(
+            out,
+            "\"bench\":{{\"span\":{span},\"passes\":{passes},\"device_ns\":{device_ns},\
+             \"relaxed_ns\":{relaxed_ns},\"device_mibs\":{device_mibs},\
+             \"relaxed_mibs\":{relaxed_mibs}}},"
+        );
+    } else {
+        out.push_str("\"bench\":null,");
+    }
+    match result {
+        Ok(()) => out.push_str("\"error\":null}"),
+        Err(e) => {
+            let _ = write!(out, "\"error\":{{\"code\":{},\"message\":", e.exit_code());
+            let mut message = String::new();
+            let _ = write!(message, "{e}");
+            string(&mut out, &message);
+            out.push_str("}}");
+        }
+    }
+    println!("{PREFIX}{out}");
+}
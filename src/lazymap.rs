@@ -0,0 +1,116 @@
+//! Fault-driven mapping of the flash window.
+//!
+//! The classic ArceOS paging exercise: leave a region unmapped and map it
+//! from the page-fault handler on first access. With the `lazy-map` feature
+//! the app removes the bank from the kernel page table just before its
+//! first read, so every access to a page nobody has touched yet faults.
+//! The handler registered for `PAGE_FAULT` checks that the address lies in
+//! the bank, maps that 4K page onto the flash with device attributes and
+//! returns; the faulting access then runs again and succeeds. A fault
+//! outside the bank, or a page that cannot be mapped, is left to the
+//! kernel, which treats it as fatal.
+//!
+//! The handler only records each fault, since printing from it could
+//! deadlock on a console lock held by the interrupted code. [`report`]
+//! prints the record: after the first read, to show the fault-and-map
+//! sequence, and once the demos are done, with how much of the bank they
+//! touched.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::os::arceos::modules::axhal::mem::{PhysAddr, VirtAddr};
+use std::os::arceos::modules::axhal::paging::MappingFlags;
+use std::os::arceos::modules::axhal::trap::{PAGE_FAULT, register_trap_handler};
+use std::os::arceos::modules::axmm::kernel_aspace;
+
+/// Bytes mapped per fault.
+const PAGE: usize = 0x1000;
+/// Faults recorded in detail; later ones are only counted.
+const LOGGED: usize = 8;
+
+/// The window the handler serves: virtual base, physical base and size.
+/// A size of zero (before [`start`]) leaves every fault to the kernel.
+static WINDOW: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+/// Faults taken in the window.
+static FAULTS: AtomicUsize = AtomicUsize::new(0);
+/// Faults already shown by [`report`].
+static REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+/// The first [`LOGGED`] faults: the address and whether it was a write.
+static LOG: [(AtomicUsize, AtomicBool); LOGGED] =
+    [const { (AtomicUsize::new(0), AtomicBool::new(false)) }; LOGGED];
+
+#[register_trap_handler(PAGE_FAULT)]
+fn page_fault(vaddr: VirtAddr, access: MappingFlags) -> bool {
+    let [va, pa, size] = WINDOW.each_ref().map(|w| w.load(Ordering::Acquire));
+    let addr = vaddr.as_usize();
+    if size == 0 || !(va..va + size).contains(&addr) {
+        return false;
+    }
+    let page = addr & !(PAGE - 1);
+    let mapped = kernel_aspace().lock().map_linear(
+        VirtAddr::from(page),
+        PhysAddr::from(pa + (page - va)),
+        PAGE,
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+    );
+    // Unhandled, the fault is fatal, and the kernel reports it.
+    if mapped.is_err() {
+        return false;
+    }
+    if let Some((at, write)) = LOG.get(FAULTS.fetch_add(1, Ordering::Relaxed)) {
+        at.store(addr, Ordering::Relaxed);
+        write.store(access.contains(MappingFlags::WRITE), Ordering::Relaxed);
+    }
+    true
+}
+
+/// Unmap the bank of `size` bytes at physical address `phys`, mapped at
+/// `va`, so that the page-fault handler maps it a page at a time. Returns
+/// `false`, with the bank still mapped, if it cannot be unmapped or the
+/// architecture does not reach it through the page table.
+pub fn start(phys: usize, va: usize, size: usize) -> bool {
+    if cfg!(target_arch = "loongarch64") {
+        println!(
+            "Lazy mapping: not on loongarch64, which reads the bank through the direct-mapped window and takes no page faults there"
+        );
+        return false;
+    }
+    if let Err(e) = kernel_aspace().lock().unmap(VirtAddr::from(va), size) {
+        println!("Lazy mapping: FAIL (cannot unmap the bank at {va:#x}: {e})");
+        return false;
+    }
+    WINDOW[0].store(va, Ordering::Release);
+    WINDOW[1].store(phys, Ordering::Release);
+    WINDOW[2].store(size, Ordering::Release);
+    println!(
+        "Lazy mapping: unmapped the bank [{va:#x}, +{size:#x}]; each page is mapped on its first access"
+    );
+    true
+}
+
+/// Print the faults taken since the last report and the running total.
+pub fn report() {
+    let [va, pa, _] = WINDOW.each_ref().map(|w| w.load(Ordering::Acquire));
+    let total = FAULTS.load(Ordering::Relaxed);
+    let shown = REPORTED.swap(total, Ordering::Relaxed);
+    for (n, (at, write)) in LOG.iter().enumerate().take(total).skip(shown) {
+        let addr = at.load(Ordering::Relaxed);
+        let access = match write.load(Ordering::Relaxed) {
+            true => "write",
+            false => "read",
+        };
+        let page = addr & !(PAGE - 1);
+        println!(
+            "  fault {n}: {access} at {addr:#x} -> mapped the 4K page at {page:#x} onto {:#x} as device memory",
+            pa + (page - va)
+        );
+    }
+    let unlogged = total.saturating_sub(shown.max(LOGGED));
+    if unlogged > 0 {
+        println!("  ... and {unlogged} more fault(s), not recorded in detail");
+    }
+    println!(
+        "Lazy mapping: {total} fault(s) so far, {} KiB of the bank mapped",
+        total * PAGE / 1024
+    );
+}
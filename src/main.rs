@@ -2,55 +2,885 @@
 #![cfg_attr(feature = "axstd", no_main)]
 
 #[cfg(feature = "axstd")]
-#[macro_use]
+#[cfg_attr(not(feature = "flash-log"), macro_use)]
 extern crate axstd as std;
 
+/// Also append every console line to the flash log.
+#[cfg(feature = "flash-log")]
+macro_rules! println {
+    () => {
+        println!("")
+    };
+    ($($arg:tt)*) => {{
+        ::std::println!($($arg)*);
+        $crate::flashlog::record(format_args!($($arg)*));
+    }};
+}
+
+#[cfg(feature = "axstd")]
+mod bank;
+#[cfg(feature = "banner")]
+mod banner;
+#[cfg(feature = "bench-record")]
+mod benchlog;
+#[cfg(any(feature = "ext2", feature = "fs-write"))]
+#[cfg_attr(not(feature = "ext2"), allow(dead_code))]
+mod block;
+#[cfg(feature = "cached-alias")]
+mod cachemap;
+#[cfg(any(
+    feature = "journal",
+    feature = "fs-write",
+    feature = "identify",
+    feature = "erase-suspend",
+    feature = "write-queue",
+    feature = "replicas",
+    feature = "flash-log",
+    feature = "panic-record",
+    feature = "selftest",
+    feature = "device-map",
+    feature = "bench-record",
+    feature = "shell",
+    feature = "flash-script"
+))]
+#[cfg_attr(
+    not(all(
+        feature = "journal",
+        feature = "fs-write",
+        feature = "identify",
+        feature = "erase-suspend",
+        feature = "write-queue",
+        feature = "replicas",
+        feature = "flash-log",
+        feature = "panic-record"
+    )),
+    allow(dead_code)
+)]
+mod cfi;
+#[cfg(feature = "cpio")]
+mod cpio;
+#[cfg(feature = "panic-record")]
+#[cfg_attr(not(feature = "panic-test"), allow(dead_code))]
+mod crash;
+#[cfg(any(
+    feature = "heap-hash",
+    feature = "map-info",
+    feature = "pattern",
+    feature = "write-combining",
+    feature = "shadow",
+    feature = "cached-alias"
+))]
+mod cycles;
+#[cfg(feature = "decrypt")]
+mod decrypt;
+#[cfg(feature = "device-map")]
+mod devmap;
+#[cfg(feature = "dispatch")]
+mod dispatch;
+#[cfg(all(feature = "el2", target_arch = "aarch64"))]
+mod el2;
+// The demo variants are only built with the demos.
+#[cfg(feature = "axstd")]
+#[allow(dead_code)]
+mod error;
+#[cfg(feature = "ext2")]
+mod ext2;
+#[cfg(all(
+    feature = "axstd",
+    any(
+        target_arch = "aarch64",
+        all(
+            any(
+                feature = "selftest",
+                feature = "access-width",
+                feature = "decrypt",
+                feature = "heap-hash",
+                feature = "semihosting"
+            ),
+            target_arch = "riscv64"
+        )
+    )
+))]
+#[cfg_attr(
+    not(all(
+        any(
+            feature = "selftest",
+            feature = "access-width",
+            feature = "decrypt",
+            feature = "heap-hash",
+            feature = "semihosting"
+        ),
+        target_arch = "aarch64"
+    )),
+    allow(dead_code)
+)]
+mod fdt;
+#[cfg(feature = "flash-log")]
+mod flashlog;
+#[cfg(feature = "heap-hash")]
+mod heaphash;
+#[cfg(feature = "huge-pages")]
+mod hugepage;
+#[cfg(feature = "identify")]
+mod identify;
+#[cfg(feature = "axstd")]
+#[cfg_attr(not(feature = "integrity"), allow(dead_code))]
+mod integrity;
+#[cfg(feature = "journal")]
+mod journal;
+#[cfg(feature = "json-report")]
+mod json;
+#[cfg(feature = "lazy-map")]
+mod lazymap;
+#[cfg(feature = "map-info")]
+mod mapinfo;
+#[cfg(feature = "measure")]
+mod measure;
+#[cfg(feature = "mem-regions")]
+mod memregions;
+#[cfg(feature = "messages")]
+mod messages;
+#[cfg(feature = "meta")]
+mod meta;
+#[cfg(feature = "partitions")]
+mod partition;
+#[cfg(feature = "pattern")]
+mod pattern;
+#[cfg(feature = "write-queue")]
+mod queue;
+#[cfg(feature = "layout-regions")]
+mod regions;
+#[cfg(feature = "replicas")]
+mod replica;
+#[cfg(feature = "report")]
+mod report;
+#[cfg(feature = "romfs")]
+mod romfs;
+#[cfg(feature = "flash-script")]
+mod script;
+#[cfg(feature = "selftest")]
+mod selftest;
+#[cfg(all(
+    feature = "semihosting",
+    any(target_arch = "riscv64", target_arch = "aarch64")
+))]
+mod semihost;
+#[cfg(feature = "shadow")]
+mod shadow;
+#[cfg(any(feature = "shell", feature = "flash-script"))]
+#[cfg_attr(not(feature = "shell"), allow(dead_code))]
+mod shell;
+#[cfg(feature = "erase-suspend")]
+mod suspend;
+#[cfg(feature = "tar")]
+mod tar;
+#[cfg(any(feature = "cpio", feature = "tar", feature = "romfs", feature = "ext2"))]
+mod text;
+#[cfg(feature = "verify")]
+mod verify;
+#[cfg(feature = "watchdog")]
+mod watchdog;
+#[cfg(feature = "write-combining")]
+mod wcmap;
+#[cfg(feature = "access-width")]
+mod width;
+#[cfg(feature = "fs-write")]
+mod writeback;
+// `note` is only called by the demos that write.
+#[cfg(feature = "write-trace")]
+#[allow(dead_code)]
+mod writetrace;
+#[cfg(feature = "xip")]
+mod xip;
+
+// Both demos erase and reprogram the last block of the journal region, which
+// the journal demo keeps its log in.
+#[cfg(all(
+    feature = "journal",
+    any(feature = "erase-suspend", feature = "write-queue")
+))]
+compile_error!(
+    "the erase-suspend and write-queue demos borrow the journal region; build them without `journal`"
+);
+
+#[cfg(feature = "axstd")]
+use error::PflashError;
+/// The image format, shared with xtask (`readpflash-layout/`).
+#[cfg(feature = "axstd")]
+use readpflash_layout as layout;
 #[cfg(feature = "axstd")]
 use std::os::arceos::modules::axhal::mem::phys_to_virt;
 
-/// PFlash1 physical address on RISC-V 64 QEMU virt machine.
-/// pflash0 @ 0x20000000 (32MB), pflash1 @ 0x22000000 (32MB).
+/// The flash banks of the board: `pflash-paddr`, `pflash-size` and
+/// `pflash0-paddr` in the `[devices]` table of the axconfig the app is built
+/// with (`configs/<arch>.toml`), written out by `build.rs`. A board with
+/// flash elsewhere only needs its config to name it. `pci-ecam-base` comes
+/// along for the demos that find PCI devices.
+#[allow(dead_code)]
+mod board {
+    include!(concat!(env!("OUT_DIR"), "/board.rs"));
+}
+
+/// Physical address of the flash bank holding the image: pflash1 on the
+/// riscv64, aarch64 and loongarch64 virt machines, pflash0 (ending at 4 GiB)
+/// on x86_64 q35. The `bank0` feature picks pflash0 instead (see
+/// `build.rs`).
+#[cfg(feature = "axstd")]
+const PFLASH_START: usize = board::PFLASH_PADDR;
+
+/// Physical address of the firmware bank, pflash0 on the riscv64 virt
+/// machine. It holds the firmware when booted with `cargo xtask run --bios
+/// flash`. `None` with `bank0`, which reads it as the image bank.
 #[cfg(target_arch = "riscv64")]
-const PFLASH_START: usize = 0x2200_0000;
+const PFLASH0_START: Option<usize> = board::PFLASH0_PADDR;
+
+/// Size of the PFlash bank, matching the image size chosen by xtask:
+/// 32MB on riscv64 and 64MB on aarch64 (fixed by the virt machines),
+/// 4MB elsewhere.
+#[cfg(feature = "axstd")]
+const PFLASH_SIZE: usize = board::PFLASH_SIZE;
+
+/// The demo mode the app is built for (`cargo xtask run --mode`), whose
+/// `mode-*` feature pulls in the demos of one lesson.
+#[cfg(any(
+    feature = "mode-read",
+    feature = "mode-write",
+    feature = "mode-fs",
+    feature = "mode-bench",
+    feature = "mode-selftest",
+    feature = "mode-shell"
+))]
+const MODE: &str = if cfg!(feature = "mode-read") {
+    "read"
+} else if cfg!(feature = "mode-write") {
+    "write"
+} else if cfg!(feature = "mode-fs") {
+    "fs"
+} else if cfg!(feature = "mode-bench") {
+    "bench"
+} else if cfg!(feature = "mode-selftest") {
+    "selftest"
+} else {
+    "shell"
+};
+
+/// Virtual address of the flash bank at physical address `phys`.
+///
+/// With the `paging` feature the kernel page table maps the `mmio-ranges`
+/// of the axconfig into the linear map. Without it only the boot page table
+/// is live, which identity maps the low 1 GiB (riscv64, aarch64) or 4 GiB
+/// (x86_64) where the banks are. loongarch64 reaches them through the
+/// direct-mapped window `phys_to_virt` uses either way.
+#[cfg(feature = "axstd")]
+fn flash_va(phys: usize) -> usize {
+    if cfg!(any(feature = "paging", target_arch = "loongarch64")) {
+        phys_to_virt(phys.into()).as_usize()
+    } else {
+        phys
+    }
+}
+
+/// List the flash banks in the device tree and probe the ones non-secure
+/// software may access.
+///
+/// With `secure=on` (`cargo xtask run --arch aarch64 --secure`) QEMU puts
+/// pflash0 in the secure address space only and describes it with a
+/// `secflash@` node marked `status = "disabled"`, `secure-status = "okay"`.
+/// Reading it from here would take a synchronous external abort, so such
+/// banks are reported but not touched.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn report_flash_banks() {
+    use std::os::arceos::modules::axhal::dtb::get_bootarg;
+
+    let dtb = get_bootarg();
+    let ptr = phys_to_virt(dtb.into()).as_usize() as *const u8;
+    let size = if dtb != 0 {
+        unsafe { fdt::total_size(ptr) }
+    } else {
+        None
+    };
+    let Some(size) = size else {
+        println!(
+            "No device tree at {:#X}; not probing other flash banks",
+            dtb
+        );
+        return;
+    };
+    let fdt = unsafe { core::slice::from_raw_parts(ptr, size) };
+    println!("Flash banks in the device tree:");
+    let parsed = fdt::for_each_flash_node(fdt, |node| {
+        for &(base, len) in node.regs() {
+            if node.non_secure() {
+                let va = flash_va(base as usize);
+                let word = unsafe { *(va as *const u32) };
+                println!(
+                    "  {:<16} [{:#010X}, +{:#X}] non-secure, first word {:#X}",
+                    node.name, base, len, word
+                );
+            } else {
+                println!(
+                    "  {:<16} [{:#010X}, +{:#X}] secure-only (status {}, secure-status {}), not probed",
+                    node.name,
+                    base,
+                    len,
+                    node.status.unwrap_or("-"),
+                    node.secure_status.unwrap_or("-")
+                );
+            }
+        }
+    });
+    if parsed.is_none() {
+        println!("  (malformed device tree)");
+    }
+}
+
+/// Whether the device tree marks the bank at `phys` secure-only, so that
+/// reading it would abort (see [`report_flash_banks`]).
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn secure_only(phys: usize) -> bool {
+    use std::os::arceos::modules::axhal::dtb::get_bootarg;
+
+    let dtb = get_bootarg();
+    let ptr = phys_to_virt(dtb.into()).as_usize() as *const u8;
+    let Some(size) = (dtb != 0)
+        .then(|| unsafe { fdt::total_size(ptr) })
+        .flatten()
+    else {
+        return false;
+    };
+    let fdt = unsafe { core::slice::from_raw_parts(ptr, size) };
+    let mut secure = false;
+    fdt::for_each_flash_node(fdt, |node| {
+        if !node.non_secure() && node.regs().iter().any(|&(base, _)| base as usize == phys) {
+            secure = true;
+        }
+    });
+    secure
+}
+
+/// The kernel command line: `/chosen/bootargs` in the device tree QEMU
+/// passes to the kernel (set with `-append`). x86_64 and loongarch64 have
+/// no device tree to read it from.
+#[cfg(any(
+    feature = "selftest",
+    feature = "access-width",
+    feature = "decrypt",
+    feature = "heap-hash",
+    all(
+        feature = "semihosting",
+        any(target_arch = "riscv64", target_arch = "aarch64")
+    )
+))]
+fn bootargs() -> Option<&'static str> {
+    #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+    {
+        use std::os::arceos::modules::axhal::dtb::get_bootarg;
+
+        let dtb = get_bootarg();
+        if dtb == 0 {
+            return None;
+        }
+        let ptr = phys_to_virt(dtb.into()).as_usize() as *const u8;
+        let size = unsafe { fdt::total_size(ptr) }?;
+        fdt::chosen_bootargs(unsafe { core::slice::from_raw_parts(ptr, size) })
+    }
+    #[cfg(not(any(target_arch = "riscv64", target_arch = "aarch64")))]
+    None
+}
+
+/// Bytes shown as hex.
+#[cfg(any(feature = "decrypt", feature = "measure", feature = "heap-hash"))]
+struct Hex<'a>(&'a [u8]);
+
+#[cfg(any(feature = "decrypt", feature = "measure", feature = "heap-hash"))]
+impl core::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+/// The value of `key=value` on the kernel command line.
+#[cfg(any(
+    feature = "access-width",
+    feature = "decrypt",
+    feature = "heap-hash",
+    all(
+        feature = "semihosting",
+        any(target_arch = "riscv64", target_arch = "aarch64")
+    )
+))]
+fn bootarg(key: &str) -> Option<&'static str> {
+    bootargs()?
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix(key)?.strip_prefix('='))
+}
+
+/// Whether to run the self-test instead of the demos: `selftest` on the
+/// kernel command line. Without a command line (x86_64, loongarch64) the
+/// feature alone selects it.
+#[cfg(feature = "selftest")]
+fn selftest_selected() -> bool {
+    match bootargs() {
+        Some(args) => args.split_whitespace().any(|arg| arg == "selftest"),
+        None => !cfg!(any(target_arch = "riscv64", target_arch = "aarch64")),
+    }
+}
+
+/// Check that the bank of `size` bytes at `phys` is mapped onto itself at
+/// `va`, page by page, before the first read, which would fault or hang
+/// otherwise. The error says where the mapping breaks off.
+#[cfg(feature = "paging")]
+fn check_mapped(phys: usize, va: usize, size: usize) -> Result<(), PflashError> {
+    use std::os::arceos::modules::axhal::mem::VirtAddr;
+    use std::os::arceos::modules::axmm::kernel_aspace;
+
+    let aspace = kernel_aspace().lock();
+    let mut offset = 0;
+    while offset < size {
+        let fault = match aspace.page_table().query(VirtAddr::from(va + offset)) {
+            Ok((pa, _, page)) if pa.as_usize() == phys + offset => {
+                // On to the start of the next page.
+                let page = usize::from(page);
+                offset = ((va + offset) / page + 1) * page - va;
+                continue;
+            }
+            Ok((pa, _, _)) => error::MapFault::Elsewhere {
+                offset,
+                to: pa.as_usize(),
+            },
+            Err(_) => error::MapFault::Missing(offset),
+        };
+        return Err(PflashError::Unmapped {
+            paddr: phys,
+            va,
+            size,
+            fault,
+        });
+    }
+    Ok(())
+}
 
-/// PFlash1 physical address on AArch64 QEMU virt machine.
-/// pflash0 @ 0x00000000 (64MB), pflash1 @ 0x04000000 (64MB).
-#[cfg(target_arch = "aarch64")]
-const PFLASH_START: usize = 0x0400_0000;
+/// Map the `size` bytes at `va` with `flags` for the `demo` that prints the
+/// failure, if any.
+#[cfg(any(feature = "device-map", feature = "write-combining"))]
+fn remap(
+    demo: &str,
+    va: usize,
+    size: usize,
+    flags: std::os::arceos::modules::axhal::paging::MappingFlags,
+) -> bool {
+    use std::os::arceos::modules::axhal::mem::VirtAddr;
+    use std::os::arceos::modules::axmm::kernel_aspace;
 
-/// PFlash0 physical address on x86_64 QEMU Q35 machine.
-/// 4MB flash image mapped at 4GB - 4MB = 0xFFC00000.
-#[cfg(target_arch = "x86_64")]
-const PFLASH_START: usize = 0xFFC0_0000;
+    match kernel_aspace()
+        .lock()
+        .protect(VirtAddr::from(va), size, flags)
+    {
+        Ok(()) => true,
+        Err(e) => {
+            println!("{demo}: cannot remap {size:#x} bytes at {va:#x} as {flags:?}: {e}");
+            false
+        }
+    }
+}
+
+/// Offset and length of the region `name` (the first writable one if
+/// `None`) of the image in the bank mapped at `base`, or `None` if the image
+/// has none. A region that is not marked writable, or a manifest that does
+/// not parse, is an error.
+#[cfg(any(
+    feature = "journal",
+    feature = "fs-write",
+    feature = "erase-suspend",
+    feature = "write-queue",
+    feature = "replicas",
+    feature = "flash-log",
+    feature = "selftest"
+))]
+fn writable_region(
+    base: usize,
+    size: usize,
+    name: Option<&str>,
+) -> Result<Option<(usize, usize)>, std::string::String> {
+    // Only read the manifest through a slice; the callers change the flash.
+    let flash = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+    let region = layout::find_region(flash, |r| name.map_or(r.writable(), |name| r.name == name))
+        .map_err(|e| std::format!("cannot read manifest: {e}"))?;
+    match region {
+        Some((offset, len, true)) => Ok(Some((offset, len))),
+        Some((..)) => Err(std::format!(
+            "{} region is not marked writable",
+            name.unwrap_or_default()
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Virtual address to look for the image at in the bank at `phys`, or
+/// `None` if reading it would fault: a secure-only bank on aarch64, a bank
+/// the page table does not map, or one at address 0 outside the linear map.
+#[cfg(feature = "axstd")]
+fn bank_va(phys: usize) -> Option<usize> {
+    #[cfg(target_arch = "aarch64")]
+    if secure_only(phys) {
+        return None;
+    }
+    let va = flash_va(phys);
+    #[cfg(feature = "paging")]
+    check_mapped(phys, va, PFLASH_SIZE).ok()?;
+    Some(va).filter(|&va| va != 0)
+}
+
+/// Check the image at the start of `flash` before the demos read it: its
+/// magic, format version and header CRC.
+#[cfg(feature = "axstd")]
+fn check_image(flash: &[u8]) -> Result<(), PflashError> {
+    let magic: [u8; 4] = flash[..4].try_into().unwrap();
+    if magic != *layout::MAGIC {
+        return Err(PflashError::BadMagic(magic));
+    }
+    let header = layout::Header::parse(flash)?;
+    let computed = integrity::crc32(&flash[..layout::HEADER_CRC_LEN]);
+    if computed != header.header_crc {
+        return Err(PflashError::BadCrc {
+            stored: header.header_crc,
+            computed,
+        });
+    }
+    Ok(())
+}
+
+/// The first demo that failed. Every demo still gets its turn; the run
+/// then ends with this error.
+#[cfg(feature = "axstd")]
+#[derive(Default)]
+struct Failures(Option<PflashError>);
+
+#[cfg(feature = "axstd")]
+impl Failures {
+    /// Record `error` unless the demo `passed` or an earlier one failed.
+    #[allow(dead_code)]
+    fn check(&mut self, passed: bool, error: PflashError) {
+        if !passed && self.0.is_none() {
+            self.0 = Some(error);
+        }
+    }
+
+    fn into_result(self) -> Result<(), PflashError> {
+        self.0.map_or(Ok(()), Err)
+    }
+}
+
+/// Read the bank and run the demos the app is built with.
+#[cfg(feature = "axstd")]
+#[cfg_attr(feature = "panic-test", allow(unreachable_code))]
+fn run() -> Result<(), PflashError> {
+    println!("Reading PFlash at physical address {:#X}...", PFLASH_START);
+    #[cfg(any(feature = "bank0", feature = "bank1"))]
+    println!("Built for flash bank pflash{}", board::PFLASH_UNIT);
+    #[cfg(any(
+        feature = "mode-read",
+        feature = "mode-write",
+        feature = "mode-fs",
+        feature = "mode-bench",
+        feature = "mode-selftest",
+        feature = "mode-shell"
+    ))]
+    println!("Built for the {} mode", MODE);
+    #[cfg(all(feature = "el2", target_arch = "aarch64"))]
+    el2::run();
+
+    // Before the first read, which faults or hangs on a bad mapping.
+    #[cfg(feature = "mem-regions")]
+    if !memregions::run(PFLASH_START, PFLASH_SIZE) {
+        return Err(PflashError::Unmapped {
+            paddr: PFLASH_START,
+            va: flash_va(PFLASH_START),
+            size: PFLASH_SIZE,
+            fault: error::MapFault::Listed,
+        });
+    }
+    #[cfg(feature = "map-info")]
+    if !mapinfo::run(PFLASH_START, PFLASH_SIZE) {
+        return Err(PflashError::Unmapped {
+            paddr: PFLASH_START,
+            va: flash_va(PFLASH_START),
+            size: PFLASH_SIZE,
+            fault: error::MapFault::Listed,
+        });
+    }
+    // Remaps the start of the bank, so before anything else reads it.
+    #[cfg(feature = "huge-pages")]
+    let huge_pages = hugepage::run(PFLASH_START, flash_va(PFLASH_START), PFLASH_SIZE);
+
+    // Convert physical address to virtual address via linear mapping.
+    // The paging feature ensures MMIO regions (including PFlash) are
+    // mapped in the kernel page tables; see `flash_va` for builds
+    // without it.
+    #[cfg(not(feature = "paging"))]
+    println!("Built without paging: reading flash through the boot page table");
+    #[cfg(all(feature = "paging", not(feature = "map-info")))]
+    check_mapped(PFLASH_START, flash_va(PFLASH_START), PFLASH_SIZE)?;
+    let (_, start) = bank::select(
+        (board::PFLASH_UNIT, PFLASH_START),
+        board::PFLASH_OTHER,
+        PFLASH_SIZE,
+        bank_va,
+    );
+    let va = flash_va(start);
+    // From here on the bank is mapped a page at a time, as it is touched.
+    #[cfg(feature = "lazy-map")]
+    let lazy = lazymap::start(start, va, PFLASH_SIZE);
+    let word = unsafe { (va as *const u32).read_volatile() };
+    println!(
+        "Try to access pflash dev region [{:#X}], got {:#X}",
+        va, word
+    );
+    println!("Got pflash magic: {}", word.to_ne_bytes().escape_ascii());
+    #[cfg(feature = "lazy-map")]
+    if lazy {
+        lazymap::report();
+    }
+
+    // The whole bank against the file QEMU was given, before anything
+    // writes to it.
+    #[cfg(all(
+        feature = "semihosting",
+        any(target_arch = "riscv64", target_arch = "aarch64")
+    ))]
+    let host_copy = semihost::run(va, PFLASH_SIZE, bootarg("semihost"));
+
+    // The image may sit in a partition rather than start the bank; from
+    // here on `start`, `va` and `size` are the image's.
+    #[cfg(feature = "partitions")]
+    #[cfg_attr(
+        not(any(feature = "report", target_arch = "riscv64")),
+        allow(unused_variables)
+    )]
+    let (start, va, size) = {
+        let bank = unsafe { core::slice::from_raw_parts(va as *const u8, PFLASH_SIZE) };
+        match partition::run(bank) {
+            Some((offset, len)) => (start + offset, va + offset, len),
+            None => (start, va, PFLASH_SIZE),
+        }
+    };
+    #[cfg(not(feature = "partitions"))]
+    let size = PFLASH_SIZE;
+    #[cfg(feature = "json-report")]
+    json::locate(start, va, size);
+
+    // Before anything else reads the bank through the mapping. panic-test
+    // crashes at the end instead of returning them.
+    #[allow(unused_mut)]
+    #[cfg_attr(feature = "panic-test", allow(unused_variables))]
+    let mut failures = Failures::default();
+    #[cfg(feature = "huge-pages")]
+    failures.check(huge_pages, PflashError::CheckFailed("huge-pages"));
+    #[cfg(all(
+        feature = "semihosting",
+        any(target_arch = "riscv64", target_arch = "aarch64")
+    ))]
+    failures.check(host_copy, PflashError::CheckFailed("semihosting"));
+    #[cfg(feature = "device-map")]
+    failures.check(
+        devmap::run(va, size),
+        PflashError::CheckFailed("device-map"),
+    );
+    #[cfg(feature = "banner")]
+    banner::run(va, size);
 
-/// PFlash1 physical address on LoongArch64 QEMU virt machine.
-/// VIRT_FLASH region starts at 0x1d000000. pflash0 is reserved for
-/// firmware, so we use pflash1. When pflash0 is absent, pflash1 maps
-/// at the base of the flash region: 0x1d000000.
-#[cfg(target_arch = "loongarch64")]
-const PFLASH_START: usize = 0x1d00_0000;
+    // Every demo after it may be configured out.
+    #[cfg(feature = "selftest")]
+    if selftest_selected() {
+        failures.check(
+            selftest::run(va, size),
+            PflashError::CheckFailed("selftest"),
+        );
+        return failures.into_result();
+    }
+
+    // The whole bank is mapped, so the image can be read as a slice.
+    let flash = unsafe { core::slice::from_raw_parts(va as *const u8, size) };
+    check_image(flash)?;
+
+    // Mirrors everything printed from here on into the log region.
+    #[cfg(feature = "flash-log")]
+    flashlog::start(va, size);
+    #[cfg(feature = "panic-record")]
+    crash::init(va, size);
+
+    #[cfg(target_arch = "aarch64")]
+    report_flash_banks();
+
+    // Bank 0 is the firmware bank: with `--bios flash` it starts with
+    // the boot trampoline (0x297, `auipc t0, 0`), otherwise it is unused.
+    #[cfg(target_arch = "riscv64")]
+    if let Some(pflash0) = PFLASH0_START.filter(|&pflash0| pflash0 != start) {
+        let va0 = flash_va(pflash0);
+        let word = unsafe { (va0 as *const u32).read_volatile() };
+        let content = match word {
+            0x0000_0297 => "firmware boot code",
+            0xFFFF_FFFF | 0 => "empty",
+            _ => "unknown",
+        };
+        println!(
+            "pflash0 (firmware bank) at [{:#X}]: first word {:#X} ({})",
+            va0, word, content
+        );
+    }
+
+    // Switches the bank to query and identifier modes and back.
+    #[cfg(feature = "identify")]
+    failures.check(
+        identify::run(va, flash),
+        PflashError::CheckFailed("identify"),
+    );
+    #[cfg(feature = "access-width")]
+    failures.check(
+        width::run(va, bootarg("width")),
+        PflashError::CheckFailed("access-width"),
+    );
+    // Leaves the bank mapped as device memory.
+    #[cfg(feature = "write-combining")]
+    failures.check(
+        wcmap::run(va, size),
+        PflashError::CheckFailed("write-combining"),
+    );
+    // Only while the bank reads as an array; unmaps its alias again.
+    #[cfg(feature = "cached-alias")]
+    failures.check(
+        cachemap::run(start, va, size),
+        PflashError::CheckFailed("cached-alias"),
+    );
+
+    // Before the demos read the image, as firmware measures what it
+    // loads before running it.
+    #[cfg(feature = "measure")]
+    measure::run(flash);
+    // Early, so the tags head the log of whatever follows.
+    #[cfg(feature = "meta")]
+    meta::run(flash);
+    #[cfg(feature = "messages")]
+    failures.check(messages::run(flash), PflashError::CheckFailed("messages"));
+    #[cfg(feature = "report")]
+    failures.check(
+        report::run(start, va, flash),
+        PflashError::CheckFailed("report"),
+    );
+    #[cfg(feature = "verify")]
+    failures.check(
+        verify::verify_manifest(flash),
+        PflashError::CheckFailed("verify"),
+    );
+    #[cfg(feature = "heap-hash")]
+    failures.check(
+        heaphash::run(flash, bootarg("hash-mib")),
+        PflashError::CheckFailed("heap-hash"),
+    );
+    #[cfg(feature = "dispatch")]
+    failures.check(dispatch::run(flash), PflashError::CheckFailed("dispatch"));
+    #[cfg(feature = "layout-regions")]
+    failures.check(
+        regions::run(flash),
+        PflashError::CheckFailed("layout-regions"),
+    );
+    #[cfg(feature = "integrity")]
+    failures.check(integrity::run(flash), PflashError::CheckFailed("integrity"));
+    #[cfg(feature = "pattern")]
+    failures.check(pattern::run(flash), PflashError::CheckFailed("pattern"));
+    #[cfg(feature = "xip")]
+    failures.check(xip::run_xip(flash), PflashError::CheckFailed("xip"));
+    #[cfg(feature = "romfs")]
+    failures.check(romfs::run(flash), PflashError::CheckFailed("romfs"));
+    // The payload copied into RAM, which the archive demos parse instead
+    // of flash.
+    #[cfg(feature = "shadow")]
+    let shadow = shadow::run(flash);
+    #[cfg(feature = "shadow")]
+    failures.check(shadow.is_some(), PflashError::CheckFailed("shadow"));
+    // The payload as the archive demos see it: decrypted, and only
+    // once its tag verified, if it is encrypted.
+    #[cfg(feature = "decrypt")]
+    #[cfg_attr(not(any(feature = "cpio", feature = "tar")), allow(unused_variables))]
+    let plaintext = decrypt::run(flash, bootarg("key"));
+    #[cfg(all(not(feature = "decrypt"), any(feature = "cpio", feature = "tar")))]
+    let plaintext: Option<std::vec::Vec<u8>> = None;
+    // An encrypted payload's plaintext is in RAM already.
+    #[cfg(all(feature = "shadow", any(feature = "cpio", feature = "tar")))]
+    let plaintext = plaintext.or(shadow);
+    #[cfg(feature = "cpio")]
+    failures.check(
+        cpio::run(flash, plaintext.as_deref()),
+        PflashError::CheckFailed("cpio"),
+    );
+    #[cfg(feature = "tar")]
+    failures.check(
+        tar::run(flash, plaintext.as_deref()),
+        PflashError::CheckFailed("tar"),
+    );
+    #[cfg(feature = "ext2")]
+    failures.check(ext2::run(flash), PflashError::CheckFailed("ext2"));
+    // Writes to the bank, so it gets the address rather than the slice.
+    #[cfg(feature = "journal")]
+    failures.check(journal::run(va, size), PflashError::WriteFailed("journal"));
+    #[cfg(feature = "fs-write")]
+    failures.check(
+        writeback::run(va, size),
+        PflashError::WriteFailed("fs-write"),
+    );
+    #[cfg(feature = "erase-suspend")]
+    failures.check(
+        suspend::run(va, size),
+        PflashError::WriteFailed("erase-suspend"),
+    );
+    #[cfg(feature = "write-queue")]
+    failures.check(
+        queue::run(va, size),
+        PflashError::WriteFailed("write-queue"),
+    );
+    #[cfg(feature = "replicas")]
+    failures.check(replica::run(va, size), PflashError::WriteFailed("replicas"));
+    #[cfg(feature = "watchdog")]
+    watchdog::run(flash, cfg!(feature = "watchdog-starve"));
+    #[cfg(feature = "flash-script")]
+    failures.check(
+        script::run(va, size),
+        PflashError::WriteFailed("flash-script"),
+    );
+    // How much of the bank the demos touched.
+    #[cfg(feature = "lazy-map")]
+    if lazy {
+        lazymap::report();
+    }
+    // Reads commands from the console until `Q`.
+    #[cfg(feature = "shell")]
+    shell::run(va);
+    #[cfg(feature = "panic-test")]
+    crash::fatal(format_args!("deliberate crash from the panic-test feature"));
+    failures.into_result()
+}
 
 #[cfg_attr(feature = "axstd", unsafe(no_mangle))]
 fn main() {
     #[cfg(feature = "axstd")]
     {
-        println!("Reading PFlash at physical address {:#X}...", PFLASH_START);
-
-        // Convert physical address to virtual address via linear mapping.
-        // The paging feature ensures MMIO regions (including PFlash) are
-        // mapped in the kernel page tables.
-        let va = phys_to_virt(PFLASH_START.into()).as_usize();
-        let ptr = va as *const u32;
-        unsafe {
-            println!(
-                "Try to access pflash dev region [{:#X}], got {:#X}",
-                va, *ptr
-            );
-            let magic = (*ptr).to_ne_bytes();
-            println!(
-                "Got pflash magic: {}",
-                core::str::from_utf8(&magic).unwrap()
-            );
+        let result = run();
+        if let Err(e) = &result {
+            println!("PFlash error {}: {e}", e.exit_code());
+        }
+        // After everything that writes to the bank, so the flash log stops
+        // first: the line it would append for this one is not counted.
+        #[cfg(feature = "write-trace")]
+        {
+            #[cfg(feature = "flash-log")]
+            flashlog::stop();
+            writetrace::report();
+        }
+        // Last, so it sums up everything above.
+        #[cfg(feature = "json-report")]
+        json::emit(&result);
+        if let Err(e) = result {
+            std::process::exit(e.exit_code());
         }
     }
     #[cfg(not(feature = "axstd"))]
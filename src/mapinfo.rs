@@ -0,0 +1,138 @@
+//! Page-table diagnostics for the flash window.
+//!
+//! The app reaches the bank through the linear map, which only covers it if
+//! the platform config lists the bank among its MMIO ranges. When it does
+//! not, or lists it with the wrong size, the first read faults or hangs with
+//! nothing on the console to say why. This module walks the page table over
+//! the whole bank before anything touches it and prints what it finds: the
+//! extents that are mapped, with their page size and flags, and the ones
+//! that are not or that translate somewhere else. Only when every page maps
+//! to the bank does it time the first read.
+
+use crate::cycles::Stopwatch;
+use std::os::arceos::modules::axhal::mem::{VirtAddr, phys_to_virt};
+use std::os::arceos::modules::axhal::paging::{MappingFlags, PageSize};
+use std::os::arceos::modules::axmm::kernel_aspace;
+use std::vec::Vec;
+
+/// Granule stepped over unmapped addresses.
+const SMALL_PAGE: usize = 0x1000;
+
+/// How one page of the window is mapped.
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// Mapped onto the matching bank address.
+    Mapped(MappingFlags, PageSize),
+    /// Mapped, but onto a different physical address.
+    Elsewhere(MappingFlags, PageSize),
+    /// Not mapped.
+    Unmapped,
+}
+
+/// Consecutive pages of the window in the same state.
+struct Extent {
+    offset: usize,
+    len: usize,
+    state: State,
+}
+
+/// Walk the page table over the `size` bytes at `va`, which should
+/// translate to `phys`, merging pages in the same state.
+fn walk(phys: usize, va: usize, size: usize) -> Vec<Extent> {
+    let aspace = kernel_aspace().lock();
+    let mut extents: Vec<Extent> = Vec::new();
+    let mut off = 0;
+    while off < size {
+        let (state, page) = match aspace.page_table().query(VirtAddr::from(va + off)) {
+            Ok((pa, flags, page)) if pa.as_usize() == phys + off => {
+                (State::Mapped(flags, page), page.into())
+            }
+            Ok((_, flags, page)) => (State::Elsewhere(flags, page), page.into()),
+            Err(_) => (State::Unmapped, SMALL_PAGE),
+        };
+        // The end of the page holding `va + off`, clipped to the window.
+        let next = ((va + off) / page + 1) * page - va;
+        let len = next.min(size) - off;
+        match extents.last_mut() {
+            Some(last) if last.state == state => last.len += len,
+            _ => extents.push(Extent {
+                offset: off,
+                len,
+                state,
+            }),
+        }
+        off += len;
+    }
+    extents
+}
+
+/// Check the mapping of the bank of `size` bytes at physical address `phys`
+/// and time the first read through it.
+///
+/// Returns `true` if the whole bank is mapped onto itself and readable; the
+/// caller should not touch the bank otherwise.
+pub fn run(phys: usize, size: usize) -> bool {
+    let watch = Stopwatch::start();
+    let va = phys_to_virt(phys.into()).as_usize();
+    let translate = watch.stop();
+    println!(
+        "Mapping: phys {phys:#x} -> virt {va:#x} (phys_to_virt took {} ns, {} cycles)",
+        translate.time.as_nanos(),
+        translate.cycles
+    );
+
+    let extents = walk(phys, va, size);
+    println!("  offset      length      page  flags");
+    let mut ok = true;
+    let mut device = true;
+    for extent in &extents {
+        let (page, detail) = match extent.state {
+            State::Mapped(flags, page) => {
+                ok &= flags.contains(MappingFlags::READ);
+                device &= flags.contains(MappingFlags::DEVICE);
+                (usize::from(page), std::format!("{flags:?}"))
+            }
+            State::Elsewhere(flags, page) => {
+                ok = false;
+                (
+                    usize::from(page),
+                    std::format!("{flags:?}, translates outside the bank"),
+                )
+            }
+            State::Unmapped => {
+                ok = false;
+                (0, "not mapped".into())
+            }
+        };
+        let page = match page {
+            0 => "-".into(),
+            p if p >= 1 << 30 => std::format!("{}G", p >> 30),
+            p if p >= 1 << 20 => std::format!("{}M", p >> 20),
+            p => std::format!("{}K", p >> 10),
+        };
+        println!(
+            "  {:#010x}  {:#010x}  {page:<4}  {detail}",
+            extent.offset, extent.len
+        );
+    }
+    if !ok {
+        println!(
+            "Mapping: FAIL (add [{phys:#x}, {size:#x}] to the mmio-ranges of the platform config)"
+        );
+        return false;
+    }
+    if !device {
+        println!("Mapping: warning: the bank is not mapped as device memory");
+    }
+
+    let watch = Stopwatch::start();
+    let word = unsafe { (va as *const u32).read_volatile() };
+    let first = watch.stop();
+    println!(
+        "Mapping: PASS ({} extent(s); first read {word:#x} took {} ns, {} cycles)",
+        extents.len(),
+        first.time.as_nanos(),
+        first.cycles
+    );
+    true
+}
@@ -0,0 +1,113 @@
+//! Measured-boot style hashing of the image.
+//!
+//! Firmware with a TPM records what it loads before running it: each
+//! measurement (a SHA-256 digest) is logged as an event and extended into a
+//! platform configuration register, `PCR = SHA-256(PCR || digest)`,
+//! starting from zeros. The app does the same early in boot, in this order:
+//!
+//! - the manifest, then the header, into PCR 1 (configuration);
+//! - the kernel and firmware regions into PCR 0 (code);
+//! - every other read-only region into PCR 2 (data).
+//!
+//! Regions after the header follow the manifest order. Writable regions
+//! change at runtime and are not measured.
+//!
+//! `cargo xtask mkimage` computes the same log from the image it builds and
+//! writes it to `<image>.measurements`, and `cargo xtask test` compares the
+//! `Measure:` lines of a run with it.
+
+use crate::Hex;
+use crate::layout::{Header, MANIFEST_ENTRY_SIZE, MANIFEST_PREAMBLE, Manifest};
+use sha2::{Digest, Sha256};
+
+/// PCRs extended.
+const PCRS: usize = 3;
+
+/// The PCR and TCG event type a part of the image is measured into.
+fn classify(name: &str) -> (usize, &'static str) {
+    match name {
+        "kernel" | "firmware" => (0, "EV_POST_CODE"),
+        "header" | "manifest" => (1, "EV_PLATFORM_CONFIG_FLAGS"),
+        _ => (2, "EV_IPL"),
+    }
+}
+
+/// The PCRs and the number of events logged so far.
+struct Log {
+    pcrs: [[u8; 32]; PCRS],
+    events: usize,
+}
+
+impl Log {
+    /// Measure `data` as the event `name` and print the event.
+    fn extend(&mut self, name: &str, data: &[u8]) {
+        let (pcr, kind) = classify(name);
+        let digest = Sha256::digest(data);
+        let mut hasher = Sha256::new();
+        hasher.update(self.pcrs[pcr]);
+        hasher.update(digest);
+        self.pcrs[pcr] = hasher.finalize().into();
+        println!(
+            "Measure: #{:<2} PCR{pcr} {kind:<24} {} {name}",
+            self.events,
+            Hex(&digest)
+        );
+        self.events += 1;
+    }
+}
+
+/// Measure the image in `flash` and print the event log and the final PCR
+/// values.
+pub fn run(flash: &[u8]) {
+    let header = match Header::parse(flash) {
+        Ok(header) => header,
+        Err(e) => {
+            println!("Measure: FAIL (cannot read header: {e})");
+            return;
+        }
+    };
+    let manifest = match Manifest::parse(flash, &header) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("Measure: FAIL (cannot read manifest: {e})");
+            return;
+        }
+    };
+    println!("Measured boot event log (SHA-256, PCR = SHA-256(PCR || digest)):");
+    let mut log = Log {
+        pcrs: [[0; 32]; PCRS],
+        events: 0,
+    };
+    // Manifest::parse checked that the entries lie within the bank.
+    let start = header.manifest_offset as usize;
+    let end = start + MANIFEST_PREAMBLE + manifest.len() * MANIFEST_ENTRY_SIZE;
+    log.extend("manifest", &flash[start..end]);
+    let mut skipped = 0;
+    for region in manifest.regions() {
+        let region = match region {
+            Ok(region) => region,
+            Err(e) => {
+                println!("Measure: FAIL (invalid manifest entry: {e})");
+                continue;
+            }
+        };
+        if region.writable() {
+            skipped += 1;
+            continue;
+        }
+        match region.data(flash) {
+            Some(data) => log.extend(region.name, data),
+            None => println!(
+                "Measure: FAIL ({} lies past the end of the bank)",
+                region.name
+            ),
+        }
+    }
+    for (i, pcr) in log.pcrs.iter().enumerate() {
+        println!("Measure: PCR{i} {}", Hex(pcr));
+    }
+    println!(
+        "Measured boot: {} events, {skipped} writable region(s) not measured",
+        log.events
+    );
+}
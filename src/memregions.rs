@@ -0,0 +1,119 @@
+//! The physical memory map axhal builds, and the bank's place in it.
+//!
+//! axhal collects the kernel image sections, the free RAM and the
+//! `mmio-ranges` of the platform config into one table of memory regions,
+//! which the `paging` feature maps into the kernel page table. This module
+//! prints that table at boot and checks that the bank lies inside MMIO
+//! regions and overlaps no RAM. A bank left out of `mmio-ranges` (or a
+//! `pflash-paddr` that moved without it) is otherwise only noticed when the
+//! first read faults.
+
+use std::os::arceos::modules::axhal::mem::{MemRegionFlags, PhysMemRegion, memory_regions};
+use std::string::String;
+use std::vec::Vec;
+
+/// `RWX` access letters, then the other flags the region has.
+fn flags(flags: MemRegionFlags) -> String {
+    let mut text = String::new();
+    for (flag, set, unset) in [
+        (MemRegionFlags::READ, 'R', '-'),
+        (MemRegionFlags::WRITE, 'W', '-'),
+        (MemRegionFlags::EXECUTE, 'X', '-'),
+    ] {
+        text.push(if flags.contains(flag) { set } else { unset });
+    }
+    for (flag, name) in [
+        (MemRegionFlags::DEVICE, " device"),
+        (MemRegionFlags::UNCACHED, " uncached"),
+        (MemRegionFlags::FREE, " free"),
+        (MemRegionFlags::RESERVED, " reserved"),
+    ] {
+        if flags.contains(flag) {
+            text.push_str(name);
+        }
+    }
+    text
+}
+
+fn end(region: &PhysMemRegion) -> usize {
+    region.paddr.as_usize() + region.size
+}
+
+/// Why `[phys, phys + size)` is not covered by the MMIO regions of
+/// `regions`, or `None` if it is.
+fn uncovered(regions: &[PhysMemRegion], phys: usize, size: usize) -> Option<String> {
+    let is_device = |region: &PhysMemRegion| region.flags.contains(MemRegionFlags::DEVICE);
+    if let Some(ram) = regions
+        .iter()
+        .find(|r| !is_device(r) && r.paddr.as_usize() < phys + size && phys < end(r))
+    {
+        return Some(std::format!(
+            "it overlaps {} [{:#x}, +{:#x}], which is not MMIO; check pflash-paddr \
+             and the RAM of the platform config",
+            ram.name,
+            ram.paddr.as_usize(),
+            ram.size
+        ));
+    }
+    // Adjacent MMIO regions may cover it between them.
+    let mut at = phys;
+    while at < phys + size {
+        match regions
+            .iter()
+            .find(|r| is_device(r) && r.paddr.as_usize() <= at && at < end(r))
+        {
+            Some(region) => at = end(region),
+            None if at == phys => {
+                return Some(std::format!(
+                    "no MMIO region holds it; add [{phys:#x}, {size:#x}] to the \
+                     mmio-ranges of the platform config"
+                ));
+            }
+            None => {
+                return Some(std::format!(
+                    "the MMIO regions end at {at:#x}, {:#x} bytes into it; widen the \
+                     mmio-ranges entry or lower pflash-size",
+                    at - phys
+                ));
+            }
+        }
+    }
+    None
+}
+
+/// Print the memory regions and check that the bank of `size` bytes at
+/// physical address `phys` lies in MMIO regions.
+///
+/// Returns `false` if it does not. Only a kernel built with paging relies
+/// on those regions to reach the bank; without it this is a warning.
+pub fn run(phys: usize, size: usize) -> bool {
+    let regions: Vec<PhysMemRegion> = memory_regions().collect();
+    println!("Memory regions:");
+    println!("  paddr               size                flags           name");
+    for region in &regions {
+        println!(
+            "  {:#018x}  {:#018x}  {:<14}  {}",
+            region.paddr.as_usize(),
+            region.size,
+            flags(region.flags),
+            region.name
+        );
+    }
+    match uncovered(&regions, phys, size) {
+        None => {
+            println!("Memory regions: PASS (the bank [{phys:#x}, +{size:#x}] is MMIO)");
+            true
+        }
+        Some(why) if cfg!(feature = "paging") => {
+            println!("Memory regions: FAIL (the bank [{phys:#x}, +{size:#x}]: {why})");
+            false
+        }
+        Some(why) => {
+            println!(
+                "Memory regions: warning: the bank [{phys:#x}, +{size:#x}]: {why} \
+                 (read through the boot page table, so not needed without paging)"
+            );
+            true
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Messages looked up by number in a catalog stored in flash.
+//!
+//! `cargo xtask mkimage --messages <FILE>` packs numbered strings into the
+//! "messages" region and tags it [`Kind::Catalog`]. The app finds the
+//! region by that tag, not by name, and prints the messages it uses by
+//! their ids, so the text can change with the image while the app stays
+//! the same (`messages/en.txt` and `messages/zh.txt` hold the same ids). The
+//! strings are read where they are; nothing is copied to RAM.
+
+use crate::layout::catalog::Catalog;
+use crate::layout::{Header, Kind, Manifest};
+
+/// Ids of the messages the app prints, with the text it falls back to
+/// when the catalog has none.
+const USED: [(u32, &str); 4] = [
+    (1, "PFlash reader"),
+    (2, "This text comes from the message catalog in flash."),
+    (3, "The image header and manifest were checked before this."),
+    (
+        4,
+        "Change the text with `cargo xtask run --messages <FILE>`.",
+    ),
+];
+
+/// Print the messages of [`USED`] from the catalog of the image in
+/// `flash`.
+///
+/// Returns `true` if the image has a readable catalog; ids it lacks are
+/// printed with their built-in text.
+pub fn run(flash: &[u8]) -> bool {
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest
+            .regions()
+            .flatten()
+            .find(|r| r.kind() == Some(Kind::Catalog)))
+    });
+    let (name, data) = match region {
+        Ok(Some(region)) => match region.data(flash) {
+            Some(data) => (region.name, data),
+            None => {
+                println!(
+                    "Messages: FAIL (the {} region runs past the bank)",
+                    region.name
+                );
+                return false;
+            }
+        },
+        Ok(None) => {
+            println!("Messages: FAIL (no catalog region; add one with --messages <FILE>)");
+            return false;
+        }
+        Err(e) => {
+            println!("Messages: FAIL (cannot read manifest: {e})");
+            return false;
+        }
+    };
+    let catalog = match Catalog::parse(data) {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            println!("Messages: FAIL ({name} region: {e})");
+            return false;
+        }
+    };
+    println!(
+        "Messages: {} in the {name} region at {:#x}",
+        catalog.len(),
+        data.as_ptr() as usize
+    );
+    let mut missing = 0;
+    for (id, builtin) in USED {
+        match catalog.get(id) {
+            Some(text) => {
+                for line in text.lines() {
+                    println!("  [{id}] {line}");
+                }
+            }
+            None => {
+                missing += 1;
+                println!("  [{id}] {builtin} (not in the catalog; built-in text)");
+            }
+        }
+    }
+    println!(
+        "Messages: PASS ({} of {} resolved from flash)",
+        USED.len() - missing,
+        USED.len()
+    );
+    true
+}
@@ -0,0 +1,43 @@
+//! Image metadata.
+//!
+//! `cargo xtask mkimage --meta KEY=VALUE` (repeatable) stores the pairs in
+//! the "meta" region as UTF-8 text, one `KEY=VALUE` line each, in the order
+//! given. Printing them at boot ties a guest log to the image (and so the
+//! experiment) it ran against.
+
+use crate::layout::{Header, Manifest};
+
+/// Print the key/value pairs in the meta region of the image in `flash`.
+pub fn run(flash: &[u8]) {
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest
+            .regions()
+            .flatten()
+            .find(|r| r.name == "meta")
+            .and_then(|r| r.data(flash)))
+    });
+    let text = match region {
+        Ok(Some(data)) => match core::str::from_utf8(data) {
+            Ok(text) => text,
+            Err(_) => {
+                println!("Image metadata: the meta region is not UTF-8");
+                return;
+            }
+        },
+        Ok(None) => {
+            println!("Image metadata: none (add it with `cargo xtask mkimage --meta KEY=VALUE`)");
+            return;
+        }
+        Err(e) => {
+            println!("Image metadata: cannot read manifest: {e}");
+            return;
+        }
+    };
+    let pairs = text.lines().filter_map(|line| line.split_once('='));
+    let width = pairs.clone().map(|(key, _)| key.len()).max().unwrap_or(0);
+    println!("Image metadata ({} entries):", pairs.clone().count());
+    for (key, value) in pairs {
+        println!("  {key:<width$} = {value}");
+    }
+}
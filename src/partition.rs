@@ -0,0 +1,173 @@
+//! Partition tables in front of the image.
+//!
+//! `cargo xtask mkimage --partition-table mbr|gpt` starts the bank with a
+//! partition table, as on a disk, and puts the image in a data partition
+//! (see `xtask/src/partition.rs`). This module lists the entries of the
+//! MBR, or of the GPT behind a protective MBR, checking the GPT's CRCs, and
+//! returns where the data partition is so the demos read the image there.
+
+use crate::integrity::crc32;
+use crate::layout::{GPT_DATA_TYPE, MAGIC, MBR_DATA_TYPE, MBR_PROTECTIVE, SECTOR};
+
+/// Size of the GPT header covered by its CRC.
+const GPT_HEADER_SIZE: usize = 92;
+
+/// A GUID in its on-disk byte order, shown in the usual form.
+struct Guid<'a>(&'a [u8]);
+
+impl core::fmt::Display for Guid<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let g = self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]])
+        )?;
+        g[8..10].iter().try_for_each(|b| write!(f, "{b:02X}"))?;
+        f.write_str("-")?;
+        g[10..16].iter().try_for_each(|b| write!(f, "{b:02X}"))
+    }
+}
+
+/// A NUL-padded UTF-16LE name, as GPT entries store them.
+struct Utf16<'a>(&'a [u8]);
+
+impl core::fmt::Display for Utf16<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let units = self
+            .0
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0);
+        char::decode_utf16(units)
+            .try_for_each(|c| write!(f, "{}", c.unwrap_or(char::REPLACEMENT_CHARACTER)))
+    }
+}
+
+fn u32_at(data: &[u8], at: usize) -> usize {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize
+}
+
+fn u64_at(data: &[u8], at: usize) -> usize {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap()) as usize
+}
+
+/// Whether `bank` starts with a partition table rather than the image.
+pub fn has_table(bank: &[u8]) -> bool {
+    !bank.starts_with(MAGIC) && bank.len() >= SECTOR && bank[510..512] == [0x55, 0xAA]
+}
+
+/// Print the entries of the MBR at the start of `bank` and return the
+/// (offset, length) of the data partition, if there is one.
+fn mbr(bank: &[u8]) -> Option<(usize, usize)> {
+    let mut data = None;
+    for i in 0..4 {
+        let entry = &bank[446 + 16 * i..462 + 16 * i];
+        let (kind, first, sectors) = (entry[4], u32_at(entry, 8), u32_at(entry, 12));
+        if kind == 0 {
+            continue;
+        }
+        println!(
+            "  MBR {}: type {kind:#04X}, LBA {first}, {sectors} sectors{}",
+            i + 1,
+            if entry[0] == 0x80 { ", active" } else { "" }
+        );
+        if kind == MBR_DATA_TYPE && data.is_none() {
+            data = Some((first * SECTOR, sectors * SECTOR));
+        }
+    }
+    data
+}
+
+/// Print the entries of the GPT behind the protective MBR of `bank` and
+/// return the (offset, length) of the data partition, if there is one.
+fn gpt(bank: &[u8]) -> Option<(usize, usize)> {
+    let header = &bank[SECTOR..2 * SECTOR];
+    if &header[..8] != b"EFI PART" {
+        println!("Partitions: FAIL (protective MBR, but no GPT header at LBA 1)");
+        return None;
+    }
+    let mut copy = [0; GPT_HEADER_SIZE];
+    copy.copy_from_slice(&header[..GPT_HEADER_SIZE]);
+    copy[16..20].fill(0);
+    let (stored, computed) = (u32_at(header, 16) as u32, crc32(&copy));
+    if stored != computed {
+        println!(
+            "Partitions: FAIL (GPT header CRC mismatch: stored {stored:#010x}, computed {computed:#010x})"
+        );
+        return None;
+    }
+    let entries = u64_at(header, 72) * SECTOR;
+    let (count, entry_size) = (u32_at(header, 80), u32_at(header, 84));
+    let Some(array) = count
+        .checked_mul(entry_size)
+        .and_then(|len| bank.get(entries..entries.checked_add(len)?))
+        .filter(|_| entry_size >= 128)
+    else {
+        println!("Partitions: FAIL (the GPT entry array lies outside the bank)");
+        return None;
+    };
+    if crc32(array) as usize != u32_at(header, 88) {
+        println!("Partitions: FAIL (GPT entry array CRC mismatch)");
+        return None;
+    }
+    println!(
+        "  GPT: disk {}, {count} entries of {entry_size} bytes at LBA {}",
+        Guid(&header[56..72]),
+        entries / SECTOR
+    );
+    let mut data = None;
+    for (i, entry) in array.chunks_exact(entry_size).enumerate() {
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        println!(
+            "  GPT {}: \"{}\" type {}, LBA {first}..={last}",
+            i + 1,
+            Utf16(&entry[56..128]),
+            Guid(&entry[..16])
+        );
+        if entry[..16] == GPT_DATA_TYPE && data.is_none() && last >= first {
+            data = Some((first * SECTOR, (last - first + 1) * SECTOR));
+        }
+    }
+    data
+}
+
+/// List the partition table at the start of `bank`, if it has one, and
+/// return the (offset, length) of the partition holding the image.
+pub fn run(bank: &[u8]) -> Option<(usize, usize)> {
+    if !has_table(bank) {
+        println!("Partitions: none (no partition table at the start of the bank)");
+        return None;
+    }
+    let protective = (0..4).any(|i| bank[446 + 16 * i + 4] == MBR_PROTECTIVE);
+    println!(
+        "Partitions: {}",
+        if protective {
+            "GPT (protective MBR)"
+        } else {
+            "MBR"
+        }
+    );
+    let data = if protective { gpt(bank) } else { mbr(bank) };
+    match data {
+        Some((offset, len)) if offset.checked_add(len).is_some_and(|end| end <= bank.len()) => {
+            println!("Partitions: image in the data partition at {offset:#X} (+{len:#X})");
+            Some((offset, len))
+        }
+        Some((offset, len)) => {
+            println!(
+                "Partitions: FAIL (the data partition at {offset:#X} (+{len:#X}) runs past the bank)"
+            );
+            None
+        }
+        None => {
+            println!("Partitions: FAIL (no data partition)");
+            None
+        }
+    }
+}
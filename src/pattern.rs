@@ -0,0 +1,103 @@
+//! Seeded pseudo-random payload check.
+//!
+//! `cargo xtask mkimage --pattern prng:<seed>:<len>` fills the payload
+//! region with a splitmix64 stream and records the seed in the header. The
+//! app regenerates the stream from the seed and compares it with flash, so
+//! a data-integrity test of any size needs no reference copy.
+
+use crate::cycles::Stopwatch;
+use crate::layout::{Header, Manifest};
+
+/// Mismatches printed individually before only counting the rest.
+const SHOWN: usize = 4;
+
+/// The splitmix64 generator, matching `prng_stream` in
+/// `xtask/src/image.rs`.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Compare the payload of the image in `flash` with the stream its header
+/// seed generates.
+///
+/// Returns `true` if every byte matches.
+pub fn run(flash: &[u8]) -> bool {
+    let found = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        let payload = manifest
+            .regions()
+            .flatten()
+            .find(|r| r.name == "payload")
+            .and_then(|r| Some((r.offset, r.data(flash)?)));
+        Ok((header.pattern(), payload))
+    });
+    let (seed, offset, data) = match found {
+        Ok((Some(seed), Some((offset, data)))) => (seed, offset, data),
+        Ok((None, _)) => {
+            println!(
+                "Pattern: FAIL (the payload is not a PRNG pattern; create it with `cargo xtask mkimage --pattern prng:<SEED>:<LEN>`)"
+            );
+            return false;
+        }
+        Ok((Some(_), None)) => {
+            println!("Pattern: FAIL (no payload region within the bank)");
+            return false;
+        }
+        Err(e) => {
+            println!("Pattern: FAIL (cannot read manifest: {e})");
+            return false;
+        }
+    };
+    println!(
+        "Pattern: checking {} bytes at {offset:#x} against the stream of seed {seed:#x}",
+        data.len()
+    );
+
+    let mut prng = SplitMix64(seed);
+    let mut mismatches = 0;
+    let mut first = None;
+    let watch = Stopwatch::start();
+    for (i, chunk) in data.chunks(8).enumerate() {
+        let expected = prng.next().to_le_bytes();
+        if chunk == &expected[..chunk.len()] {
+            continue;
+        }
+        for (j, (&got, &want)) in chunk.iter().zip(&expected).enumerate() {
+            if got == want {
+                continue;
+            }
+            let at = offset as usize + i * 8 + j;
+            if mismatches < SHOWN {
+                println!("  {at:#010x}: got {got:#04x}, expected {want:#04x}");
+            }
+            first.get_or_insert(at);
+            mismatches += 1;
+        }
+    }
+    let elapsed = watch.stop();
+
+    match first {
+        None => {
+            let nanos = elapsed.time.as_nanos().max(1);
+            println!(
+                "Pattern: PASS ({} bytes match, {} MiB/s, {} cycles)",
+                data.len(),
+                data.len() as u128 * 1_000_000_000 / nanos / (1024 * 1024),
+                elapsed.cycles
+            );
+            true
+        }
+        Some(at) => {
+            println!("Pattern: FAIL ({mismatches} bytes differ, the first at {at:#x})");
+            false
+        }
+    }
+}
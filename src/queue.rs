@@ -0,0 +1,240 @@
+//! Buffered flash writes.
+//!
+//! Rewriting a few bytes of NOR flash in place costs a whole block erase
+//! whenever a bit has to go from 0 to 1, so a burst of small updates wears
+//! the same block again and again. [`WriteQueue`] collects writes in RAM
+//! copies of the erase blocks they touch and commits each block once, on
+//! [`flush`](WriteQueue::flush) or when it has to make room for another.
+//!
+//! The demo updates four small records in one block of the writable
+//! "journal" region 64 times (`cargo xtask run --features write-queue`
+//! adds the region), flushes, compares the erase count with what direct
+//! writes would have needed, and then restores the block.
+
+use crate::cfi::{CfiFlash, FlashError};
+use std::vec;
+use std::vec::Vec;
+
+/// Erase blocks the demo queue holds at once.
+const CAPACITY: usize = 2;
+/// Records updated by the demo, and the size of each.
+const RECORDS: usize = 4;
+const RECORD_SIZE: usize = 16;
+/// Updates the demo makes.
+const UPDATES: u32 = 64;
+
+/// What a queue has done so far.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    /// Calls to [`WriteQueue::write`].
+    pub writes: usize,
+    pub bytes: usize,
+    /// Erases the same writes would have cost if programmed one by one.
+    pub unbuffered_erases: usize,
+    /// Blocks committed to flash.
+    pub blocks_flushed: usize,
+    pub erases: usize,
+    /// Bank-width words programmed.
+    pub words: usize,
+}
+
+/// Writes to a flash bank, held back per erase block until flushed.
+pub struct WriteQueue<'a> {
+    flash: &'a CfiFlash,
+    /// Pending blocks, oldest first: bank offset and new contents.
+    blocks: Vec<(usize, Vec<u8>)>,
+    capacity: usize,
+    pub stats: QueueStats,
+}
+
+impl<'a> WriteQueue<'a> {
+    /// A queue holding up to `capacity` erase blocks of `flash`.
+    pub fn new(flash: &'a CfiFlash, capacity: usize) -> Self {
+        Self {
+            flash,
+            blocks: Vec::new(),
+            capacity: capacity.max(1),
+            stats: QueueStats::default(),
+        }
+    }
+
+    /// Number of erase blocks waiting to be flushed.
+    pub fn pending(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The queued copy of the block at `block`, loading it from flash if
+    /// needed.
+    fn block(&mut self, block: usize) -> Result<&mut Vec<u8>, FlashError> {
+        let index = match self.blocks.iter().position(|(at, _)| *at == block) {
+            Some(index) => index,
+            None => {
+                if self.blocks.len() == self.capacity {
+                    self.commit(0)?;
+                }
+                let mut data = vec![0; self.flash.erase_size];
+                self.flash.read(block, &mut data);
+                self.blocks.push((block, data));
+                self.blocks.len() - 1
+            }
+        };
+        Ok(&mut self.blocks[index].1)
+    }
+
+    /// Write the `index`th pending block to flash and drop it.
+    fn commit(&mut self, index: usize) -> Result<(), FlashError> {
+        let (block, data) = &self.blocks[index];
+        let (erased, words) = self.flash.rewrite_block(*block, data)?;
+        self.stats.blocks_flushed += 1;
+        self.stats.erases += usize::from(erased);
+        self.stats.words += words;
+        self.blocks.remove(index);
+        Ok(())
+    }
+
+    /// Queue `data` for bank offset `off`. It may span erase blocks.
+    pub fn write(&mut self, off: usize, data: &[u8]) -> Result<(), FlashError> {
+        self.stats.writes += 1;
+        self.stats.bytes += data.len();
+        let mut needs_erase = false;
+        let mut done = 0;
+        while done < data.len() {
+            let pos = off + done;
+            let block = pos - pos % self.flash.erase_size;
+            let start = pos - block;
+            let n = (self.flash.erase_size - start).min(data.len() - done);
+            let new = &data[done..done + n];
+            let old = &mut self.block(block)?[start..start + n];
+            needs_erase |= old.iter().zip(new).any(|(old, new)| old & new != *new);
+            old.copy_from_slice(new);
+            done += n;
+        }
+        self.stats.unbuffered_erases += usize::from(needs_erase);
+        Ok(())
+    }
+
+    /// Read `buf.len()` bytes at `off`, including queued writes.
+    pub fn read(&self, off: usize, buf: &mut [u8]) {
+        self.flash.read(off, buf);
+        for (block, data) in &self.blocks {
+            let start = off.max(*block);
+            let end = (off + buf.len()).min(block + data.len());
+            if start < end {
+                buf[start - off..end - off].copy_from_slice(&data[start - block..end - block]);
+            }
+        }
+    }
+
+    /// Commit every pending block to flash.
+    pub fn flush(&mut self) -> Result<(), FlashError> {
+        while !self.blocks.is_empty() {
+            self.commit(0)?;
+        }
+        Ok(())
+    }
+}
+
+/// A demo record: a tag and the update number.
+fn record(update: u32) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+    record[..4].copy_from_slice(b"QREC");
+    record[4..8].copy_from_slice(&update.to_le_bytes());
+    record
+}
+
+/// Run the update burst on `block`, then restore it.
+fn burst(flash: &CfiFlash, block: usize) -> Result<(bool, bool, QueueStats), FlashError> {
+    let mut saved = vec![0; flash.erase_size];
+    flash.read(block, &mut saved);
+
+    let mut queue = WriteQueue::new(flash, CAPACITY);
+    for update in 0..UPDATES {
+        let slot = update as usize % RECORDS;
+        queue.write(block + slot * RECORD_SIZE, &record(update))?;
+    }
+    // Nothing has reached the flash yet, but reads see the new data.
+    let mut now = [0; RECORDS * RECORD_SIZE];
+    flash.read(block, &mut now);
+    let deferred = queue.pending() == 1 && now == saved[..now.len()];
+    queue.read(block, &mut now);
+    let last = (UPDATES - RECORDS as u32..UPDATES).flat_map(record);
+    let mut visible = now.iter().copied().eq(last.clone());
+
+    queue.flush()?;
+    flash.read(block, &mut now);
+    visible &= now.iter().copied().eq(last);
+    let stats = core::mem::take(&mut queue.stats);
+
+    queue.write(block, &saved)?;
+    queue.flush()?;
+    Ok((deferred, visible, stats))
+}
+
+/// Run a burst of small writes through a [`WriteQueue`] on the last erase
+/// block of the journal region of the bank mapped at `base`.
+///
+/// Returns `true` if the writes were held back until the flush, landed
+/// intact, and cost fewer erases than direct writes.
+pub fn run(base: usize, size: usize) -> bool {
+    let (offset, len) = match crate::writable_region(base, size, Some("journal")) {
+        Ok(Some(region)) => region,
+        Ok(None) => {
+            println!(
+                "Queue: no journal region in the image (create it with `cargo xtask mkimage --journal`)"
+            );
+            return false;
+        }
+        Err(e) => {
+            println!("Queue: FAIL ({e})");
+            return false;
+        }
+    };
+
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => {
+            println!("Queue: FAIL (flash probe: {e})");
+            return false;
+        }
+    };
+    let end = offset + len;
+    let Some(block) = (end - end % flash.erase_size)
+        .checked_sub(flash.erase_size)
+        .filter(|&block| block >= offset)
+    else {
+        println!("Queue: FAIL (journal region holds no whole erase block)");
+        return false;
+    };
+
+    let (deferred, visible, stats) = match burst(&flash, block) {
+        Ok(result) => result,
+        Err(e) => {
+            println!("Queue: FAIL ({e})");
+            return false;
+        }
+    };
+    println!(
+        "Queue: {} writes ({} bytes) to block {block:#x}: {} block(s) flushed, {} erase(s), {} words programmed",
+        stats.writes, stats.bytes, stats.blocks_flushed, stats.erases, stats.words
+    );
+    println!(
+        "Queue: writing them directly would have cost {} erase(s)",
+        stats.unbuffered_erases
+    );
+    if !deferred {
+        println!("Queue: FAIL (writes reached the flash before the flush)");
+        false
+    } else if !visible {
+        println!("Queue: FAIL (queued data read back wrong)");
+        false
+    } else if stats.erases >= stats.unbuffered_erases {
+        println!("Queue: FAIL (queueing saved no erases)");
+        false
+    } else {
+        println!(
+            "Queue: PASS ({} erase(s) saved, block restored)",
+            stats.unbuffered_erases - stats.erases
+        );
+        true
+    }
+}
@@ -0,0 +1,56 @@
+//! The regions of the layout file the app was built with.
+//!
+//! `cargo xtask run --layout <FILE>` builds with `READPFLASH_LAYOUT`
+//! naming the file, and `build.rs` turns its `[[region]]` tables into
+//! constants: `<NAME>_OFFSET`, the offset the file fixes (`None` where the
+//! image builder places the region), and `<NAME>_LEN`. Code that needs a
+//! layout region takes its extent from these instead of a literal that the
+//! builder may stop matching. At boot every region is checked against the
+//! manifest, which catches an image made from another layout.
+
+use crate::layout::{Header, Manifest};
+
+#[allow(dead_code)]
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/regions.rs"));
+}
+
+#[allow(unused_imports)]
+pub use generated::*;
+
+/// Check every region of the layout file against the manifest of `flash`.
+/// Returns `true` if each one is there, at its fixed offset if it has one,
+/// with its length.
+pub fn run(flash: &[u8]) -> bool {
+    let Some(path) = LAYOUT else {
+        println!("Layout regions: none (built without `cargo xtask run --layout`)");
+        return true;
+    };
+    let manifest = match Header::parse(flash).and_then(|header| Manifest::parse(flash, &header)) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("Layout regions: FAIL (cannot read the manifest: {e})");
+            return false;
+        }
+    };
+    println!("Layout regions of {path}:");
+    let mut ok = true;
+    for &(name, offset, len) in REGIONS {
+        let Some(region) = manifest.regions().flatten().find(|r| r.name == name) else {
+            println!("  {name:<16} FAIL (not in the manifest)");
+            ok = false;
+            continue;
+        };
+        let (at, got) = (region.offset as usize, region.len as usize);
+        if offset.is_some_and(|offset| offset != at) || got != len {
+            let want = offset.map_or_else(|| "anywhere".into(), |o| std::format!("at {o:#x}"));
+            println!(
+                "  {name:<16} FAIL (built for {len:#x} bytes {want}, the image has {got:#x} at {at:#x})"
+            );
+            ok = false;
+            continue;
+        }
+        println!("  {name:<16} at {at:#x}, {len:#x} bytes: OK");
+    }
+    ok
+}
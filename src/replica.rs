@@ -0,0 +1,238 @@
+//! Redundant metadata copies with majority voting.
+//!
+//! Boot metadata (a boot counter and the selected slot) is small but must
+//! survive a bad cell or an update torn by a power cut. [`Replicated`] keeps
+//! [`COPIES`] copies of it in separate erase blocks of the writable
+//! "replicas" region (`cargo xtask mkimage --replicas`) and rebuilds the
+//! record on read by a bitwise majority vote, so any damage confined to one
+//! copy per bit is outvoted. Updates rewrite one copy at a time: however an
+//! update is interrupted, at most one copy is half-written and the vote
+//! still returns either the old record or the new one.
+//!
+//! The demo bumps the boot counter, then clears a different bit in each of
+//! two copies and checks the vote recovers the record before repairing them.
+
+use crate::cfi::{CfiFlash, FlashError};
+
+/// Copies kept of each record. Odd, so every bit has a majority.
+pub const COPIES: usize = 3;
+/// Magic at the start of a boot metadata record.
+const MAGIC: &[u8; 4] = b"BOOT";
+/// Size of a record in each copy.
+pub const RECORD_SIZE: usize = 16;
+
+/// The boot metadata kept in the replicas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootMeta {
+    pub boots: u32,
+    /// Selected boot slot: 0 for A, 1 for B.
+    pub slot: u32,
+}
+
+impl BootMeta {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0; RECORD_SIZE];
+        record[..4].copy_from_slice(MAGIC);
+        record[4..8].copy_from_slice(&self.boots.to_le_bytes());
+        record[8..12].copy_from_slice(&self.slot.to_le_bytes());
+        record
+    }
+
+    /// Decode a record, or `None` if it holds no metadata (e.g. erased).
+    fn decode(record: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let word = |at: usize| u32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+        (record.starts_with(MAGIC) && word(8) < 2).then(|| Self {
+            boots: word(4),
+            slot: word(8),
+        })
+    }
+
+    fn slot_name(&self) -> char {
+        if self.slot == 0 { 'A' } else { 'B' }
+    }
+}
+
+/// The result of reading all copies.
+pub struct Voted {
+    /// The record rebuilt by the vote.
+    pub record: [u8; RECORD_SIZE],
+    /// Copies that differ from it.
+    pub outvoted: [bool; COPIES],
+}
+
+impl Voted {
+    pub fn unanimous(&self) -> bool {
+        !self.outvoted.contains(&true)
+    }
+}
+
+/// A record stored in [`COPIES`] erase blocks of a flash bank.
+pub struct Replicated<'a> {
+    flash: &'a CfiFlash,
+    /// Bank offset of each copy, each in its own erase block.
+    copies: [usize; COPIES],
+}
+
+impl<'a> Replicated<'a> {
+    /// Spread the copies over the `len` bytes at `offset`, or `None` if the
+    /// range does not hold [`COPIES`] whole erase blocks.
+    pub fn new(flash: &'a CfiFlash, offset: usize, len: usize) -> Option<Self> {
+        let first = offset.next_multiple_of(flash.erase_size);
+        let stride = (offset + len).checked_sub(first)? / COPIES;
+        let stride = stride - stride % flash.erase_size;
+        (stride > 0).then(|| Self {
+            flash,
+            copies: core::array::from_fn(|i| first + i * stride),
+        })
+    }
+
+    /// Read every copy and vote on each bit.
+    pub fn read(&self) -> Voted {
+        let mut copies = [[0; RECORD_SIZE]; COPIES];
+        for (copy, &at) in copies.iter_mut().zip(&self.copies) {
+            self.flash.read(at, copy);
+        }
+        let mut record = [0; RECORD_SIZE];
+        for (i, byte) in record.iter_mut().enumerate() {
+            for bit in 0..8 {
+                let ones = copies.iter().filter(|c| c[i] & (1 << bit) != 0).count();
+                if ones > COPIES / 2 {
+                    *byte |= 1 << bit;
+                }
+            }
+        }
+        Voted {
+            record,
+            outvoted: copies.map(|copy| copy != record),
+        }
+    }
+
+    /// Rewrite copy `index` with `record`.
+    fn write_copy(&self, index: usize, record: &[u8; RECORD_SIZE]) -> Result<(), FlashError> {
+        let at = self.copies[index];
+        self.flash.erase(at)?;
+        self.flash.program(at, record)
+    }
+
+    /// Store `record` in every copy that does not already hold it, one copy
+    /// at a time. Returns the number of copies written.
+    pub fn write(&self, record: &[u8; RECORD_SIZE]) -> Result<usize, FlashError> {
+        let mut written = 0;
+        let mut current = [0; RECORD_SIZE];
+        for index in 0..COPIES {
+            self.flash.read(self.copies[index], &mut current);
+            if current != *record {
+                self.write_copy(index, record)?;
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Rewrite the copies outvoted in `voted` with the voted record.
+    pub fn repair(&self, voted: &Voted) -> Result<usize, FlashError> {
+        let mut repaired = 0;
+        for index in (0..COPIES).filter(|&i| voted.outvoted[i]) {
+            self.write_copy(index, &voted.record)?;
+            repaired += 1;
+        }
+        Ok(repaired)
+    }
+
+    /// Clear bit `bit` of byte `byte` in copy `index`, as a failing cell
+    /// would. Programming can only clear bits, so no erase is needed.
+    fn damage(&self, index: usize, byte: usize, bit: u8) -> Result<(), FlashError> {
+        // Reprogramming the unchanged bits leaves them as they are.
+        let mut record = [0; RECORD_SIZE];
+        self.flash.read(self.copies[index], &mut record);
+        record[byte] &= !(1 << bit);
+        self.flash.program(self.copies[index], &record)
+    }
+}
+
+/// Load, bump and store the boot metadata, then damage two copies and
+/// check the vote outweighs the damage.
+fn exercise(meta: &Replicated) -> Result<bool, FlashError> {
+    let voted = meta.read();
+    let old = BootMeta::decode(&voted.record);
+    match old {
+        Some(old) if voted.unanimous() => println!(
+            "Replicas: boot {} from slot {}, all {COPIES} copies agree",
+            old.boots,
+            old.slot_name()
+        ),
+        Some(old) => println!(
+            "Replicas: boot {} from slot {}, {} copy(ies) outvoted and repaired",
+            old.boots,
+            old.slot_name(),
+            meta.repair(&voted)?
+        ),
+        None => println!("Replicas: no boot metadata yet, starting at slot A"),
+    }
+    let new = BootMeta {
+        boots: old.map_or(1, |old| old.boots + 1),
+        slot: old.map_or(0, |old| old.slot),
+    };
+    let record = new.encode();
+    let written = meta.write(&record)?;
+    println!("Replicas: stored boot {} in {written} copy(ies)", new.boots);
+
+    // Lose a different bit of the magic ("BOOT") in each of two copies.
+    meta.damage(0, 0, 6)?;
+    meta.damage(1, 3, 2)?;
+    let voted = meta.read();
+    let outvoted = voted.outvoted.iter().filter(|&&o| o).count();
+    let recovered = BootMeta::decode(&voted.record) == Some(new);
+    println!(
+        "Replicas: after damaging copies 0 and 1 the vote {} the record ({outvoted} copy(ies) outvoted)",
+        if recovered { "recovers" } else { "loses" }
+    );
+    let repaired = meta.repair(&voted)?;
+    Ok(recovered && outvoted == 2 && repaired == 2 && meta.read().unanimous())
+}
+
+/// Keep the boot metadata in the replicas region of the bank mapped at
+/// `base`.
+///
+/// Returns `true` if the damaged copies were outvoted and repaired.
+pub fn run(base: usize, size: usize) -> bool {
+    let (offset, len) = match crate::writable_region(base, size, Some("replicas")) {
+        Ok(Some(region)) => region,
+        Ok(None) => {
+            println!(
+                "Replicas: no replicas region in the image (create it with `cargo xtask mkimage --replicas`)"
+            );
+            return false;
+        }
+        Err(e) => {
+            println!("Replicas: FAIL ({e})");
+            return false;
+        }
+    };
+
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => {
+            println!("Replicas: FAIL (flash probe: {e})");
+            return false;
+        }
+    };
+    let Some(meta) = Replicated::new(&flash, offset, len) else {
+        println!("Replicas: FAIL (replicas region holds fewer than {COPIES} erase blocks)");
+        return false;
+    };
+    match exercise(&meta) {
+        Ok(true) => {
+            println!("Replicas: PASS (damaged copies outvoted and repaired)");
+            true
+        }
+        Ok(false) => {
+            println!("Replicas: FAIL (vote did not recover the record)");
+            false
+        }
+        Err(e) => {
+            println!("Replicas: FAIL ({e})");
+            false
+        }
+    }
+}
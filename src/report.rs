@@ -0,0 +1,90 @@
+//! Flash region report.
+//!
+//! Prints the state of the bank in one table at boot: where it is mapped,
+//! the image header, and every region in the manifest with its extent, its
+//! flags and, if the image has a "crc" region (`cargo xtask mkimage --crc`),
+//! how many of its sectors match their CRC.
+
+use crate::integrity::{CrcStatus, CrcTable, crc_status};
+use crate::layout::{Header, Manifest};
+
+/// Print the report for the bank at physical address `phys`, mapped at
+/// `virt` as `flash`.
+///
+/// Returns `true` if the image could be parsed and no region fails its CRC.
+pub fn run(phys: usize, virt: usize, flash: &[u8]) -> bool {
+    println!("Flash report:");
+    println!(
+        "  bank      phys {phys:#x}  virt {virt:#x}  size {:#x} ({} MiB)",
+        flash.len(),
+        flash.len() >> 20
+    );
+    let header = match Header::parse(flash) {
+        Ok(header) => header,
+        Err(e) => {
+            println!("  header    unreadable: {e}");
+            return false;
+        }
+    };
+    println!(
+        "  header    version {}  flags {:#x}  header {} bytes  image {:#x} bytes",
+        header.version, header.flags, header.header_size, header.image_size
+    );
+    match header.kernel() {
+        Some((offset, len)) => println!("  kernel    at {offset:#x} ({len} bytes)"),
+        None => println!("  kernel    not embedded"),
+    }
+    let manifest = match Manifest::parse(flash, &header) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("  manifest  unreadable: {e}");
+            return false;
+        }
+    };
+    let table = manifest
+        .regions()
+        .flatten()
+        .find(|r| r.name == "crc")
+        .and_then(|r| r.data(flash))
+        .and_then(|data| CrcTable::parse(data).ok());
+    println!(
+        "  manifest  at {:#x}, {} regions",
+        header.manifest_offset,
+        manifest.len()
+    );
+    println!("  #  name             offset      length      mode  crc");
+    let mut ok = true;
+    for (i, region) in manifest.regions().enumerate() {
+        let region = match region {
+            Ok(region) => region,
+            Err(e) => {
+                println!("  {i:<2} <invalid entry: {e}>");
+                ok = false;
+                continue;
+            }
+        };
+        let status = if region.data(flash).is_some() {
+            crc_status(flash, table.as_ref(), &region)
+        } else {
+            ok = false;
+            println!(
+                "  {:<2} {:<16} {:#010x}  {:<10}  out of bounds",
+                i, region.name, region.offset, region.len
+            );
+            continue;
+        };
+        if matches!(status, CrcStatus::Checked { bad, .. } if bad > 0) {
+            ok = false;
+        }
+        println!(
+            "  {:<2} {:<16} {:#010x}  {:<10}  {:<4}  {}",
+            i,
+            region.name,
+            region.offset,
+            region.len,
+            if region.writable() { "rw" } else { "ro" },
+            status
+        );
+    }
+    ok
+}
@@ -0,0 +1,293 @@
+//! Read-only romfs reader.
+//!
+//! `cargo xtask run --romfs <DIR>` packs a directory tree into a romfs image
+//! in the "fs" region. romfs suits NOR flash well: it is a chain of 16-byte
+//! aligned headers with the file data right behind them, so files are read
+//! in place without any block layer. This module checks the checksums,
+//! walks the tree and prints it with the start of every text file. See
+//! `xtask/src/romfs.rs` for the writer side.
+
+use crate::layout::{Header, Manifest};
+use crate::text::preview;
+use core::fmt;
+
+/// Magic at the start of every romfs image.
+pub const MAGIC: &[u8; 8] = b"-rom1fs-";
+const ALIGN: usize = 16;
+/// Size of a file header without its name.
+const HEADER_SIZE: usize = 16;
+/// Bytes covered by the volume checksum.
+const VOLUME_CHECKSUM_LEN: usize = 512;
+/// Deepest directory level printed.
+const MAX_DEPTH: usize = 8;
+
+/// Entry types, from the low three bits of the `next` word.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    HardLink,
+    Dir,
+    File,
+    Symlink,
+    BlockDev,
+    CharDev,
+    Socket,
+    Fifo,
+}
+
+/// Reasons an image or entry cannot be read.
+#[derive(Debug)]
+pub enum RomfsError {
+    /// A header or data extends past the end of the image.
+    Truncated,
+    /// The image does not start with `"-rom1fs-"`.
+    BadMagic,
+    /// The checksum of the volume (offset 0) or a file header failed.
+    BadChecksum(usize),
+    /// A name is not valid UTF-8.
+    BadName,
+    /// A directory chain loops.
+    Loop,
+}
+
+impl fmt::Display for RomfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "image truncated"),
+            Self::BadMagic => write!(f, "bad romfs magic"),
+            Self::BadChecksum(off) => write!(f, "bad checksum in header at {off:#x}"),
+            Self::BadName => write!(f, "name is not UTF-8"),
+            Self::Loop => write!(f, "directory chain loops"),
+        }
+    }
+}
+
+fn be_u32(bytes: &[u8], off: usize) -> Result<u32, RomfsError> {
+    let word = bytes.get(off..off + 4).ok_or(RomfsError::Truncated)?;
+    Ok(u32::from_be_bytes(word.try_into().unwrap()))
+}
+
+/// Whether the big-endian words of `bytes` sum to zero.
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.chunks_exact(4).fold(0u32, |sum, w| {
+        sum.wrapping_add(u32::from_be_bytes(w.try_into().unwrap()))
+    }) == 0
+}
+
+/// A NUL-terminated name starting at `off`, and the offset after its padding.
+fn name_at(bytes: &[u8], off: usize) -> Result<(&str, usize), RomfsError> {
+    let rest = bytes.get(off..).ok_or(RomfsError::Truncated)?;
+    let len = rest
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(RomfsError::Truncated)?;
+    let name = core::str::from_utf8(&rest[..len]).map_err(|_| RomfsError::BadName)?;
+    Ok((name, (off + len + 1).next_multiple_of(ALIGN)))
+}
+
+/// One file header.
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub kind: Kind,
+    pub exec: bool,
+    /// Type-specific word: first entry of a directory, target of a hard link.
+    pub spec: u32,
+    /// Offset of the next entry in the same directory, or 0.
+    next: usize,
+    pub data: &'a [u8],
+}
+
+/// A romfs image.
+pub struct Romfs<'a> {
+    image: &'a [u8],
+    pub volume: &'a str,
+    /// Offset of the root directory header.
+    root: usize,
+}
+
+impl<'a> Romfs<'a> {
+    /// Validate the volume header of the image at the start of `bytes`.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, RomfsError> {
+        if bytes.get(..8) != Some(MAGIC.as_slice()) {
+            return Err(RomfsError::BadMagic);
+        }
+        let size = be_u32(bytes, 8)? as usize;
+        let image = bytes.get(..size).ok_or(RomfsError::Truncated)?;
+        if !checksum_ok(&image[..VOLUME_CHECKSUM_LEN.min(size) & !3]) {
+            return Err(RomfsError::BadChecksum(0));
+        }
+        let (volume, root) = name_at(image, HEADER_SIZE)?;
+        Ok(Self {
+            image,
+            volume,
+            root,
+        })
+    }
+
+    /// Size of the image in bytes.
+    pub fn size(&self) -> usize {
+        self.image.len()
+    }
+
+    /// Read and check the header at `offset`.
+    pub fn entry(&self, offset: usize) -> Result<Entry<'a>, RomfsError> {
+        let next = be_u32(self.image, offset)?;
+        let spec = be_u32(self.image, offset + 4)?;
+        let size = be_u32(self.image, offset + 8)? as usize;
+        let (name, data) = name_at(self.image, offset + HEADER_SIZE)?;
+        if !checksum_ok(&self.image[offset..data]) {
+            return Err(RomfsError::BadChecksum(offset));
+        }
+        let kind = match next & 7 {
+            0 => Kind::HardLink,
+            1 => Kind::Dir,
+            2 => Kind::File,
+            3 => Kind::Symlink,
+            4 => Kind::BlockDev,
+            5 => Kind::CharDev,
+            6 => Kind::Socket,
+            _ => Kind::Fifo,
+        };
+        Ok(Entry {
+            name,
+            kind,
+            exec: next & 8 != 0,
+            spec,
+            next: (next & !0xF) as usize,
+            data: self
+                .image
+                .get(data..data + size)
+                .ok_or(RomfsError::Truncated)?,
+        })
+    }
+
+    /// The root directory.
+    pub fn root(&self) -> Result<Entry<'a>, RomfsError> {
+        self.entry(self.root)
+    }
+
+    /// Call `f` for every entry of directory `dir`, including `.` and `..`.
+    pub fn for_each_entry(
+        &self,
+        dir: &Entry<'a>,
+        mut f: impl FnMut(Entry<'a>) -> Result<(), RomfsError>,
+    ) -> Result<(), RomfsError> {
+        let mut offset = dir.spec as usize & !0xF;
+        // Every header takes at least 32 bytes, which bounds a sane chain.
+        let mut left = self.image.len() / (2 * ALIGN);
+        while offset != 0 {
+            left = left.checked_sub(1).ok_or(RomfsError::Loop)?;
+            let entry = self.entry(offset)?;
+            offset = entry.next;
+            f(entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the entries under `dir`, recursing into subdirectories. Returns the
+/// number of entries printed.
+fn print_tree(fs: &Romfs, dir: &Entry, depth: usize) -> Result<usize, RomfsError> {
+    let mut count = 0;
+    fs.for_each_entry(dir, |entry| {
+        if entry.name == "." || entry.name == ".." {
+            return Ok(());
+        }
+        count += 1;
+        let indent = 2 * (depth + 1);
+        match entry.kind {
+            Kind::Dir => {
+                println!("{:indent$}{}/", "", entry.name);
+                if depth + 1 < MAX_DEPTH {
+                    count += print_tree(fs, &entry, depth + 1)?;
+                } else {
+                    println!("{:indent$}  ...", "");
+                }
+            }
+            Kind::File => {
+                let exec = if entry.exec { ", executable" } else { "" };
+                match preview(entry.data) {
+                    Some(text) => println!(
+                        "{:indent$}{} ({} bytes{exec}): {:?}{}",
+                        "",
+                        entry.name,
+                        entry.data.len(),
+                        text,
+                        if text.len() < entry.data.len() {
+                            "..."
+                        } else {
+                            ""
+                        }
+                    ),
+                    None => println!(
+                        "{:indent$}{} ({} bytes{exec})",
+                        "",
+                        entry.name,
+                        entry.data.len()
+                    ),
+                }
+            }
+            Kind::Symlink => println!(
+                "{:indent$}{} -> {}",
+                "",
+                entry.name,
+                core::str::from_utf8(entry.data).unwrap_or("?")
+            ),
+            kind => println!("{:indent$}{} ({kind:?})", "", entry.name),
+        }
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+/// Find the romfs image in the fs region of `flash` and list its contents.
+///
+/// Returns `true` if the whole tree could be read.
+pub fn run(flash: &[u8]) -> bool {
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest.regions().flatten().find(|r| r.name == "fs"))
+    });
+    let data = match region {
+        Ok(Some(region)) => match region.data(flash) {
+            Some(data) => data,
+            None => {
+                println!("Romfs: FAIL (fs region out of bounds)");
+                return false;
+            }
+        },
+        Ok(None) => {
+            println!(
+                "Romfs: no fs region in the image (create it with `cargo xtask mkimage --romfs <DIR>`)"
+            );
+            return false;
+        }
+        Err(e) => {
+            println!("Romfs: cannot read manifest: {e}");
+            return false;
+        }
+    };
+
+    let fs = match Romfs::parse(data) {
+        Ok(fs) => fs,
+        Err(e) => {
+            println!("Romfs: FAIL (fs region is not a romfs image: {e})");
+            return false;
+        }
+    };
+    println!(
+        "Romfs: volume {:?}, {} bytes at {:#x}",
+        fs.volume,
+        fs.size(),
+        data.as_ptr() as usize
+    );
+    match fs.root().and_then(|root| print_tree(&fs, &root, 0)) {
+        Ok(count) => {
+            println!("Romfs: PASS ({count} entries, all header checksums valid)");
+            true
+        }
+        Err(e) => {
+            println!("Romfs: FAIL ({e})");
+            false
+        }
+    }
+}
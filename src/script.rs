@@ -0,0 +1,94 @@
+//! Test scripts stored in flash.
+//!
+//! `cargo xtask mkimage --flash-script <FILE>` stores a text script in the
+//! "script" region. Each line is one flash shell command (see `shell.rs`),
+//! optionally followed by `=> <reply>`, the start of the reply the step
+//! expects (`OK` if omitted):
+//!
+//! ```text
+//! # The image starts with "PFLA".
+//! R 0 4 => OK 50464c41
+//! E 1000 => ERR no erase block
+//! ```
+//!
+//! At boot the app runs the steps in order and prints one PASS/FAIL line
+//! for each, so a new scenario only needs a new image, not a new kernel.
+
+use crate::cfi::CfiFlash;
+use crate::layout::{Header, Manifest};
+use crate::shell;
+use std::string::String;
+
+/// Run the script in the bank of `size` bytes mapped at `base`.
+///
+/// Returns `true` if every step got the reply it expects.
+pub fn run(base: usize, size: usize) -> bool {
+    // Copied out first: steps may program and erase the bank.
+    let script = {
+        let flash = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+        let region = Header::parse(flash).and_then(|header| {
+            let manifest = Manifest::parse(flash, &header)?;
+            Ok(manifest
+                .regions()
+                .flatten()
+                .find(|r| r.name == "script")
+                .and_then(|r| r.data(flash)))
+        });
+        match region {
+            Ok(Some(data)) => match core::str::from_utf8(data) {
+                Ok(text) => String::from(text),
+                Err(_) => {
+                    println!("Script: FAIL (the script region is not UTF-8)");
+                    return false;
+                }
+            },
+            Ok(None) => {
+                println!(
+                    "Script: no script region in the image (create it with `cargo xtask mkimage --flash-script <FILE>`)"
+                );
+                return false;
+            }
+            Err(e) => {
+                println!("Script: FAIL (cannot read manifest: {e})");
+                return false;
+            }
+        }
+    };
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => {
+            println!("Script: FAIL (flash probe: {e})");
+            return false;
+        }
+    };
+
+    let steps = script
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty());
+    let (mut total, mut failed) = (0, 0);
+    for line in steps {
+        total += 1;
+        let (command, expected) = match line.split_once("=>") {
+            Some((command, expected)) => (command.trim(), expected.trim()),
+            None => (line, "OK"),
+        };
+        let reply = match shell::execute(&flash, command) {
+            Ok(Some(reply)) => std::format!("OK {reply}"),
+            Ok(None) => "OK bye".into(),
+            Err(e) => std::format!("ERR {e}"),
+        };
+        if reply.starts_with(expected) {
+            println!("  {total:>2}. {command:<28} PASS ({reply})");
+        } else {
+            failed += 1;
+            println!("  {total:>2}. {command:<28} FAIL (got {reply}, expected {expected})");
+        }
+    }
+    if failed == 0 {
+        println!("Script: PASS ({total} steps)");
+    } else {
+        println!("Script: FAIL ({failed} of {total} steps failed)");
+    }
+    failed == 0
+}
@@ -0,0 +1,181 @@
+//! Built-in self-test.
+//!
+//! With `selftest` on the kernel command line (`cargo xtask run --selftest`
+//! passes it with `-append`), the app runs a fixed battery of checks on the
+//! bank instead of its demos: the image magic, the header CRC, reads of
+//! every width, reads at the very end of the bank and, if the image has a
+//! writable region, an erase/program round trip. Each check is printed as a
+//! numbered PASS/FAIL (or SKIP) line, followed by one overall verdict line.
+
+use crate::cfi::{CfiFlash, FlashError};
+use crate::integrity::crc32;
+use crate::layout::{HEADER_CRC_LEN, Header, MAGIC};
+use std::string::{String, ToString};
+use std::vec;
+
+/// Bytes compared by each of the read checks.
+const READ_SPAN: usize = 16;
+/// Bytes programmed by the round trip.
+const PATTERN_LEN: usize = 64;
+
+enum Outcome {
+    Pass,
+    Skip(&'static str),
+    Fail(String),
+}
+
+/// The image starts with the `"PFLA"` magic.
+fn magic(flash: &[u8]) -> Outcome {
+    if flash.get(..MAGIC.len()) == Some(MAGIC.as_slice()) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail("the bank does not start with \"PFLA\"".into())
+    }
+}
+
+/// The header matches the CRC-32 stored in it.
+fn header_crc(flash: &[u8]) -> Outcome {
+    match Header::parse(flash) {
+        Ok(header) => {
+            let crc = crc32(&flash[..HEADER_CRC_LEN]);
+            if crc == header.header_crc {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(std::format!(
+                    "stored {:#010x}, computed {crc:#010x}",
+                    header.header_crc
+                ))
+            }
+        }
+        Err(e) => Outcome::Fail(e.to_string()),
+    }
+}
+
+/// Whether reading the [`READ_SPAN`] bytes at `at` 2, 4 and 8 bytes at a
+/// time returns the same bytes as reading them one at a time.
+fn widths_agree(at: usize) -> bool {
+    let mut bytes = [0u8; READ_SPAN];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = unsafe { ((at + i) as *const u8).read_volatile() };
+    }
+    [2, 4, 8].into_iter().all(|width| {
+        (0..READ_SPAN).step_by(width).all(|off| {
+            let p = at + off;
+            let mut word = [0u8; 8];
+            unsafe {
+                match width {
+                    2 => {
+                        word[..2].copy_from_slice(&(p as *const u16).read_volatile().to_ne_bytes())
+                    }
+                    4 => {
+                        word[..4].copy_from_slice(&(p as *const u32).read_volatile().to_ne_bytes())
+                    }
+                    _ => word.copy_from_slice(&(p as *const u64).read_volatile().to_ne_bytes()),
+                }
+            }
+            word[..width] == bytes[off..off + width]
+        })
+    })
+}
+
+/// 8-, 16-, 32- and 64-bit reads of the header agree.
+fn read_widths(base: usize) -> Outcome {
+    if widths_agree(base) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail("wide reads differ from byte reads".into())
+    }
+}
+
+/// The last bytes of the bank can be read at every width. A mapping that
+/// stops short of the bank faults here.
+fn bank_end(base: usize, size: usize) -> Outcome {
+    if widths_agree(base + size - READ_SPAN) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail("wide reads at the end of the bank differ from byte reads".into())
+    }
+}
+
+/// Erase the last erase block of the first writable region, program a
+/// pattern into it, read both back, and restore the block.
+fn round_trip(base: usize, size: usize) -> Outcome {
+    let (offset, len) = match crate::writable_region(base, size, None) {
+        Ok(Some(region)) => region,
+        Ok(None) => return Outcome::Skip("no writable region"),
+        Err(e) => return Outcome::Fail(e),
+    };
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => return Outcome::Fail(std::format!("flash probe: {e}")),
+    };
+    let end = offset + len;
+    let Some(block) = (end - end % flash.erase_size)
+        .checked_sub(flash.erase_size)
+        .filter(|&block| block >= offset)
+    else {
+        return Outcome::Skip("writable region smaller than an erase block");
+    };
+
+    let mut saved = vec![0; flash.erase_size];
+    flash.read(block, &mut saved);
+    let pattern: [u8; PATTERN_LEN] = core::array::from_fn(|i| i as u8 ^ 0xA5);
+    let result = (|| -> Result<Option<&'static str>, FlashError> {
+        flash.erase(block)?;
+        let mut buf = vec![0; flash.erase_size];
+        flash.read(block, &mut buf);
+        if buf.iter().any(|&b| b != 0xFF) {
+            return Ok(Some("block not erased"));
+        }
+        flash.program(block, &pattern)?;
+        flash.read(block, &mut buf[..PATTERN_LEN]);
+        Ok((buf[..PATTERN_LEN] != pattern).then_some("pattern read back wrong"))
+    })();
+    let restored = flash.rewrite_block(block, &saved);
+    match (result, restored) {
+        (Err(e), _) | (Ok(_), Err(e)) => Outcome::Fail(e.to_string()),
+        (Ok(Some(why)), _) => Outcome::Fail(std::format!("{why} at {block:#x}")),
+        (Ok(None), Ok(_)) => Outcome::Pass,
+    }
+}
+
+/// Run the battery on the bank of `size` bytes mapped at `base`.
+///
+/// Returns `true` if no check failed.
+pub fn run(base: usize, size: usize) -> bool {
+    let flash = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+    let checks: [(&str, &dyn Fn() -> Outcome); 5] = [
+        ("magic", &|| magic(flash)),
+        ("header CRC", &|| header_crc(flash)),
+        ("read widths", &|| read_widths(base)),
+        ("bank end", &|| bank_end(base, size)),
+        ("write/erase round trip", &|| round_trip(base, size)),
+    ];
+    println!("Selftest: {} checks", checks.len());
+    let (mut failed, mut skipped) = (0, 0);
+    for (i, (name, check)) in checks.iter().enumerate() {
+        match check() {
+            Outcome::Pass => println!("  {:>2}. {name:<24} PASS", i + 1),
+            Outcome::Skip(why) => {
+                skipped += 1;
+                println!("  {:>2}. {name:<24} SKIP ({why})", i + 1);
+            }
+            Outcome::Fail(why) => {
+                failed += 1;
+                println!("  {:>2}. {name:<24} FAIL ({why})", i + 1);
+            }
+        }
+    }
+    if failed == 0 {
+        println!(
+            "Selftest: PASS ({} passed, {skipped} skipped)",
+            checks.len() - skipped
+        );
+    } else {
+        println!(
+            "Selftest: FAIL ({failed} of {} checks failed)",
+            checks.len()
+        );
+    }
+    failed == 0
+}
@@ -0,0 +1,163 @@
+//! Host file access over semihosting (aarch64, riscv64).
+//!
+//! `cargo xtask run --semihost` starts QEMU with
+//! `-semihosting-config enable=on,target=native` and puts the path of the
+//! image it attaches on the kernel command line as `semihost=<PATH>`. The
+//! app opens that file on the host through semihosting calls and compares
+//! it with the bank byte for byte before any demo writes to flash, so a
+//! bank that reads differently from the file QEMU was given (another unit,
+//! a mapping onto the wrong pages, a stale image) shows up with the first
+//! offset that differs.
+
+use std::vec::Vec;
+
+/// Semihosting operations (Arm's semihosting specification, which RISC-V
+/// semihosting adopts unchanged).
+const SYS_OPEN: usize = 0x01;
+const SYS_CLOSE: usize = 0x02;
+const SYS_READ: usize = 0x06;
+const SYS_FLEN: usize = 0x0C;
+/// `SYS_OPEN` mode for `"rb"`.
+const MODE_RB: usize = 1;
+/// Bytes read from the host per `SYS_READ`.
+const CHUNK: usize = 4096;
+
+/// Make semihosting call `op` with the parameter block at `param`.
+///
+/// # Safety
+///
+/// `param` must point to the parameter block `op` expects, and any
+/// buffers it names must be valid for the call.
+#[cfg(target_arch = "aarch64")]
+unsafe fn call(op: usize, param: usize) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!("hlt #0xf000", inout("x0") op => ret, in("x1") param, options(nostack))
+    };
+    ret
+}
+
+/// Make semihosting call `op` with the parameter block at `param`. QEMU
+/// recognizes the `ebreak` by the two instructions around it, which must
+/// not be compressed.
+///
+/// # Safety
+///
+/// `param` must point to the parameter block `op` expects, and any
+/// buffers it names must be valid for the call.
+#[cfg(target_arch = "riscv64")]
+unsafe fn call(op: usize, param: usize) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            ".option push",
+            ".option norvc",
+            ".balign 16",
+            "slli x0, x0, 0x1f",
+            "ebreak",
+            "srai x0, x0, 7",
+            ".option pop",
+            inout("a0") op => ret,
+            in("a1") param,
+            options(nostack)
+        )
+    };
+    ret
+}
+
+/// An open host file, closed on drop.
+struct HostFile(usize);
+
+impl HostFile {
+    fn open(path: &str) -> Option<Self> {
+        let mut name = Vec::with_capacity(path.len() + 1);
+        name.extend_from_slice(path.as_bytes());
+        name.push(0);
+        let param = [name.as_ptr() as usize, MODE_RB, path.len()];
+        let handle = unsafe { call(SYS_OPEN, param.as_ptr() as usize) };
+        (handle >= 0).then_some(Self(handle as usize))
+    }
+
+    fn len(&self) -> Option<usize> {
+        let param = [self.0];
+        let len = unsafe { call(SYS_FLEN, param.as_ptr() as usize) };
+        (len >= 0).then_some(len as usize)
+    }
+
+    /// Fill `buf` from the current position; the bytes read.
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let param = [self.0, buf.as_mut_ptr() as usize, buf.len()];
+        // The result is the number of bytes *not* read.
+        let left = unsafe { call(SYS_READ, param.as_ptr() as usize) };
+        buf.len() - (left.max(0) as usize).min(buf.len())
+    }
+}
+
+impl Drop for HostFile {
+    fn drop(&mut self) {
+        let param = [self.0];
+        unsafe { call(SYS_CLOSE, param.as_ptr() as usize) };
+    }
+}
+
+/// Compare the bank of `size` bytes at `va` with the host file at `path`
+/// (`semihost=` on the command line). Returns `false` if they differ or
+/// the file cannot be read.
+pub fn run(va: usize, size: usize, path: Option<&str>) -> bool {
+    let Some(path) = path else {
+        println!(
+            "Semihosting: FAIL (no semihost=<PATH> on the command line; run with `cargo xtask run --semihost`)"
+        );
+        return false;
+    };
+    let Some(file) = HostFile::open(path) else {
+        println!(
+            "Semihosting: FAIL (cannot open {path} on the host; is semihosting enabled in QEMU?)"
+        );
+        return false;
+    };
+    let len = file.len().unwrap_or(0);
+    if len != size {
+        println!(
+            "Semihosting: {path} is {len:#X} bytes, the bank {size:#X}; comparing the first {:#X}",
+            len.min(size)
+        );
+    }
+    let flash = unsafe { core::slice::from_raw_parts(va as *const u8, size) };
+    let mut buf = [0u8; CHUNK];
+    let (mut offset, mut first, mut differing) = (0, None, 0usize);
+    while offset < len.min(size) {
+        let want = CHUNK.min(len.min(size) - offset);
+        let got = file.read(&mut buf[..want]);
+        if got == 0 {
+            println!("Semihosting: FAIL (reading {path} stopped at {offset:#X})");
+            return false;
+        }
+        for (i, (&host, &bank)) in buf[..got].iter().zip(&flash[offset..]).enumerate() {
+            if host != bank {
+                first.get_or_insert((offset + i, host, bank));
+                differing += 1;
+            }
+        }
+        offset += got;
+    }
+    match first {
+        None if len == size => {
+            println!("Semihosting: the bank matches {path} ({size:#X} bytes)");
+            true
+        }
+        None => {
+            println!(
+                "Semihosting: FAIL (the bank matches {path} as far as both go, but the sizes differ)"
+            );
+            false
+        }
+        Some((at, host, bank)) => {
+            println!(
+                "Semihosting: FAIL ({differing} byte(s) differ from {path}, the first at {at:#X}: \
+                 file {host:#04X}, flash {bank:#04X})"
+            );
+            false
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Shadowing the payload in RAM.
+//!
+//! Firmware rarely parses flash in place: every access is an MMIO read,
+//! slow and uncached on real parts, so it copies what it needs into RAM
+//! once and works on the copy. This demo does the same with the payload
+//! region. It times the copy, then [`PASSES`] CRC-32 passes over flash and
+//! over the copy, and prints after how many passes the copy has paid for
+//! itself. The copy is then what the cpio and tar demos parse, so the rest
+//! of the run reads RAM instead of the bank.
+
+use crate::cycles::{Span, Stopwatch};
+use crate::integrity::crc32;
+use crate::layout::{Header, Manifest};
+use std::time::Duration;
+use std::vec::Vec;
+
+/// CRC-32 passes timed over flash and over the copy.
+const PASSES: u32 = 4;
+
+/// Average time of [`PASSES`] CRC-32 passes over `data`, and the CRC.
+fn crc_passes(data: &[u8]) -> (Span, u32) {
+    let watch = Stopwatch::start();
+    let mut crc = 0;
+    for _ in 0..PASSES {
+        crc = crc32(data);
+    }
+    (watch.stop().per(PASSES), crc)
+}
+
+fn mib_per_s(len: usize, elapsed: Duration) -> u128 {
+    len as u128 * 1_000_000_000 / elapsed.as_nanos().max(1) / (1024 * 1024)
+}
+
+/// Copy the payload of the image in `flash` into RAM and compare working
+/// on the copy with working on flash.
+///
+/// Returns the copy if the image has a payload and the copy reads the same
+/// as flash, else `None`.
+pub fn run(flash: &[u8]) -> Option<Vec<u8>> {
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest.regions().flatten().find(|r| r.name == "payload"))
+    });
+    let payload = match region {
+        Ok(Some(region)) => match region.data(flash) {
+            Some(payload) => payload,
+            None => {
+                println!("Shadow: FAIL (payload region out of bounds)");
+                return None;
+            }
+        },
+        Ok(None) => {
+            println!("Shadow: FAIL (no payload region in the image)");
+            return None;
+        }
+        Err(e) => {
+            println!("Shadow: FAIL (cannot read manifest: {e})");
+            return None;
+        }
+    };
+
+    let watch = Stopwatch::start();
+    let copy = payload.to_vec();
+    let copy_time = watch.stop();
+    println!(
+        "Shadow: copied the payload ({:#x} bytes) to RAM in {} us, {} cycles ({} MiB/s)",
+        payload.len(),
+        copy_time.time.as_micros(),
+        copy_time.cycles,
+        mib_per_s(payload.len(), copy_time.time)
+    );
+    let (flash_time, flash_crc) = crc_passes(payload);
+    let (ram_time, ram_crc) = crc_passes(&copy);
+    for (label, elapsed) in [("flash", flash_time), ("RAM", ram_time)] {
+        println!(
+            "  crc32 over {label:<6} {:>8} us per pass  {:>6} MiB/s  {:>10} cycles",
+            elapsed.time.as_micros(),
+            mib_per_s(payload.len(), elapsed.time),
+            elapsed.cycles
+        );
+    }
+    if flash_crc != ram_crc {
+        println!(
+            "Shadow: FAIL (crc32 {flash_crc:#010x} over flash, {ram_crc:#010x} over the copy)"
+        );
+        return None;
+    }
+    // The copy pays off once the passes it saves outweigh what it cost.
+    let saved = flash_time.cycles.saturating_sub(ram_time.cycles);
+    let payoff = match saved {
+        0 => "never pays for itself here".into(),
+        saved => std::format!(
+            "pays for itself after {} pass(es)",
+            copy_time.cycles.div_ceil(saved).max(1)
+        ),
+    };
+    println!("Shadow: PASS (crc32 {ram_crc:#010x}; the copy {payoff})");
+    Some(copy)
+}
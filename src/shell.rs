@@ -0,0 +1,214 @@
+//! Line-based flash command protocol on the console.
+//!
+//! After the demos the app prints [`READY`] and reads commands from the
+//! serial port, one per line, until `Q`. Every command gets exactly one
+//! reply line, `OK ...` or `ERR <reason>`, so a host program
+//! (`cargo xtask shell`) can drive flash experiments from a script.
+//! Numbers are decimal or `0x`-prefixed hexadecimal; data is hex bytes.
+//!
+//! ```text
+//! I                 OK <manufacturer> <device> size=<bytes> erase=<bytes>
+//! R <off> <len>     OK <hex>            read up to 4K
+//! W <off> <hex>     OK <len>            program (bits only go 1 -> 0)
+//! E <sector>        OK <off>            erase erase block number <sector>
+//! dumpb64 <off> <len>
+//!                   OK <len> crc32=<crc>  stream any length as base64
+//! Q                 OK bye              leave the shell
+//! ```
+//!
+//! `dumpb64` is the one command with more than its reply line: before it
+//! come a `BEGIN-B64 <off> <len>` line, the bytes in base64 with
+//! [`B64_LINE`] bytes per line, each line behind a `:` so none can be taken
+//! for a reply, and `END-B64 <crc>`. The CRC-32 covers the decoded bytes,
+//! so a host that only has the serial console can recover flash contents
+//! and know they arrived intact.
+
+use crate::cfi::CfiFlash;
+use crate::integrity::crc32_update;
+use std::io;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// Line printed when the shell starts reading commands.
+pub const READY: &str = "PFLASH SHELL READY";
+/// Most bytes a single `R` returns.
+const MAX_READ: usize = 0x1000;
+/// Bytes per line of a `dumpb64` stream: 76 base64 characters.
+const B64_LINE: usize = 57;
+
+const B64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// `data` in standard base64 with `=` padding.
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &b)| acc | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(B64_ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Stream `len` bytes at `off` as a framed base64 dump and return their
+/// CRC-32.
+fn dump_base64(flash: &CfiFlash, off: usize, len: usize) -> u32 {
+    println!("BEGIN-B64 {off:#x} {len:#x}");
+    let mut crc = !0;
+    let mut buf = [0; B64_LINE];
+    let mut at = off;
+    while at < off + len {
+        let line = &mut buf[..B64_LINE.min(off + len - at)];
+        flash.read(at, line);
+        crc = crc32_update(crc, line);
+        println!(":{}", base64(line));
+        at += line.len();
+    }
+    println!("END-B64 {:08x}", !crc);
+    !crc
+}
+
+fn number(arg: Option<&str>) -> Result<usize, String> {
+    let arg = arg.ok_or("missing argument")?;
+    let parsed = match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.map_err(|_| std::format!("bad number '{arg}'"))
+}
+
+fn hex_bytes(arg: Option<&str>) -> Result<Vec<u8>, String> {
+    let arg = arg.ok_or("missing data")?;
+    if arg.len() % 2 != 0 {
+        return Err("odd number of hex digits".into());
+    }
+    (0..arg.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&arg[i..i + 2], 16).map_err(|_| std::format!("bad hex '{arg}'"))
+        })
+        .collect()
+}
+
+/// `off..off + len` if it lies inside the bank.
+fn range(flash: &CfiFlash, off: usize, len: usize) -> Result<(), String> {
+    match off.checked_add(len) {
+        Some(end) if end <= flash.size => Ok(()),
+        _ => Err(std::format!(
+            "{len} bytes at {off:#x} run past the {:#x}-byte bank",
+            flash.size
+        )),
+    }
+}
+
+/// Carry out one command line. `Ok(None)` ends the shell.
+pub fn execute(flash: &CfiFlash, line: &str) -> Result<Option<String>, String> {
+    let mut args = line.split_whitespace();
+    let Some(command) = args.next() else {
+        return Err("empty command".into());
+    };
+    let reply = match command {
+        "I" => {
+            let id = flash.identify();
+            std::format!(
+                "{:#06x} {:#06x} size={:#x} erase={:#x}",
+                id.manufacturer,
+                id.device,
+                flash.size,
+                flash.erase_size
+            )
+        }
+        "R" => {
+            let (off, len) = (number(args.next())?, number(args.next())?);
+            if len > MAX_READ {
+                return Err(std::format!("at most {MAX_READ} bytes per read"));
+            }
+            range(flash, off, len)?;
+            let mut buf = std::vec![0; len];
+            flash.read(off, &mut buf);
+            buf.iter().map(|b| std::format!("{b:02x}")).collect()
+        }
+        "W" => {
+            let off = number(args.next())?;
+            let data = hex_bytes(args.next())?;
+            if !off.is_multiple_of(flash.width) || !data.len().is_multiple_of(flash.width) {
+                return Err(std::format!(
+                    "offset and length must be multiples of {}",
+                    flash.width
+                ));
+            }
+            range(flash, off, data.len())?;
+            flash.program(off, &data).map_err(|e| e.to_string())?;
+            std::format!("{}", data.len())
+        }
+        "E" => {
+            let sector = number(args.next())?;
+            let off = sector
+                .checked_mul(flash.erase_size)
+                .filter(|&off| off < flash.size)
+                .ok_or_else(|| std::format!("no erase block {sector}"))?;
+            flash.erase(off).map_err(|e| e.to_string())?;
+            std::format!("{off:#x}")
+        }
+        "dumpb64" => {
+            let (off, len) = (number(args.next())?, number(args.next())?);
+            if let Some(extra) = args.next() {
+                return Err(std::format!("unexpected argument '{extra}'"));
+            }
+            range(flash, off, len)?;
+            let crc = dump_base64(flash, off, len);
+            std::format!("{len} crc32={crc:08x}")
+        }
+        "Q" => return Ok(None),
+        other => return Err(std::format!("unknown command '{other}'")),
+    };
+    match args.next() {
+        Some(extra) => Err(std::format!("unexpected argument '{extra}'")),
+        None => Ok(Some(reply)),
+    }
+}
+
+/// Serve commands for the bank mapped at `base` until `Q` or the end of
+/// input.
+pub fn run(base: usize) {
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => {
+            println!("Shell: cannot probe flash: {e}");
+            return;
+        }
+    };
+    println!("{READY}");
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match stdin.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                println!("ERR {e}");
+                break;
+            }
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match execute(&flash, line) {
+            Ok(Some(reply)) => println!("OK {reply}"),
+            Ok(None) => {
+                println!("OK bye");
+                break;
+            }
+            Err(e) => println!("ERR {e}"),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Erase suspend demo.
+//!
+//! A block erase keeps a real NOR chip busy for up to seconds, and during
+//! that time reads anywhere in the bank return the status register. Erase
+//! suspend lets a driver pause the erase, read other blocks (here the image
+//! header, as a boot path reading code would) and resume.
+//!
+//! The demo erases the last erase block of the writable "journal" region
+//! (`cargo xtask run --features erase-suspend` adds it) and restores its
+//! contents afterwards. It reports what the device actually did: QEMU's
+//! `cfi.pflash01` erases instantly and has no suspend command, so there the
+//! erase is already complete when the suspend is issued.
+
+use crate::cfi::{CfiFlash, FlashError};
+use crate::layout::HEADER_SIZE;
+use std::vec;
+
+/// What happened during the interleaved erase.
+struct Observed {
+    /// First bank word read while the erase was in progress.
+    busy_word: u32,
+    /// Whether the chip reported the erase as suspended.
+    suspended: bool,
+    /// Whether the header read during the erase matched.
+    header_ok: bool,
+    /// Whether the block read back erased.
+    erased: bool,
+}
+
+/// Erase the block at `block` while reading the header, then restore it.
+fn interleave(flash: &CfiFlash, block: usize) -> Result<Observed, FlashError> {
+    let mut header = [0; HEADER_SIZE];
+    flash.read(0, &mut header);
+    let mut saved = vec![0; flash.erase_size];
+    flash.read(block, &mut saved);
+
+    let erase = flash.erase_start(block);
+    let mut busy = [0; 4];
+    flash.read(0, &mut busy[..flash.width]);
+    let suspended = flash.erase_suspend(&erase)?;
+    let mut during = [0; HEADER_SIZE];
+    flash.read(0, &mut during);
+    if suspended {
+        flash.erase_resume(&erase);
+    }
+    flash.erase_wait(erase)?;
+
+    let mut erased = true;
+    let mut buf = [0; 4];
+    let word = &mut buf[..flash.width];
+    for (i, old) in saved.chunks_exact(flash.width).enumerate() {
+        let at = block + i * flash.width;
+        flash.read(at, word);
+        erased &= word.iter().all(|&b| b == 0xFF);
+        if old.iter().any(|&b| b != 0xFF) {
+            flash.program(at, old)?;
+        }
+    }
+    Ok(Observed {
+        busy_word: u32::from_le_bytes(busy),
+        suspended,
+        header_ok: during == header,
+        erased,
+    })
+}
+
+/// Interleave an erase in the journal region of the bank mapped at `base`
+/// with reads of the image header.
+///
+/// Returns `true` if the header read correctly during the erase and the
+/// block was erased and restored.
+pub fn run(base: usize, size: usize) -> bool {
+    let (offset, len) = match crate::writable_region(base, size, Some("journal")) {
+        Ok(Some(region)) => region,
+        Ok(None) => {
+            println!(
+                "Suspend: no journal region in the image (create it with `cargo xtask mkimage --journal`)"
+            );
+            return false;
+        }
+        Err(e) => {
+            println!("Suspend: FAIL ({e})");
+            return false;
+        }
+    };
+
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => {
+            println!("Suspend: FAIL (flash probe: {e})");
+            return false;
+        }
+    };
+    let end = offset + len;
+    let Some(block) = (end - end % flash.erase_size)
+        .checked_sub(flash.erase_size)
+        .filter(|&block| block >= offset)
+    else {
+        println!("Suspend: FAIL (journal region holds no whole erase block)");
+        return false;
+    };
+
+    println!(
+        "Suspend: erasing block {block:#x} ({} KiB) while reading the header at 0x0",
+        flash.erase_size / 1024
+    );
+    let seen = match interleave(&flash, block) {
+        Ok(seen) => seen,
+        Err(e) => {
+            println!("Suspend: FAIL ({e})");
+            return false;
+        }
+    };
+    println!(
+        "Suspend: a read during the erase returned {:#x} (the status register, not data)",
+        seen.busy_word
+    );
+    if seen.suspended {
+        println!("Suspend: erase suspended, header read, erase resumed");
+    } else {
+        println!(
+            "Suspend: erase had already completed when suspend was issued (no suspend needed)"
+        );
+    }
+    if seen.header_ok && seen.erased {
+        println!(
+            "Suspend: PASS (header intact during the erase, block erased and restored{})",
+            if seen.suspended {
+                ", suspend observed"
+            } else {
+                ""
+            }
+        );
+        true
+    } else if !seen.header_ok {
+        println!("Suspend: FAIL (header read differently during the erase)");
+        false
+    } else {
+        println!("Suspend: FAIL (block not erased)");
+        false
+    }
+}
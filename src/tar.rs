@@ -0,0 +1,290 @@
+//! ustar archive reader.
+//!
+//! A tar archive stored as the payload or fs region (`cargo xtask run
+//! --payload`/`--fs`) is read in place from flash: [`for_each_member`]
+//! lists it and [`find`] extracts a single member by path, which is all a
+//! `cat <member>` needs. The demo lists the archive and then extracts the
+//! first text file by name.
+//!
+//! An archive is a sequence of 512-byte blocks: a header block per member
+//! with its data in the following blocks, ended by two zero blocks.
+//! Numbers in the header are octal ASCII; GNU base-256 sizes are accepted
+//! too. pax extended headers are skipped.
+
+use crate::layout::{Header, Manifest};
+use crate::text::text;
+use core::fmt;
+
+const BLOCK: usize = 512;
+/// Offset and value of the ustar magic (POSIX `"ustar\0"`, GNU `"ustar "`).
+const MAGIC_OFFSET: usize = 257;
+const MAGIC: &[u8; 5] = b"ustar";
+
+/// Reasons an archive cannot be read.
+#[derive(Debug)]
+pub enum TarError {
+    /// A header or member data extends past the end of the region.
+    Truncated(usize),
+    /// The header at this offset has no ustar magic.
+    BadMagic(usize),
+    /// The header checksum at this offset does not match.
+    BadChecksum(usize),
+    /// A numeric field at this offset is malformed.
+    BadField(usize),
+    /// A name at this offset is not valid UTF-8.
+    BadName(usize),
+}
+
+impl fmt::Display for TarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated(off) => write!(f, "member at {off:#x} truncated"),
+            Self::BadMagic(off) => write!(f, "no ustar magic in header at {off:#x}"),
+            Self::BadChecksum(off) => write!(f, "bad checksum in header at {off:#x}"),
+            Self::BadField(off) => write!(f, "bad numeric field in header at {off:#x}"),
+            Self::BadName(off) => write!(f, "name in header at {off:#x} is not UTF-8"),
+        }
+    }
+}
+
+/// One archive member.
+pub struct Member<'a> {
+    /// ustar `prefix` field; the path is `prefix/name` when it is set.
+    prefix: &'a str,
+    name: &'a str,
+    /// Type flag: `b'0'` regular file, `b'5'` directory, `b'2'` symlink...
+    pub kind: u8,
+    pub mode: u32,
+    pub link: &'a str,
+    pub data: &'a [u8],
+}
+
+impl Member<'_> {
+    /// Whether the member's full path is `path`, ignoring a leading `./`
+    /// and a trailing `/`.
+    pub fn is(&self, path: &str) -> bool {
+        let path = path.trim_start_matches("./").trim_end_matches('/');
+        let name = self.name.trim_start_matches("./").trim_end_matches('/');
+        if self.prefix.is_empty() {
+            return name == path;
+        }
+        path.strip_prefix(self.prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .is_some_and(|rest| rest == name)
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self.kind, b'0' | 0 | b'7')
+    }
+}
+
+impl fmt::Display for Member<'_> {
+    /// The full path of the member.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.prefix.is_empty() {
+            write!(f, "{}/", self.prefix)?;
+        }
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Whether `data` starts with a ustar header.
+pub fn is_ustar(data: &[u8]) -> bool {
+    data.get(MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()) == Some(MAGIC.as_slice())
+}
+
+/// A NUL-terminated (or full-width) string field.
+fn str_field(header: &[u8], range: core::ops::Range<usize>, off: usize) -> Result<&str, TarError> {
+    let field = &header[range];
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| TarError::BadName(off))
+}
+
+/// An octal (or GNU base-256) number field.
+fn num_field(header: &[u8], range: core::ops::Range<usize>, off: usize) -> Result<u64, TarError> {
+    let field = &header[range];
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7F), |acc, &b| {
+                acc << 8 | u64::from(b)
+            }));
+    }
+    let mut digits = field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ');
+    digits.try_fold(0, |n, &b| match b {
+        b'0'..=b'7' => Ok(n << 3 | u64::from(b - b'0')),
+        _ => Err(TarError::BadField(off)),
+    })
+}
+
+/// Call `f` for every member of the archive in `data`, until it returns
+/// `false`.
+///
+/// Returns the offset of the end-of-archive marker (or of the member `f`
+/// stopped at).
+pub fn for_each_member<'a>(
+    data: &'a [u8],
+    mut f: impl FnMut(Member<'a>) -> bool,
+) -> Result<usize, TarError> {
+    let mut off = 0;
+    loop {
+        let header = data.get(off..off + BLOCK).ok_or(TarError::Truncated(off))?;
+        if header.iter().all(|&b| b == 0) {
+            return Ok(off);
+        }
+        if !is_ustar(header) {
+            return Err(TarError::BadMagic(off));
+        }
+        let sum = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    u32::from(b' ')
+                } else {
+                    u32::from(b)
+                }
+            })
+            .sum::<u32>();
+        if u64::from(sum) != num_field(header, 148..156, off)? {
+            return Err(TarError::BadChecksum(off));
+        }
+        let size = num_field(header, 124..136, off)? as usize;
+        let start = off + BLOCK;
+        let body = data
+            .get(start..start + size)
+            .ok_or(TarError::Truncated(off))?;
+        let kind = header[156];
+        // pax extended and global headers only describe other members.
+        if kind != b'x' && kind != b'g' {
+            let member = Member {
+                prefix: str_field(header, 345..500, off)?,
+                name: str_field(header, 0..100, off)?,
+                kind,
+                mode: num_field(header, 100..108, off)? as u32,
+                link: str_field(header, 157..257, off)?,
+                data: body,
+            };
+            if !f(member) {
+                return Ok(off);
+            }
+        }
+        off = start + size.next_multiple_of(BLOCK);
+    }
+}
+
+/// Extract the member at `path`.
+pub fn find<'a>(data: &'a [u8], path: &str) -> Result<Option<Member<'a>>, TarError> {
+    let mut found = None;
+    for_each_member(data, |member| {
+        if member.is(path) {
+            found = Some(member);
+        }
+        found.is_none()
+    })?;
+    Ok(found)
+}
+
+/// List the tar archive in the payload or fs region of `flash` and extract
+/// its first text file. An encrypted payload is read from its `decrypted`
+/// plaintext.
+///
+/// Returns `true` if the archive was read to its end.
+pub fn run<'a>(flash: &'a [u8], decrypted: Option<&'a [u8]>) -> bool {
+    let archive = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest
+            .regions()
+            .flatten()
+            .filter(|r| r.name == "payload" || r.name == "fs")
+            .find_map(|r| {
+                let data = match (r.name, decrypted) {
+                    ("payload", Some(plaintext)) => plaintext,
+                    _ => r.data(flash)?,
+                };
+                Some((r.name, data)).filter(|(_, d)| is_ustar(d))
+            }))
+    });
+    let (region, data) = match archive {
+        Ok(Some(archive)) => archive,
+        Ok(None) => {
+            println!(
+                "Tar: no ustar archive in the payload or fs region (pass one with `cargo xtask run --fs <FILE>`)"
+            );
+            return false;
+        }
+        Err(e) => {
+            println!("Tar: cannot read manifest: {e}");
+            return false;
+        }
+    };
+
+    println!(
+        "Tar: ustar archive in the {region} region ({} bytes):",
+        data.len()
+    );
+    let mut count = 0;
+    let mut first_text = None;
+    let result = for_each_member(data, |member| {
+        count += 1;
+        let kind = match member.kind {
+            b'5' => 'd',
+            b'2' => 'l',
+            b'1' => 'h',
+            b'3' => 'c',
+            b'4' => 'b',
+            b'6' => 'p',
+            _ if member.is_file() => '-',
+            _ => '?',
+        };
+        match member.kind {
+            b'1' | b'2' => println!(
+                "  {kind} {:04o} {:>8}  {member} -> {}",
+                member.mode & 0o7777,
+                member.data.len(),
+                member.link
+            ),
+            _ => println!(
+                "  {kind} {:04o} {:>8}  {member}",
+                member.mode & 0o7777,
+                member.data.len()
+            ),
+        }
+        if first_text.is_none()
+            && member.is_file()
+            && !member.data.is_empty()
+            && text(member.data).is_some()
+        {
+            first_text = Some(member);
+        }
+        true
+    });
+    let end = match result {
+        Ok(end) => end,
+        Err(e) => {
+            println!("Tar: FAIL ({e})");
+            return false;
+        }
+    };
+
+    // Extract by name, as `cat <member>` would.
+    if let Some(member) = first_text {
+        let path = std::format!("{member}");
+        match find(data, &path) {
+            Ok(Some(found)) => {
+                println!("Tar: cat {path}");
+                for line in text(found.data).unwrap_or("").lines() {
+                    println!("  | {line}");
+                }
+            }
+            Ok(None) => println!("Tar: FAIL ({path} listed but not found by name)"),
+            Err(e) => println!("Tar: FAIL ({e})"),
+        }
+    }
+
+    println!("Tar: PASS ({count} members, end of archive at {end:#x})");
+    true
+}
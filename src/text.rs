@@ -0,0 +1,44 @@
+//! Files shown as text by the archive and filesystem demos.
+//!
+//! Small files are printed when they look like text: valid UTF-8 with no
+//! control characters but whitespace. Filesystem listings show the start
+//! of each one instead.
+
+/// Largest text file printed in full.
+#[cfg(any(feature = "cpio", feature = "tar"))]
+const CAT_MAX: usize = 256;
+/// Bytes of a text file shown in a listing.
+#[cfg(any(feature = "romfs", feature = "ext2"))]
+pub const PREVIEW_LEN: usize = 48;
+
+/// The file as text, if it is short and printable.
+#[cfg(any(feature = "cpio", feature = "tar"))]
+pub fn text(data: &[u8]) -> Option<&str> {
+    if data.len() > CAT_MAX {
+        return None;
+    }
+    let text = core::str::from_utf8(data).ok()?;
+    printable(text).then_some(text)
+}
+
+/// The printable start of `data`, up to [`PREVIEW_LEN`] bytes, if it looks
+/// like text.
+#[cfg(any(feature = "romfs", feature = "ext2"))]
+pub fn preview(data: &[u8]) -> Option<&str> {
+    let head = &data[..data.len().min(PREVIEW_LEN)];
+    let text = match core::str::from_utf8(head) {
+        Ok(text) => text,
+        // Only accept a character cut off by the preview length.
+        Err(e) if e.error_len().is_none() => {
+            core::str::from_utf8(&head[..e.valid_up_to()]).unwrap()
+        }
+        Err(_) => return None,
+    };
+    printable(text).then_some(text)
+}
+
+/// Whether `text` has no control characters but whitespace.
+fn printable(text: &str) -> bool {
+    text.chars()
+        .all(|c| !c.is_control() || c.is_ascii_whitespace())
+}
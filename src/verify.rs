@@ -0,0 +1,80 @@
+//! Manifest verification mode.
+//!
+//! Reads the manifest that `cargo xtask mkimage` stored in flash, recomputes
+//! the SHA-256 digest of every listed region directly from the flash mapping
+//! and prints a PASS/FAIL table.
+
+use crate::layout::{Header, Manifest};
+use sha2::{Digest, Sha256};
+
+/// Verify every region listed in the on-flash manifest.
+///
+/// Returns `true` if all regions match their recorded digests.
+pub fn verify_manifest(flash: &[u8]) -> bool {
+    let header = match Header::parse(flash) {
+        Ok(header) => header,
+        Err(e) => {
+            println!("Manifest verification: cannot read header: {e}");
+            return false;
+        }
+    };
+    println!(
+        "Image header: version {}, flags {:#x}, header {} bytes, image {} bytes",
+        header.version, header.flags, header.header_size, header.image_size
+    );
+    if let Some((offset, len)) = header.kernel() {
+        println!("Image header: kernel embedded at {offset:#x} ({len} bytes)");
+    }
+    let manifest = match Manifest::parse(flash, &header) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("Manifest verification: cannot read manifest: {e}");
+            return false;
+        }
+    };
+
+    println!("Verifying pflash manifest ({} regions):", manifest.len());
+    println!("  #  name             offset      length      result");
+    let mut failed = 0;
+    let mut skipped = 0;
+    for (i, region) in manifest.regions().enumerate() {
+        let region = match region {
+            Ok(region) => region,
+            Err(e) => {
+                println!("  {i:<2} <invalid entry: {e}>                          FAIL");
+                failed += 1;
+                continue;
+            }
+        };
+        let result = match region.data(flash) {
+            Some(_) if region.writable() => "SKIP (writable)",
+            Some(data) if Sha256::digest(data).as_slice() == region.sha256 => "PASS",
+            Some(_) => "FAIL (digest mismatch)",
+            None => "FAIL (out of bounds)",
+        };
+        if result.starts_with("FAIL") {
+            failed += 1;
+        } else if result != "PASS" {
+            skipped += 1;
+        }
+        println!(
+            "  {:<2} {:<16} {:#010x}  {:<10}  {}",
+            i, region.name, region.offset, region.len, result
+        );
+    }
+
+    if failed == 0 && skipped > 0 {
+        println!(
+            "Manifest verification: all {} read-only regions PASS ({skipped} writable skipped)",
+            manifest.len() - skipped
+        );
+    } else if failed == 0 {
+        println!("Manifest verification: all {} regions PASS", manifest.len());
+    } else {
+        println!(
+            "Manifest verification: {failed} of {} regions FAILED",
+            manifest.len()
+        );
+    }
+    failed == 0
+}
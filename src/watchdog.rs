@@ -0,0 +1,191 @@
+//! Watchdog demo.
+//!
+//! `cargo xtask run --watchdog` attaches a watchdog to the machine: an IB700
+//! (ISA I/O ports) on x86_64, an Intel 6300ESB (PCI) elsewhere. The app arms
+//! it with a ~2 s timeout and scans the whole flash bank, petting it after
+//! every chunk. With `--watchdog=starve` it stops petting instead, so QEMU's
+//! watchdog action ends the run: a hang during a long flash scan is detected
+//! rather than waited on forever.
+
+/// Bytes read between two pets.
+const CHUNK: usize = 64 * 1024;
+
+#[cfg(target_arch = "x86_64")]
+mod dev {
+    //! IB700: a write to 0x443 (re)starts the timer, with the timeout picked
+    //! from a table by the low nibble; any write to 0x441 stops it.
+
+    const ENABLE_PORT: u16 = 0x443;
+    const DISABLE_PORT: u16 = 0x441;
+    /// Table index for a 2 second timeout (30 - 2 * 14).
+    const TIMEOUT_2S: u8 = 14;
+
+    fn outb(port: u16, value: u8) {
+        unsafe { core::arch::asm!("out dx, al", in("dx") port, in("al") value) };
+    }
+
+    pub struct Watchdog;
+
+    impl Watchdog {
+        pub const NAME: &'static str = "ib700";
+
+        pub fn start() -> Result<Self, &'static str> {
+            outb(ENABLE_PORT, TIMEOUT_2S);
+            Ok(Self)
+        }
+
+        pub fn pet(&self) {
+            outb(ENABLE_PORT, TIMEOUT_2S);
+        }
+
+        pub fn stop(&self) {
+            outb(DISABLE_PORT, 0);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod dev {
+    //! 6300ESB on PCI bus 0, found and set up through ECAM. Nothing assigns
+    //! PCI BARs before the kernel on these machines, so BAR0 is placed at
+    //! the start of the PCI memory window. The ECAM base comes from the
+    //! config (`pci-ecam-base`), as it moves with aarch64's `highmem=off`.
+
+    use std::os::arceos::modules::axhal::mem::phys_to_virt;
+
+    /// PCIe ECAM base.
+    const ECAM_BASE: usize = crate::board::PCI_ECAM_PADDR;
+    /// Start of the 32-bit PCI memory window.
+    #[cfg(target_arch = "riscv64")]
+    const BAR_BASE: usize = 0x4000_0000;
+    #[cfg(target_arch = "aarch64")]
+    const BAR_BASE: usize = 0x1000_0000;
+    #[cfg(target_arch = "loongarch64")]
+    const BAR_BASE: usize = 0x4000_0000;
+
+    /// Vendor/device ID of the 6300ESB watchdog (Intel 0x25ab).
+    const ESB_ID: u32 = 0x25ab_8086;
+    /// Config space: clock scale / reboot behavior.
+    const ESB_CONFIG: usize = 0x60;
+    /// Config space: enable/lock bits.
+    const ESB_LOCK: usize = 0x68;
+    const ESB_WDT_ENABLE: u8 = 0x02;
+    /// BAR0 registers: stage 1/2 preload values and the reload register.
+    const ESB_TIMER1: usize = 0x00;
+    const ESB_TIMER2: usize = 0x04;
+    const ESB_RELOAD: usize = 0x0C;
+    const ESB_WDT_RELOAD: u16 = 0x100;
+    /// Preload for ~1 s per stage at the 1 kHz clock; the action fires
+    /// when stage 2 expires.
+    const PRELOAD_1S: u32 = 1000;
+
+    fn read32(addr: usize) -> u32 {
+        unsafe { (addr as *const u32).read_volatile() }
+    }
+
+    fn write32(addr: usize, value: u32) {
+        unsafe { (addr as *mut u32).write_volatile(value) }
+    }
+
+    fn write16(addr: usize, value: u16) {
+        unsafe { (addr as *mut u16).write_volatile(value) }
+    }
+
+    fn write8(addr: usize, value: u8) {
+        unsafe { (addr as *mut u8).write_volatile(value) }
+    }
+
+    pub struct Watchdog {
+        /// Config space of the device.
+        cfg: usize,
+        /// Mapped BAR0.
+        regs: usize,
+    }
+
+    impl Watchdog {
+        pub const NAME: &'static str = "i6300esb";
+
+        pub fn start() -> Result<Self, &'static str> {
+            let ecam = phys_to_virt(ECAM_BASE.into()).as_usize();
+            let cfg = (0..32)
+                .map(|dev| ecam + (dev << 15))
+                .find(|&cfg| read32(cfg) == ESB_ID)
+                .ok_or("no i6300esb on PCI bus 0 (run with `cargo xtask run --watchdog`)")?;
+            write32(cfg + 0x10, BAR_BASE as u32);
+            // Enable memory decoding.
+            write16(cfg + 0x04, 0x0002);
+            let wdt = Self {
+                cfg,
+                regs: phys_to_virt(BAR_BASE.into()).as_usize(),
+            };
+            // 1 kHz clock, perform the watchdog action on expiry.
+            write16(cfg + ESB_CONFIG, 0);
+            wdt.unlock();
+            write32(wdt.regs + ESB_TIMER1, PRELOAD_1S);
+            wdt.unlock();
+            write32(wdt.regs + ESB_TIMER2, PRELOAD_1S);
+            wdt.pet();
+            write8(cfg + ESB_LOCK, ESB_WDT_ENABLE);
+            Ok(wdt)
+        }
+
+        /// Register writes only take effect after this unlock sequence.
+        fn unlock(&self) {
+            write16(self.regs + ESB_RELOAD, 0x80);
+            write16(self.regs + ESB_RELOAD, 0x86);
+        }
+
+        pub fn pet(&self) {
+            self.unlock();
+            write16(self.regs + ESB_RELOAD, ESB_WDT_RELOAD);
+        }
+
+        pub fn stop(&self) {
+            write8(self.cfg + ESB_LOCK, 0);
+        }
+    }
+}
+
+/// Arm the watchdog and scan `flash`, petting it after every chunk unless
+/// `starve` is set.
+pub fn run(flash: &[u8], starve: bool) {
+    let wdt = match dev::Watchdog::start() {
+        Ok(wdt) => wdt,
+        Err(e) => {
+            println!("Watchdog: not started: {e}");
+            return;
+        }
+    };
+    println!(
+        "Watchdog: {} armed (~2 s), scanning {} bytes of flash in {} KiB chunks{}",
+        dev::Watchdog::NAME,
+        flash.len(),
+        CHUNK / 1024,
+        if starve { " without petting" } else { "" }
+    );
+
+    let mut sum = 0u32;
+    let mut pets = 0;
+    for chunk in flash.chunks(CHUNK) {
+        sum = chunk
+            .iter()
+            .fold(sum, |acc, &b| acc.rotate_left(1) ^ u32::from(b));
+        if !starve {
+            wdt.pet();
+            pets += 1;
+        }
+    }
+    println!(
+        "Watchdog: scan done, checksum {:#010x}, petted {} times",
+        sum, pets
+    );
+
+    if starve {
+        println!("Watchdog: no longer petting; the watchdog should end the run");
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+    wdt.stop();
+    println!("Watchdog: stopped");
+}
@@ -0,0 +1,256 @@
+//! Bulk read throughput through a write-combining mapping.
+//!
+//! Command sequences need the bank mapped as device memory (see the
+//! `device-map` feature), but a driver that only copies data out in
+//! read-array mode may do better with a relaxed mapping that lets the CPU
+//! merge and speculate accesses. This experiment times the same sequential
+//! 64-bit reads through both and prints the two rates, then puts the strict
+//! mapping back.
+//!
+//! `MappingFlags::UNCACHED` is the relaxed type: Normal Non-cacheable on
+//! aarch64 and weakly-ordered uncached (WUC) on loongarch64, both of which
+//! allow write combining. Under QEMU's TCG every flash read is the same
+//! memory access whatever the mapping, so expect the numbers to be close;
+//! they only mean something on hardware.
+//!
+//! After that the relaxed mapping goes back on while the same span is
+//! copied into RAM in each of the ways in [`STRATEGIES`]: a `read_volatile`
+//! loop, one `copy_from_slice` out of the mapped window, and chunked copies
+//! sized to a cache line, a page and a larger block. Each is timed and
+//! checked to read what the loop above read, which shows how a driver
+//! should move bulk data out of the bank.
+//!
+//! With the `bench-record` feature each passing run is also appended to the
+//! bench region of the image (see `benchlog.rs`), and with `json-report` it
+//! goes into the report line (see `json.rs`).
+
+use crate::cycles::{Span, Stopwatch};
+use crate::remap;
+use std::os::arceos::modules::axhal::mem::VirtAddr;
+use std::os::arceos::modules::axhal::paging::MappingFlags;
+use std::os::arceos::modules::axmm::kernel_aspace;
+use std::time::Duration;
+use std::vec;
+
+/// Bytes read per pass, from the start of the bank.
+const SPAN: usize = 1024 * 1024;
+/// Passes timed through each mapping; the fastest one counts.
+const PASSES: usize = 4;
+
+/// Ways to copy the span into RAM: a label and the bytes per copy, with
+/// `None` for 64-bit `read_volatile` loads one word at a time.
+const STRATEGIES: [(&str, Option<usize>); 5] = [
+    ("read_volatile", None),
+    ("copy_from_slice", Some(SPAN)),
+    ("chunks 64 B", Some(64)),
+    ("chunks 4 KiB", Some(4 * 1024)),
+    ("chunks 64 KiB", Some(64 * 1024)),
+];
+
+/// What `MappingFlags::UNCACHED` selects on this architecture.
+#[cfg(target_arch = "aarch64")]
+const RELAXED_TYPE: &str = "Normal Non-cacheable";
+#[cfg(target_arch = "loongarch64")]
+const RELAXED_TYPE: &str = "weakly-ordered uncached (MAT 2)";
+#[cfg(target_arch = "x86_64")]
+const RELAXED_TYPE: &str = "uncached; write combining needs a PAT entry";
+#[cfg(target_arch = "riscv64")]
+const RELAXED_TYPE: &str = "no page attribute without Svpbmt; the platform's PMAs decide";
+
+/// Fold one word into a running check value.
+fn fold(sum: u64, word: u64) -> u64 {
+    sum.rotate_left(1) ^ word
+}
+
+/// Read `SPAN` bytes at `va` 64 bits at a time and fold them together, so
+/// the passes can be checked against each other.
+fn pass(va: usize) -> (Span, u64) {
+    let watch = Stopwatch::start();
+    let mut sum = 0u64;
+    for off in (0..SPAN).step_by(8) {
+        let word = unsafe { ((va + off) as *const u64).read_volatile() };
+        sum = fold(sum, word);
+    }
+    (watch.stop(), sum)
+}
+
+/// Copy `SPAN` bytes at `va` into `dst` with `chunk` bytes per copy (see
+/// [`STRATEGIES`]) and return how long it took.
+fn copy_pass(va: usize, dst: &mut [u64], chunk: Option<usize>) -> Span {
+    // Read-array mode: the window reads like memory while nothing writes.
+    let src = unsafe { core::slice::from_raw_parts(va as *const u64, SPAN / 8) };
+    let watch = Stopwatch::start();
+    match chunk {
+        None => {
+            for (d, s) in dst.iter_mut().zip(src) {
+                *d = unsafe { (s as *const u64).read_volatile() };
+            }
+        }
+        Some(chunk) => {
+            for (d, s) in dst.chunks_mut(chunk / 8).zip(src.chunks(chunk / 8)) {
+                d.copy_from_slice(s);
+            }
+        }
+    }
+    watch.stop()
+}
+
+/// Time every strategy in [`STRATEGIES`] on the bank at `va`, best of
+/// [`PASSES`] each, and check that every copy folds to `sum`. Returns
+/// `true` if they all do.
+fn strategies(va: usize, sum: u64) -> bool {
+    println!("Access strategies: copying the span into RAM, best of {PASSES} passes");
+    let mut dst = vec![0u64; SPAN / 8];
+    let mut ok = true;
+    let mut fastest: Option<(&str, Span)> = None;
+    for (label, chunk) in STRATEGIES {
+        let mut best: Option<Span> = None;
+        let mut same = true;
+        for _ in 0..PASSES {
+            dst.fill(0);
+            let span = copy_pass(va, &mut dst, chunk);
+            best = Some(best.map_or(span, |best| best.min(span)));
+            same &= dst.iter().fold(0, |sum, &word| fold(sum, word)) == sum;
+        }
+        let best = best.unwrap();
+        println!(
+            "  {label:<16} {:>8} us  {:>6} MiB/s  {:>10} cycles{}",
+            best.time.as_micros(),
+            mib_per_s(best.time),
+            best.cycles,
+            if same { "" } else { "  (different data)" }
+        );
+        ok &= same;
+        if fastest.is_none_or(|(_, time)| best < time) {
+            fastest = Some((label, best));
+        }
+    }
+    match fastest {
+        Some((label, _)) if ok => {
+            println!("Access strategies: PASS (same data; fastest is {label})");
+        }
+        _ => println!("Access strategies: FAIL (a copy read different data)"),
+    }
+    ok
+}
+
+/// The fastest of [`PASSES`] passes and the value every pass folded to, or
+/// `None` if the passes disagree.
+fn time(va: usize) -> (Span, Option<u64>) {
+    let (mut best, sum) = pass(va);
+    let mut agree = true;
+    for _ in 1..PASSES {
+        let (elapsed, again) = pass(va);
+        best = best.min(elapsed);
+        agree &= again == sum;
+    }
+    (best, agree.then_some(sum))
+}
+
+/// Figures of one benchmark run.
+#[cfg_attr(
+    not(any(feature = "bench-record", feature = "json-report")),
+    allow(dead_code)
+)]
+pub struct Sample {
+    pub span: usize,
+    pub passes: usize,
+    pub device: Duration,
+    pub relaxed: Duration,
+    pub device_mibs: u64,
+    pub relaxed_mibs: u64,
+}
+
+fn mib_per_s(elapsed: Duration) -> u64 {
+    let nanos = elapsed.as_nanos().max(1);
+    (SPAN as u128 * 1_000_000_000 / nanos / (1024 * 1024)) as u64
+}
+
+/// Time sequential reads of the bank of `size` bytes at `va` through a
+/// device mapping and a write-combining one.
+///
+/// Returns `true` if both mappings read the same data and every strategy
+/// in [`STRATEGIES`] copies it intact. The bank is left mapped as device
+/// memory.
+pub fn run(va: usize, size: usize) -> bool {
+    let access = match kernel_aspace()
+        .lock()
+        .page_table()
+        .query(VirtAddr::from(va))
+    {
+        Ok((_, flags, _)) => flags & (MappingFlags::READ | MappingFlags::WRITE),
+        Err(e) => {
+            println!("Write-combining: FAIL (bank is not mapped: {e:?})");
+            return false;
+        }
+    };
+    let strict = access | MappingFlags::DEVICE;
+    let relaxed = access | MappingFlags::UNCACHED;
+    println!("Write-combining: relaxed mapping here is {RELAXED_TYPE}");
+    println!(
+        "Write-combining: reading {} KiB at {va:#x}, best of {PASSES} passes",
+        SPAN / 1024
+    );
+
+    if !remap("Write-combining", va, size, strict) {
+        return false;
+    }
+    let (strict_time, strict_sum) = time(va);
+    if !remap("Write-combining", va, size, relaxed) {
+        return false;
+    }
+    let (relaxed_time, relaxed_sum) = time(va);
+    // Command sequences after this one need the strict mapping.
+    if !remap("Write-combining", va, size, strict) {
+        return false;
+    }
+
+    for (label, elapsed) in [("device", strict_time), ("relaxed", relaxed_time)] {
+        println!(
+            "  {label:<8} {:>8} us  {:>6} MiB/s  {:>10} cycles",
+            elapsed.time.as_micros(),
+            mib_per_s(elapsed.time),
+            elapsed.cycles
+        );
+    }
+    match (strict_sum, relaxed_sum) {
+        (Some(a), Some(b)) if a == b => {
+            let ratio = strict_time.cycles * 100 / relaxed_time.cycles.max(1);
+            println!(
+                "Write-combining: PASS (same data; relaxed is {}.{:02}x the device rate)",
+                ratio / 100,
+                ratio % 100
+            );
+            #[cfg_attr(
+                not(any(feature = "bench-record", feature = "json-report")),
+                allow(unused_variables)
+            )]
+            let sample = Sample {
+                span: SPAN,
+                passes: PASSES,
+                device: strict_time.time,
+                relaxed: relaxed_time.time,
+                device_mibs: mib_per_s(strict_time.time),
+                relaxed_mibs: mib_per_s(relaxed_time.time),
+            };
+            #[cfg(feature = "bench-record")]
+            crate::benchlog::record(va, size, &sample);
+            #[cfg(feature = "json-report")]
+            crate::json::bench(&sample);
+            // Bulk copies suit read-array mode, so time them relaxed too.
+            if !remap("Write-combining", va, size, relaxed) {
+                return false;
+            }
+            let copies = strategies(va, a);
+            remap("Write-combining", va, size, strict) && copies
+        }
+        (Some(_), Some(_)) => {
+            println!("Write-combining: FAIL (the two mappings read different data)");
+            false
+        }
+        _ => {
+            println!("Write-combining: FAIL (passes through one mapping read different data)");
+            false
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Flash reads at a chosen access width.
+//!
+//! A NOR bank is wired to the bus at a fixed width, and how it answers
+//! narrower or wider accesses is up to the chip and the bus bridge: QEMU's
+//! `cfi.pflash01` serves reads in read-array mode from a ROM-mode memory
+//! region that takes any width, while real chips may return a single lane
+//! or fault. The app reads the start of the bank with 8-, 16-, 32- or 64-bit
+//! accesses only and compares the result with byte reads.
+//!
+//! `width=8|16|32|64` on the kernel command line (`cargo xtask run
+//! --access-width`) picks one width; without it all four are tried.
+
+/// Bytes read and compared at each width: the header and manifest sector.
+const WINDOW: usize = 0x1000;
+
+/// Width of each access.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    W8,
+    W16,
+    W32,
+    W64,
+}
+
+impl Width {
+    pub const ALL: [Width; 4] = [Width::W8, Width::W16, Width::W32, Width::W64];
+
+    /// Parse a width in bits (`8`, `16`, `32` or `64`).
+    pub fn parse(bits: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|w| bits.parse() == Ok(w.bits()))
+    }
+
+    pub fn bits(self) -> u32 {
+        self.bytes() as u32 * 8
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::W8 => 1,
+            Self::W16 => 2,
+            Self::W32 => 4,
+            Self::W64 => 8,
+        }
+    }
+}
+
+/// Copy `buf.len()` bytes at `addr` into `buf` with `width` accesses only.
+///
+/// `addr` and `buf.len()` must be multiples of the width.
+pub fn read(addr: usize, buf: &mut [u8], width: Width) {
+    debug_assert!(addr.is_multiple_of(width.bytes()) && buf.len().is_multiple_of(width.bytes()));
+    for (i, chunk) in buf.chunks_exact_mut(width.bytes()).enumerate() {
+        let p = addr + i * width.bytes();
+        unsafe {
+            match width {
+                Width::W8 => chunk[0] = (p as *const u8).read_volatile(),
+                Width::W16 => {
+                    chunk.copy_from_slice(&(p as *const u16).read_volatile().to_ne_bytes())
+                }
+                Width::W32 => {
+                    chunk.copy_from_slice(&(p as *const u32).read_volatile().to_ne_bytes())
+                }
+                Width::W64 => {
+                    chunk.copy_from_slice(&(p as *const u64).read_volatile().to_ne_bytes())
+                }
+            }
+        }
+    }
+}
+
+/// Read the start of the bank mapped at `base` at the width `arg` names in
+/// bits, or at every width without one, and compare with byte reads.
+///
+/// Returns `true` if every width read returned the same data.
+pub fn run(base: usize, arg: Option<&str>) -> bool {
+    let widths = match arg.map(|bits| (bits, Width::parse(bits))) {
+        Some((_, Some(width))) => &[width][..],
+        Some((bits, None)) => {
+            println!("Access width: ignoring width={bits} (expected 8, 16, 32 or 64)");
+            &Width::ALL[..]
+        }
+        None => &Width::ALL[..],
+    };
+    let mut reference = [0u8; WINDOW];
+    read(base, &mut reference, Width::W8);
+    println!("Access width: reading {WINDOW:#x} bytes at {base:#x}");
+
+    let mut failed = 0;
+    let mut buf = [0u8; WINDOW];
+    for &width in widths {
+        read(base, &mut buf, width);
+        let magic = u32::from_le_bytes(buf[..4].try_into().unwrap());
+        match buf.iter().zip(&reference).position(|(a, b)| a != b) {
+            None => println!("  {:>2}-bit: PASS (first word {magic:#010x})", width.bits()),
+            Some(off) => {
+                failed += 1;
+                println!(
+                    "  {:>2}-bit: FAIL (first difference at {off:#x}: {:#04x}, byte read {:#04x})",
+                    width.bits(),
+                    buf[off],
+                    reference[off]
+                );
+            }
+        }
+    }
+    if failed == 0 {
+        println!(
+            "Access width: PASS ({} width(s) agree with byte reads)",
+            widths.len()
+        );
+    } else {
+        println!(
+            "Access width: FAIL ({failed} of {} width(s) differ)",
+            widths.len()
+        );
+    }
+    failed == 0
+}
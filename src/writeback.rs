@@ -0,0 +1,106 @@
+//! Filesystem write-back to flash.
+//!
+//! `cargo xtask run --fs-writable` marks the fs region writable, appends an
+//! erased scratch sector to it and attaches the bank read-write. The demo
+//! writes a record into that sector through [`FlashBlocksMut`], the same
+//! [`BlockDeviceMut`] path a filesystem driver would use to write back, so
+//! the change survives into the host's image file. Every boot increments the
+//! counter in the record; the rest of the filesystem image shares the erase
+//! block and is checked to be unchanged after the read-modify-write.
+
+use crate::block::{BlockDevice, BlockDeviceMut, BlockError, FlashBlocksMut, SECTOR_SIZE};
+use crate::cfi::CfiFlash;
+
+/// Start of the record in the scratch sector.
+const MARKER: &[u8; 16] = b"PFLASH WRITEBACK";
+
+/// FNV-1a hash of the erase block around `pos`, skipping the sector at
+/// `pos` itself.
+fn neighbours_hash(flash: &CfiFlash, pos: usize) -> u64 {
+    let start = pos - pos % flash.erase_size;
+    let mut sector = [0; SECTOR_SIZE];
+    let mut hash = 0xCBF2_9CE4_8422_2325u64;
+    for at in (start..start + flash.erase_size).step_by(SECTOR_SIZE) {
+        if at == pos {
+            continue;
+        }
+        flash.read(at, &mut sector);
+        for &b in &sector {
+            hash = (hash ^ u64::from(b)).wrapping_mul(0x100_0000_01B3);
+        }
+    }
+    hash
+}
+
+/// Update the record in the scratch sector of the writable fs region of the
+/// bank mapped at `base`.
+///
+/// Returns `true` if the sector was written and read back, and the rest of
+/// its erase block kept its contents.
+pub fn run(base: usize, size: usize) -> bool {
+    let (offset, len) = match crate::writable_region(base, size, Some("fs")) {
+        Ok(Some(region)) => region,
+        Ok(None) => {
+            println!("Writeback: no fs region in the image");
+            return false;
+        }
+        Err(e) => {
+            println!("Writeback: FAIL ({e}; create it with `cargo xtask mkimage --fs-writable`)");
+            return false;
+        }
+    };
+
+    let flash = match CfiFlash::probe(base) {
+        Ok(flash) => flash,
+        Err(e) => {
+            println!("Writeback: FAIL (flash probe: {e})");
+            return false;
+        }
+    };
+    let mut disk = FlashBlocksMut::new(&flash, offset, len);
+    let Some(lba) = disk.num_blocks().checked_sub(1) else {
+        println!("Writeback: FAIL (fs region holds no sector)");
+        return false;
+    };
+    let pos = offset + lba as usize * SECTOR_SIZE;
+    let before = neighbours_hash(&flash, pos);
+
+    let result = (|| -> Result<_, BlockError> {
+        let mut sector = [0; SECTOR_SIZE];
+        disk.read_block(lba, &mut sector)?;
+        let writes = if sector.starts_with(MARKER) {
+            u32::from_le_bytes(sector[16..20].try_into().unwrap()) + 1
+        } else {
+            1
+        };
+        sector.fill(0);
+        sector[..16].copy_from_slice(MARKER);
+        sector[16..20].copy_from_slice(&writes.to_le_bytes());
+        disk.write_block(lba, &sector)?;
+        disk.flush()?;
+        Ok((sector, writes))
+    })();
+    let (sector, writes) = match result {
+        Ok(written) => written,
+        Err(e) => {
+            println!("Writeback: FAIL ({e})");
+            return false;
+        }
+    };
+
+    let mut back = [0; SECTOR_SIZE];
+    flash.read(pos, &mut back);
+    if back != sector {
+        println!("Writeback: FAIL (sector {lba} reads back differently)");
+        false
+    } else if neighbours_hash(&flash, pos) != before {
+        println!("Writeback: FAIL (rest of the erase block changed)");
+        false
+    } else {
+        println!(
+            "Writeback: PASS (write {writes} to fs sector {lba}, image offset {pos:#x}, {} KiB erase block preserved)",
+            flash.erase_size / 1024
+        );
+        true
+    }
+}
@@ -0,0 +1,70 @@
+//! The erase blocks the app wrote to.
+//!
+//! Every program and erase goes through [`crate::cfi`], which marks the
+//! erase block it touches here. At the end of the run [`report`] prints
+//! them as one line:
+//!
+//! ```text
+//! Flash writes: 2 block(s) of 0x40000 bytes at 0x80000 0xc0000
+//! ```
+//!
+//! Offsets are from the start of the bank, so they are offsets into the
+//! image file too. `cargo xtask verify-run` compares the line with the
+//! blocks of the file that changed during the run.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::string::String;
+use std::vec::Vec;
+
+/// Blocks tracked, from the start of the bank: as many as the 4K sectors
+/// of the largest bank with blocks that small (x86_64's).
+const BLOCKS: usize = 1024;
+
+static WRITTEN: [AtomicU64; BLOCKS / 64] = [const { AtomicU64::new(0) }; BLOCKS / 64];
+/// Erase block size of the bank, once anything was written.
+static BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// Whether a block past the first [`BLOCKS`] was written.
+static BEYOND: AtomicBool = AtomicBool::new(false);
+
+/// Mark the erase blocks of `erase_size` bytes overlapping `[off, off +
+/// len)` as written.
+pub fn note(off: usize, len: usize, erase_size: usize) {
+    if len == 0 {
+        return;
+    }
+    BLOCK_SIZE.store(erase_size, Ordering::Relaxed);
+    for index in off / erase_size..=(off + len - 1) / erase_size {
+        match WRITTEN.get(index / 64) {
+            Some(word) => {
+                word.fetch_or(1 << (index % 64), Ordering::Relaxed);
+            }
+            None => BEYOND.store(true, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Print the blocks written so far.
+pub fn report() {
+    let size = BLOCK_SIZE.load(Ordering::Relaxed);
+    let blocks: Vec<usize> = (0..BLOCKS)
+        .filter(|&i| WRITTEN[i / 64].load(Ordering::Relaxed) & 1 << (i % 64) != 0)
+        .collect();
+    if size == 0 {
+        println!("Flash writes: none");
+        return;
+    }
+    let mut line = String::new();
+    let _ = write!(
+        line,
+        "Flash writes: {} block(s) of {size:#x} bytes at",
+        blocks.len()
+    );
+    for block in blocks {
+        let _ = write!(line, " {:#x}", block * size);
+    }
+    if BEYOND.load(Ordering::Relaxed) {
+        let _ = write!(line, " and more past {:#x}", BLOCKS * size);
+    }
+    println!("{line}");
+}
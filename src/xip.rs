@@ -0,0 +1,167 @@
+//! Execute-in-place (XIP) demo.
+//!
+//! `cargo xtask mkimage --xip` stores a small position-independent leaf
+//! function in the "xip" region of the image. This module finds it through
+//! the manifest, checks that it was built for this architecture, makes sure
+//! its pages are mapped executable as normal memory, and then calls it
+//! directly from the flash mapping.
+
+use crate::layout::{Header, Manifest, XIP_HEADER_SIZE, XIP_MAGIC, XIP_RESULT};
+use std::os::arceos::modules::axhal::mem::{VirtAddr, virt_to_phys};
+use std::os::arceos::modules::axhal::paging::MappingFlags;
+use std::os::arceos::modules::axmm::kernel_aspace;
+
+/// Granularity used when changing the mapping of the code.
+const PAGE_SIZE: usize = 0x1000;
+
+/// ELF e_machine of the code this kernel can execute.
+#[cfg(target_arch = "riscv64")]
+const EM_HOST: u16 = 243;
+#[cfg(target_arch = "aarch64")]
+const EM_HOST: u16 = 183;
+#[cfg(target_arch = "x86_64")]
+const EM_HOST: u16 = 62;
+#[cfg(target_arch = "loongarch64")]
+const EM_HOST: u16 = 258;
+
+/// Make instruction fetch observe the flash contents.
+fn sync_icache() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("fence.i")
+    };
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!("dsb ish", "ic iallu", "dsb ish", "isb")
+    };
+    #[cfg(target_arch = "loongarch64")]
+    unsafe {
+        core::arch::asm!("ibar 0")
+    };
+}
+
+/// Why instruction fetch from a mapping with `flags` would fault, if it would.
+fn fetch_blocker(flags: MappingFlags) -> Option<&'static str> {
+    if !flags.contains(MappingFlags::EXECUTE) {
+        return Some("the mapping is not executable (MMIO regions are mapped without EXECUTE)");
+    }
+    if cfg!(target_arch = "aarch64") && flags.contains(MappingFlags::DEVICE) {
+        return Some("the mapping is Device memory, which AArch64 never executes from");
+    }
+    None
+}
+
+/// Locate the xip stub in `flash` and run it in place.
+///
+/// Returns `true` if the function ran and returned the expected value.
+pub fn run_xip(flash: &[u8]) -> bool {
+    let region = Header::parse(flash).and_then(|header| {
+        let manifest = Manifest::parse(flash, &header)?;
+        Ok(manifest.regions().flatten().find(|r| r.name == "xip"))
+    });
+    let stub = match region {
+        Ok(Some(region)) => match region.data(flash) {
+            Some(stub) => stub,
+            None => {
+                println!("XIP: FAIL (xip region out of bounds)");
+                return false;
+            }
+        },
+        Ok(None) => {
+            println!(
+                "XIP: no xip region in the image (create it with `cargo xtask mkimage --xip`)"
+            );
+            return false;
+        }
+        Err(e) => {
+            println!("XIP: cannot read manifest: {e}");
+            return false;
+        }
+    };
+
+    if stub.len() < XIP_HEADER_SIZE || &stub[0..4] != XIP_MAGIC {
+        println!("XIP: FAIL (bad stub header)");
+        return false;
+    }
+    let machine = u16::from_le_bytes([stub[4], stub[5]]);
+    let code_len = u32::from_le_bytes(stub[8..12].try_into().unwrap()) as usize;
+    let expected = u32::from_le_bytes(stub[12..16].try_into().unwrap());
+    if machine != EM_HOST {
+        println!("XIP: FAIL (stub is for ELF machine {machine}, this kernel is {EM_HOST})");
+        return false;
+    }
+    let Some(code) = stub.get(XIP_HEADER_SIZE..XIP_HEADER_SIZE + code_len) else {
+        println!("XIP: FAIL (code length {code_len} exceeds the region)");
+        return false;
+    };
+    if expected != XIP_RESULT {
+        println!("XIP: note: stub returns {expected:#x}, not the usual {XIP_RESULT:#x}");
+    }
+
+    let entry = code.as_ptr() as usize;
+    let pages_start = entry & !(PAGE_SIZE - 1);
+    let pages_len = (entry + code.len()).next_multiple_of(PAGE_SIZE) - pages_start;
+    println!(
+        "XIP: {} bytes of code at VA {entry:#x} (PA {:#x})",
+        code.len(),
+        virt_to_phys(VirtAddr::from(entry)).as_usize()
+    );
+
+    let query = kernel_aspace()
+        .lock()
+        .page_table()
+        .query(VirtAddr::from(entry));
+    let old_flags = match query {
+        Ok((_, flags, size)) => {
+            println!("XIP: mapped with {flags:?} ({size:?} page)");
+            flags
+        }
+        Err(e) => {
+            println!("XIP: FAIL (code is not mapped: {e:?})");
+            return false;
+        }
+    };
+
+    // Instruction fetch would fault on the MMIO mapping; remap the code
+    // pages as normal executable memory for the duration of the call.
+    let remapped = match fetch_blocker(old_flags) {
+        Some(reason) => {
+            println!("XIP: cannot execute in place as mapped: {reason}");
+            let flags = (old_flags | MappingFlags::EXECUTE) - MappingFlags::DEVICE;
+            if let Err(e) =
+                kernel_aspace()
+                    .lock()
+                    .protect(VirtAddr::from(pages_start), pages_len, flags)
+            {
+                println!(
+                    "XIP: FAIL (cannot remap {pages_len:#x} bytes at {pages_start:#x} as {flags:?}: {e})"
+                );
+                return false;
+            }
+            println!("XIP: remapped {pages_len:#x} bytes at {pages_start:#x} as {flags:?}");
+            true
+        }
+        None => false,
+    };
+
+    sync_icache();
+    // SAFETY: the code was checked to target this architecture, follows the
+    // C calling convention, touches only the return register and is mapped
+    // executable above.
+    let func: extern "C" fn() -> u32 = unsafe { core::mem::transmute(entry) };
+    let result = func();
+
+    if remapped {
+        let _ = kernel_aspace()
+            .lock()
+            .protect(VirtAddr::from(pages_start), pages_len, old_flags);
+    }
+
+    if result == expected {
+        println!("XIP: PASS (function in flash returned {result:#x})");
+        true
+    } else {
+        println!("XIP: FAIL (function in flash returned {result:#x}, expected {expected:#x})");
+        false
+    }
+}
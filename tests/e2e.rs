@@ -0,0 +1,169 @@
+//! End-to-end tests: build the app for each architecture with the xtask
+//! binary, boot it under QEMU and check what it prints on the serial
+//! console.
+//!
+//! The tests are ignored by a plain `cargo test`; run them with `cargo e2e`
+//! (`cargo test --no-default-features --features xtask --test e2e --
+//! --include-ignored`), or `cargo e2e riscv64` for one architecture. An
+//! architecture whose QEMU binary or Rust target is not installed fails.
+
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time allowed for one build and boot.
+const TIMEOUT: Duration = Duration::from_secs(900);
+
+/// Guest features every run enables.
+const FEATURES: &str = "verify,identify";
+
+/// Lines every successful run prints.
+const EXPECTED: &[&str] = &[
+    "Got pflash magic: PFLA",
+    "Manifest verification: all",
+    "Identify: PASS",
+];
+
+/// The Rust target of `arch`, from the TARGET column of `cargo xtask list`.
+fn rust_target(arch: &str) -> String {
+    let list = Command::new(env!("CARGO_BIN_EXE_xtask"))
+        .current_dir(Path::new(env!("CARGO_MANIFEST_DIR")))
+        .arg("list")
+        .output()
+        .expect("failed to run xtask list");
+    String::from_utf8_lossy(&list.stdout)
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.first() == Some(&arch))
+        .and_then(|columns| columns.get(1).map(|target| target.to_string()))
+        .unwrap_or_else(|| panic!("{arch} is not in the output of xtask list"))
+}
+
+/// Why `arch` cannot run here, if it cannot.
+fn missing_tool(arch: &str) -> Option<String> {
+    let qemu = format!("qemu-system-{arch}");
+    let on_path = std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(&qemu).is_file()));
+    if !on_path {
+        return Some(format!("{qemu} not found in PATH"));
+    }
+    // Without rustup the build reports a missing target itself.
+    let installed = Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .output()
+        .ok()?;
+    let target = rust_target(arch);
+    (!String::from_utf8_lossy(&installed.stdout)
+        .lines()
+        .any(|line| line.trim() == target))
+    .then(|| format!("Rust target {target} not installed"))
+}
+
+/// Kill `child` and everything it started (QEMU runs as its child).
+fn kill_tree(child: &mut Child) {
+    #[cfg(unix)]
+    let _ = Command::new("kill")
+        .args(["-KILL", &format!("-{}", child.id())])
+        .status();
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Run `cargo xtask run --arch <arch>` with `extra` arguments and return
+/// whether it succeeded and everything it printed.
+fn xtask_run(arch: &str, extra: &[&str]) -> (bool, String) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_xtask"));
+    command
+        .current_dir(Path::new(env!("CARGO_MANIFEST_DIR")))
+        .args(["run", "--arch", arch])
+        .args(extra)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command.spawn().expect("failed to start xtask");
+
+    let (tx, rx) = mpsc::channel();
+    let readers: Vec<Box<dyn Read + Send>> = vec![
+        Box::new(child.stdout.take().unwrap()),
+        Box::new(child.stderr.take().unwrap()),
+    ];
+    for reader in readers {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(reader).lines().map_while(Result::ok) {
+                let _ = tx.send(line);
+            }
+        });
+    }
+    drop(tx);
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut output = String::new();
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) => {
+                output.push_str(&line);
+                output.push('\n');
+            }
+            // Both pipes closed: xtask and QEMU have exited.
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                kill_tree(&mut child);
+                panic!(
+                    "{arch}: no exit within {} s; output so far:\n{output}",
+                    TIMEOUT.as_secs()
+                );
+            }
+        }
+    }
+    let status = child.wait().expect("failed to wait for xtask");
+    (status.success(), output)
+}
+
+/// Build and boot `arch` and check the console output.
+fn boot(arch: &str) {
+    if let Some(reason) = missing_tool(arch) {
+        panic!("{arch}: cannot boot here: {reason}");
+    }
+    let (success, output) = xtask_run(arch, &["--features", FEATURES]);
+    assert!(success, "{arch}: cargo xtask run failed:\n{output}");
+    for expected in EXPECTED {
+        assert!(
+            output.contains(expected),
+            "{arch}: missing {expected:?} in the output:\n{output}"
+        );
+    }
+    assert!(
+        !output.lines().any(|line| line.contains("FAIL")),
+        "{arch}: a check failed:\n{output}"
+    );
+}
+
+#[test]
+#[ignore = "boots QEMU; run with `cargo e2e`"]
+fn riscv64() {
+    boot("riscv64");
+}
+
+#[test]
+#[ignore = "boots QEMU; run with `cargo e2e`"]
+fn aarch64() {
+    boot("aarch64");
+}
+
+#[test]
+#[ignore = "boots QEMU; run with `cargo e2e`"]
+fn x86_64() {
+    boot("x86_64");
+}
+
+#[test]
+#[ignore = "boots QEMU; run with `cargo e2e`"]
+fn loongarch64() {
+    boot("loongarch64");
+}
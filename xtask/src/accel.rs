@@ -0,0 +1,90 @@
+//! Hardware acceleration for QEMU (`run --accel`).
+//!
+//! QEMU emulates the guest CPU with TCG unless told to use the host's
+//! hypervisor: KVM on Linux, HVF on macOS. Either only runs a guest of the
+//! host's own architecture, and KVM also needs a `/dev/kvm` the user may
+//! open. QEMU itself reports a missing or unusable accelerator tersely, and
+//! only after the build. [`unavailable`] checks the same things up front, so
+//! `xtask run` can say why and fall back to TCG, or stop with
+//! `--strict-accel`.
+
+use crate::Arch;
+use clap::ValueEnum;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::process::Command;
+
+/// Accelerator passed to QEMU's `-accel`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Accel {
+    #[default]
+    Tcg,
+    Kvm,
+    Hvf,
+}
+
+impl Accel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Tcg => "tcg",
+            Self::Kvm => "kvm",
+            Self::Hvf => "hvf",
+        }
+    }
+
+    /// Whether the guest runs on the host CPU, so it needs `-cpu host`
+    /// rather than a model only TCG emulates.
+    pub fn is_hardware(self) -> bool {
+        self != Self::Tcg
+    }
+}
+
+/// Why `accel` cannot run a guest of `arch` on this host, or `None` if it
+/// can as far as can be told without starting QEMU. `secure` is the
+/// aarch64 secure world (`--secure`), which only TCG emulates.
+pub fn unavailable(accel: Accel, arch: Arch, secure: bool) -> Option<String> {
+    let (os, host) = (std::env::consts::OS, std::env::consts::ARCH);
+    match accel {
+        Accel::Tcg => return None,
+        Accel::Kvm if os != "linux" => return Some(format!("KVM needs a Linux host, not {os}")),
+        Accel::Hvf if os != "macos" => return Some(format!("HVF needs a macOS host, not {os}")),
+        _ => {}
+    }
+    if host != arch.name() {
+        return Some(format!(
+            "{} only runs guests of the host's architecture ({host}), not {arch}",
+            accel.name().to_uppercase()
+        ));
+    }
+    if secure {
+        return Some(format!(
+            "{} does not emulate the secure world (--secure)",
+            accel.name().to_uppercase()
+        ));
+    }
+    if accel == Accel::Kvm {
+        // QEMU opens it read-write.
+        if let Err(e) = OpenOptions::new().read(true).write(true).open("/dev/kvm") {
+            return Some(match e.kind() {
+                ErrorKind::NotFound => "there is no /dev/kvm: KVM is not loaded, or (in a VM) \
+                                        nested virtualization is off"
+                    .into(),
+                ErrorKind::PermissionDenied => "you may not open /dev/kvm; add yourself to \
+                                                the kvm group (`sudo usermod -aG kvm $USER`, \
+                                                then log in again)"
+                    .into(),
+                _ => format!("cannot open /dev/kvm: {e}"),
+            });
+        }
+    }
+    if accel == Accel::Hvf {
+        let support = Command::new("sysctl")
+            .args(["-n", "kern.hv_support"])
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string());
+        if support.is_ok_and(|support| support == "0") {
+            return Some("this Mac does not support Hypervisor.framework".into());
+        }
+    }
+    None
+}
@@ -0,0 +1,424 @@
+//! Repeated benchmark runs with statistics (`cargo xtask bench`).
+//!
+//! A single run of the write-combining benchmark says little: the host is
+//! busy with other work, QEMU's TCG translates code on first use, and the
+//! first run of a build pays for the build. `bench` runs `xtask run` with
+//! the `write-combining` and `json-report` features `--warmup` times,
+//! throwing the results away, then `--runs` times. From each measured run
+//! it keeps:
+//!
+//! - `host_s`, the seconds the run took as seen from the host (build check,
+//!   QEMU start-up and the guest's whole run);
+//! - every number in the `bench` object of the guest's JSON report (see
+//!   [`crate::json`]), e.g. `bench.device_mibs`.
+//!
+//! For each figure it prints the mean, the sample standard deviation, the
+//! coefficient of variation, the range, and the runs whose value lies
+//! outside Tukey's fences (more than 1.5 interquartile ranges beyond the
+//! quartiles). Outliers are reported, not dropped; a figure with a large
+//! coefficient of variation needs more runs before it is compared across
+//! architectures. The serial log of every run is kept in
+//! `target/bench/<arch>-<n>.log`.
+//!
+//! With `--export <FILE>` the statistics are also appended to a file that
+//! collects them across invocations, so the figures can be tracked over
+//! ArceOS and QEMU upgrades. Each invocation adds, besides the figures, the
+//! date (UTC), the git revision of the tree (`+dirty` with uncommitted
+//! changes), the architecture, the QEMU version and the features. The
+//! format follows the extension:
+//!
+//! - `.csv`: one row per figure, under a header written when the file is
+//!   new;
+//! - `.json`: one JSON object per line (JSON Lines), with the figures in a
+//!   `metrics` object keyed by name.
+
+use crate::{Arch, RUN_GRACE, json, json_string, qmp, run_self, runs};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Features every benchmark run needs.
+const FEATURES: &str = "write-combining,json-report";
+
+/// Columns of an exported CSV file.
+const CSV_HEADER: &str =
+    "date,git_rev,arch,qemu,features,runs,warmup,figure,n,mean,stddev,min,max,outliers";
+
+/// Format of an `--export` file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Export {
+    Csv,
+    Json,
+}
+
+impl Export {
+    /// The format of `path`, from its extension.
+    pub fn of(path: &Path) -> Result<Self, String> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => Ok(Export::Csv),
+            Some("json") => Ok(Export::Json),
+            _ => Err(format!(
+                "cannot tell the format of {}; use a .csv or .json file",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Options of `cargo xtask bench`.
+pub struct Options<'a> {
+    pub arch: Arch,
+    /// Further kernel features, besides [`FEATURES`].
+    pub features: &'a str,
+    pub runs: usize,
+    pub warmup: usize,
+    /// Further `xtask run` options.
+    pub run_args: &'a [String],
+    pub timeout: Duration,
+    /// File to append the statistics to, and its format.
+    pub export: Option<(&'a Path, Export)>,
+}
+
+/// Summary statistics of one figure over the measured runs.
+struct Stats {
+    n: usize,
+    mean: f64,
+    /// Sample standard deviation (divided by n - 1); 0 for a single run.
+    stddev: f64,
+    min: f64,
+    max: f64,
+    /// Indices of the values outside Tukey's fences.
+    outliers: Vec<usize>,
+}
+
+/// The `q` quantile of the sorted `values`, interpolating linearly between
+/// neighbours.
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let pos = q * (sorted.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (pos - lo as f64)
+}
+
+/// Statistics of `values`, which must not be empty.
+fn stats(values: &[f64]) -> Stats {
+    let n = values.len();
+    let mean = values.iter().sum::<f64>() / n as f64;
+    let stddev = match n {
+        1 => 0.0,
+        _ => (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64).sqrt(),
+    };
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let (q1, q3) = (quantile(&sorted, 0.25), quantile(&sorted, 0.75));
+    let fence = 1.5 * (q3 - q1);
+    let outliers = values
+        .iter()
+        .enumerate()
+        .filter(|&(_, &v)| v < q1 - fence || v > q3 + fence)
+        .map(|(i, _)| i)
+        .collect();
+    Stats {
+        n,
+        mean,
+        stddev,
+        min: sorted[0],
+        max: sorted[n - 1],
+        outliers,
+    }
+}
+
+/// What the exported rows record about the tree and the tools.
+struct Context {
+    date: String,
+    git_rev: String,
+    qemu: String,
+}
+
+impl Context {
+    fn new(root: &Path, arch: Arch) -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let output = |program: &str, args: &[&str]| {
+            Command::new(program)
+                .args(args)
+                .current_dir(root)
+                .output()
+                .ok()
+                .filter(|out| out.status.success())
+                .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        };
+        let git_rev = match output("git", &["rev-parse", "--short", "HEAD"]) {
+            Some(rev) => match output("git", &["status", "--porcelain"]) {
+                Some(changes) if !changes.is_empty() => format!("{rev}+dirty"),
+                _ => rev,
+            },
+            None => "unknown".into(),
+        };
+        let qemu = output(&format!("qemu-system-{arch}"), &["--version"])
+            .and_then(|text| {
+                let line = text.lines().next()?;
+                Some(
+                    line.trim_start_matches("QEMU emulator version ")
+                        .to_string(),
+                )
+            })
+            .unwrap_or_else(|| "unknown".into());
+        Context {
+            date: runs::timestamp(secs),
+            git_rev,
+            qemu,
+        }
+    }
+}
+
+/// `field` quoted for CSV if it needs to be.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// A JSON number; non-finite values, which JSON has none for, as `null`.
+fn json_number(value: f64) -> String {
+    match value.is_finite() {
+        true => value.to_string(),
+        false => "null".into(),
+    }
+}
+
+/// Append the statistics of `table` to the file at `path`.
+fn export(
+    path: &Path,
+    format: Export,
+    context: &Context,
+    opts: &Options,
+    features: &str,
+    table: &[(String, Vec<f64>, Stats)],
+) -> std::io::Result<()> {
+    let new = std::fs::metadata(path).map_or(true, |meta| meta.len() == 0);
+    let mut text = String::new();
+    match format {
+        Export::Csv => {
+            if new {
+                text.push_str(CSV_HEADER);
+                text.push('\n');
+            }
+            for (name, _, s) in table {
+                let outliers: Vec<String> =
+                    s.outliers.iter().map(|i| (i + 1).to_string()).collect();
+                let fields = [
+                    context.date.clone(),
+                    context.git_rev.clone(),
+                    opts.arch.to_string(),
+                    context.qemu.clone(),
+                    features.to_string(),
+                    opts.runs.to_string(),
+                    opts.warmup.to_string(),
+                    name.clone(),
+                    s.n.to_string(),
+                    s.mean.to_string(),
+                    s.stddev.to_string(),
+                    s.min.to_string(),
+                    s.max.to_string(),
+                    outliers.join(" "),
+                ];
+                let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+                text.push_str(&row.join(","));
+                text.push('\n');
+            }
+        }
+        Export::Json => {
+            let _ = write!(
+                text,
+                "{{\"date\":{},\"git_rev\":{},\"arch\":{},\"qemu\":{},\"features\":{},\
+                 \"runs\":{},\"warmup\":{},\"metrics\":{{",
+                json_string(&context.date),
+                json_string(&context.git_rev),
+                json_string(opts.arch.name()),
+                json_string(&context.qemu),
+                json_string(features),
+                opts.runs,
+                opts.warmup
+            );
+            for (i, (name, _, s)) in table.iter().enumerate() {
+                let outliers: Vec<String> =
+                    s.outliers.iter().map(|i| (i + 1).to_string()).collect();
+                let _ = write!(
+                    text,
+                    "{}{}:{{\"n\":{},\"mean\":{},\"stddev\":{},\"min\":{},\"max\":{},\
+                     \"outliers\":[{}]}}",
+                    if i == 0 { "" } else { "," },
+                    json_string(name),
+                    s.n,
+                    json_number(s.mean),
+                    json_number(s.stddev),
+                    json_number(s.min),
+                    json_number(s.max),
+                    outliers.join(",")
+                );
+            }
+            text.push_str("}}\n");
+        }
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(text.as_bytes())
+}
+
+/// The numbers in `value`, with their dotted paths under `prefix`.
+fn numbers(prefix: &str, value: &json::Value, out: &mut Vec<(String, f64)>) {
+    match value {
+        json::Value::Number(n) => out.push((prefix.to_string(), *n)),
+        json::Value::Object(fields) => {
+            for (key, field) in fields {
+                numbers(&format!("{prefix}.{key}"), field, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The figures of one finished run: the host time and the guest's
+/// benchmark numbers.
+fn figures(output: &str, host: Duration) -> Result<Vec<(String, f64)>, String> {
+    let report = json::report(output)?.ok_or("no JSON report in the output")?;
+    let json::Value::Object(fields) = &report else {
+        return Err("the JSON report is not an object".into());
+    };
+    let bench = match fields.iter().find(|(key, _)| key == "bench") {
+        Some((_, bench @ json::Value::Object(_))) => bench,
+        _ => return Err("the JSON report has no benchmark figures".into()),
+    };
+    let mut out = vec![("host_s".to_string(), host.as_secs_f64())];
+    numbers("bench", bench, &mut out);
+    Ok(out)
+}
+
+/// Run the benchmark `opts.warmup + opts.runs` times and print statistics
+/// of the measured runs. Exits with status 1 if a run fails.
+pub fn run(root: &Path, opts: &Options) {
+    let dir = root.join("target").join("bench");
+    std::fs::create_dir_all(&dir).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create {}: {e}", dir.display());
+        process::exit(1);
+    });
+    let features = match opts.features {
+        "" => FEATURES.to_string(),
+        more => format!("{FEATURES},{more}"),
+    };
+    let secs = opts.timeout.as_secs().to_string();
+    let mut args = vec![
+        "run",
+        "--arch",
+        opts.arch.name(),
+        "--timeout",
+        &secs,
+        "--features",
+        &features,
+    ];
+    args.extend(opts.run_args.iter().map(String::as_str));
+
+    // Names in the order of the first run, and each figure's values.
+    let mut table: Vec<(String, Vec<f64>)> = Vec::new();
+    for n in 0..opts.warmup + opts.runs {
+        let warmup = n < opts.warmup;
+        let label = match warmup {
+            true => format!("warm-up {}/{}", n + 1, opts.warmup),
+            false => format!("run {}/{}", n - opts.warmup + 1, opts.runs),
+        };
+        let started = Instant::now();
+        let run = run_self(&args, opts.timeout + RUN_GRACE, false);
+        let host = started.elapsed();
+        let log = dir.join(format!("{}-{n}.log", opts.arch));
+        if let Err(e) = std::fs::write(&log, &run.stdout) {
+            eprintln!("Warning: failed to write {}: {e}", log.display());
+        }
+        let problem = match run.status {
+            None => Some(format!("no exit within {} s", opts.timeout.as_secs())),
+            Some(status) if status.code() == Some(qmp::TIMEOUT_EXIT) => {
+                Some(format!("timed out after {} s", opts.timeout.as_secs()))
+            }
+            Some(status) if !status.success() => Some(format!("run failed ({status})")),
+            Some(_) => None,
+        };
+        let figures = match problem {
+            Some(problem) => Err(problem),
+            None => figures(&run.stdout, host),
+        };
+        let figures = match figures {
+            Ok(figures) => figures,
+            Err(e) => {
+                eprintln!("Error: {label}: {e} (log: {})", log.display());
+                process::exit(1);
+            }
+        };
+        println!("{label}: {:.1} s", host.as_secs_f64());
+        if warmup {
+            continue;
+        }
+        for (name, value) in figures {
+            match table.iter_mut().find(|(known, _)| *known == name) {
+                Some((_, values)) => values.push(value),
+                None => table.push((name, vec![value])),
+            }
+        }
+    }
+
+    println!(
+        "Benchmark on {} ({} run(s) after {} warm-up run(s)):",
+        opts.arch, opts.runs, opts.warmup
+    );
+    println!(
+        "  {:<22} {:>3} {:>12} {:>12} {:>7} {:>12} {:>12}  outliers",
+        "figure", "n", "mean", "stddev", "cv", "min", "max"
+    );
+    let table: Vec<(String, Vec<f64>, Stats)> = table
+        .into_iter()
+        .map(|(name, values)| {
+            let s = stats(&values);
+            (name, values, s)
+        })
+        .collect();
+    for (name, values, s) in &table {
+        let cv = if s.mean == 0.0 {
+            "-".to_string()
+        } else {
+            format!("{:.1}%", s.stddev / s.mean.abs() * 100.0)
+        };
+        let outliers = match s.outliers.as_slice() {
+            [] => "none".to_string(),
+            runs => runs
+                .iter()
+                .map(|&i| format!("run {} ({})", i + 1, values[i]))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        println!(
+            "  {name:<22} {:>3} {:>12.3} {:>12.3} {cv:>7} {:>12.3} {:>12.3}  {outliers}",
+            s.n, s.mean, s.stddev, s.min, s.max
+        );
+    }
+    println!("Logs in {}", dir.display());
+
+    if let Some((path, format)) = opts.export {
+        let context = Context::new(root, opts.arch);
+        if let Err(e) = export(path, format, &context, opts, &features, &table) {
+            eprintln!("Error: failed to append to {}: {e}", path.display());
+            process::exit(1);
+        }
+        println!(
+            "Appended {} figure(s) to {} (git {}, QEMU {})",
+            table.len(),
+            path.display(),
+            context.git_rev,
+            context.qemu
+        );
+    }
+}
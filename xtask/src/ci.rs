@@ -0,0 +1,399 @@
+//! The whole verification pipeline in one command (`cargo xtask ci`).
+//!
+//! Four stages, each made of one check per architecture:
+//!
+//! 1. doctor: QEMU and the Rust target are installed (plus, once, cargo and
+//!    rust-objcopy);
+//! 2. build: `xtask build` succeeds;
+//! 3. size: `xtask size --check` finds the kernel within its budget in
+//!    `xtask.toml`;
+//! 4. run: `xtask run` finishes within the timeout, prints the pflash magic
+//!    and every `--expect` line, and no line containing "FAIL".
+//!
+//! The build stage is skipped for an architecture whose doctor check
+//! failed, and the size and run stages for one that did not build; a
+//! kernel over its size budget is still run.
+//! The results go to `junit.xml` (for CI test report viewers) and
+//! `report.json` in the report directory, and the command exits non-zero if
+//! any check failed.
+
+use crate::{Arch, Finished, arch_info, json_string, qemu_too_old, run_self};
+use std::fmt::Write as _;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+/// Line every successful run prints.
+const MAGIC_LINE: &str = "Got pflash magic: PFLA";
+
+/// Output kept in the report for a failed check.
+const OUTPUT_TAIL: usize = 8 * 1024;
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+/// One check of the pipeline.
+struct Check {
+    stage: &'static str,
+    name: String,
+    outcome: Outcome,
+    time: Duration,
+    /// Console output, for failed checks.
+    output: String,
+}
+
+/// Options of `cargo xtask ci`.
+pub struct Options<'a> {
+    pub archs: Vec<Arch>,
+    pub features: &'a str,
+    pub expect: &'a [String],
+    pub timeout: Duration,
+}
+
+/// Whether `program` can be started, i.e. is installed and on `PATH`.
+fn runs(program: &str, arg: &str) -> bool {
+    Command::new(program)
+        .arg(arg)
+        .output()
+        .is_ok_and(|out| out.status.success())
+}
+
+/// First line `program --version` prints.
+fn version(program: &str) -> Option<String> {
+    let out = Command::new(program).arg("--version").output().ok()?;
+    let text = String::from_utf8_lossy(&out.stdout);
+    out.status
+        .success()
+        .then(|| text.lines().next().unwrap_or_default().trim().to_string())
+}
+
+/// Rust targets rustup reports as installed, or `None` without rustup.
+fn installed_targets() -> Option<Vec<String>> {
+    let out = Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .output()
+        .ok()?;
+    out.status.success().then(|| {
+        String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(|line| line.trim().to_string())
+            .collect()
+    })
+}
+
+/// The last `OUTPUT_TAIL` bytes of `text`.
+fn tail(text: &str) -> &str {
+    let mut start = text.len().saturating_sub(OUTPUT_TAIL);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+/// Turn a finished child into an outcome, with `check` run on its output
+/// if it exited successfully.
+fn outcome(
+    finished: &Finished,
+    timeout: Duration,
+    check: impl FnOnce(&str) -> Option<String>,
+) -> Outcome {
+    match finished.status {
+        None => Outcome::Fail(format!("no exit within {} s", timeout.as_secs())),
+        Some(status) if !status.success() => Outcome::Fail(format!("exited with {status}")),
+        Some(_) => check(&finished.stdout).map_or(Outcome::Pass, Outcome::Fail),
+    }
+}
+
+/// Problems found by the run-stage assertions on `output`.
+fn check_output(output: &str, expect: &[String]) -> Option<String> {
+    let missing: Vec<&str> = std::iter::once(MAGIC_LINE)
+        .chain(expect.iter().map(String::as_str))
+        .filter(|line| !output.contains(line))
+        .collect();
+    if !missing.is_empty() {
+        return Some(format!("missing {missing:?}"));
+    }
+    output
+        .lines()
+        .find(|line| line.contains("FAIL"))
+        .map(|line| format!("a check failed: {}", line.trim()))
+}
+
+/// Run the pipeline and return every check.
+fn pipeline(opts: &Options) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut record = |stage, name: &str, outcome: Outcome, time, output: String| {
+        let label = match &outcome {
+            Outcome::Pass => "ok".to_string(),
+            Outcome::Fail(why) => format!("FAILED ({why})"),
+            Outcome::Skip(why) => format!("skipped ({why})"),
+        };
+        println!("ci: {stage} {name}: {label}");
+        let failed = matches!(outcome, Outcome::Fail(_));
+        checks.push(Check {
+            stage,
+            name: name.into(),
+            outcome,
+            time,
+            output: if failed {
+                tail(&output).into()
+            } else {
+                String::new()
+            },
+        });
+        failed
+    };
+
+    // Doctor.
+    let mut host_ok = true;
+    for (tool, arg, hint) in [
+        ("cargo", "--version", "install Rust with rustup"),
+        ("rust-objcopy", "--version", "cargo install cargo-binutils"),
+    ] {
+        let outcome = if runs(tool, arg) {
+            Outcome::Pass
+        } else {
+            Outcome::Fail(format!("{tool} not found ({hint})"))
+        };
+        host_ok &= !record("doctor", tool, outcome, Duration::ZERO, String::new());
+    }
+    let targets = installed_targets();
+    let mut ready = Vec::new();
+    for &arch in &opts.archs {
+        let target = arch_info(arch).target;
+        let qemu = format!("qemu-system-{arch}");
+        let outcome = match version(&qemu) {
+            None => Outcome::Fail(format!("{qemu} not found in PATH")),
+            // Without rustup the build reports a missing target itself.
+            Some(_)
+                if targets
+                    .as_ref()
+                    .is_some_and(|t| !t.iter().any(|t| t == target)) =>
+            {
+                Outcome::Fail(format!("Rust target {target} not installed"))
+            }
+            Some(version) => {
+                println!("ci: {arch}: {version}");
+                match qemu_too_old(arch) {
+                    Some(e) => Outcome::Fail(e),
+                    None => Outcome::Pass,
+                }
+            }
+        };
+        if !record(
+            "doctor",
+            arch.name(),
+            outcome,
+            Duration::ZERO,
+            String::new(),
+        ) && host_ok
+        {
+            ready.push(arch);
+        }
+    }
+
+    // Build, then run.
+    let features = opts.features;
+    let mut built = Vec::new();
+    for &arch in &opts.archs {
+        if !ready.contains(&arch) {
+            record(
+                "build",
+                arch.name(),
+                Outcome::Skip("doctor failed".into()),
+                Duration::ZERO,
+                String::new(),
+            );
+            continue;
+        }
+        let start = Instant::now();
+        let mut args = vec!["build", "--arch", arch.name()];
+        if !features.is_empty() {
+            args.extend(["--features", features]);
+        }
+        let finished = run_self(&args, opts.timeout, true);
+        let outcome = outcome(&finished, opts.timeout, |_| None);
+        let output = finished.stdout + &finished.stderr;
+        if !record("build", arch.name(), outcome, start.elapsed(), output) {
+            built.push(arch);
+        }
+    }
+    for &arch in &opts.archs {
+        if !built.contains(&arch) {
+            record(
+                "size",
+                arch.name(),
+                Outcome::Skip("not built".into()),
+                Duration::ZERO,
+                String::new(),
+            );
+            continue;
+        }
+        let start = Instant::now();
+        let mut args = vec!["size", "--arch", arch.name(), "--check"];
+        if !features.is_empty() {
+            args.extend(["--features", features]);
+        }
+        let finished = run_self(&args, opts.timeout, true);
+        let outcome = outcome(&finished, opts.timeout, |_| None);
+        let output = finished.stdout + &finished.stderr;
+        record("size", arch.name(), outcome, start.elapsed(), output);
+    }
+    for &arch in &opts.archs {
+        if !built.contains(&arch) {
+            record(
+                "run",
+                arch.name(),
+                Outcome::Skip("not built".into()),
+                Duration::ZERO,
+                String::new(),
+            );
+            continue;
+        }
+        let start = Instant::now();
+        let mut args = vec!["run", "--arch", arch.name()];
+        if !features.is_empty() {
+            args.extend(["--features", features]);
+        }
+        let finished = run_self(&args, opts.timeout, true);
+        let outcome = outcome(&finished, opts.timeout, |out| {
+            check_output(out, opts.expect)
+        });
+        let output = finished.stdout + &finished.stderr;
+        record("run", arch.name(), outcome, start.elapsed(), output);
+    }
+    checks
+}
+
+/// Escape text for XML content and attribute values.
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            // Not allowed in XML 1.0, even escaped.
+            c if (c as u32) < 0x20 => out.push('?'),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn junit(checks: &[Check]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"xtask ci\">\n",
+    );
+    for stage in ["doctor", "build", "size", "run"] {
+        let cases: Vec<&Check> = checks.iter().filter(|c| c.stage == stage).collect();
+        let count = |f: fn(&Outcome) -> bool| cases.iter().filter(|c| f(&c.outcome)).count();
+        let time: Duration = cases.iter().map(|c| c.time).sum();
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{stage}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+            cases.len(),
+            count(|o| matches!(o, Outcome::Fail(_))),
+            count(|o| matches!(o, Outcome::Skip(_))),
+            time.as_secs_f64()
+        );
+        for case in cases {
+            let _ = write!(
+                xml,
+                "    <testcase classname=\"ci.{stage}\" name=\"{}\" time=\"{:.3}\"",
+                xml_escape(&case.name),
+                case.time.as_secs_f64()
+            );
+            match &case.outcome {
+                Outcome::Pass => xml.push_str("/>\n"),
+                Outcome::Fail(why) => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                        xml_escape(why),
+                        xml_escape(&case.output)
+                    );
+                }
+                Outcome::Skip(why) => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <skipped message=\"{}\"/>\n    </testcase>",
+                        xml_escape(why)
+                    );
+                }
+            }
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn json(checks: &[Check], opts: &Options) -> String {
+    let count = |f: fn(&Outcome) -> bool| checks.iter().filter(|c| f(&c.outcome)).count();
+    let entries: Vec<String> = checks
+        .iter()
+        .map(|c| {
+            let (status, message) = match &c.outcome {
+                Outcome::Pass => ("pass", ""),
+                Outcome::Fail(why) => ("fail", why.as_str()),
+                Outcome::Skip(why) => ("skip", why.as_str()),
+            };
+            format!(
+                "{{\"stage\":{},\"name\":{},\"status\":\"{status}\",\"seconds\":{:.3},\"message\":{},\"output\":{}}}",
+                json_string(c.stage),
+                json_string(&c.name),
+                c.time.as_secs_f64(),
+                json_string(message),
+                json_string(&c.output)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"features\":{},\"passed\":{},\"failed\":{},\"skipped\":{},\"checks\":[{}]}}\n",
+        json_string(opts.features),
+        count(|o| matches!(o, Outcome::Pass)),
+        count(|o| matches!(o, Outcome::Fail(_))),
+        count(|o| matches!(o, Outcome::Skip(_))),
+        entries.join(",")
+    )
+}
+
+/// Run the pipeline and write `junit.xml` and `report.json` to
+/// `report_dir`. Exits with status 1 if any check failed.
+pub fn run(opts: &Options, report_dir: &Path) {
+    let checks = pipeline(opts);
+    let write = |name: &str, contents: String| {
+        let path = report_dir.join(name);
+        std::fs::create_dir_all(report_dir)
+            .and_then(|()| std::fs::write(&path, contents))
+            .unwrap_or_else(|e| {
+                eprintln!("Error: failed to write {}: {}", path.display(), e);
+                process::exit(1);
+            });
+        path
+    };
+    let junit = write("junit.xml", junit(&checks));
+    let json = write("report.json", json(&checks, opts));
+
+    let failed = checks
+        .iter()
+        .filter(|c| matches!(c.outcome, Outcome::Fail(_)))
+        .count();
+    println!(
+        "ci: {} check(s), {failed} failed; reports in {} and {}",
+        checks.len(),
+        junit.display(),
+        json.display()
+    );
+    if failed > 0 {
+        eprintln!("Error: {failed} CI check(s) failed");
+        process::exit(1);
+    }
+}
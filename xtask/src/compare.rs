@@ -0,0 +1,184 @@
+//! Cross-architecture output comparison (`cargo xtask compare`).
+//!
+//! The flash logic of the app does not depend on the architecture, so a run
+//! of the same scenario should print the same on each one. `compare` runs
+//! `xtask run` with the same options on every selected architecture,
+//! normalizes the output as for snapshots (see [`crate::snapshot`]), and
+//! further:
+//!
+//! - drops axlog lines (`[T ...]`), which come from the kernel, not the
+//!   app;
+//! - replaces the architecture's own name with `<arch>`;
+//! - drops lines containing an `--ignore` string, for output that is
+//!   known to differ (e.g. the device tree listing, which x86_64 has not).
+//!
+//! The first architecture is the reference; every other one is diffed
+//! against it line by line. The normalized outputs are kept in
+//! `target/compare/<arch>.out` and the raw ones in `<arch>.log`.
+
+use crate::{Arch, RUN_GRACE, qmp, run_self, snapshot};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+/// Differing lines shown per architecture.
+const DIFF_LIMIT: usize = 40;
+
+/// Options of `cargo xtask compare`.
+pub struct Options<'a> {
+    pub archs: Vec<Arch>,
+    pub features: &'a str,
+    pub ignore: &'a [String],
+    /// Further `xtask run` options, the same for every architecture.
+    pub run_args: &'a [String],
+    pub timeout: Duration,
+}
+
+/// The output of a run on `arch`, reduced to what every architecture
+/// should print alike.
+fn normalize(output: &str, arch: Arch, ignore: &[String]) -> String {
+    let mut out = String::new();
+    for line in snapshot::normalize(output).lines() {
+        if line.starts_with("[T") || ignore.iter().any(|s| line.contains(s.as_str())) {
+            continue;
+        }
+        out.push_str(&line.replace(arch.name(), "<arch>"));
+        out.push('\n');
+    }
+    out
+}
+
+/// One line of a diff.
+enum Edit<'a> {
+    Same,
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// A line diff of `a` and `b` (longest common subsequence).
+fn diff<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<(usize, Edit<'a>)> {
+    // lcs[i][j]: length of the LCS of a[i..] and b[j..].
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut edits = Vec::new();
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            edits.push((i, Edit::Same));
+            (i, j) = (i + 1, j + 1);
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            edits.push((i, Edit::Added(b[j])));
+            j += 1;
+        } else {
+            edits.push((i, Edit::Removed(a[i])));
+            i += 1;
+        }
+    }
+    edits
+}
+
+/// Run the scenario on each architecture and diff the outputs. Exits with
+/// status 1 if a run failed or an output diverges from the reference.
+pub fn run(root: &Path, opts: &Options) {
+    let dir = root.join("target").join("compare");
+    std::fs::create_dir_all(&dir).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create {}: {e}", dir.display());
+        process::exit(1);
+    });
+    let secs = opts.timeout.as_secs().to_string();
+    let mut outputs: Vec<(Arch, String)> = Vec::new();
+    let mut failed = 0;
+    for &arch in &opts.archs {
+        println!("Running {arch} (features {})...", opts.features);
+        let mut args = vec!["run", "--arch", arch.name(), "--timeout", &secs];
+        if !opts.features.is_empty() {
+            args.extend(["--features", opts.features]);
+        }
+        args.extend(opts.run_args.iter().map(String::as_str));
+        let run = run_self(&args, opts.timeout + RUN_GRACE, false);
+        let log = dir.join(format!("{arch}.log"));
+        if let Err(e) = std::fs::write(&log, &run.stdout) {
+            eprintln!("Warning: failed to write {}: {e}", log.display());
+        }
+        let problem = match run.status {
+            None => Some(format!("no exit within {} s", opts.timeout.as_secs())),
+            Some(status) if status.code() == Some(qmp::TIMEOUT_EXIT) => {
+                Some(format!("timed out after {} s", opts.timeout.as_secs()))
+            }
+            Some(status) if !status.success() => Some(format!("run failed ({status})")),
+            Some(_) if snapshot::normalize(&run.stdout).is_empty() => Some(format!(
+                "the app printed nothing; no {:?} line",
+                snapshot::APP_START
+            )),
+            Some(_) => None,
+        };
+        if let Some(problem) = problem {
+            println!("{arch}: FAIL ({problem}; log: {})", log.display());
+            failed += 1;
+            continue;
+        }
+        let output = normalize(&run.stdout, arch, opts.ignore);
+        let out = dir.join(format!("{arch}.out"));
+        if let Err(e) = std::fs::write(&out, &output) {
+            eprintln!("Warning: failed to write {}: {e}", out.display());
+        }
+        outputs.push((arch, output));
+    }
+
+    let mut diverged = 0;
+    if let Some(((reference, expected), rest)) = outputs.split_first() {
+        let expected: Vec<&str> = expected.lines().collect();
+        for (arch, output) in rest {
+            let actual: Vec<&str> = output.lines().collect();
+            let edits = diff(&expected, &actual);
+            let changed: Vec<_> = edits
+                .iter()
+                .filter(|(_, edit)| !matches!(edit, Edit::Same))
+                .collect();
+            if changed.is_empty() {
+                println!("{arch}: same as {reference} ({} lines)", actual.len());
+                continue;
+            }
+            diverged += 1;
+            println!(
+                "{arch}: DIVERGES from {reference} ({} line(s) differ)",
+                changed.len()
+            );
+            for (line, edit) in changed.iter().take(DIFF_LIMIT) {
+                match edit {
+                    Edit::Removed(text) => println!("  {:>4} - {text}", line + 1),
+                    Edit::Added(text) => println!("  {:>4} + {text}", line + 1),
+                    Edit::Same => {}
+                }
+            }
+            if changed.len() > DIFF_LIMIT {
+                println!(
+                    "  ... {} more; see {}",
+                    changed.len() - DIFF_LIMIT,
+                    dir.join(format!("{arch}.out")).display()
+                );
+            }
+        }
+    }
+    if failed > 0 || diverged > 0 {
+        eprintln!(
+            "Error: {failed} run(s) failed, {diverged} architecture(s) diverge \
+             (outputs in {})",
+            dir.display()
+        );
+        process::exit(1);
+    }
+    println!(
+        "All {} architecture(s) print the same (outputs in {})",
+        outputs.len(),
+        dir.display()
+    );
+}
@@ -0,0 +1,229 @@
+//! Background runs (`xtask run --daemon`, `xtask status`, `xtask stop`).
+//!
+//! A daemonized run starts QEMU in its own process group with the serial port on
+//! a local TCP socket and returns once it is up. Its record in
+//! `target/daemon/<arch>.pid` holds the process ID, the serial and QMP
+//! ports and the log file, one `key=value` per line:
+//!
+//! ```text
+//! pid=12345
+//! serial=40123
+//! qmp=40124
+//! started=1760000000
+//! log=/path/to/target/daemon/aarch64.log
+//! ```
+//!
+//! `stop` asks QEMU to `quit` over QMP and kills it if that does not work
+//! within a few seconds, so a guest can always be cleaned up.
+
+use crate::{Arch, diagnose, qmp};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long `stop` waits for QEMU to exit after asking it to quit.
+const STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// What the pidfile of a daemonized run records.
+struct Record {
+    pid: u32,
+    serial: u16,
+    qmp: u16,
+    /// Seconds since the Unix epoch.
+    started: u64,
+    log: PathBuf,
+}
+
+fn daemon_dir(root: &Path) -> PathBuf {
+    root.join("target").join("daemon")
+}
+
+fn pidfile(root: &Path, arch: Arch) -> PathBuf {
+    daemon_dir(root).join(format!("{arch}.pid"))
+}
+
+fn read_record(path: &Path) -> Option<Record> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let field = |key: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+    };
+    Some(Record {
+        pid: field("pid")?.parse().ok()?,
+        serial: field("serial")?.parse().ok()?,
+        qmp: field("qmp")?.parse().ok()?,
+        started: field("started")?.parse().ok()?,
+        log: field("log")?.into(),
+    })
+}
+
+/// Whether process `pid` still exists.
+fn alive(pid: u32) -> bool {
+    Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Start `qemu` with `args` (whose serial port is on the local TCP port
+/// `serial`) in the background for `arch` and record it.
+pub fn start(root: &Path, arch: Arch, qemu: &str, args: &[String], serial: u16) {
+    let path = pidfile(root, arch);
+    if let Some(record) = read_record(&path).filter(|record| alive(record.pid)) {
+        eprintln!(
+            "Error: a daemonized {arch} run is already running (pid {}); \
+             stop it with `cargo xtask stop --arch {arch}`",
+            record.pid
+        );
+        process::exit(1);
+    }
+    let dir = daemon_dir(root);
+    std::fs::create_dir_all(&dir).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create {}: {e}", dir.display());
+        process::exit(1);
+    });
+    let Some(qmp_port) = qmp::free_port() else {
+        eprintln!("Error: cannot find a free local port for QMP");
+        process::exit(1);
+    };
+    let log = dir.join(format!("{arch}.log"));
+    let output = File::create(&log)
+        .and_then(|file| Ok((file.try_clone()?, file)))
+        .unwrap_or_else(|e| {
+            eprintln!("Error: failed to create {}: {e}", log.display());
+            process::exit(1);
+        });
+    let mut command = Command::new(qemu);
+    command
+        .args(args)
+        .args(qmp::server_args(qmp_port))
+        .stdin(Stdio::null())
+        .stdout(output.0)
+        .stderr(output.1);
+    // Its own process group, so a Ctrl-C in this terminal leaves it alone.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    println!("Running in the background: {} {}", qemu, args.join(" "));
+    let mut child = command.spawn().unwrap_or_else(|e| {
+        eprintln!("Error: {}", diagnose::spawn_error(qemu, &e));
+        process::exit(1);
+    });
+    // A QEMU that rejects its arguments exits at once.
+    std::thread::sleep(Duration::from_millis(500));
+    if let Ok(Some(status)) = child.try_wait() {
+        eprintln!(
+            "Error: QEMU exited at startup ({status}); see {}",
+            log.display()
+        );
+        if let Ok(text) = std::fs::read_to_string(&log) {
+            diagnose::report(&text);
+        }
+        process::exit(1);
+    }
+    let record = format!(
+        "pid={}\nserial={serial}\nqmp={qmp_port}\nstarted={}\nlog={}\n",
+        child.id(),
+        now(),
+        log.display()
+    );
+    std::fs::write(&path, record).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {e}", path.display());
+        process::exit(1);
+    });
+    println!(
+        "Started {arch} in the background (pid {}): serial on 127.0.0.1:{serial}, log {}",
+        child.id(),
+        log.display()
+    );
+    println!(
+        "The guest starts when a client connects to the serial port, e.g. `nc 127.0.0.1 {serial}`"
+    );
+    println!("Stop it with `cargo xtask stop --arch {arch}`");
+}
+
+/// Print every recorded daemonized run and whether it is still running.
+pub fn status(root: &Path) {
+    let runs: Vec<(Arch, Record)> = Arch::ALL
+        .into_iter()
+        .filter_map(|arch| Some((arch, read_record(&pidfile(root, arch))?)))
+        .collect();
+    if runs.is_empty() {
+        println!("No daemonized runs (start one with `cargo xtask run --daemon`)");
+        return;
+    }
+    println!(
+        "{:<12} {:>7}  {:<8} {:>6} {:>6} {:>8}  LOG",
+        "ARCH", "PID", "STATE", "SERIAL", "QMP", "UPTIME"
+    );
+    for (arch, record) in runs {
+        let state = if alive(record.pid) {
+            "running"
+        } else {
+            "exited"
+        };
+        println!(
+            "{:<12} {:>7}  {state:<8} {:>6} {:>6} {:>7}s  {}",
+            arch.name(),
+            record.pid,
+            record.serial,
+            record.qmp,
+            now().saturating_sub(record.started),
+            record.log.display()
+        );
+    }
+}
+
+/// Stop the daemonized runs of `archs` and remove their records.
+///
+/// Exits with status 1 if one of them could not be stopped.
+pub fn stop(root: &Path, archs: &[Arch]) {
+    let mut found = 0;
+    let mut failed = 0;
+    for &arch in archs {
+        let path = pidfile(root, arch);
+        let Some(record) = read_record(&path) else {
+            continue;
+        };
+        found += 1;
+        if !alive(record.pid) {
+            println!("{arch}: already exited (pid {})", record.pid);
+        } else {
+            qmp::request_quit(record.qmp);
+            let deadline = Instant::now() + STOP_GRACE;
+            while alive(record.pid) && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            let killed = alive(record.pid);
+            if killed {
+                let _ = Command::new("kill")
+                    .args(["-KILL", &record.pid.to_string()])
+                    .status();
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            if alive(record.pid) {
+                eprintln!("Error: {arch}: pid {} did not stop", record.pid);
+                failed += 1;
+                continue;
+            }
+            let how = if killed { "killed" } else { "quit over QMP" };
+            println!("{arch}: stopped pid {} ({how})", record.pid);
+        }
+        if let Err(e) = std::fs::remove_file(&path) {
+            eprintln!("Warning: failed to remove {}: {e}", path.display());
+        }
+    }
+    if found == 0 {
+        println!("No daemonized runs to stop");
+    }
+    if failed > 0 {
+        process::exit(1);
+    }
+}
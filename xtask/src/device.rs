@@ -0,0 +1,162 @@
+//! Flashing a physical board (`xtask flash-device`).
+//!
+//! Under QEMU the pflash image is a file; a board needs it written to its
+//! NOR flash. `flash-device` builds the kernel and the image as `run` does
+//! and hands both to a debug probe tool, chosen with `--probe`:
+//!
+//! - `probe-rs:<CHIP>[@<PROBE>]` writes the image with `probe-rs download`
+//!   at the bank address, then loads and starts the kernel ELF with
+//!   `probe-rs run`, which stays attached until Ctrl-C. `<PROBE>` selects
+//!   one of several probes (`VID:PID[:SERIAL]`).
+//! - `openocd:<CFG>[,<CFG>...]` runs openocd with those config files (board
+//!   or interface and target), programs the image with `flash write_image
+//!   erase`, loads the kernel ELF into RAM and resumes at its entry.
+//!
+//! The bank address and size come from `pflash-paddr` and `pflash-size` in
+//! the board config, the same keys the app is built with (see `build.rs`),
+//! so the app reads the bank where the image was written.
+
+use crate::shell_quote;
+use std::path::Path;
+use std::process::{self, Command};
+
+/// A debug probe tool and how to reach the board with it.
+#[derive(Clone, Debug)]
+pub enum Probe {
+    ProbeRs {
+        chip: String,
+        selector: Option<String>,
+    },
+    OpenOcd {
+        configs: Vec<String>,
+    },
+}
+
+/// Parse a `--probe` value: `probe-rs:<CHIP>[@<PROBE>]` or
+/// `openocd:<CFG>[,<CFG>...]`.
+pub fn parse_probe(s: &str) -> Result<Probe, String> {
+    let Some((tool, spec)) = s.split_once(':') else {
+        return Err(format!(
+            "'{s}' is not probe-rs:<CHIP>[@<PROBE>] or openocd:<CFG>[,<CFG>...]"
+        ));
+    };
+    match tool {
+        "probe-rs" => {
+            let (chip, selector) = match spec.split_once('@') {
+                Some((chip, selector)) => (chip, Some(selector.to_string())),
+                None => (spec, None),
+            };
+            if chip.is_empty() {
+                return Err("probe-rs needs a chip name, e.g. probe-rs:STM32H743ZITx".into());
+            }
+            Ok(Probe::ProbeRs {
+                chip: chip.into(),
+                selector,
+            })
+        }
+        "openocd" => {
+            let configs: Vec<String> = spec
+                .split(',')
+                .filter(|cfg| !cfg.is_empty())
+                .map(String::from)
+                .collect();
+            if configs.is_empty() {
+                return Err("openocd needs a config file, e.g. openocd:board/my-board.cfg".into());
+            }
+            Ok(Probe::OpenOcd { configs })
+        }
+        _ => Err(format!(
+            "unknown probe tool '{tool}', expected probe-rs or openocd"
+        )),
+    }
+}
+
+/// What to write to the board.
+pub struct Target<'a> {
+    /// The kernel ELF.
+    pub kernel: &'a Path,
+    /// Physical address the kernel starts at (`kernel-base-paddr`).
+    pub entry: u64,
+    /// The pflash image.
+    pub image: &'a Path,
+    /// Physical address of the flash bank (`pflash-paddr`).
+    pub flash: u64,
+}
+
+/// The commands that write `target` to the board through `probe`.
+fn commands(probe: &Probe, target: &Target) -> Vec<Vec<String>> {
+    let kernel = target.kernel.display().to_string();
+    let image = target.image.display().to_string();
+    match probe {
+        Probe::ProbeRs { chip, selector } => {
+            let mut common = vec!["--chip".to_string(), chip.clone()];
+            if let Some(selector) = selector {
+                common.extend(["--probe".into(), selector.clone()]);
+            }
+            let download = ["probe-rs", "download"]
+                .into_iter()
+                .map(String::from)
+                .chain(common.iter().cloned())
+                .chain([
+                    "--binary-format".into(),
+                    "bin".into(),
+                    "--base-address".into(),
+                    format!("{:#x}", target.flash),
+                    image,
+                ])
+                .collect();
+            let run = ["probe-rs", "run"]
+                .into_iter()
+                .map(String::from)
+                .chain(common)
+                .chain([kernel])
+                .collect();
+            vec![download, run]
+        }
+        Probe::OpenOcd { configs } => {
+            let mut command = vec!["openocd".to_string()];
+            for cfg in configs {
+                command.extend(["-f".into(), cfg.clone()]);
+            }
+            for step in [
+                "init".to_string(),
+                "reset halt".into(),
+                format!("flash write_image erase {image} {:#x} bin", target.flash),
+                format!("verify_image {image} {:#x} bin", target.flash),
+                format!("load_image {kernel}"),
+                format!("resume {:#x}", target.entry),
+                "shutdown".into(),
+            ] {
+                command.extend(["-c".into(), step]);
+            }
+            vec![command]
+        }
+    }
+}
+
+/// Write `target` to the board through `probe`, or with `dry_run` only
+/// print the commands. Exits with status 1 if one of them fails.
+pub fn flash(probe: &Probe, target: &Target, dry_run: bool) {
+    for command in commands(probe, target) {
+        let quoted: Vec<String> = command.iter().map(|arg| shell_quote(arg)).collect();
+        if dry_run {
+            println!("{}", quoted.join(" "));
+            continue;
+        }
+        println!("Running: {}", quoted.join(" "));
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .status()
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "Error: failed to run {} ({e}); is it installed and on PATH?",
+                    command[0]
+                );
+                process::exit(1);
+            });
+        if !status.success() {
+            eprintln!("Error: {} failed ({status})", command[0]);
+            process::exit(1);
+        }
+    }
+}
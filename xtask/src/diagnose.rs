@@ -0,0 +1,114 @@
+//! Remedies for the ways QEMU commonly fails to start.
+//!
+//! QEMU reports a bad command line or a missing file on its standard error
+//! and exits with status 1, which on its own says little about what to
+//! change. `xtask run` keeps a copy of what QEMU wrote there (it still goes
+//! to the terminal as well), and when QEMU fails, [`report`] looks for
+//! known messages in it and prints a hint for each one found. A QEMU that
+//! cannot be started at all is explained by [`spawn_error`].
+
+use std::io::ErrorKind;
+
+/// A failure QEMU reports: lower-case text that must all appear in one
+/// line of its standard error, whatever its case, and what to do about it.
+struct Signature {
+    needles: &'static [&'static str],
+    hint: &'static str,
+}
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        needles: &["device requires", "block backend provides"],
+        hint: "the pflash image is not the size of the bank QEMU emulates. \
+               Rebuild it with `cargo xtask image` rather than editing or truncating it, \
+               and check that a --layout does not set another size",
+    },
+    Signature {
+        needles: &["romsize"],
+        hint: "a ROM image does not fit the ROM size it is given. With a microvm \
+               machine the pflash image is the firmware ROM, so its size must be one \
+               the machine accepts; rebuild it with `cargo xtask image`",
+    },
+    Signature {
+        needles: &["could not load pc bios"],
+        hint: "QEMU cannot load the x86_64 firmware. Install SeaBIOS (see Prerequisites \
+               in the README) or pass its path with --bios",
+    },
+    Signature {
+        needles: &["kvm", "permission denied"],
+        hint: "you may not open /dev/kvm. Add yourself to the kvm group \
+               (`sudo usermod -aG kvm $USER`, then log in again), or run with --accel tcg",
+    },
+    Signature {
+        needles: &["kvm", "no such file or directory"],
+        hint: "this host has no /dev/kvm: KVM is not loaded, or (in a VM) nested \
+               virtualization is off. Run with --accel tcg",
+    },
+    Signature {
+        needles: &["failed to get", "lock"],
+        hint: "another QEMU has the pflash image open. Stop it first; a background run \
+               is stopped with `cargo xtask stop`",
+    },
+    Signature {
+        needles: &["address already in use"],
+        hint: "a TCP port QEMU was told to listen on is taken. Pick another --port, \
+               or stop the process that holds it",
+    },
+    Signature {
+        needles: &["could not open", "no such file or directory"],
+        hint: "a file on the QEMU command line is missing. `cargo xtask build` and \
+               `cargo xtask image` create the kernel and the pflash image",
+    },
+];
+
+/// The Debian/Ubuntu package that provides `qemu`, a `qemu-system-<arch>`
+/// binary.
+fn package(qemu: &str) -> &'static str {
+    match qemu.strip_prefix("qemu-system-") {
+        Some("aarch64") => "qemu-system-arm",
+        Some("x86_64") => "qemu-system-x86",
+        _ => "qemu-system-misc",
+    }
+}
+
+/// Explain why `qemu` could not be started.
+pub fn spawn_error(qemu: &str, e: &std::io::Error) -> String {
+    if e.kind() != ErrorKind::NotFound {
+        return format!("failed to run {qemu}: {e}");
+    }
+    let mut message = format!(
+        "{qemu} is not installed or not on PATH. Install it with `sudo apt install {}` \
+         (Debian/Ubuntu) or `brew install qemu` (macOS)",
+        package(qemu)
+    );
+    if qemu.ends_with("loongarch64") {
+        message.push_str(
+            "; loongarch64 needs QEMU 9.1 or later, so an older distribution may have to \
+             build QEMU from source with --target-list=loongarch64-softmmu",
+        );
+    }
+    message
+}
+
+/// The hints for the known failures in `stderr`, in the order of
+/// [`SIGNATURES`], each once.
+pub fn hints(stderr: &str) -> Vec<&'static str> {
+    SIGNATURES
+        .iter()
+        .filter(|sig| {
+            stderr.lines().any(|line| {
+                let line = line.to_lowercase();
+                sig.needles.iter().all(|needle| line.contains(needle))
+            })
+        })
+        .map(|sig| sig.hint)
+        .collect()
+}
+
+/// Print a hint for each known failure in `stderr`, the standard error of
+/// a QEMU that failed. Nothing is printed for failures not known here.
+pub fn report(stderr: &str) {
+    for hint in hints(stderr) {
+        eprintln!("Hint: {hint}");
+    }
+}
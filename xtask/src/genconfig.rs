@@ -0,0 +1,242 @@
+//! Per-architecture axconfig files from one template (`cargo xtask
+//! gen-configs`).
+//!
+//! `configs/<ARCH>.toml` are rendered from `configs/template.toml`, which
+//! holds what every architecture shares (stack sizes, tick rate, memory
+//! size, the comments), and `configs/params.toml`, which has one table per
+//! architecture with the values that differ. A common change is made once
+//! in the template; a per-architecture one shows up in the parameter table.
+//! The flash bank placeholders are filled from readpflash-layout's `banks`,
+//! the same table the app and the image builder use.
+//!
+//! The rendered files stay checked in, since the build and `--config`
+//! read them as before; `gen-configs --check` fails if they are stale.
+
+use crate::Arch;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::path::Path;
+use std::process;
+
+/// First line of every rendered file.
+const HEADER: &str = "# Generated by `cargo xtask gen-configs` from configs/template.toml and\n\
+                      # configs/params.toml; edit those instead.\n";
+
+/// Placeholders xtask fills itself, which the parameter table must not set.
+const BUILTIN: [&str; 5] = [
+    "arch",
+    "pflash-paddr",
+    "pflash-size",
+    "pflash-unit",
+    "pflash0",
+];
+
+/// `value` in hex with `_` between groups of four digits, as the configs
+/// write addresses.
+fn hex(value: usize) -> String {
+    if value == 0 {
+        return "0".into();
+    }
+    let digits = format!("{value:x}");
+    let mut out = String::from("0x");
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 4 == 0 {
+            out.push('_');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Values of the placeholders xtask fills for `arch`.
+fn builtins(arch: Arch) -> BTreeMap<String, String> {
+    let bank = arch.bank();
+    let pflash0 = bank.pflash0.map_or_else(String::new, |paddr| {
+        format!(
+            "# Physical address of the firmware flash bank (pflash0), read instead of\n\
+             # the bank above with the `bank0` feature.\n\
+             pflash0-paddr = {} # uint",
+            hex(paddr)
+        )
+    });
+    [
+        ("arch", arch.name().to_string()),
+        ("pflash-paddr", hex(bank.paddr)),
+        ("pflash-size", hex(bank.size)),
+        ("pflash-unit", bank.unit.to_string()),
+        ("pflash0", pflash0),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect()
+}
+
+/// Parse the parameter table: architecture name to placeholder values.
+fn parse_params(text: &str) -> Result<BTreeMap<String, BTreeMap<String, String>>, String> {
+    let mut tables: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    let mut table: Option<String> = None;
+    let mut lines = text.lines().enumerate();
+    while let Some((n, line)) = lines.next() {
+        let n = n + 1;
+        let at = |e: String| format!("line {n}: {e}");
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            if Arch::ALL.iter().all(|arch| arch.name() != name) {
+                return Err(at(format!("unknown architecture [{name}]")));
+            }
+            if tables.insert(name.to_string(), BTreeMap::new()).is_some() {
+                return Err(at(format!("[{name}] appears twice")));
+            }
+            table = Some(name.to_string());
+            continue;
+        }
+        let Some(name) = &table else {
+            return Err(at("values must be in an [ARCH] table".into()));
+        };
+        let Some((key, value)) = trimmed.split_once('=') else {
+            return Err(at("expected key = value".into()));
+        };
+        let key = key.trim();
+        if BUILTIN.contains(&key) {
+            return Err(at(format!("{key} is filled by xtask, not the table")));
+        }
+        let mut value = value.trim().to_string();
+        if value == "'''" {
+            // Whole lines, up to the closing `'''`.
+            let mut block = Vec::new();
+            loop {
+                let Some((_, line)) = lines.next() else {
+                    return Err(at(format!("{key}: ''' block is not closed")));
+                };
+                if line.trim() == "'''" {
+                    break;
+                }
+                block.push(line);
+            }
+            value = block.join("\n");
+        } else {
+            // An array continues until its brackets balance.
+            let depth = |s: &str| s.matches('[').count() as isize - s.matches(']').count() as isize;
+            let mut open = depth(&value);
+            while open > 0 {
+                let Some((_, line)) = lines.next() else {
+                    return Err(at(format!("{key}: array is not closed")));
+                };
+                open += depth(line);
+                value.push('\n');
+                value.push_str(line);
+            }
+        }
+        if tables
+            .get_mut(name)
+            .unwrap()
+            .insert(key.to_string(), value)
+            .is_some()
+        {
+            return Err(at(format!("{key} is set twice in [{name}]")));
+        }
+    }
+    Ok(tables)
+}
+
+/// Render the template for one architecture with `values`. Every
+/// placeholder must have a value and every value must be used.
+fn render(template: &str, values: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut out = String::from(HEADER);
+    let mut used = BTreeSet::new();
+    for (n, line) in template.lines().enumerate() {
+        if line.starts_with("##") {
+            continue;
+        }
+        let mut rendered = String::new();
+        let mut rest = line;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start..].find("}}") else {
+                return Err(format!("template line {}: unclosed {{{{", n + 1));
+            };
+            let key = &rest[start + 2..start + len];
+            let Some(value) = values.get(key) else {
+                return Err(format!(
+                    "no value for {{{{{key}}}}} (template line {})",
+                    n + 1
+                ));
+            };
+            used.insert(key);
+            rendered.push_str(&rest[..start]);
+            rendered.push_str(value);
+            rest = &rest[start + len + 2..];
+        }
+        rendered.push_str(rest);
+        // A placeholder on a line of its own with nothing to put there.
+        if rendered.is_empty() && !line.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "{rendered}");
+    }
+    let unused: Vec<_> = values
+        .keys()
+        .filter(|key| !used.contains(key.as_str()) && !BUILTIN.contains(&key.as_str()))
+        .map(String::as_str)
+        .collect();
+    if !unused.is_empty() {
+        return Err(format!("{} not used by the template", unused.join(", ")));
+    }
+    Ok(out)
+}
+
+/// Render every `configs/<ARCH>.toml`. With `check`, only report the files
+/// that differ from their rendering and fail if there are any.
+pub fn run(root: &Path, check: bool) {
+    let dir = root.join("configs");
+    let read = |name: &str| {
+        let path = dir.join(name);
+        std::fs::read_to_string(&path).unwrap_or_else(|e| {
+            eprintln!("Error: cannot read {}: {e}", path.display());
+            process::exit(1);
+        })
+    };
+    let template = read("template.toml");
+    let mut params = parse_params(&read("params.toml")).unwrap_or_else(|e| {
+        eprintln!("Error: configs/params.toml: {e}");
+        process::exit(1);
+    });
+    let mut stale = Vec::new();
+    for arch in Arch::ALL {
+        let Some(mut values) = params.remove(arch.name()) else {
+            eprintln!("Error: configs/params.toml has no [{arch}] table");
+            process::exit(1);
+        };
+        values.extend(builtins(arch));
+        let text = render(&template, &values).unwrap_or_else(|e| {
+            eprintln!("Error: configs/{arch}.toml: {e}");
+            process::exit(1);
+        });
+        let path = dir.join(format!("{arch}.toml"));
+        if std::fs::read_to_string(&path).is_ok_and(|old| old == text) {
+            continue;
+        }
+        if check {
+            stale.push(format!("configs/{arch}.toml"));
+            continue;
+        }
+        std::fs::write(&path, text).unwrap_or_else(|e| {
+            eprintln!("Error: cannot write {}: {e}", path.display());
+            process::exit(1);
+        });
+        println!("Rendered configs/{arch}.toml");
+    }
+    if !stale.is_empty() {
+        eprintln!(
+            "Error: {} out of date with configs/template.toml and configs/params.toml; \
+             run `cargo xtask gen-configs`",
+            stale.join(", ")
+        );
+        process::exit(1);
+    }
+    if check {
+        println!("configs/*.toml are up to date");
+    }
+}
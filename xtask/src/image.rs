@@ -0,0 +1,2109 @@
+//! PFlash image construction.
+//!
+//! Image layout (header and manifest integers in the byte order recorded
+//! at header offset 0x24, little-endian by default; integers inside
+//! regions are always little-endian):
+//!
+//! ```text
+//! 0x0000  header    magic "PFLA", version, image size, manifest location,
+//!                   kernel location (if embedded); 0x80 bytes with the
+//!                   encryption fields if the payload is encrypted
+//! 0x0040  manifest  magic "MNFS", entry count, one 64-byte entry per region
+//!                   (at 0x0080 after an encrypted header)
+//! 0x1000  payload   user data (`--payload`, a PRNG stream from `--pattern`,
+//!                   or a built-in greeting)
+//! ......  fs        optional filesystem image (`--fs`, or a romfs/ext2
+//!                   image built from `--romfs`/`--ext2 <DIR>`), 4K-aligned;
+//!                   with `--fs-writable` flagged writable and followed by
+//!                   an erased 512-byte scratch sector
+//! ......  xip       optional execute-in-place test code (`--xip`), 4K-aligned
+//! ......  script    optional test script for the guest (`--flash-script`),
+//!                   4K-aligned
+//! ......  meta      optional `KEY=VALUE` lines, one per `--meta`, 4K-aligned
+//! ......  banner    optional text the guest prints at startup (`--banner`),
+//!                   4K-aligned
+//! ......  journal   optional writable area for the guest's journaling
+//!                   filesystem (`--journal`), 256K-aligned, left erased
+//! ......  replicas  optional writable area for three copies of the guest's
+//!                   boot metadata (`--replicas`), 256K-aligned, left erased
+//! ......  log       optional writable ring buffer for the guest's console
+//!                   log (`--log-ring`), 256K-aligned, left erased
+//! ......  panics    optional writable area for the guest's crash records
+//!                   (`--panic-region`), 256K-aligned, left erased
+//! ......  bench     optional writable area for the guest's benchmark
+//!                   results (`--bench-region`), 256K-aligned, left erased
+//! ......  crc       optional CRC-32 of every 512-byte sector from 0x1000 up
+//!                   to this region (`--crc`), 4K-aligned
+//! OFFSET  kernel    optional kernel image (`--kernel-in-flash OFFSET`)
+//! tail    firmware  x86_64 only: SeaBIOS, ending at the top of the bank
+//! ```
+//!
+//! Header fields:
+//!
+//! ```text
+//! 0x00  magic            [u8; 4]  "PFLA"
+//! 0x04  version          u16
+//! 0x06  flags            u16      bit 0: kernel embedded,
+//!                                 bit 1: payload is a PRNG pattern,
+//!                                 bit 2: payload is encrypted,
+//!                                 bit 3: flash geometry recorded
+//! 0x08  header_size      u32
+//! 0x0C  image_size       u32
+//! 0x10  manifest_offset  u32
+//! 0x14  region_count     u32
+//! 0x18  kernel_offset    u32      0 unless flags bit 0 is set
+//! 0x1C  kernel_len       u32
+//! 0x20  header_crc       u32      CRC-32 (IEEE) of bytes 0x00..0x20
+//! 0x24  endian           u8       byte order of the header and manifest:
+//!                                 0 little-endian, 1 big-endian
+//! 0x28  pattern_seed     u64      0 unless flags bit 1 is set
+//! 0x30  sector_size      u32      0 unless flags bit 3 is set: the
+//!                                 geometry of `--flash-geometry`
+//! 0x34  device_width     u8       bytes per chip
+//! 0x35  devices          u8       chips on the bus
+//! ```
+//!
+//! With flags bit 2 set, `header_size` is 0x80 and the header goes on:
+//!
+//! ```text
+//! 0x40  nonce   [u8; 12]  AES-GCM nonce of the payload
+//! 0x4C  reserved
+//! 0x50  tag     [u8; 16]  AES-GCM authentication tag of the payload
+//! 0x60  key_id  [u8; 8]   first 8 bytes of the SHA-256 of the key
+//! 0x68  reserved
+//! ```
+//!
+//! `--encrypt <KEYFILE>` encrypts the payload region with AES-256-GCM (no
+//! associated data); its manifest digest is that of the ciphertext, as
+//! stored. The nonce is the start of the SHA-256 of the key and the
+//! plaintext, so an image is rebuilt bit for bit from the same inputs, and
+//! a nonce only repeats for the same plaintext under the same key, where
+//! the ciphertext repeats too.
+//!
+//! The magic strings and the endian byte read the same either way, so a
+//! reader checks the magic, then picks the byte order for the rest.
+//! `--header-endian big` writes a big-endian header and manifest for
+//! cross-endian experiments; the guest parser reads both.
+//!
+//! `--pattern prng:<seed>:<len>` fills the payload with [`prng_stream`], so
+//! the guest can regenerate it from `pattern_seed` and the payload length
+//! and compare without the data being stored anywhere else.
+//!
+//! `--flash-geometry 64K:2x16` records the geometry `run` asks QEMU to
+//! emulate, so the guest's CFI driver drives the bus at that width and its
+//! `identify` feature checks what the chips report against it.
+//!
+//! The xip region holds a 16-byte stub header followed by a function for the
+//! target architecture that takes no arguments and returns [`XIP_RESULT`]:
+//!
+//! ```text
+//! 0x00  magic     [u8; 4]  "XIPC"
+//! 0x04  machine   u16      ELF e_machine of the code
+//! 0x06  reserved  u16
+//! 0x08  code_len  u32
+//! 0x0C  result    u32      value the function returns
+//! 0x10  code
+//! ```
+//!
+//! The crc region lets the guest find which sector of a region went bad,
+//! where the manifest digest only says that something in it did:
+//!
+//! ```text
+//! 0x00  magic        [u8; 4]  "CRCT"
+//! 0x04  sector_size  u32      512
+//! 0x08  start        u32      image offset of the first sector
+//! 0x0C  count        u32
+//! 0x10  crc          [u32; count]  CRC-32 (IEEE) of each sector
+//! ```
+//!
+//! Every manifest entry records the region name, offset, length, flags and
+//! the SHA-256 digest of its bytes, so the image describes (and can verify)
+//! its own contents. Regions flagged writable (bit 0) are modified by the
+//! guest, so their digest only describes the image as created. The same
+//! description is written next to the image as `<image>.manifest.json` for
+//! host-side tooling, and the measured-boot log the guest's `measure`
+//! feature should print as `<image>.measurements` (see [`crate::measure`]).
+
+use crate::layout::{Contents, LayoutRegion};
+use crate::partition::{self, PartitionTable};
+use crate::{Arch, measure};
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use clap::{Args, ValueEnum};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+pub use readpflash_layout::{
+    BENCH_MAGIC, BENCH_SLOT, CRC_HEADER_SIZE, CRC_MAGIC, CRC_SECTOR, CRYPT_FIELDS_SIZE, DEV_KEY,
+    ENDIAN_BIG, ENDIAN_LITTLE, ENDIAN_OFFSET, FLAG_ENCRYPTED, FLAG_GEOMETRY, FLAG_KERNEL,
+    FLAG_PATTERN, FlashGeometry, HEADER_SIZE, Header, KEY_LEN, KIND_OFFSET, Kind, MAGIC,
+    MANIFEST_ENTRY_SIZE, MANIFEST_MAGIC, MANIFEST_PREAMBLE, Manifest, NAME_LEN, PANIC_MAGIC,
+    PANIC_SLOT, REGION_ALIGN, REGION_WRITABLE, VERSION, XIP_HEADER_SIZE, XIP_MAGIC, XIP_RESULT,
+    catalog,
+};
+
+/// Size of the journal region: a journal and two checkpoint areas of one
+/// 256K erase block each (the largest block size of the emulated devices).
+pub const JOURNAL_LEN: usize = 3 * JOURNAL_ALIGN;
+/// Alignment of the journal region, so it starts on an erase block.
+pub const JOURNAL_ALIGN: usize = 0x4_0000;
+/// Size of the replicas region: one 256K erase block per copy.
+pub const REPLICAS_LEN: usize = 3 * JOURNAL_ALIGN;
+/// Size of the log region: a ring of two 256K erase blocks, so one keeps
+/// the older lines while the other is erased for reuse.
+pub const LOG_LEN: usize = 2 * JOURNAL_ALIGN;
+/// Size of the panics region: one 256K erase block of crash records.
+pub const PANICS_LEN: usize = JOURNAL_ALIGN;
+/// Size of the bench region: one 256K erase block of results records.
+pub const BENCH_LEN: usize = JOURNAL_ALIGN;
+/// Erased sector appended to a writable fs region for the guest to write.
+pub const SCRATCH_SECTOR: usize = 512;
+
+/// Payload used when no `--payload` file is given.
+const DEFAULT_PAYLOAD: &[u8] = b"Hello from PFlash! This payload was placed by cargo xtask.\n";
+
+/// Image contents selectable on the command line.
+#[derive(Args, Clone, Default)]
+pub struct ImageArgs {
+    /// Read image options and extra regions from this layout file (see
+    /// `xtask/src/layout.rs`); options given here take precedence
+    #[arg(long, value_name = "FILE")]
+    pub layout: Option<PathBuf>,
+    /// The `[[region]]`s of `--layout`, filled in by `layout::apply`
+    #[arg(skip)]
+    pub regions: Vec<LayoutRegion>,
+    /// File to store in the payload region (defaults to a short greeting)
+    #[arg(long, value_name = "FILE")]
+    pub payload: Option<PathBuf>,
+    /// Fill the payload with a seeded PRNG stream of this length instead,
+    /// checked by the guest's `pattern` feature, e.g. `prng:42:16M`
+    #[arg(long, value_name = "prng:SEED:LEN", value_parser = parse_pattern, conflicts_with = "payload")]
+    pub pattern: Option<Pattern>,
+    /// Type tag of the payload in the manifest (raw, text, config, dtb,
+    /// archive, executable), which the guest's `dispatch` feature parses it
+    /// by; told from the contents if not given
+    #[arg(long, value_name = "TYPE", value_parser = parse_kind)]
+    pub payload_type: Option<Kind>,
+    /// Filesystem image to store in the fs region
+    #[arg(long, value_name = "FILE")]
+    pub fs: Option<PathBuf>,
+    /// Pack this directory into a romfs image for the fs region (read by
+    /// the guest's `romfs` feature)
+    #[arg(long, value_name = "DIR", conflicts_with = "fs")]
+    pub romfs: Option<PathBuf>,
+    /// Build an ext2 image of this directory with `mke2fs -d` for the fs
+    /// region (read by the guest's `ext2` feature)
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["fs", "romfs"])]
+    pub ext2: Option<PathBuf>,
+    /// Also place the kernel image in flash at this (4K-aligned) offset,
+    /// recorded in the header, e.g. `0x100000`
+    #[arg(long, value_name = "OFFSET", value_parser = parse_offset)]
+    pub kernel_in_flash: Option<usize>,
+    /// Store position-independent test code for the guest's `xip` feature
+    #[arg(long)]
+    pub xip: bool,
+    /// Store this test script (flash shell commands with expected replies)
+    /// for the guest's `flash-script` feature to run at boot
+    #[arg(long, value_name = "FILE")]
+    pub flash_script: Option<PathBuf>,
+    /// Store a KEY=VALUE string in the meta region, printed at boot by the
+    /// guest's `meta` feature (repeatable)
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_meta)]
+    pub meta: Vec<String>,
+    /// Text for the guest's `banner` feature to print at startup: the
+    /// contents of this file if it exists, otherwise the argument itself
+    #[arg(long, value_name = "FILE|TEXT")]
+    pub banner: Option<String>,
+    /// Store the messages of this file (`ID=TEXT` lines) as a catalog in
+    /// the messages region, printed by number by the guest's `messages`
+    /// feature
+    #[arg(long, value_name = "FILE")]
+    pub messages: Option<PathBuf>,
+    /// Flag the fs region writable and append an erased scratch sector to
+    /// it for the guest's `fs-write` feature
+    #[arg(long)]
+    pub fs_writable: bool,
+    /// Reserve an erased, writable region for the guest's `journal` feature
+    #[arg(long)]
+    pub journal: bool,
+    /// Reserve erased, writable erase blocks for the three copies kept by
+    /// the guest's `replicas` feature
+    #[arg(long)]
+    pub replicas: bool,
+    /// Reserve an erased, writable ring buffer for the guest's `flash-log`
+    /// feature
+    #[arg(long)]
+    pub log_ring: bool,
+    /// Reserve an erased, writable region for the crash records of the
+    /// guest's `panic-record` feature
+    #[arg(long)]
+    pub panic_region: bool,
+    /// Reserve an erased, writable region for the results records of the
+    /// guest's `bench-record` feature
+    #[arg(long)]
+    pub bench_region: bool,
+    /// Add a table of per-sector CRC-32s for the guest's `integrity`
+    /// feature
+    #[arg(long)]
+    pub crc: bool,
+    /// Byte order of the header and manifest integers (default: little)
+    #[arg(long, value_enum, value_name = "ORDER")]
+    pub header_endian: Option<Endian>,
+    /// Record a flash geometry for `run` to give QEMU's `cfi.pflash01`:
+    /// erase block size, then the chips, e.g. `64K:2x16` for 64K blocks on
+    /// two 16-bit chips (see `parse_geometry`)
+    #[arg(long, value_name = "SECTOR:NxBITS", value_parser = parse_geometry)]
+    pub flash_geometry: Option<FlashGeometry>,
+    /// Encrypt the payload with AES-256-GCM under the key in this file (32
+    /// raw bytes or 64 hex digits), keeping the nonce and tag in the header
+    #[arg(long, value_name = "KEYFILE", conflicts_with = "pattern")]
+    pub encrypt: Option<PathBuf>,
+    /// Without `--encrypt`, seal the payload with [`DEV_KEY`]; set by `run`
+    /// for the guest's `decrypt` feature
+    #[arg(skip)]
+    pub dev_key: bool,
+    /// Start the bank with this partition table and put the image in a
+    /// data partition at 1 MiB (read by the guest's `partitions` feature)
+    #[arg(long, value_enum, value_name = "TABLE")]
+    pub partition_table: Option<PartitionTable>,
+    /// Rebuild the image even if its inputs did not change since it was
+    /// written
+    #[arg(long)]
+    pub force_image: bool,
+    /// Write the image and the files generated with it to this directory
+    /// (created if missing) instead of the project root
+    #[arg(long, value_name = "DIR")]
+    pub pflash_out: Option<PathBuf>,
+}
+
+/// Directory the images are written to: `out` (`--pflash-out`), or the
+/// project root.
+pub fn image_dir(root: &Path, out: Option<&Path>) -> PathBuf {
+    out.unwrap_or(root).to_path_buf()
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal offset.
+pub fn parse_offset(s: &str) -> Result<usize, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse(),
+    };
+    parsed.map_err(|e| format!("invalid offset '{s}': {e}"))
+}
+
+/// Check a `--meta` pair: a non-empty key without `=`, and neither side
+/// spanning lines.
+fn parse_meta(s: &str) -> Result<String, String> {
+    match s.split_once('=') {
+        Some((key, _)) if key.trim().is_empty() => Err(format!("empty key in '{s}'")),
+        Some(_) if s.contains(['\n', '\r']) => Err(format!("line break in '{s}'")),
+        Some(_) => Ok(s.to_string()),
+        None => Err(format!("expected KEY=VALUE, got '{s}'")),
+    }
+}
+
+/// Byte order of the header and manifest integers (`--header-endian`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// The [`ENDIAN_OFFSET`] byte recording this byte order.
+    fn marker(self) -> u8 {
+        match self {
+            Self::Little => ENDIAN_LITTLE,
+            Self::Big => ENDIAN_BIG,
+        }
+    }
+
+    fn u16(self, value: u16) -> [u8; 2] {
+        match self {
+            Self::Little => value.to_le_bytes(),
+            Self::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn u32(self, value: u32) -> [u8; 4] {
+        match self {
+            Self::Little => value.to_le_bytes(),
+            Self::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn u64(self, value: u64) -> [u8; 8] {
+        match self {
+            Self::Little => value.to_le_bytes(),
+            Self::Big => value.to_be_bytes(),
+        }
+    }
+}
+
+/// Parse `<SECTOR>:<N>x<BITS>`: the erase block size of the bank (which
+/// may end in `K`), then N chips of BITS bits each.
+///
+/// The bus may be 8, 16 or 32 bits wide, the widths the guest's CFI driver
+/// accesses. A block is a power of two from [`REGION_ALIGN`] to
+/// [`JOURNAL_ALIGN`], so the writable regions still start on one.
+pub fn parse_geometry(s: &str) -> Result<FlashGeometry, String> {
+    let usage = || format!("expected <SECTOR>:<N>x<BITS>, e.g. 64K:2x16, got '{s}'");
+    let (sector, chips) = s.split_once(':').ok_or_else(usage)?;
+    let (devices, bits) = chips.split_once(['x', 'X']).ok_or_else(usage)?;
+    let sector = match sector.strip_suffix(['K', 'k']) {
+        Some(kib) => parse_offset(kib)?.saturating_mul(1024),
+        None => parse_offset(sector)?,
+    };
+    let devices: u8 = devices.parse().map_err(|_| usage())?;
+    let device_width = match bits {
+        "8" => 1,
+        "16" => 2,
+        "32" => 4,
+        _ => return Err(format!("chip width must be 8, 16 or 32 bits, not '{bits}'")),
+    };
+    if ![1, 2, 4].contains(&devices) {
+        return Err(format!("the bus holds 1, 2 or 4 chips, not {devices}"));
+    }
+    let bank_width = usize::from(device_width) * usize::from(devices);
+    if bank_width > 4 {
+        return Err(format!(
+            "{devices} x{bits} chips make a {}-bit bus; the CFI driver drives at most 32 bits",
+            bank_width * 8
+        ));
+    }
+    if !sector.is_power_of_two() || !(REGION_ALIGN..=JOURNAL_ALIGN).contains(&sector) {
+        return Err(format!(
+            "the erase block size must be a power of two from {REGION_ALIGN:#x} to \
+             {JOURNAL_ALIGN:#x} bytes, not {sector:#x}"
+        ));
+    }
+    Ok(FlashGeometry {
+        sector_size: sector as u32,
+        device_width,
+        devices,
+    })
+}
+
+/// A `--pattern` payload.
+#[derive(Clone, Copy)]
+pub struct Pattern {
+    pub seed: u64,
+    pub len: usize,
+}
+
+/// Parse a region type name (`--payload-type`, `type` in layout files).
+pub fn parse_kind(s: &str) -> Result<Kind, String> {
+    Kind::from_name(s).ok_or_else(|| {
+        let names: Vec<_> = Kind::ALL.iter().map(|kind| kind.name()).collect();
+        format!("unknown region type '{s}' (expected {})", names.join(", "))
+    })
+}
+
+/// The type of a region holding `data`, told from its contents: a device
+/// tree, ELF, archive or filesystem magic, then UTF-8 text, which is a
+/// config if every line that is not blank or a `#` comment is `key=value`.
+fn detect_kind(data: &[u8]) -> Kind {
+    let at = |offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+    if at(0, &[0xD0, 0x0D, 0xFE, 0xED]) {
+        return Kind::Dtb;
+    }
+    if at(0, b"\x7FELF") {
+        return Kind::Executable;
+    }
+    let archive = at(0, b"070701")
+        || at(0, b"070702")
+        || at(257, b"ustar")
+        || at(0, b"-rom1fs-")
+        || at(0, &[0x1F, 0x8B])
+        || at(1080, &[0x53, 0xEF]);
+    if archive {
+        return Kind::Archive;
+    }
+    let Ok(text) = std::str::from_utf8(data) else {
+        return Kind::Raw;
+    };
+    if text.is_empty() || text.contains('\0') {
+        return Kind::Raw;
+    }
+    let lines: Vec<_> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let pair = |line: &&str| line.split_once('=').is_some_and(|(key, _)| !key.is_empty());
+    match !lines.is_empty() && lines.iter().all(pair) {
+        true => Kind::Config,
+        false => Kind::Text,
+    }
+}
+
+/// Parse `prng:<seed>:<len>`. The length may end in `K` or `M`.
+pub fn parse_pattern(s: &str) -> Result<Pattern, String> {
+    let mut parts = s.splitn(3, ':');
+    let (Some("prng"), Some(seed), Some(len)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("expected prng:<SEED>:<LEN>, got '{s}'"));
+    };
+    let seed = parse_offset(seed)? as u64;
+    let (len, unit) = match len.strip_suffix(['K', 'k']) {
+        Some(len) => (len, 1024),
+        None => match len.strip_suffix(['M', 'm']) {
+            Some(len) => (len, 1024 * 1024),
+            None => (len, 1),
+        },
+    };
+    let len = parse_offset(len)?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("pattern length in '{s}' is too large"))?;
+    if len == 0 {
+        return Err("the pattern length must not be zero".into());
+    }
+    Ok(Pattern { seed, len })
+}
+
+/// `len` bytes of the splitmix64 stream started at `seed`, each output word
+/// stored little-endian. The guest's `pattern.rs` generates the same bytes.
+pub fn prng_stream(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    let mut out = Vec::with_capacity(len.next_multiple_of(8));
+    while out.len() < len {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        out.extend_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+    }
+    out.truncate(len);
+    out
+}
+
+/// An image as the data placed in it over erased flash (0xFF), so a bank
+/// of any size costs only the memory of what it holds.
+struct Image {
+    size: usize,
+    /// Disjoint `(offset, bytes)` extents.
+    extents: Vec<(usize, Vec<u8>)>,
+}
+
+impl Image {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            extents: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Place `data` at `offset`, which must not overlap earlier extents.
+    fn place(&mut self, offset: usize, data: Vec<u8>) {
+        debug_assert!(offset + data.len() <= self.size);
+        self.extents.push((offset, data));
+    }
+
+    /// Fill `buf` with the image bytes starting at `offset`.
+    fn read_at(&self, offset: usize, buf: &mut [u8]) {
+        buf.fill(0xFF);
+        let end = offset + buf.len();
+        for (start, data) in &self.extents {
+            let from = offset.max(*start);
+            let to = end.min(start + data.len());
+            if from < to {
+                buf[from - offset..to - offset].copy_from_slice(&data[from - start..to - start]);
+            }
+        }
+    }
+
+    /// This image placed at `offset` in one of `size` bytes, together with
+    /// the `(offset, bytes)` extents `around` it (a partition table).
+    fn embed(self, offset: usize, size: usize, around: Vec<(usize, Vec<u8>)>) -> Self {
+        debug_assert!(offset + self.size <= size);
+        let mut extents = around;
+        extents.extend(
+            self.extents
+                .into_iter()
+                .map(|(start, data)| (offset + start, data)),
+        );
+        Self { size, extents }
+    }
+
+    /// The whole image in memory.
+    #[cfg(test)]
+    fn to_vec(&self) -> Vec<u8> {
+        let mut image = vec![0; self.size];
+        self.read_at(0, &mut image);
+        image
+    }
+}
+
+/// A named, hashed region of the image.
+struct Region {
+    name: &'static str,
+    offset: usize,
+    len: usize,
+    flags: u32,
+    kind: Kind,
+    sha256: [u8; 32],
+}
+
+/// Where SeaBIOS is looked for, in order.
+const SEABIOS_PATHS: [&str; 5] = [
+    "/usr/share/qemu/bios-256k.bin",
+    "/usr/share/seabios/bios-256k.bin",
+    "/usr/local/share/qemu/bios-256k.bin",
+    "/usr/share/qemu/bios.bin",
+    "/usr/share/seabios/bios.bin",
+];
+
+/// The SeaBIOS binary embedded in x86_64 images, if installed.
+pub fn seabios_path() -> Option<PathBuf> {
+    SEABIOS_PATHS.iter().map(PathBuf::from).find(|p| p.exists())
+}
+
+/// Find SeaBIOS binary on the system (needed for x86_64 pflash).
+fn find_seabios() -> PathBuf {
+    if let Some(path) = seabios_path() {
+        return path;
+    }
+    eprintln!("Error: Could not find SeaBIOS binary for x86_64 pflash.");
+    eprintln!("Looked in:");
+    for p in &SEABIOS_PATHS {
+        eprintln!("  - {p}");
+    }
+    eprintln!("Install with: sudo apt install seabios  (or equivalent)");
+    process::exit(1);
+}
+
+/// Size of the PFlash image for each architecture: the size of its bank
+/// (see `readpflash_layout::banks`), which QEMU requires the image file to
+/// match.
+pub fn pflash_size(arch: Arch) -> usize {
+    arch.bank().size
+}
+
+/// Build the xip region: stub header plus a leaf function returning
+/// [`XIP_RESULT`], encoded for `arch`.
+fn xip_stub(arch: Arch) -> Vec<u8> {
+    let (machine, code): (u16, &[u8]) = match arch {
+        // lui a0, 0x58495; addiw a0, a0, 0x21; ret
+        Arch::Riscv64 => (
+            243,
+            &[
+                0x37, 0x55, 0x49, 0x58, 0x1b, 0x05, 0x15, 0x02, 0x67, 0x80, 0x00, 0x00,
+            ],
+        ),
+        // movz w0, #0x5021; movk w0, #0x5849, lsl #16; ret
+        Arch::Aarch64 => (
+            183,
+            &[
+                0x20, 0x04, 0x8a, 0x52, 0x20, 0x09, 0xab, 0x72, 0xc0, 0x03, 0x5f, 0xd6,
+            ],
+        ),
+        // mov eax, 0x58495021; ret
+        Arch::X86_64 => (62, &[0xb8, 0x21, 0x50, 0x49, 0x58, 0xc3]),
+        // lu12i.w $a0, 0x58495; ori $a0, $a0, 0x21; jirl $zero, $ra, 0
+        Arch::Loongarch64 => (
+            258,
+            &[
+                0xa4, 0x92, 0xb0, 0x14, 0x84, 0x84, 0x80, 0x03, 0x20, 0x00, 0x00, 0x4c,
+            ],
+        ),
+    };
+    let mut stub = Vec::with_capacity(XIP_HEADER_SIZE + code.len());
+    stub.extend_from_slice(XIP_MAGIC);
+    stub.extend_from_slice(&machine.to_le_bytes());
+    stub.extend_from_slice(&0u16.to_le_bytes());
+    stub.extend_from_slice(&(code.len() as u32).to_le_bytes());
+    stub.extend_from_slice(&XIP_RESULT.to_le_bytes());
+    stub.extend_from_slice(code);
+    stub
+}
+
+/// riscv64 pflash0 boot code: copies the firmware stored at flash offset
+/// 0x1000 (length in the word at 0x3C) to the DRAM base and jumps to it,
+/// leaving a0-a2 (hart id, FDT, fw_dynamic info) untouched.
+///
+/// ```text
+/// auipc t0, 0;  lwu t1, 60(t0);  lui t3, 1;  add t0, t0, t3
+/// li t2, 1;  slli t2, t2, 31;  mv t3, t2
+/// 1: ld t4, 0(t0);  sd t4, 0(t3);  addi t0, t0, 8;  addi t3, t3, 8
+///    addi t1, t1, -8;  bgtz t1, 1b
+/// fence.i;  jr t2
+/// ```
+const RISCV_FLASH_TRAMPOLINE: [u8; 60] = [
+    0x97, 0x02, 0x00, 0x00, 0x03, 0xe3, 0xc2, 0x03, 0x37, 0x1e, 0x00, 0x00, 0xb3, 0x82, 0xc2, 0x01,
+    0x93, 0x03, 0x10, 0x00, 0x93, 0x93, 0xf3, 0x01, 0x13, 0x8e, 0x03, 0x00, 0x83, 0xbe, 0x02, 0x00,
+    0x23, 0x30, 0xde, 0x01, 0x93, 0x82, 0x82, 0x00, 0x13, 0x0e, 0x8e, 0x00, 0x13, 0x03, 0x83, 0xff,
+    0xe3, 0x46, 0x60, 0xfe, 0x0f, 0x10, 0x00, 0x00, 0x67, 0x80, 0x03, 0x00,
+];
+/// Largest firmware that fits below the kernel load address (DRAM + 2MB).
+const RISCV_FIRMWARE_MAX: usize = 2 * 1024 * 1024;
+
+/// Create the riscv64 pflash0 image with `firmware` (an OpenSBI build)
+/// behind a boot trampoline, for `-bios none`.
+///
+/// QEMU jumps to the pflash0 base after reset when a pflash0 drive is
+/// attached. OpenSBI writes to its own image while starting, so it cannot
+/// run from flash directly; the trampoline copies it to DRAM first.
+pub fn create_firmware_image(out: &Path, arch: Arch, firmware: &Path) -> PathBuf {
+    let size = pflash_size(arch);
+    let path = out.join(format!("pflash-{arch}-fw.img"));
+    let data = read_input("firmware", firmware);
+    if data.len() > RISCV_FIRMWARE_MAX {
+        eprintln!(
+            "Error: firmware ({} bytes) is larger than the {RISCV_FIRMWARE_MAX} bytes \
+             below the kernel load address",
+            data.len()
+        );
+        process::exit(1);
+    }
+    let mut head = vec![0xFFu8; 0x40];
+    head[..RISCV_FLASH_TRAMPOLINE.len()].copy_from_slice(&RISCV_FLASH_TRAMPOLINE);
+    head[0x3C..0x40].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    let digest = sha256(&data);
+    let mut image = Image::new(size);
+    image.place(0, head);
+    image.place(REGION_ALIGN, data);
+    write_image(&path, &image).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write firmware image: {}", e);
+        process::exit(1);
+    });
+    println!(
+        "Created pflash0 firmware image: {} ({} bytes, firmware {} at {REGION_ALIGN:#x}, sha256={})",
+        path.display(),
+        size,
+        firmware.display(),
+        hex(&digest)
+    );
+    path
+}
+
+/// Names of the regions xtask places itself, which layout file regions
+/// cannot take.
+const RESERVED_NAMES: [&str; 16] = [
+    "header", "payload", "fs", "xip", "script", "meta", "banner", "messages", "journal",
+    "replicas", "log", "panics", "bench", "crc", "kernel", "firmware",
+];
+
+/// Bytes compared and rewritten at a time by [`write_image`].
+const WRITE_CHUNK: usize = 0x1_0000;
+
+/// Write `image` to `path` a chunk at a time, returning how many bytes
+/// were written.
+///
+/// Most of an image is erased filler (0xFF), which file holes cannot
+/// represent since they read as zeros. So when a file of the same size is
+/// already there, only the chunks that differ from it are rewritten: after
+/// the first run that is the header, manifest and whatever regions changed
+/// or the guest modified, not the whole bank.
+fn write_image(path: &Path, image: &Image) -> std::io::Result<usize> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let same_size = file.metadata()?.len() == image.len() as u64;
+    if !same_size {
+        file.set_len(0)?;
+    }
+    let (mut new, mut old) = (vec![0; WRITE_CHUNK], vec![0; WRITE_CHUNK]);
+    let mut written = 0;
+    for start in (0..image.len()).step_by(WRITE_CHUNK) {
+        let len = WRITE_CHUNK.min(image.len() - start);
+        let new = &mut new[..len];
+        image.read_at(start, new);
+        if same_size {
+            let old = &mut old[..len];
+            file.read_exact(old)?;
+            if old == new {
+                continue;
+            }
+            file.seek(SeekFrom::Start(start as u64))?;
+        }
+        file.write_all(new)?;
+        written += len;
+    }
+    Ok(written)
+}
+
+fn read_input(what: &str, path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {what} {}: {}", path.display(), e);
+        process::exit(1);
+    })
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+/// CRC-32 (IEEE 802.3), as the guest computes it.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ u32::from(b), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & 0u32.wrapping_sub(crc & 1))
+        })
+    })
+}
+
+/// The crc region covering the sectors of `image` in `[start, end)`.
+fn crc_table(image: &Image, start: usize, end: usize) -> Vec<u8> {
+    let count = (end - start).div_ceil(CRC_SECTOR);
+    let mut table = Vec::with_capacity(CRC_HEADER_SIZE + 4 * count);
+    table.extend_from_slice(CRC_MAGIC);
+    table.extend_from_slice(&(CRC_SECTOR as u32).to_le_bytes());
+    table.extend_from_slice(&(start as u32).to_le_bytes());
+    table.extend_from_slice(&(count as u32).to_le_bytes());
+    let mut sector = [0; CRC_SECTOR];
+    for at in (start..end).step_by(CRC_SECTOR) {
+        let sector = &mut sector[..CRC_SECTOR.min(end - at)];
+        image.read_at(at, sector);
+        table.extend_from_slice(&crc32(sector).to_le_bytes());
+    }
+    table
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// SHA-256 of `len` erased (0xFF) bytes.
+fn erased_sha256(len: usize) -> [u8; 32] {
+    let filler = [0xFF; REGION_ALIGN];
+    let mut hasher = Sha256::new();
+    for at in (0..len).step_by(REGION_ALIGN) {
+        hasher.update(&filler[..REGION_ALIGN.min(len - at)]);
+    }
+    hasher.finalize().into()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// How the payload of an image was encrypted.
+struct Sealed {
+    nonce: [u8; 12],
+    tag: [u8; 16],
+    key_id: [u8; 8],
+}
+
+/// Size of the header, with the encryption fields if `sealed`.
+fn header_len(sealed: Option<&Sealed>) -> usize {
+    match sealed {
+        Some(_) => HEADER_SIZE + CRYPT_FIELDS_SIZE,
+        None => HEADER_SIZE,
+    }
+}
+
+/// First 8 bytes of the SHA-256 of `key`, naming it without revealing it.
+pub fn key_id(key: &[u8; KEY_LEN]) -> [u8; 8] {
+    sha256(key)[..8].try_into().unwrap()
+}
+
+/// Read an `--encrypt` key: [`KEY_LEN`] raw bytes, or as many bytes in hex
+/// (surrounding whitespace is ignored).
+pub fn read_key(path: &Path) -> [u8; KEY_LEN] {
+    let data = read_input("key file", path);
+    if let Ok(key) = <[u8; KEY_LEN]>::try_from(data.as_slice()) {
+        return key;
+    }
+    let text = String::from_utf8_lossy(&data);
+    let text = text.trim();
+    let parsed: Option<Vec<u8>> = (text.len() == 2 * KEY_LEN)
+        .then(|| {
+            (0..text.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+                .collect()
+        })
+        .flatten();
+    match parsed.and_then(|key| <[u8; KEY_LEN]>::try_from(key).ok()) {
+        Some(key) => key,
+        None => {
+            eprintln!(
+                "Error: {} must hold a {KEY_LEN}-byte AES-256 key, as raw bytes or {} hex \
+                 digits ({} bytes found)",
+                path.display(),
+                2 * KEY_LEN,
+                data.len()
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Encrypt `data` in place with AES-256-GCM under `key`.
+fn seal(key: &[u8; KEY_LEN], data: &mut [u8]) -> Sealed {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update(&*data);
+    let nonce: [u8; 12] = hasher.finalize()[..12].try_into().unwrap();
+    let cipher = Aes256Gcm::new_from_slice(key).expect("AES-256 key length");
+    let tag = cipher
+        .encrypt_in_place_detached(Nonce::from_slice(&nonce), b"", data)
+        .expect("AES-GCM payload length");
+    Sealed {
+        nonce,
+        tag: tag.as_slice().try_into().unwrap(),
+        key_id: key_id(key),
+    }
+}
+
+/// Serialize the header of an image of `size` bytes in byte order
+/// `endian`, with the pattern seed and flash geometry of `args` and the
+/// encryption fields if the payload is `sealed`.
+fn write_header(
+    image: &mut [u8],
+    size: usize,
+    region_count: usize,
+    kernel: Option<&Region>,
+    args: &ImageArgs,
+    sealed: Option<&Sealed>,
+    endian: Endian,
+) {
+    let len = header_len(sealed);
+    let header = &mut image[..len];
+    header.fill(0);
+    header[0x00..0x04].copy_from_slice(MAGIC);
+    header[0x04..0x06].copy_from_slice(&endian.u16(VERSION));
+    header[0x08..0x0C].copy_from_slice(&endian.u32(len as u32));
+    header[0x0C..0x10].copy_from_slice(&endian.u32(size as u32));
+    header[0x10..0x14].copy_from_slice(&endian.u32(len as u32));
+    header[0x14..0x18].copy_from_slice(&endian.u32(region_count as u32));
+    let mut flags = 0;
+    if let Some(kernel) = kernel {
+        flags |= FLAG_KERNEL;
+        header[0x18..0x1C].copy_from_slice(&endian.u32(kernel.offset as u32));
+        header[0x1C..0x20].copy_from_slice(&endian.u32(kernel.len as u32));
+    }
+    if let Some(pattern) = args.pattern {
+        flags |= FLAG_PATTERN;
+        header[0x28..0x30].copy_from_slice(&endian.u64(pattern.seed));
+    }
+    if let Some(geometry) = args.flash_geometry {
+        flags |= FLAG_GEOMETRY;
+        header[0x30..0x34].copy_from_slice(&endian.u32(geometry.sector_size));
+        header[0x34] = geometry.device_width;
+        header[0x35] = geometry.devices;
+    }
+    if let Some(sealed) = sealed {
+        flags |= FLAG_ENCRYPTED;
+        header[0x40..0x4C].copy_from_slice(&sealed.nonce);
+        header[0x50..0x60].copy_from_slice(&sealed.tag);
+        header[0x60..0x68].copy_from_slice(&sealed.key_id);
+    }
+    header[0x06..0x08].copy_from_slice(&endian.u16(flags));
+    let crc = crc32(&header[..0x20]);
+    header[0x20..0x24].copy_from_slice(&endian.u32(crc));
+    header[ENDIAN_OFFSET] = endian.marker();
+}
+
+/// Serialize the manifest describing `regions` at `offset` in byte order
+/// `endian`.
+fn write_manifest(image: &mut [u8], offset: usize, regions: &[Region], endian: Endian) {
+    let mut off = offset;
+    image[off..off + 4].copy_from_slice(MANIFEST_MAGIC);
+    image[off + 4..off + 8].copy_from_slice(&endian.u32(regions.len() as u32));
+    off += MANIFEST_PREAMBLE;
+    for region in regions {
+        let entry = &mut image[off..off + MANIFEST_ENTRY_SIZE];
+        entry.fill(0);
+        assert!(region.name.len() <= NAME_LEN, "region name too long");
+        entry[..region.name.len()].copy_from_slice(region.name.as_bytes());
+        entry[0x10..0x14].copy_from_slice(&endian.u32(region.offset as u32));
+        entry[0x14..0x18].copy_from_slice(&endian.u32(region.len as u32));
+        entry[0x18..0x1C].copy_from_slice(&endian.u32(region.flags));
+        entry[KIND_OFFSET] = region.kind.tag();
+        // 0x1D..0x20: reserved
+        entry[0x20..0x40].copy_from_slice(&region.sha256);
+        off += MANIFEST_ENTRY_SIZE;
+    }
+}
+
+/// Bytes and number of entries under `dir`, for sizing a filesystem image.
+fn tree_size(dir: &Path) -> (u64, u64) {
+    let entries = std::fs::read_dir(dir).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {}", dir.display(), e);
+        process::exit(1);
+    });
+    entries.flatten().fold((0, 0), |(bytes, count), entry| {
+        match entry.path().symlink_metadata() {
+            Ok(meta) if meta.is_dir() => {
+                let (b, c) = tree_size(&entry.path());
+                (bytes + b, count + c + 1)
+            }
+            Ok(meta) => (bytes + meta.len(), count + 1),
+            Err(_) => (bytes, count + 1),
+        }
+    })
+}
+
+/// Build an ext2 image (1K blocks) of the tree under `dir` with `mke2fs -d`.
+fn make_ext2(out: &Path, arch: Arch, dir: &Path) -> Vec<u8> {
+    if !dir.is_dir() {
+        eprintln!("Error: --ext2 needs a directory: {}", dir.display());
+        process::exit(1);
+    }
+    // Data plus a block per entry, 50% headroom for metadata, at least 512K.
+    let (bytes, entries) = tree_size(dir);
+    let blocks = ((bytes.div_ceil(1024) + entries) * 3 / 2 + 256).max(512);
+    let label: String = std::path::absolute(dir)
+        .ok()
+        .and_then(|d| d.file_name()?.to_str().map(String::from))
+        .unwrap_or_default()
+        .chars()
+        .take(16)
+        .collect();
+    let path = out.join(format!("pflash-{arch}-fs.ext2"));
+    let _ = std::fs::remove_file(&path);
+    let status = Command::new("mke2fs")
+        .args(["-q", "-F", "-t", "ext2", "-b", "1024", "-m", "0", "-L"])
+        .arg(&label)
+        .arg("-d")
+        .arg(dir)
+        .arg(&path)
+        .arg(blocks.to_string())
+        .stdout(process::Stdio::null())
+        .status()
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "Error: failed to run mke2fs ({e}). Install with: sudo apt install e2fsprogs"
+            );
+            process::exit(1);
+        });
+    if !status.success() {
+        eprintln!(
+            "Error: mke2fs failed to build an ext2 image of {}",
+            dir.display()
+        );
+        process::exit(status.code().unwrap_or(1));
+    }
+    println!(
+        "Built ext2 image {} ({blocks} KiB) from {}",
+        path.display(),
+        dir.display()
+    );
+    read_input("ext2 image", &path)
+}
+
+/// Render the manifest as JSON for the host-side sidecar file.
+fn manifest_json(image_path: &Path, size: usize, regions: &[Region]) -> String {
+    let entries: Vec<String> = regions
+        .iter()
+        .map(|r| {
+            format!(
+                "    {{\"name\": {}, \"offset\": {}, \"len\": {}, \"writable\": {}, \"type\": \"{}\", \"sha256\": \"{}\"}}",
+                crate::json_string(r.name),
+                r.offset,
+                r.len,
+                r.flags & REGION_WRITABLE != 0,
+                r.kind,
+                hex(&r.sha256)
+            )
+        })
+        .collect();
+    format!(
+        "{{\n  \"image\": {},\n  \"version\": {},\n  \"size\": {},\n  \"regions\": [\n{}\n  ]\n}}\n",
+        crate::json_string(&image_path.display().to_string()),
+        VERSION,
+        size,
+        entries.join(",\n")
+    )
+}
+
+/// What goes into an image besides the regions [`ImageArgs`] reserves,
+/// read from disk by [`create_pflash_image`].
+struct Inputs {
+    /// Data regions in placement order: payload, then fs and xip if present.
+    contents: Vec<(&'static str, Vec<u8>)>,
+    /// Regions of a layout file, placed after `contents`.
+    custom: Vec<Custom>,
+    /// Kernel image for `--kernel-in-flash`.
+    kernel: Vec<u8>,
+    /// Firmware placed at the top of the bank (x86_64).
+    firmware: Option<Vec<u8>>,
+    /// Key to encrypt the payload with (`--encrypt`).
+    key: Option<[u8; KEY_LEN]>,
+}
+
+/// A region of a layout file, with its file read.
+struct Custom {
+    name: &'static str,
+    /// File contents, or `None` for `len` erased bytes.
+    data: Option<Vec<u8>>,
+    len: usize,
+    offset: Option<usize>,
+    align: usize,
+    writable: bool,
+    /// Type tag, if the layout file gives one.
+    kind: Option<Kind>,
+}
+
+/// The measured-boot log of `image`, whose header region comes first in
+/// `regions`: the manifest, then every read-only region.
+fn measurements(image: &Image, regions: &[Region]) -> String {
+    let start = regions[0].len;
+    let mut manifest = vec![0; MANIFEST_PREAMBLE + regions.len() * MANIFEST_ENTRY_SIZE];
+    image.read_at(start, &mut manifest);
+    let parts = std::iter::once(("manifest", sha256(&manifest))).chain(
+        regions
+            .iter()
+            .filter(|r| r.flags & REGION_WRITABLE == 0)
+            .map(|r| (r.name, r.sha256)),
+    );
+    let mut log = measure::log(parts).join("\n");
+    log.push('\n');
+    log
+}
+
+/// `offset..end` of `len` bytes at `offset`, for layout diagnostics.
+fn span(offset: usize, len: usize) -> String {
+    format!("{offset:#x}..{:#x}", offset.saturating_add(len))
+}
+
+/// Check that `len` bytes at `offset` fit in an image of `size` bytes.
+fn check_fits(name: &str, offset: usize, len: usize, size: usize) -> Result<(), String> {
+    let end = offset.saturating_add(len);
+    if end <= size {
+        return Ok(());
+    }
+    Err(format!(
+        "{name} region ({len} bytes, {}) runs {:#x} bytes past the end of the {size:#x}-byte \
+         pflash image",
+        span(offset, len),
+        end - size
+    ))
+}
+
+/// Check that `len` bytes at `offset` overlap none of `regions`, naming
+/// every one they do and by how much.
+fn check_overlaps(regions: &[Region], name: &str, offset: usize, len: usize) -> Result<(), String> {
+    let end = offset.saturating_add(len);
+    let clashes: Vec<String> = regions
+        .iter()
+        .filter_map(|r| {
+            let shared = end
+                .min(r.offset + r.len)
+                .saturating_sub(offset.max(r.offset));
+            (shared > 0).then(|| {
+                format!(
+                    "{} ({}) by {shared:#x} bytes",
+                    r.name,
+                    span(r.offset, r.len)
+                )
+            })
+        })
+        .collect();
+    if clashes.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "{name} region ({}) overlaps {}",
+        span(offset, len),
+        clashes.join(", ")
+    ))
+}
+
+/// Check a finished layout: every name fits its manifest entry, and every
+/// region lies inside the image and overlaps no other.
+///
+/// Reports every problem found, one per line, not just the first.
+fn check_layout(regions: &[Region], size: usize) -> Result<(), String> {
+    let mut problems = Vec::new();
+    for (i, region) in regions.iter().enumerate() {
+        if region.name.len() > NAME_LEN {
+            problems.push(format!(
+                "region name '{}' is {} bytes, {} more than a manifest entry holds",
+                region.name,
+                region.name.len(),
+                region.name.len() - NAME_LEN
+            ));
+        }
+        let checks = [
+            check_fits(region.name, region.offset, region.len, size),
+            check_overlaps(&regions[..i], region.name, region.offset, region.len),
+        ];
+        problems.extend(checks.into_iter().filter_map(Result::err));
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("invalid layout:\n  {}", problems.join("\n  ")))
+    }
+}
+
+/// Lay out `inputs` and the regions `args` asks for in an erased image of
+/// `size` bytes.
+///
+/// Returns the image and its regions in manifest order, or why the layout
+/// is impossible; no region ever overlaps another or the end of the image.
+fn build_image(
+    size: usize,
+    args: &ImageArgs,
+    inputs: Inputs,
+) -> Result<(Image, Vec<Region>), String> {
+    let Inputs {
+        mut contents,
+        custom,
+        kernel,
+        firmware,
+        key,
+    } = inputs;
+    let mut image = Image::new(size); // CFI flash erased state is 0xFF
+
+    // The payload comes first in `contents`.
+    let sealed = key.map(|key| seal(&key, &mut contents[0].1));
+    let header_len = header_len(sealed.as_ref());
+
+    if args.fs_writable {
+        let Some((_, data)) = contents.iter_mut().find(|(name, _)| *name == "fs") else {
+            return Err("--fs-writable needs an fs region (--fs, --romfs or --ext2)".into());
+        };
+        // Pad to whole sectors so the scratch sector is one.
+        data.resize(data.len().next_multiple_of(SCRATCH_SECTOR), 0xFF);
+        data.extend_from_slice(&[0xFF; SCRATCH_SECTOR]);
+    }
+
+    // The manifest lists the header plus every data region.
+    let region_count = 1
+        + contents.len()
+        + custom.len()
+        + usize::from(args.journal)
+        + usize::from(args.replicas)
+        + usize::from(args.log_ring)
+        + usize::from(args.panic_region)
+        + usize::from(args.bench_region)
+        + usize::from(args.crc)
+        + usize::from(args.kernel_in_flash.is_some())
+        + usize::from(firmware.is_some());
+    let manifest_end = header_len + MANIFEST_PREAMBLE + region_count * MANIFEST_ENTRY_SIZE;
+    if manifest_end > REGION_ALIGN.min(size) {
+        return Err(format!(
+            "the manifest of {region_count} regions ends at {manifest_end:#x}, {:#x} bytes \
+             past the header sector ({:#x} bytes) that must hold it",
+            manifest_end - REGION_ALIGN.min(size),
+            REGION_ALIGN.min(size)
+        ));
+    }
+
+    let mut regions = vec![Region {
+        name: "header",
+        offset: 0,
+        len: header_len,
+        flags: 0,
+        kind: Kind::Raw,
+        sha256: [0; 32],
+    }];
+    let mut next = REGION_ALIGN;
+    for (name, data) in contents {
+        let offset = align_up(next, REGION_ALIGN);
+        check_fits(name, offset, data.len(), size)?;
+        let writable = name == "fs" && args.fs_writable;
+        let kind = match name {
+            // What the flash holds: ciphertext or a PRNG stream.
+            "payload" if sealed.is_some() || args.pattern.is_some() => Kind::Raw,
+            "payload" => args.payload_type.unwrap_or_else(|| detect_kind(&data)),
+            "xip" => Kind::Executable,
+            "meta" => Kind::Config,
+            "script" | "banner" => Kind::Text,
+            "messages" => Kind::Catalog,
+            _ => detect_kind(&data),
+        };
+        regions.push(Region {
+            name,
+            offset,
+            len: data.len(),
+            flags: if writable { REGION_WRITABLE } else { 0 },
+            kind,
+            sha256: sha256(&data),
+        });
+        next = offset + data.len();
+        image.place(offset, data);
+    }
+
+    // Layout file regions, at their offset or the next boundary. An exact
+    // offset may fill a gap left by alignment, as long as it overlaps
+    // nothing.
+    for region in custom {
+        let Custom { name, len, .. } = region;
+        let offset = match region.offset {
+            Some(offset) => {
+                check_overlaps(&regions, name, offset, len)?;
+                offset
+            }
+            None => align_up(next, region.align),
+        };
+        check_fits(name, offset, len, size)?;
+        if region.writable {
+            // The guest can only erase whole blocks, so a writable region
+            // must not share one with its neighbours.
+            let start = offset % JOURNAL_ALIGN;
+            let end = (offset + len) % JOURNAL_ALIGN;
+            if start != 0 || end != 0 {
+                return Err(format!(
+                    "{name} region ({}) is writable, so it must start and end on {JOURNAL_ALIGN:#x}-byte \
+                     erase-block boundaries, but its {} (set align = {JOURNAL_ALIGN:#x} and a size \
+                     that is a multiple of it)",
+                    span(offset, len),
+                    match (start, end) {
+                        (0, end) => format!("end is {end:#x} bytes past one"),
+                        (start, 0) => format!("start is {start:#x} bytes past one"),
+                        (start, end) => {
+                            format!("start is {start:#x} and its end {end:#x} bytes past one")
+                        }
+                    }
+                ));
+            }
+        }
+        let sha256 = match &region.data {
+            Some(data) => sha256(data),
+            None => erased_sha256(len),
+        };
+        let kind = region
+            .kind
+            .unwrap_or_else(|| region.data.as_deref().map_or(Kind::Raw, detect_kind));
+        regions.push(Region {
+            name,
+            offset,
+            len,
+            flags: if region.writable { REGION_WRITABLE } else { 0 },
+            kind,
+            sha256,
+        });
+        next = next.max(offset + len);
+        if let Some(data) = region.data {
+            image.place(offset, data);
+        }
+    }
+
+    // Erased regions the guest writes, each starting on an erase block.
+    let reserved = [
+        // The guest formats the journal on first mount.
+        (args.journal, "journal", JOURNAL_LEN),
+        // The guest writes the first copies on first boot.
+        (args.replicas, "replicas", REPLICAS_LEN),
+        // The guest starts the ring on first boot.
+        (args.log_ring, "log", LOG_LEN),
+        // Every slot is free.
+        (args.panic_region, "panics", PANICS_LEN),
+        // Every slot is free.
+        (args.bench_region, "bench", BENCH_LEN),
+    ];
+    for (_, name, len) in reserved.into_iter().filter(|&(wanted, ..)| wanted) {
+        let offset = align_up(next, JOURNAL_ALIGN);
+        check_fits(name, offset, len, size)?;
+        regions.push(Region {
+            name,
+            offset,
+            len,
+            flags: REGION_WRITABLE,
+            kind: Kind::Raw,
+            sha256: erased_sha256(len),
+        });
+        next = offset + len;
+    }
+
+    if args.crc {
+        // Covers everything placed so far except the header sector, whose
+        // manifest holds the digest of this region.
+        let offset = align_up(next, REGION_ALIGN);
+        check_fits("crc", offset, 0, size)?;
+        let table = crc_table(&image, REGION_ALIGN, offset);
+        check_fits("crc", offset, table.len(), size)?;
+        regions.push(Region {
+            name: "crc",
+            offset,
+            len: table.len(),
+            flags: 0,
+            kind: Kind::Raw,
+            sha256: sha256(&table),
+        });
+        next = offset + table.len();
+        image.place(offset, table);
+    }
+
+    if let Some(offset) = args.kernel_in_flash {
+        if offset % REGION_ALIGN != 0 {
+            return Err(format!(
+                "--kernel-in-flash offset {offset:#x} must be {REGION_ALIGN:#x}-aligned \
+                 ({:#x} bytes past a boundary)",
+                offset % REGION_ALIGN
+            ));
+        }
+        check_overlaps(&regions, "kernel", offset, kernel.len())?;
+        if offset < next {
+            return Err(format!(
+                "--kernel-in-flash offset {offset:#x} lies {:#x} bytes before the end of the \
+                 data regions ({next:#x})",
+                next - offset
+            ));
+        }
+        check_fits("kernel", offset, kernel.len(), size)?;
+        regions.push(Region {
+            name: "kernel",
+            offset,
+            len: kernel.len(),
+            flags: 0,
+            kind: Kind::Executable,
+            sha256: sha256(&kernel),
+        });
+        next = offset + kernel.len();
+        image.place(offset, kernel);
+    }
+
+    if let Some(firmware) = firmware {
+        // For x86_64 Q35: pflash0 replaces the BIOS ROM.
+        // We embed SeaBIOS at the end of the image so the CPU reset
+        // vector (0xFFFFFFF0) lands inside SeaBIOS code.
+        let len = firmware.len();
+        if len > size - next {
+            return Err(format!(
+                "SeaBIOS binary ({len} bytes) does not fit after the data regions \
+                 (ending at {next:#x}) in the {size:#x}-byte pflash image: {:#x} bytes short",
+                len - (size - next)
+            ));
+        }
+        regions.push(Region {
+            name: "firmware",
+            offset: size - len,
+            len,
+            flags: 0,
+            kind: Kind::Executable,
+            sha256: sha256(&firmware),
+        });
+        image.place(size - len, firmware);
+    }
+
+    check_layout(&regions, size)?;
+    let kernel_region = regions.iter().find(|r| r.name == "kernel");
+    // Header and manifest, up to the erased rest of the first sector.
+    let mut head = vec![0xFF; manifest_end];
+    let endian = args.header_endian.unwrap_or_default();
+    write_header(
+        &mut head,
+        size,
+        regions.len(),
+        kernel_region,
+        args,
+        sealed.as_ref(),
+        endian,
+    );
+    regions[0].sha256 = sha256(&head[..header_len]);
+    write_manifest(&mut head, header_len, &regions, endian);
+    image.place(0, head);
+    Ok((image, regions))
+}
+
+/// Digest of everything [`build_image`] turns into an image of `size`
+/// bytes for `arch`.
+fn inputs_digest(arch: Arch, size: usize, args: &ImageArgs, inputs: &Inputs) -> String {
+    let mut hasher = Sha256::new();
+    let flags = [
+        args.fs_writable,
+        args.journal,
+        args.replicas,
+        args.log_ring,
+        args.panic_region,
+        args.bench_region,
+        args.crc,
+    ];
+    hasher.update(format!(
+        "v{VERSION} {arch} {size} {flags:?} {:?} {:?} {:?} {:?} {:?} {:?}\n",
+        args.kernel_in_flash,
+        args.pattern.map(|pattern| pattern.seed),
+        args.payload_type,
+        args.header_endian.unwrap_or_default(),
+        args.partition_table,
+        args.flash_geometry
+    ));
+    if let Some(key) = &inputs.key {
+        hasher.update(format!("key {}\n", hex(&sha256(key))));
+    }
+    for region in &inputs.custom {
+        hasher.update(format!(
+            "region {} {:?} {} {} {} {:?}\n",
+            region.name, region.offset, region.align, region.writable, region.len, region.kind
+        ));
+        hasher.update(region.data.as_deref().unwrap_or_default());
+    }
+    let kernel = ("kernel", &inputs.kernel);
+    let firmware = inputs.firmware.as_ref().map(|data| ("firmware", data));
+    for (name, data) in inputs
+        .contents
+        .iter()
+        .map(|(name, data)| (*name, data))
+        .chain([kernel])
+        .chain(firmware)
+    {
+        hasher.update(format!("{name} {}\n", data.len()));
+        hasher.update(data);
+    }
+    hex(&hasher.finalize())
+}
+
+/// The stamp recorded next to the image at `path` built from inputs with
+/// `digest`: the digest plus the image's size and modification time, which
+/// change when the guest or `image corrupt` writes to it.
+fn image_stamp(path: &Path, digest: &str) -> Option<String> {
+    let meta = std::fs::metadata(path).ok()?;
+    let modified = meta.modified().ok()?;
+    let nanos = modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_nanos();
+    Some(format!("{digest} {} {nanos}\n", meta.len()))
+}
+
+/// Create the PFlash image for `arch` and its `.manifest.json` sidecar.
+///
+/// An image built from the same inputs and not modified since is kept as
+/// it is, unless `--force-image` is given; `<image>.inputs` records what
+/// it was built from.
+///
+/// `kernel` is the image embedded with `--kernel-in-flash` (the same file
+/// QEMU would get via `-kernel`).
+///
+/// For x86_64, the image also includes SeaBIOS at the end so that
+/// pflash0 can serve as both data storage and boot ROM.
+pub fn create_pflash_image(root: &Path, arch: Arch, args: &ImageArgs, kernel: &Path) -> PathBuf {
+    let size = pflash_size(arch);
+    let out = image_dir(root, args.pflash_out.as_deref());
+    std::fs::create_dir_all(&out).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create {}: {}", out.display(), e);
+        process::exit(1);
+    });
+    let pflash_path = out.join(format!("pflash-{arch}.img"));
+
+    if args.partition_table == Some(PartitionTable::Gpt) && arch == Arch::X86_64 {
+        // SeaBIOS ends pflash0 with its reset vector, where the backup GPT
+        // would go.
+        eprintln!("Error: --partition-table gpt cannot be used on x86_64; use mbr");
+        process::exit(1);
+    }
+    if (args.encrypt.is_some() || args.dev_key) && args.pattern.is_some() {
+        // The guest could not check the stream against the ciphertext.
+        eprintln!("Error: --encrypt cannot be combined with a --pattern payload");
+        process::exit(1);
+    }
+    // Data regions in placement order, each starting on a 4K boundary.
+    let payload = match (&args.payload, args.pattern) {
+        (Some(path), _) => read_input("payload", path),
+        (None, Some(pattern)) => prng_stream(pattern.seed, pattern.len),
+        (None, None) => DEFAULT_PAYLOAD.to_vec(),
+    };
+    let mut contents: Vec<(&'static str, Vec<u8>)> = vec![("payload", payload)];
+    if let Some(path) = &args.fs {
+        contents.push(("fs", read_input("filesystem image", path)));
+    } else if let Some(dir) = &args.romfs {
+        contents.push(("fs", crate::romfs::build(dir)));
+    } else if let Some(dir) = &args.ext2 {
+        contents.push(("fs", make_ext2(&out, arch, dir)));
+    }
+    if args.xip {
+        contents.push(("xip", xip_stub(arch)));
+    }
+    if let Some(path) = &args.flash_script {
+        let script = read_input("flash script", path);
+        if let Err(e) = check_script(&script) {
+            eprintln!("Error: {}: {e}", path.display());
+            process::exit(1);
+        }
+        contents.push(("script", script));
+    }
+    if !args.meta.is_empty() {
+        let lines: String = args.meta.iter().map(|pair| format!("{pair}\n")).collect();
+        contents.push(("meta", lines.into_bytes()));
+    }
+    if let Some(banner) = &args.banner {
+        let path = Path::new(banner);
+        let text = if path.is_file() {
+            read_input("banner", path)
+        } else {
+            banner.as_bytes().to_vec()
+        };
+        if std::str::from_utf8(&text).is_err() {
+            eprintln!("Error: the banner in {banner} is not UTF-8 text");
+            process::exit(1);
+        }
+        contents.push(("banner", text));
+    }
+    if let Some(path) = &args.messages {
+        let catalog = build_catalog(&read_input("message file", path)).unwrap_or_else(|e| {
+            eprintln!("Error: {}: {e}", path.display());
+            process::exit(1);
+        });
+        contents.push(("messages", catalog));
+    }
+    let kernel_data = match args.kernel_in_flash {
+        Some(_) => read_input("kernel image", kernel),
+        None => Vec::new(),
+    };
+    let builtin: Vec<&str> = contents.iter().map(|(name, _)| *name).collect();
+    let custom = args
+        .regions
+        .iter()
+        .map(|region| {
+            if RESERVED_NAMES.contains(&region.name) || builtin.contains(&region.name) {
+                eprintln!(
+                    "Error: layout region '{}' clashes with a built-in region",
+                    region.name
+                );
+                process::exit(1);
+            }
+            let data = match &region.contents {
+                Contents::File(path) => Some(read_input(region.name, path)),
+                Contents::Erased(_) => None,
+            };
+            let len = match (&data, &region.contents) {
+                (Some(data), _) => data.len(),
+                (None, Contents::Erased(len)) => *len,
+                (None, Contents::File(_)) => unreachable!(),
+            };
+            Custom {
+                name: region.name,
+                data,
+                len,
+                offset: region.offset,
+                align: region.align,
+                writable: region.writable,
+                kind: region.kind,
+            }
+        })
+        .collect();
+    let bios_path = (arch == Arch::X86_64).then(find_seabios);
+    let inputs = Inputs {
+        contents,
+        custom,
+        kernel: kernel_data,
+        firmware: bios_path
+            .as_ref()
+            .map(|path| read_input("SeaBIOS binary", path)),
+        key: match &args.encrypt {
+            Some(path) => Some(read_key(path)),
+            None => args.dev_key.then_some(DEV_KEY),
+        },
+    };
+    let key_id = inputs.key.as_ref().map(key_id);
+
+    let digest = inputs_digest(arch, size, args, &inputs);
+    let stamp_path = pflash_path.with_extension("inputs");
+    let manifest_path = pflash_path.with_extension("manifest.json");
+    let measurements_path = pflash_path.with_extension("measurements");
+    let unchanged = image_stamp(&pflash_path, &digest)
+        .is_some_and(|stamp| std::fs::read_to_string(&stamp_path).is_ok_and(|old| old == stamp));
+    if unchanged && manifest_path.exists() && measurements_path.exists() && !args.force_image {
+        println!(
+            "Reusing pflash image: {} (inputs unchanged; --force-image rebuilds it)",
+            pflash_path.display()
+        );
+        return pflash_path;
+    }
+
+    let data_len = args
+        .partition_table
+        .map_or(size, |table| partition::data_len(table, size));
+    let (image, regions) = build_image(data_len, args, inputs).unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        process::exit(1);
+    });
+    let measured = measurements(&image, &regions);
+    let bank = match args.partition_table {
+        Some(table) => {
+            let name = match table {
+                PartitionTable::Mbr => "an MBR",
+                PartitionTable::Gpt => "a GPT",
+            };
+            println!(
+                "Adding {name} partition table: image in the data partition at {:#x} \
+                 (+{data_len:#x})",
+                partition::DATA_OFFSET
+            );
+            image.embed(
+                partition::DATA_OFFSET,
+                size,
+                partition::extents(table, size),
+            )
+        }
+        None => image,
+    };
+    for r in &regions {
+        match r.name {
+            "kernel" => println!(
+                "Embedding kernel ({} bytes) from {} at {:#x}",
+                r.len,
+                kernel.display(),
+                r.offset
+            ),
+            "firmware" => println!(
+                "Embedding SeaBIOS ({} bytes) from {}",
+                r.len,
+                bios_path.as_ref().unwrap().display()
+            ),
+            _ => {}
+        }
+    }
+
+    if let Some(id) = key_id {
+        let source = match &args.encrypt {
+            Some(path) => format!("from {}", path.display()),
+            None => "the development key".into(),
+        };
+        println!(
+            "Encrypted the payload with AES-256-GCM (key id {}, {source})",
+            hex(&id)
+        );
+    }
+
+    let written = write_image(&pflash_path, &bank).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write pflash image: {}", e);
+        process::exit(1);
+    });
+    std::fs::write(&manifest_path, manifest_json(&pflash_path, size, &regions)).unwrap_or_else(
+        |e| {
+            eprintln!("Error: failed to write {}: {}", manifest_path.display(), e);
+            process::exit(1);
+        },
+    );
+    std::fs::write(&measurements_path, measured).unwrap_or_else(|e| {
+        eprintln!(
+            "Error: failed to write {}: {}",
+            measurements_path.display(),
+            e
+        );
+        process::exit(1);
+    });
+    if let Some(stamp) = image_stamp(&pflash_path, &digest) {
+        // Without a stamp the next run just rebuilds the image.
+        let _ = std::fs::write(&stamp_path, stamp);
+    }
+    println!(
+        "Created pflash image: {} ({} bytes, {} rewritten)",
+        pflash_path.display(),
+        size,
+        written
+    );
+    for r in &regions {
+        println!(
+            "  {:<8} {:#010x} +{:<8} sha256={}",
+            r.name,
+            r.offset,
+            r.len,
+            hex(&r.sha256)
+        );
+    }
+    pflash_path
+}
+
+/// Flip `bits` of the image at `path`, counted from bit 0 of the byte at
+/// `offset`, to test the guest's error detection.
+pub fn corrupt(path: &Path, offset: usize, bits: &[usize]) {
+    let mut image = read_input("pflash image", path);
+    let base = partition::image_offset(&image);
+    let regions = read_manifest(path, &image[base..]);
+    for &bit in bits {
+        let at = offset + bit / 8;
+        if at >= image.len() {
+            eprintln!(
+                "Error: bit {bit} at {offset:#x} lies past the end of the {}-byte image",
+                image.len()
+            );
+            process::exit(1);
+        }
+        let old = image[at];
+        image[at] ^= 1 << (bit % 8);
+        let region = regions
+            .iter()
+            .find(|(_, start, len)| (base + start..base + start + len).contains(&at))
+            .map_or("no region", |(name, _, _)| name.as_str());
+        println!(
+            "Flipped bit {} of byte {at:#x} ({region}): {old:#04x} -> {:#04x}",
+            bit % 8,
+            image[at]
+        );
+    }
+    std::fs::write(path, &image).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", path.display(), e);
+        process::exit(1);
+    });
+}
+
+/// Check a test script for the guest's `flash-script` feature: UTF-8 text
+/// with one flash shell command (`I`, `R`, `W` or `E`) per line, optionally
+/// followed by `=> <expected reply prefix>`. Blank lines and `#` comments
+/// are skipped.
+fn check_script(script: &[u8]) -> Result<(), String> {
+    let text = std::str::from_utf8(script).map_err(|_| "the script is not UTF-8".to_string())?;
+    let mut steps = 0;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (command, expected) = match line.split_once("=>") {
+            Some((command, expected)) => (command.trim(), Some(expected.trim())),
+            None => (line, None),
+        };
+        match command.split_whitespace().next() {
+            Some("I" | "R" | "W" | "E") => {}
+            _ => return Err(format!("line {}: unknown command '{command}'", n + 1)),
+        }
+        if expected.is_some_and(|e| !e.starts_with("OK") && !e.starts_with("ERR")) {
+            return Err(format!(
+                "line {}: the expected reply must start with OK or ERR",
+                n + 1
+            ));
+        }
+        steps += 1;
+    }
+    if steps == 0 {
+        return Err("the script has no commands".into());
+    }
+    Ok(())
+}
+
+/// The message catalog ([`catalog`]) of a message file: `ID=TEXT` lines,
+/// with decimal or `0x` ids and `\n` for a line break in the text. Blank
+/// lines and lines starting with `#` are skipped.
+fn build_catalog(file: &[u8]) -> Result<Vec<u8>, String> {
+    let text = std::str::from_utf8(file).map_err(|_| "the file is not UTF-8".to_string())?;
+    let mut messages = std::collections::BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (id, message) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected ID=TEXT", n + 1))?;
+        let id = parse_offset(id.trim())
+            .ok()
+            .and_then(|id| u32::try_from(id).ok())
+            .ok_or_else(|| format!("line {}: bad message id '{}'", n + 1, id.trim()))?;
+        if messages.insert(id, message.replace("\\n", "\n")).is_some() {
+            return Err(format!("line {}: message {id} is defined twice", n + 1));
+        }
+    }
+    if messages.is_empty() {
+        return Err("the file has no messages".into());
+    }
+    let index_end = catalog::HEADER_SIZE + catalog::ENTRY_SIZE * messages.len();
+    let mut out = Vec::with_capacity(index_end);
+    out.extend_from_slice(catalog::MAGIC);
+    out.extend_from_slice(&(messages.len() as u32).to_le_bytes());
+    let mut strings = Vec::new();
+    for (id, message) in &messages {
+        out.extend_from_slice(&id.to_le_bytes());
+        out.extend_from_slice(&((index_end + strings.len()) as u32).to_le_bytes());
+        out.extend_from_slice(&(message.len() as u32).to_le_bytes());
+        strings.extend_from_slice(message.as_bytes());
+    }
+    out.extend_from_slice(&strings);
+    Ok(out)
+}
+
+/// Print the regions of the image at `path` and, with `panics` and
+/// `bench`, the crash records and benchmark results the guest left in its
+/// panics and bench regions.
+pub fn inspect(path: &Path, panics: bool, bench: bool) {
+    let bank = read_input("pflash image", path);
+    let base = partition::image_offset(&bank);
+    let image = &bank[base..];
+    let regions = read_manifest(path, image);
+    println!("{} ({} bytes):", path.display(), bank.len());
+    if base != 0 {
+        println!("  (image in the data partition at {base:#x}; offsets relative to it)");
+    }
+    for (name, offset, len) in &regions {
+        println!("  {name:<8} {offset:#010x} +{len}");
+    }
+    if bench {
+        print_bench(image, &regions);
+    }
+    if !panics {
+        return;
+    }
+    let Some((_, offset, len)) = regions.iter().find(|(name, _, _)| name == "panics") else {
+        eprintln!("Error: the image has no panics region (create it with --panic-region)");
+        process::exit(1);
+    };
+    let records: Vec<_> = image[*offset..offset + len]
+        .chunks_exact(PANIC_SLOT)
+        .filter(|slot| slot.starts_with(PANIC_MAGIC))
+        .collect();
+    if records.is_empty() {
+        println!("No crash records.");
+    }
+    for (i, slot) in records.iter().enumerate() {
+        print_panic(i + 1, slot);
+    }
+}
+
+/// Print crash record number `n` (see the guest's `crash.rs` for the
+/// layout).
+fn print_panic(n: usize, slot: &[u8]) {
+    let u32_at = |at: usize| u32::from_le_bytes(slot[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_le_bytes(slot[at..at + 8].try_into().unwrap());
+    let text = |bytes: &[u8]| {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    };
+    let (arch, [sp, fp, ra]) = match u32_at(0x04) {
+        1 => ("riscv64", ["sp", "s0", "ra"]),
+        2 => ("aarch64", ["sp", "x29", "x30"]),
+        3 => ("x86_64", ["rsp", "rbp", "rip"]),
+        4 => ("loongarch64", ["sp", "fp", "ra"]),
+        _ => ("unknown arch", ["sp", "fp", "ra"]),
+    };
+    let message_len = (u32_at(0x2C) as usize).min(0x100);
+    println!(
+        "Crash {n} ({arch}) at {}:{}:{}: {}",
+        text(&slot[0x30..0x70]),
+        u32_at(0x08),
+        u32_at(0x0C),
+        text(&slot[0x70..0x70 + message_len])
+    );
+    println!(
+        "  {sp} {:#018x}  {fp} {:#018x}  {ra} {:#018x}",
+        u64_at(0x10),
+        u64_at(0x18),
+        u64_at(0x20)
+    );
+    let words = (u32_at(0x28) as usize).min(32);
+    for row in (0..words).step_by(4) {
+        let line: Vec<_> = (row..(row + 4).min(words))
+            .map(|i| format!("{:016x}", u64_at(0x170 + i * 8)))
+            .collect();
+        println!("  {sp}+{:#05x}: {}", row * 8, line.join(" "));
+    }
+}
+
+/// Print the benchmark results in the bench region of `image` (see the
+/// guest's `benchlog.rs` for the layout), oldest first.
+fn print_bench(image: &[u8], regions: &[(String, usize, usize)]) {
+    let Some((_, offset, len)) = regions.iter().find(|(name, _, _)| name == "bench") else {
+        eprintln!("Error: the image has no bench region (create it with --bench-region)");
+        process::exit(1);
+    };
+    let records: Vec<_> = image[*offset..offset + len]
+        .chunks_exact(BENCH_SLOT)
+        .filter(|slot| slot.starts_with(BENCH_MAGIC))
+        .collect();
+    if records.is_empty() {
+        println!("No benchmark results.");
+        return;
+    }
+    println!("Benchmark results (write-combining, best pass):");
+    println!("  #    arch          span  passes  device MiB/s  relaxed MiB/s  ratio  counter");
+    for (i, slot) in records.iter().enumerate() {
+        let u32_at = |at: usize| u32::from_le_bytes(slot[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(slot[at..at + 8].try_into().unwrap());
+        let arch = match u32_at(0x04) {
+            1 => "riscv64",
+            2 => "aarch64",
+            3 => "x86_64",
+            4 => "loongarch64",
+            _ => "unknown",
+        };
+        let (device_ns, relaxed_ns) = (u64_at(0x10), u64_at(0x18));
+        let ratio = device_ns as f64 / relaxed_ns.max(1) as f64;
+        println!(
+            "  {:<4} {arch:<11} {:>5}K  {:>6}  {:>12}  {:>13}  {ratio:>4.2}x  {}",
+            i + 1,
+            u32_at(0x08) / 1024,
+            u32_at(0x0C),
+            u32_at(0x20),
+            u32_at(0x24),
+            u64_at(0x28)
+        );
+    }
+}
+
+/// Name, offset and length of every region in the manifest of `image`,
+/// read from `path`, parsed as the guest parses it.
+fn read_manifest(path: &Path, image: &[u8]) -> Vec<(String, usize, usize)> {
+    let regions = Header::parse(image).and_then(|header| {
+        Manifest::parse(image, &header)?
+            .regions()
+            .map(|r| r.map(|r| (r.name.to_string(), r.offset as usize, r.len as usize)))
+            .collect()
+    });
+    regions.unwrap_or_else(|e| {
+        eprintln!("Error: {}: {e}", path.display());
+        process::exit(1);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// `len` bytes that differ from their neighbours and from other
+    /// regions, so misplaced or overwritten data is noticed.
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| seed.wrapping_add((i % 251) as u8))
+            .collect()
+    }
+
+    /// Names of the generated layout file regions.
+    const CUSTOM_NAMES: [&str; 3] = ["calib", "scratch", "extra"];
+
+    /// A layout file region: with file data or erased, its length, an
+    /// explicit offset or none, an alignment, writability and a type tag.
+    /// Offsets are in 4096ths of the bank, past the first eighth so that
+    /// they do not always land on the built-in regions.
+    fn custom_region()
+    -> impl Strategy<Value = (bool, usize, Option<usize>, usize, bool, Option<Kind>)> {
+        (
+            any::<bool>(),
+            1usize..0x4_0000,
+            prop::option::of(0x200usize..0x1000),
+            prop::sample::select(vec![REGION_ALIGN, 0x1_0000, JOURNAL_ALIGN]),
+            any::<bool>(),
+            prop::option::of(prop::sample::select(Kind::ALL.to_vec())),
+        )
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(256))]
+
+        /// Any mix of inputs, reserved regions and layout file regions
+        /// either fails cleanly or yields disjoint regions inside the bank,
+        /// each holding its data.
+        #[test]
+        fn regions_are_disjoint_and_in_bounds(
+            size in prop::sample::select(vec![0x8_0000usize, 0x10_0000, 0x20_0000, 0x40_0000]),
+            payload in 0usize..0x6_0000,
+            fs in prop::option::of(0usize..0x10_0000),
+            xip in any::<bool>(),
+            fs_writable in any::<bool>(),
+            journal in any::<bool>(),
+            replicas in any::<bool>(),
+            log_ring in any::<bool>(),
+            panic_region in any::<bool>(),
+            bench_region in any::<bool>(),
+            crc in any::<bool>(),
+            header_endian in prop::option::of(prop::sample::select(vec![Endian::Little, Endian::Big])),
+            encrypt in any::<bool>(),
+            // Kernel offset in 4K pages; page 0 stands for an offset whose
+            // end overflows.
+            kernel in prop::option::of((0usize..0x480, 0usize..0x4_0000)),
+            firmware in prop::option::of(0usize..0x4_0000),
+            custom in prop::collection::vec(custom_region(), 0..=CUSTOM_NAMES.len()),
+        ) {
+            let mut contents = vec![("payload", pattern(1, payload))];
+            if let Some(len) = fs {
+                contents.push(("fs", pattern(2, len)));
+            }
+            if xip {
+                contents.push(("xip", pattern(3, XIP_HEADER_SIZE + 16)));
+            }
+            let kernel_in_flash = kernel.map(|(page, _)| match page {
+                0 => usize::MAX - (REGION_ALIGN - 1),
+                page => page * REGION_ALIGN,
+            });
+            let args = ImageArgs {
+                kernel_in_flash,
+                fs_writable,
+                journal,
+                replicas,
+                log_ring,
+                panic_region,
+                bench_region,
+                crc,
+                header_endian,
+                ..Default::default()
+            };
+            let custom: Vec<Custom> = custom
+                .into_iter()
+                .zip(CUSTOM_NAMES)
+                .enumerate()
+                .map(|(i, ((has_data, len, at, align, writable, kind), name))| {
+                    // The guest erases whole blocks of a writable region.
+                    let unit = if writable { JOURNAL_ALIGN } else { REGION_ALIGN };
+                    let len = if writable { len.next_multiple_of(unit) } else { len };
+                    Custom {
+                        name,
+                        data: has_data.then(|| pattern(7 + i as u8, len)),
+                        len,
+                        offset: at.map(|at| at * size / 0x1000 / unit * unit),
+                        align,
+                        writable,
+                        kind,
+                    }
+                })
+                .collect();
+            let expected: Vec<_> = custom
+                .iter()
+                .map(|c| (c.name, c.data.clone(), c.len, c.offset))
+                .collect();
+            let inputs = Inputs {
+                contents: contents.clone(),
+                custom,
+                kernel: pattern(4, kernel.map_or(0, |(_, len)| len)),
+                firmware: firmware.map(|len| pattern(5, len)),
+                key: encrypt.then_some([6; KEY_LEN]),
+            };
+
+            let (image, regions) = match build_image(size, &args, inputs) {
+                Ok(built) => built,
+                Err(e) => {
+                    prop_assert!(!e.is_empty());
+                    return Ok(());
+                }
+            };
+            prop_assert_eq!(image.len(), size);
+            let image = image.to_vec();
+            prop_assert_eq!(regions[0].name, "header");
+            prop_assert_eq!(regions[0].offset, 0);
+            prop_assert_eq!(regions[0].len, HEADER_SIZE + usize::from(encrypt) * CRYPT_FIELDS_SIZE);
+
+            let mut placed: Vec<&Region> = regions.iter().collect();
+            placed.sort_by_key(|r| (r.offset, r.len));
+            for pair in placed.windows(2) {
+                prop_assert!(
+                    pair[0].offset + pair[0].len <= pair[1].offset,
+                    "{} and {} overlap", pair[0].name, pair[1].name
+                );
+            }
+            for r in &regions {
+                prop_assert!(r.offset + r.len <= size, "{} ends past the bank", r.name);
+                prop_assert_eq!(r.sha256, sha256(&image[r.offset..r.offset + r.len]));
+            }
+            // The payload is stored encrypted.
+            for (name, data) in contents.iter().skip(usize::from(encrypt)) {
+                let r = regions.iter().find(|r| r.name == *name).unwrap();
+                prop_assert_eq!(&image[r.offset..r.offset + data.len()], &data[..]);
+            }
+
+            // Layout file regions hold their file or are erased, at their
+            // offset if they asked for one.
+            for (name, data, len, offset) in &expected {
+                let r = regions.iter().find(|r| r.name == *name).unwrap();
+                prop_assert_eq!(r.len, *len);
+                let placed = &image[r.offset..r.offset + r.len];
+                match data {
+                    Some(data) => prop_assert_eq!(placed, &data[..]),
+                    None => prop_assert!(placed.iter().all(|&b| b == 0xFF), "{} is not erased", name),
+                }
+                if let Some(offset) = offset {
+                    prop_assert_eq!(r.offset, *offset);
+                }
+            }
+
+            let listed = read_manifest(Path::new("pflash.img"), &image);
+            prop_assert_eq!(listed.len(), regions.len());
+            for (entry, r) in listed.iter().zip(&regions) {
+                prop_assert_eq!(entry, &(r.name.to_string(), r.offset, r.len));
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! The guest's JSON report and assertions on it (`xtask test --expect`).
+//!
+//! The guest's `json-report` feature ends its output with a line holding
+//! [`PREFIX`] and one JSON object (see the guest's `json.rs`). `xtask test`
+//! finds that line, parses it, and checks every `--expect` against it. An
+//! assertion is `<FIELD> <OP> <VALUE>`:
+//!
+//! - `<FIELD>` is a dotted path into the object; array elements are numbered
+//!   from 0, and `len` of an array is its length, e.g. `crc_ok`,
+//!   `bench.device_mibs`, `regions.0.name`, `regions.len`;
+//! - `<OP>` is one of `==`, `!=`, `<`, `<=`, `>`, `>=` (the orderings need
+//!   numbers on both sides);
+//! - `<VALUE>` is a JSON literal: `true`, `false`, `null`, a number or a
+//!   `"string"` (a bare word is taken as a string).
+//!
+//! Each failed assertion is reported with the value the guest printed.
+
+use std::fmt;
+
+/// Start of the report line; `PREFIX` in the guest's `json.rs` must match.
+pub const PREFIX: &str = "PFlash JSON: ";
+
+/// A parsed JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write!(f, "{}", crate::json_string(s)),
+            Self::Array(items) => write!(f, "an array of {}", items.len()),
+            Self::Object(_) => write!(f, "an object"),
+        }
+    }
+}
+
+/// A recursive-descent parser over the text of one JSON value.
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_space(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn error(&self, what: &str) -> String {
+        format!("{what} at byte {}", self.pos)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_space();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{c}'")))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_space();
+        let rest = &self.text[self.pos..];
+        for (word, value) in [
+            ("null", Value::Null),
+            ("true", Value::Bool(true)),
+            ("false", Value::Bool(false)),
+        ] {
+            if rest.starts_with(word) {
+                self.pos += word.len();
+                return Ok(value);
+            }
+        }
+        match self.peek() {
+            Some('"') => self.string().map(Value::String),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_space();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_space();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(']') => {
+                            self.pos += 1;
+                            return Ok(Value::Array(items));
+                        }
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.skip_space();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    self.skip_space();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_space();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some('}') => {
+                            self.pos += 1;
+                            return Ok(Value::Object(fields));
+                        }
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+                    .unwrap_or(rest.len());
+                let number = rest[..len]
+                    .parse()
+                    .map_err(|_| self.error("malformed number"))?;
+                self.pos += len;
+                Ok(Value::Number(number))
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.peek() != Some('"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        let code = u32::from_str_radix(&hex, 16)
+                            .map_err(|_| self.error("malformed \\u escape"))?;
+                        out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    Some(c) => out.push(c),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+}
+
+/// Parse `text` as one JSON value.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { text, pos: 0 };
+    let value = parser.value()?;
+    parser.skip_space();
+    if parser.pos != text.len() {
+        return Err(parser.error("trailing text"));
+    }
+    Ok(value)
+}
+
+/// The report in the serial `output` of a run: the last line starting with
+/// [`PREFIX`], parsed. `Ok(None)` if there is none.
+pub fn report(output: &str) -> Result<Option<Value>, String> {
+    let Some(line) = output
+        .lines()
+        .rev()
+        .find_map(|line| line.trim_end().strip_prefix(PREFIX))
+    else {
+        return Ok(None);
+    };
+    parse(line)
+        .map(Some)
+        .map_err(|e| format!("the JSON report does not parse: {e}"))
+}
+
+/// A comparison operator of an assertion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    const ALL: [(&'static str, Op); 6] = [
+        ("==", Op::Eq),
+        ("!=", Op::Ne),
+        ("<=", Op::Le),
+        (">=", Op::Ge),
+        ("<", Op::Lt),
+        (">", Op::Gt),
+    ];
+}
+
+/// One `--expect` assertion.
+#[derive(Clone, Debug)]
+pub struct Assertion {
+    text: String,
+    path: Vec<String>,
+    op: Op,
+    value: Value,
+}
+
+/// Parse an assertion: `<FIELD> <OP> <VALUE>`.
+pub fn parse_assertion(s: &str) -> Result<Assertion, String> {
+    let Some((at, symbol, op)) = Op::ALL
+        .iter()
+        .filter_map(|&(symbol, op)| s.find(symbol).map(|at| (at, symbol, op)))
+        .min_by_key(|&(at, symbol, _)| (at, usize::MAX - symbol.len()))
+    else {
+        return Err(format!(
+            "'{s}' has no comparison; expected <FIELD> <OP> <VALUE> with OP one of == != < <= > >="
+        ));
+    };
+    let field = s[..at].trim();
+    let literal = s[at + symbol.len()..].trim();
+    if field.is_empty() || literal.is_empty() {
+        return Err(format!("'{s}' is not <FIELD> <OP> <VALUE>"));
+    }
+    let value = parse(literal).unwrap_or_else(|_| Value::String(literal.into()));
+    if matches!(op, Op::Lt | Op::Le | Op::Gt | Op::Ge) && !matches!(value, Value::Number(_)) {
+        return Err(format!("'{s}': {symbol} needs a number on the right"));
+    }
+    Ok(Assertion {
+        text: s.trim().into(),
+        path: field.split('.').map(String::from).collect(),
+        op,
+        value,
+    })
+}
+
+/// The value at `path` in `report`, or a description of where the path
+/// leaves it.
+fn lookup(report: &Value, path: &[String]) -> Result<Value, String> {
+    let mut value = report;
+    for (depth, key) in path.iter().enumerate() {
+        let here = || path[..depth].join(".");
+        value = match value {
+            Value::Object(fields) => match fields.iter().find(|(name, _)| name == key) {
+                Some((_, value)) => value,
+                None if depth == 0 => return Err(format!("the report has no field '{key}'")),
+                None => return Err(format!("{} has no field '{key}'", here())),
+            },
+            Value::Array(items) if key == "len" => return Ok(Value::Number(items.len() as f64)),
+            Value::Array(items) => match key.parse::<usize>().ok().and_then(|i| items.get(i)) {
+                Some(value) => value,
+                None => {
+                    return Err(format!(
+                        "{} has {} element(s), no '{key}'",
+                        here(),
+                        items.len()
+                    ));
+                }
+            },
+            other => return Err(format!("{} is {other}", here())),
+        };
+    }
+    Ok(value.clone())
+}
+
+impl Assertion {
+    /// Check the assertion against `report`, describing a failure.
+    fn check(&self, report: &Value) -> Result<(), String> {
+        let actual = lookup(report, &self.path).map_err(|e| format!("{}: {e}", self.text))?;
+        let holds = match (self.op, &actual, &self.value) {
+            (Op::Eq, actual, expected) => actual == expected,
+            (Op::Ne, actual, expected) => actual != expected,
+            (op, Value::Number(a), Value::Number(b)) => match op {
+                Op::Lt => a < b,
+                Op::Le => a <= b,
+                Op::Gt => a > b,
+                _ => a >= b,
+            },
+            _ => false,
+        };
+        if holds {
+            Ok(())
+        } else {
+            Err(format!("{}: got {actual}", self.text))
+        }
+    }
+}
+
+/// Check `assertions` against the report in the serial `output` of a run.
+///
+/// Without assertions every output passes; with them, one without a report
+/// fails. The message lists every assertion that does not hold.
+pub fn check(output: &str, assertions: &[Assertion]) -> Result<(), String> {
+    if assertions.is_empty() {
+        return Ok(());
+    }
+    let Some(report) = report(output)? else {
+        return Err(format!(
+            "no '{}' line in the output (the guest needs the json-report feature)",
+            PREFIX.trim_end()
+        ));
+    };
+    let failed: Vec<String> = assertions
+        .iter()
+        .filter_map(|assertion| assertion.check(&report).err())
+        .collect();
+    if failed.is_empty() {
+        println!("JSON report: {} assertion(s) hold", assertions.len());
+        Ok(())
+    } else {
+        for failure in &failed {
+            println!("  expected {failure}");
+        }
+        Err(format!(
+            "{} of {} JSON assertion(s) failed: {}",
+            failed.len(),
+            assertions.len(),
+            failed.join("; ")
+        ))
+    }
+}
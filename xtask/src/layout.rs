@@ -0,0 +1,391 @@
+//! Declarative image layouts (`mkimage --layout <FILE>`).
+//!
+//! A layout file describes the image in a small TOML subset, so its
+//! contents can be checked in and reviewed instead of living in command
+//! lines. Top-level keys are the `mkimage` options of the same name; a
+//! `[meta]` table holds the `--meta` pairs; every `[[region]]` table adds a
+//! named region of its own:
+//!
+//! ```toml
+//! payload = "data/payload.bin"    # paths are relative to this file
+//! banner = "nightly, relaxed mapping"
+//! journal = true
+//!
+//! [meta]
+//! experiment = "wc-07"
+//!
+//! [[region]]
+//! name = "calib"                  # up to 16 bytes
+//! file = "data/calib.bin"         # or `size = 0x40000` for erased space
+//! offset = 0x200000               # optional, else the next `align` boundary
+//! writable = false                # default: false for files, true if erased
+//! type = "dtb"                    # manifest type tag, else told from the file
+//! ```
+//!
+//! Values are strings, integers (decimal or `0x` hex, `_` allowed) and
+//! booleans. Options given on the command line win over the file. The size
+//! budgets in `xtask.toml` are read with the same [`lines`].
+
+use crate::image::{Endian, ImageArgs, Kind, NAME_LEN, REGION_ALIGN, parse_kind, parse_offset};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A `[[region]]` of a layout file.
+#[derive(Clone)]
+pub struct LayoutRegion {
+    /// Region names live as long as the xtask process, like the built-in
+    /// ones.
+    pub name: &'static str,
+    pub contents: Contents,
+    /// Exact offset, if given; otherwise the region follows the previous
+    /// one on an `align` boundary.
+    pub offset: Option<usize>,
+    pub align: usize,
+    pub writable: bool,
+    /// Type tag for the manifest, if given; otherwise it is told from the
+    /// contents.
+    pub kind: Option<Kind>,
+}
+
+/// What a [`LayoutRegion`] holds.
+#[derive(Clone)]
+pub enum Contents {
+    File(PathBuf),
+    /// This many erased bytes.
+    Erased(usize),
+}
+
+/// A value of a TOML-subset file.
+pub enum Value {
+    Str(String),
+    Int(usize),
+    Bool(bool),
+}
+
+/// Parse one value: a basic string, an integer or a boolean.
+fn parse_value(text: &str) -> Result<Value, String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    let tail = chars.as_str().trim();
+                    if !tail.is_empty() && !tail.starts_with('#') {
+                        return Err(format!("unexpected '{tail}' after the string"));
+                    }
+                    return Ok(Value::Str(out));
+                }
+                '\\' => match chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some(c @ ('"' | '\\')) => out.push(c),
+                    other => {
+                        return Err(format!("unsupported escape '\\{}'", other.unwrap_or(' ')));
+                    }
+                },
+                c => out.push(c),
+            }
+        }
+        return Err("unterminated string".into());
+    }
+    let text = text.split('#').next().unwrap_or("").trim();
+    match text {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        "" => Err("missing value".into()),
+        _ => parse_offset(text).map(Value::Int),
+    }
+}
+
+/// One line of a TOML-subset file that is not blank or a comment.
+pub enum Line<'a> {
+    /// `[name]`, or `[[name]]` for an element of an array of tables.
+    Table { name: &'a str, array: bool },
+    /// `key = value`, with the key unquoted.
+    Key(&'a str, Value),
+}
+
+/// The lines of the TOML-subset file `text` with their numbers, or an
+/// error naming the line that does not parse.
+pub fn lines(text: &str) -> impl Iterator<Item = Result<(usize, Line<'_>), String>> {
+    text.lines().enumerate().filter_map(|(n, line)| {
+        let n = n + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let header = line.split('#').next().unwrap_or("").trim();
+        let table = |open: &str, close: &str| {
+            let name = header.strip_prefix(open)?.strip_suffix(close)?;
+            Some(name.trim())
+        };
+        if let Some(name) = table("[[", "]]") {
+            return Some(Ok((n, Line::Table { name, array: true })));
+        }
+        if let Some(name) = table("[", "]") {
+            return Some(Ok((n, Line::Table { name, array: false })));
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Some(Err(format!("line {n}: expected key = value")));
+        };
+        let key = key.trim().trim_matches('"');
+        Some(
+            parse_value(value.trim())
+                .map(|value| (n, Line::Key(key, value)))
+                .map_err(|e| format!("line {n}: {e}")),
+        )
+    })
+}
+
+/// Which table the following keys belong to.
+enum Table {
+    Top,
+    Meta,
+    Region(usize),
+}
+
+/// A `[[region]]` as written, before its keys are checked.
+#[derive(Default)]
+struct RawRegion {
+    line: usize,
+    name: Option<String>,
+    file: Option<PathBuf>,
+    size: Option<usize>,
+    offset: Option<usize>,
+    align: Option<usize>,
+    writable: Option<bool>,
+    kind: Option<Kind>,
+}
+
+/// The image options and regions of the layout file `text`, with paths
+/// resolved against `base`.
+fn parse(text: &str, base: &Path) -> Result<(ImageArgs, Vec<LayoutRegion>), String> {
+    let mut args = ImageArgs::default();
+    let mut raw: Vec<RawRegion> = Vec::new();
+    let mut table = Table::Top;
+    for line in lines(text) {
+        let (n, line) = line?;
+        let at = |e: String| format!("line {n}: {e}");
+        let (key, value) = match line {
+            Line::Table {
+                name: "region",
+                array: true,
+            } => {
+                raw.push(RawRegion {
+                    line: n,
+                    ..Default::default()
+                });
+                table = Table::Region(raw.len() - 1);
+                continue;
+            }
+            Line::Table {
+                name: "meta",
+                array: false,
+            } => {
+                table = Table::Meta;
+                continue;
+            }
+            Line::Table { name, array } => {
+                let header = if array {
+                    format!("[[{name}]]")
+                } else {
+                    format!("[{name}]")
+                };
+                return Err(at(format!(
+                    "unknown table {header} (expected [meta] or [[region]])"
+                )));
+            }
+            Line::Key(key, value) => (key, value),
+        };
+        let path = |value: Value| match value {
+            Value::Str(s) => Ok(base.join(s)),
+            _ => Err(at(format!("{key} must be a string"))),
+        };
+        let string = |value: Value| match value {
+            Value::Str(s) => Ok(s),
+            _ => Err(at(format!("{key} must be a string"))),
+        };
+        let flag = |value: Value| match value {
+            Value::Bool(b) => Ok(b),
+            _ => Err(at(format!("{key} must be true or false"))),
+        };
+        let int = |value: Value| match value {
+            Value::Int(i) => Ok(i),
+            _ => Err(at(format!("{key} must be an integer"))),
+        };
+        match table {
+            Table::Meta => {
+                let value = string(value)?;
+                if key.is_empty() || value.contains(['\n', '\r']) {
+                    return Err(at(format!("bad meta entry '{key}'")));
+                }
+                args.meta.push(format!("{key}={value}"));
+            }
+            Table::Region(i) => {
+                let region = &mut raw[i];
+                match key {
+                    "name" => region.name = Some(string(value)?),
+                    "file" => region.file = Some(path(value)?),
+                    "size" => region.size = Some(int(value)?),
+                    "offset" => region.offset = Some(int(value)?),
+                    "align" => region.align = Some(int(value)?),
+                    "writable" => region.writable = Some(flag(value)?),
+                    "type" => region.kind = Some(parse_kind(&string(value)?).map_err(at)?),
+                    _ => return Err(at(format!("unknown region key '{key}'"))),
+                }
+            }
+            Table::Top => match key {
+                "payload" => args.payload = Some(path(value)?),
+                "pattern" => {
+                    let spec = string(value)?;
+                    args.pattern = Some(crate::image::parse_pattern(&spec).map_err(at)?);
+                }
+                "payload-type" => {
+                    args.payload_type = Some(parse_kind(&string(value)?).map_err(at)?);
+                }
+                "fs" => args.fs = Some(path(value)?),
+                "encrypt" => args.encrypt = Some(path(value)?),
+                "romfs" => args.romfs = Some(path(value)?),
+                "ext2" => args.ext2 = Some(path(value)?),
+                "kernel-in-flash" => args.kernel_in_flash = Some(int(value)?),
+                "xip" => args.xip = flag(value)?,
+                "flash-script" => args.flash_script = Some(path(value)?),
+                "banner" => {
+                    // A file next to the layout, or the text itself.
+                    let banner = string(value)?;
+                    args.banner = Some(match base.join(&banner) {
+                        file if file.is_file() => file.display().to_string(),
+                        _ => banner,
+                    });
+                }
+                "messages" => args.messages = Some(path(value)?),
+                "fs-writable" => args.fs_writable = flag(value)?,
+                "journal" => args.journal = flag(value)?,
+                "replicas" => args.replicas = flag(value)?,
+                "log-ring" => args.log_ring = flag(value)?,
+                "panic-region" => args.panic_region = flag(value)?,
+                "bench-region" => args.bench_region = flag(value)?,
+                "crc" => args.crc = flag(value)?,
+                "header-endian" => {
+                    args.header_endian = Some(match string(value)?.as_str() {
+                        "little" => Endian::Little,
+                        "big" => Endian::Big,
+                        other => {
+                            return Err(at(format!(
+                                "header-endian must be \"little\" or \"big\", not '{other}'"
+                            )));
+                        }
+                    });
+                }
+                "flash-geometry" => {
+                    let spec = string(value)?;
+                    args.flash_geometry = Some(crate::image::parse_geometry(&spec).map_err(at)?);
+                }
+                _ => return Err(at(format!("unknown option '{key}'"))),
+            },
+        }
+    }
+
+    let mut regions: Vec<LayoutRegion> = Vec::new();
+    for region in raw {
+        let at = |e: String| format!("region at line {}: {e}", region.line);
+        let Some(name) = region.name else {
+            return Err(at("missing name".into()));
+        };
+        if name.is_empty() || name.len() > NAME_LEN {
+            return Err(at(format!("name '{name}' must be 1 to {NAME_LEN} bytes")));
+        }
+        if regions.iter().any(|r| r.name == name) {
+            return Err(at(format!("name '{name}' is used twice")));
+        }
+        let contents = match (region.file, region.size) {
+            (Some(file), None) => Contents::File(file),
+            (None, Some(size)) if size > 0 => Contents::Erased(size),
+            (None, Some(_)) => return Err(at("size must not be zero".into())),
+            _ => return Err(at("needs exactly one of file and size".into())),
+        };
+        let align = region.align.unwrap_or(REGION_ALIGN);
+        if !align.is_power_of_two() || align < REGION_ALIGN {
+            return Err(at(format!(
+                "align {align:#x} must be a power of two of at least {REGION_ALIGN:#x}"
+            )));
+        }
+        if let Some(offset) = region.offset
+            && offset % REGION_ALIGN != 0
+        {
+            return Err(at(format!(
+                "offset {offset:#x} must be {REGION_ALIGN:#x}-aligned"
+            )));
+        }
+        let writable = region
+            .writable
+            .unwrap_or(matches!(contents, Contents::Erased(_)));
+        regions.push(LayoutRegion {
+            name: name.leak(),
+            contents,
+            offset: region.offset,
+            align,
+            writable,
+            kind: region.kind,
+        });
+    }
+    Ok((args, regions))
+}
+
+/// `args` with the layout file named by `--layout` (if any) merged in:
+/// options given on the command line take precedence, flags set in either
+/// place are set, and `--meta` pairs follow those of the file.
+pub fn apply(args: &ImageArgs) -> ImageArgs {
+    let Some(path) = &args.layout else {
+        return args.clone();
+    };
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Error: cannot read layout {}: {e}", path.display());
+        process::exit(1);
+    });
+    let base = path.parent().unwrap_or(Path::new("."));
+    let (file, regions) = parse(&text, base).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {e}", path.display());
+        process::exit(1);
+    });
+    // The file's payload and fs sources only apply if the command line
+    // names none.
+    let (payload, pattern) = if args.payload.is_some() || args.pattern.is_some() {
+        (args.payload.clone(), args.pattern)
+    } else {
+        (file.payload, file.pattern)
+    };
+    let payload_type = args.payload_type.or(file.payload_type);
+    let (fs, romfs, ext2) = if args.fs.is_some() || args.romfs.is_some() || args.ext2.is_some() {
+        (args.fs.clone(), args.romfs.clone(), args.ext2.clone())
+    } else {
+        (file.fs, file.romfs, file.ext2)
+    };
+    ImageArgs {
+        payload,
+        pattern,
+        payload_type,
+        fs,
+        romfs,
+        ext2,
+        kernel_in_flash: args.kernel_in_flash.or(file.kernel_in_flash),
+        xip: args.xip || file.xip,
+        flash_script: args.flash_script.clone().or(file.flash_script),
+        meta: file.meta.into_iter().chain(args.meta.clone()).collect(),
+        banner: args.banner.clone().or(file.banner),
+        messages: args.messages.clone().or(file.messages),
+        fs_writable: args.fs_writable || file.fs_writable,
+        journal: args.journal || file.journal,
+        replicas: args.replicas || file.replicas,
+        log_ring: args.log_ring || file.log_ring,
+        panic_region: args.panic_region || file.panic_region,
+        bench_region: args.bench_region || file.bench_region,
+        crc: args.crc || file.crc,
+        header_endian: args.header_endian.or(file.header_endian),
+        flash_geometry: args.flash_geometry.or(file.flash_geometry),
+        encrypt: args.encrypt.clone().or(file.encrypt),
+        regions,
+        ..args.clone()
+    }
+}
@@ -1,6 +1,112 @@
-use clap::{Parser, Subcommand};
+mod accel;
+mod bench;
+mod ci;
+mod compare;
+mod daemon;
+mod device;
+mod diagnose;
+mod genconfig;
+mod image;
+mod json;
+mod layout;
+mod measure;
+mod mode;
+mod partition;
+mod qmp;
+mod romfs;
+mod runs;
+mod settings;
+mod shell;
+mod size;
+mod snapshot;
+mod verify;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use image::{FlashGeometry, ImageArgs, create_firmware_image, create_pflash_image, pflash_size};
+use readpflash_layout::banks::{self, Bank};
+use std::fmt;
 use std::path::{Path, PathBuf};
-use std::process::{self, Command};
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// A supported architecture, with the other names other tools use for it.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Arch {
+    #[value(name = "riscv64", aliases = ["rv64", "riscv", "riscv64gc"])]
+    Riscv64,
+    #[value(name = "aarch64", aliases = ["arm64", "armv8"])]
+    Aarch64,
+    #[value(name = "x86_64", aliases = ["x86", "amd64", "x64", "x86-64"])]
+    X86_64,
+    #[value(name = "loongarch64", aliases = ["la64", "loongarch", "loong64"])]
+    Loongarch64,
+}
+
+impl Arch {
+    /// Every supported architecture, for commands that cover them all.
+    const ALL: [Arch; 4] = [
+        Arch::Riscv64,
+        Arch::Aarch64,
+        Arch::X86_64,
+        Arch::Loongarch64,
+    ];
+
+    /// Canonical name, as used in file names and QEMU binaries.
+    fn name(self) -> &'static str {
+        match self {
+            Arch::Riscv64 => "riscv64",
+            Arch::Aarch64 => "aarch64",
+            Arch::X86_64 => "x86_64",
+            Arch::Loongarch64 => "loongarch64",
+        }
+    }
+
+    /// The flash bank the image goes in on the default machine.
+    fn bank(self) -> Bank {
+        match self {
+            Arch::Riscv64 => banks::RISCV64,
+            Arch::Aarch64 => banks::AARCH64,
+            Arch::X86_64 => banks::X86_64,
+            Arch::Loongarch64 => banks::LOONGARCH64,
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// `--arch` of the commands that can cover every architecture at once.
+#[derive(Clone, Copy)]
+enum ArchSet {
+    All,
+    One(Arch),
+}
+
+impl ArchSet {
+    fn archs(self) -> Vec<Arch> {
+        match self {
+            ArchSet::All => Arch::ALL.to_vec(),
+            ArchSet::One(arch) => vec![arch],
+        }
+    }
+}
+
+/// Parse `--arch`: `all`, or an architecture name or alias.
+fn parse_arch_set(s: &str) -> Result<ArchSet, String> {
+    if s.eq_ignore_ascii_case("all") {
+        return Ok(ArchSet::All);
+    }
+    Arch::from_str(s, true).map(ArchSet::One).map_err(|_| {
+        let names: Vec<&str> = Arch::ALL.iter().map(|arch| arch.name()).collect();
+        format!(
+            "unsupported architecture '{s}', expected all or one of {}",
+            names.join(", ")
+        )
+    })
+}
 
 /// ArceOS readpflash multi-architecture build & run tool
 #[derive(Parser)]
@@ -13,71 +119,846 @@ struct Cli {
     command: Cmd,
 }
 
+// Parsed once per invocation, so the size of `Run` doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Cmd {
     /// Build the kernel for a given architecture
     Build {
-        /// Target architecture: riscv64, aarch64, x86_64, loongarch64
-        #[arg(long, default_value = "riscv64")]
-        arch: String,
+        /// Target architecture, or `all` to build each in turn (aliases
+        /// such as rv64, arm64, amd64 and la64 are accepted too)
+        #[arg(long, default_value = "riscv64", value_parser = parse_arch_set)]
+        arch: ArchSet,
+        /// Extra cargo features for the kernel, e.g. `verify`
+        #[arg(long)]
+        features: Option<String>,
+        /// Build without the `paging` feature; the app reads the bank
+        /// through the boot page table
+        #[arg(long)]
+        no_paging: bool,
+        #[command(flatten)]
+        link: LinkArgs,
+    },
+    /// Build the kernel and print the size of the stripped ELF and of the
+    /// raw binary against their budgets in `xtask.toml`
+    Size {
+        /// Target architecture, or `all` to measure each in turn (aliases
+        /// such as rv64, arm64, amd64 and la64 are accepted too)
+        #[arg(long, default_value = "riscv64", value_parser = parse_arch_set)]
+        arch: ArchSet,
+        /// Extra cargo features for the kernel, e.g. `verify`
+        #[arg(long)]
+        features: Option<String>,
+        /// Build without the `paging` feature
+        #[arg(long)]
+        no_paging: bool,
+        /// Fail if a figure is over its budget
+        #[arg(long)]
+        check: bool,
+    },
+    /// Create the PFlash image (with its SHA-256 manifest) without building
+    Mkimage {
+        /// Target architecture (aliases such as rv64, arm64, amd64 and la64
+        /// are accepted too)
+        #[arg(long, default_value = "riscv64", ignore_case = true)]
+        arch: Arch,
+        #[command(flatten)]
+        image: ImageArgs,
+        /// Kernel form to embed with `--kernel-in-flash` (the arch's
+        /// default for `run` if omitted)
+        #[arg(long, value_enum)]
+        boot_artifact: Option<BootArtifact>,
+    },
+    /// Build the kernel and the pflash image and write both to a board
+    /// through a debug probe (probe-rs or openocd)
+    FlashDevice {
+        /// Target architecture (aliases such as rv64, arm64 and la64 are
+        /// accepted too)
+        #[arg(long, default_value = "riscv64", ignore_case = true)]
+        arch: Arch,
+        /// Debug probe: `probe-rs:<CHIP>[@<VID:PID[:SERIAL]>]` or
+        /// `openocd:<CFG>[,<CFG>...]`
+        #[arg(long, value_name = "SPEC", value_parser = device::parse_probe)]
+        probe: device::Probe,
+        /// axconfig of the board, with its `pflash-paddr` and `pflash-size`
+        /// [default: configs/<ARCH>.toml]
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+        /// Extra cargo features for the kernel, e.g. `verify`
+        #[arg(long)]
+        features: Option<String>,
+        /// Print the probe commands instead of running them
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        image: ImageArgs,
+    },
+    /// Run the app and compare its normalized serial output with the
+    /// golden files in `tests/snapshots/`
+    Test {
+        /// Architecture to test, or `all`
+        #[arg(long, default_value = "all", value_parser = parse_arch_set)]
+        arch: ArchSet,
+        /// Cargo features for the kernel
+        #[arg(long, default_value = "verify,identify")]
+        features: String,
+        /// Rewrite the snapshots with the output of this run
+        #[arg(long)]
+        update_snapshots: bool,
+        /// Seconds allowed for each build and run
+        #[arg(long, default_value_t = 900)]
+        timeout: u64,
+        /// Rerun an architecture whose run timed out or crashed up to N more
+        /// times; one that passes on a rerun is reported as flaky rather than
+        /// failed. Failed checks of a finished run are not retried
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        retries: u32,
+        /// Guest RAM sizes to test each architecture with, e.g. `128M,512M`
+        #[arg(
+            long,
+            value_name = "SIZES",
+            value_delimiter = ',',
+            default_value = "128M"
+        )]
+        mem: Vec<String>,
+        /// CPU counts to test each architecture with, e.g. `1,4`
+        #[arg(
+            long,
+            value_name = "COUNTS",
+            value_delimiter = ',',
+            default_value = "1"
+        )]
+        smp: Vec<usize>,
+        /// Assertion on the app's JSON report, e.g. `crc_ok == true` or
+        /// `bench.device_mibs > 100`; repeatable, and adds the
+        /// `json-report` feature
+        #[arg(long, value_name = "ASSERTION", value_parser = json::parse_assertion)]
+        expect: Vec<json::Assertion>,
+    },
+    /// Run the same scenario on several architectures and diff their
+    /// normalized outputs, flagging behaviour that differs between them
+    Compare {
+        /// Architectures to compare, comma-separated; the first is the
+        /// reference
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "riscv64,aarch64,x86_64,loongarch64",
+            ignore_case = true
+        )]
+        archs: Vec<Arch>,
+        /// Cargo features for the kernel
+        #[arg(long, default_value = "verify,identify")]
+        features: String,
+        /// Leave out lines containing this text, for output known to differ
+        /// between architectures (repeatable)
+        #[arg(long, value_name = "TEXT")]
+        ignore: Vec<String>,
+        /// Seconds allowed for each build and run
+        #[arg(long, default_value_t = 900)]
+        timeout: u64,
+        /// Further `run` options, the same for every architecture (after
+        /// `--`)
+        #[arg(last = true, value_name = "RUN ARGS")]
+        run_args: Vec<String>,
+    },
+    /// Run the write-combining benchmark repeatedly and print the mean,
+    /// standard deviation and outliers of each figure
+    Bench {
+        /// Target architecture (aliases such as rv64, arm64, amd64 and la64
+        /// are accepted too)
+        #[arg(long, default_value = "riscv64", ignore_case = true)]
+        arch: Arch,
+        /// Further cargo features for the kernel, besides write-combining
+        /// and json-report
+        #[arg(long, default_value = "")]
+        features: String,
+        /// Measured runs
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        runs: usize,
+        /// Runs before the measured ones whose figures are thrown away
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        warmup: usize,
+        /// Append the statistics, with the date, git revision and QEMU
+        /// version, to a `.csv` or `.json` (JSON Lines) file
+        #[arg(long, value_name = "FILE")]
+        export: Option<PathBuf>,
+        /// Seconds allowed for each build and run
+        #[arg(long, default_value_t = 900)]
+        timeout: u64,
+        /// Further `run` options (after `--`)
+        #[arg(last = true, value_name = "RUN ARGS")]
+        run_args: Vec<String>,
+    },
+    /// Check the tools, then build and run every architecture, writing
+    /// JUnit and JSON reports
+    Ci {
+        /// Architecture to check, or `all`
+        #[arg(long, default_value = "all", value_parser = parse_arch_set)]
+        arch: ArchSet,
+        /// Cargo features for the kernel
+        #[arg(long, default_value = "verify,identify")]
+        features: String,
+        /// A line each run must print, besides the pflash magic (repeatable)
+        #[arg(long, value_name = "LINE")]
+        expect: Vec<String>,
+        /// Seconds allowed for each build and each run
+        #[arg(long, default_value_t = 900)]
+        timeout: u64,
+        /// Directory for `junit.xml` and `report.json`
+        #[arg(long, default_value = "target/ci")]
+        report_dir: PathBuf,
+    },
+    /// Boot the app with its flash command shell and drive it from a
+    /// script or standard input over a serial socket
+    Shell {
+        /// Target architecture (aliases such as rv64, arm64, amd64 and la64
+        /// are accepted too)
+        #[arg(long, default_value = "riscv64", ignore_case = true)]
+        arch: Arch,
+        /// Extra cargo features for the kernel (`shell` is always added)
+        #[arg(long)]
+        features: Option<String>,
+        /// Commands to send, one per line (`#` starts a comment); read
+        /// from standard input if omitted
+        #[arg(long, value_name = "FILE")]
+        script: Option<PathBuf>,
+        /// Local TCP port for the serial socket (a free one if omitted)
+        #[arg(long)]
+        port: Option<u16>,
+        /// Seconds allowed for the build and boot, and for each reply
+        #[arg(long, default_value_t = 900)]
+        timeout: u64,
+    },
+    /// Boot the app with its flash command shell, have it stream part of
+    /// the bank as base64 over the serial socket, and write the bytes to a
+    /// host file
+    CaptureDump {
+        /// Target architecture (aliases such as rv64, arm64, amd64 and la64
+        /// are accepted too)
+        #[arg(long, default_value = "riscv64", ignore_case = true)]
+        arch: Arch,
+        /// Extra cargo features for the kernel (`shell` is always added)
+        #[arg(long)]
+        features: Option<String>,
+        /// Bank offset of the first byte, e.g. `0x40000`
+        #[arg(long, default_value = "0", value_parser = image::parse_offset)]
+        offset: usize,
+        /// Bytes to capture (the rest of the bank if omitted)
+        #[arg(long, value_parser = image::parse_offset)]
+        len: Option<usize>,
+        /// Host file to write the bytes to
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+        /// Local TCP port for the serial socket (a free one if omitted)
+        #[arg(long)]
+        port: Option<u16>,
+        /// Seconds allowed for the build and boot, and for each line of
+        /// the dump
+        #[arg(long, default_value_t = 900)]
+        timeout: u64,
+    },
+    /// List the supported architectures with their platform and flash bank
+    List,
+    /// Render configs/<ARCH>.toml from configs/template.toml and
+    /// configs/params.toml
+    GenConfigs {
+        /// Only check that the rendered files are up to date
+        #[arg(long)]
+        check: bool,
+    },
+    /// List the runs started with `run --daemon` and whether they are still
+    /// running
+    Status,
+    /// Browse the archive of past runs in `runs/`
+    Runs {
+        #[command(subcommand)]
+        action: RunsCmd,
+    },
+    /// After a run that wrote to the bank, compare its image with the copy
+    /// taken as it started: check the manifest digests, list the erase
+    /// blocks that changed and cross-check them with the writes the guest
+    /// reported (`--features write-trace`)
+    VerifyRun {
+        /// Run directory name, or a prefix of it such as a date (the
+        /// latest matching run); the latest run that kept its image if
+        /// omitted
+        run: Option<String>,
+    },
+    /// Stop runs started with `run --daemon` (QMP `quit`, then a kill)
+    Stop {
+        /// Architecture whose run to stop, or `all`
+        #[arg(long, default_value = "all", value_parser = parse_arch_set)]
+        arch: ArchSet,
+    },
+    /// Print every setting a run with these flags would use
+    Env {
+        /// Target architecture (aliases such as rv64, arm64, amd64 and la64
+        /// are accepted too)
+        #[arg(long, default_value = "riscv64", ignore_case = true)]
+        arch: Arch,
+        /// Cargo features for the kernel
+        #[arg(long)]
+        features: Option<String>,
+        /// QEMU `-machine` override
+        #[arg(long)]
+        machine: Option<String>,
+        /// riscv64 firmware: `default`, `none`, `flash` or a file
+        #[arg(long)]
+        bios: Option<String>,
+        /// Guest RAM size
+        #[arg(long, value_name = "SIZE", default_value = "128M")]
+        mem: String,
+        /// CPUs: a count or a topology
+        #[arg(long, value_name = "SPEC", default_value = "1")]
+        smp: String,
+        /// Kernel form for `-kernel`: `elf` or `bin`
+        #[arg(long, value_enum)]
+        boot_artifact: Option<BootArtifact>,
+    },
+    /// Inspect or modify an existing PFlash image
+    Image {
+        #[command(subcommand)]
+        action: ImageCmd,
     },
     /// Build and run the kernel in QEMU
     Run {
-        /// Target architecture: riscv64, aarch64, x86_64, loongarch64
-        #[arg(long, default_value = "riscv64")]
-        arch: String,
+        /// Target architecture, or `all` to run each in turn (aliases such
+        /// as rv64, arm64, amd64 and la64 are accepted too)
+        #[arg(long, default_value = "riscv64", value_parser = parse_arch_set)]
+        arch: ArchSet,
+        #[command(flatten)]
+        image: ImageArgs,
+        /// Extra cargo features for the kernel, e.g. `verify`
+        #[arg(long)]
+        features: Option<String>,
+        /// Build the demos of one lesson and set up the image, bank and
+        /// command line they need
+        #[arg(long, value_enum)]
+        mode: Option<mode::Mode>,
+        /// Firmware for riscv64 `-bios`: a path to an OpenSBI build, `none`,
+        /// or `flash` to boot OpenSBI from pflash0 with `-bios none`
+        /// (defaults to QEMU's bundled OpenSBI)
+        #[arg(long)]
+        bios: Option<String>,
+        /// OpenSBI binary to place in pflash0 with `--bios flash` (searched
+        /// for if omitted)
+        #[arg(long)]
+        opensbi: Option<PathBuf>,
+        /// Boot flow; `uboot` is riscv64/aarch64 only and `flash` needs
+        /// `--kernel-in-flash` and firmware that boots from it
+        #[arg(long, value_enum, default_value_t)]
+        boot: BootFlow,
+        /// U-Boot binary to use with `--boot uboot` (searched for if omitted)
+        #[arg(long)]
+        uboot: Option<PathBuf>,
+        /// Kernel form for `-kernel` and `--kernel-in-flash`: `elf` skips the
+        /// objcopy step, for QEMU and firmware that load ELFs; `bin` is the
+        /// raw binary. Defaults to `bin`, or `elf` on x86_64 and with
+        /// `--bios flash`
+        #[arg(long, value_enum)]
+        boot_artifact: Option<BootArtifact>,
+        /// Replace the default `-machine` argument, e.g. `virt,gic-version=3`,
+        /// `virt,aclint=on` or `q35,smm=off`; `microvm` (x86_64) boots
+        /// faster, with the image as read-only firmware ROM instead of pflash;
+        /// `virt,highmem=off` (aarch64) keeps every device and all RAM below
+        /// 4 GiB, for hosts with a small physical address size
+        #[arg(long)]
+        machine: Option<String>,
+        /// Pin the machine to a versioned type of this QEMU release, e.g.
+        /// `8.2` (`virt-8.2` on aarch64, `pc-q35-8.2` on x86_64), so device
+        /// addresses and defaults do not move when QEMU is upgraded
+        #[arg(long, value_name = "VERSION")]
+        machine_version: Option<String>,
+        /// aarch64: enable the secure world (`secure=on`). pflash0 becomes
+        /// secure-only and stays empty; the data image remains on pflash1,
+        /// visible to the non-secure kernel
+        #[arg(long)]
+        secure: bool,
+        /// Accelerator for QEMU: `tcg` emulates the CPU; `kvm` (Linux) and
+        /// `hvf` (macOS) run a guest of the host's architecture on the host
+        /// CPU. If the one asked for cannot be used here, the run says why
+        /// and falls back to `tcg`
+        #[arg(long, value_enum, default_value_t)]
+        accel: accel::Accel,
+        /// Stop instead of falling back to `tcg` when `--accel` cannot be
+        /// used
+        #[arg(long)]
+        strict_accel: bool,
+        /// aarch64: enter the kernel at EL2 (`virtualization=on`) and build
+        /// the `el2` feature, which reports the level the app ends up at
+        #[arg(long)]
+        el2: bool,
+        /// aarch64, riscv64: enable semihosting in QEMU and build the
+        /// `semihosting` feature, which compares the bank with the image
+        /// file on the host (`semihost=<PATH>` on the kernel command line)
+        #[arg(long)]
+        semihost: bool,
+        /// Run the app's self-test instead of its demos: builds the
+        /// `selftest` feature and passes `selftest` on the kernel command
+        /// line (`-append`)
+        #[arg(long)]
+        selftest: bool,
+        /// Build the `access-width` feature and have it read flash with
+        /// accesses of this many bits only (`width=<BITS>` on the kernel
+        /// command line)
+        #[arg(long, value_name = "BITS", value_parser = ["8", "16", "32", "64"])]
+        access_width: Option<String>,
+        /// Build the `heap-hash` feature, which copies this many MiB of the
+        /// bank into one heap buffer and checks the manifest digests against
+        /// the copy (`hash-mib=<MIB>` on the kernel command line)
+        #[arg(long, value_name = "MIB", value_parser = clap::value_parser!(u32).range(1..))]
+        heap_hash: Option<u32>,
+        /// Build without the `paging` feature; the app reads the bank
+        /// through the boot page table
+        #[arg(long)]
+        no_paging: bool,
+        #[command(flatten)]
+        link: LinkArgs,
+        /// CPUs for `-smp`: a count (`4`) or a topology
+        /// (`sockets=2,cores=2,threads=1`); mirrored into the axconfig as
+        /// `max-cpu-num`, and more than one CPU enables the `smp` feature
+        #[arg(long, value_name = "SPEC", default_value = "1")]
+        smp: String,
+        /// Guest RAM size (`512M`, `1G`); mirrored into the axconfig as
+        /// `phys-memory-size`, up to the RAM the kernel can map linearly
+        #[arg(long, value_name = "SIZE", default_value = "128M")]
+        mem: String,
+        /// Split guest RAM and CPUs evenly into this many NUMA nodes
+        /// (mirrored into the axconfig as `numa-nodes`)
+        #[arg(long, value_name = "NODES")]
+        numa: Option<usize>,
+        /// Attach a watchdog (ib700 on x86_64, i6300esb elsewhere) that
+        /// powers the machine off when it fires, and build the app to `pet`
+        /// it during a full flash scan or to `starve` it
+        #[arg(
+            long,
+            value_name = "MODE",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "pet"
+        )]
+        watchdog: Option<WatchdogMode>,
+        /// Back guest RAM with a memory-backend-file object: `file:<PATH>`.
+        /// The file keeps the guest's memory after QEMU exits (put it on
+        /// tmpfs, e.g. /dev/shm, for speed)
+        #[arg(long, value_name = "BACKEND")]
+        mem_backend: Option<String>,
+        /// Write a standalone shell script with the QEMU invocation to this
+        /// path instead of launching QEMU
+        #[arg(long, value_name = "PATH")]
+        emit_script: Option<PathBuf>,
+        /// Print the QEMU command (`text`, shell-quoted, or `json`) as the
+        /// last line of output and exit instead of launching QEMU
+        #[arg(
+            long,
+            value_name = "FORMAT",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "text"
+        )]
+        print_cmdline: Option<CmdlineFormat>,
+        /// Extra property for the pflash `-drive` spec, overriding the
+        /// generated one if the key already exists (e.g. `readonly=off`,
+        /// `cache=none`); repeatable
+        #[arg(long = "pflash-opt", value_name = "KEY=VALUE")]
+        pflash_opts: Vec<String>,
+        /// Connect the serial port to a socket QEMU listens on at
+        /// 127.0.0.1:<PORT> instead of the terminal; the guest starts once a
+        /// client connects (used by `xtask shell`)
+        #[arg(long, value_name = "PORT")]
+        serial_tcp: Option<u16>,
+        /// Stop QEMU through QMP after this many seconds (counted from the
+        /// start of the build), after recording the machine's state; the
+        /// run then exits with status 124
+        #[arg(long, value_name = "SECS")]
+        timeout: Option<u64>,
+        /// Enable QEMU's `pflash_*` trace events and log them to FILE
+        /// (default: `pflash-<arch>.trace` next to the image)
+        #[arg(
+            long,
+            value_name = "FILE",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = ""
+        )]
+        trace_pflash: Option<PathBuf>,
+        /// Start QEMU in the background with the serial port on a local
+        /// TCP socket (`--serial-tcp`, or a free port), record it in
+        /// `target/daemon/<arch>.pid` and return; see `status` and `stop`
+        #[arg(long)]
+        daemon: bool,
+    },
+}
+
+/// Where the kernel is linked and loaded (`build`, `run`).
+#[derive(Args, Clone, Default)]
+struct LinkArgs {
+    /// Link the kernel with this linker script instead of the one axhal
+    /// generates for the platform; start from a copy of that one,
+    /// `target/<TARGET>/release/linker_<PLATFORM>.lds`
+    #[arg(long, value_name = "FILE")]
+    link_script: Option<PathBuf>,
+    /// Physical address to link and load the kernel at, e.g. `0x8040_0000`
+    /// (sets kernel-base-paddr and kernel-base-vaddr in the installed
+    /// config; `run` then boots the ELF, which QEMU loads there)
+    #[arg(long, value_name = "ADDR", value_parser = image::parse_offset)]
+    load_addr: Option<usize>,
+}
+
+#[derive(Subcommand)]
+enum ImageCmd {
+    /// Flip bits in `pflash-<ARCH>.img` to test the guest's error detection
+    /// (`run` recreates the image, so rerun a script from `--emit-script`)
+    Corrupt {
+        /// Target architecture (aliases such as rv64, arm64, amd64 and la64
+        /// are accepted too)
+        #[arg(long, default_value = "riscv64", ignore_case = true)]
+        arch: Arch,
+        /// Image offset of the first byte to corrupt, e.g. `0x1004`
+        #[arg(long, value_parser = image::parse_offset)]
+        offset: usize,
+        /// Bits to flip, counted from bit 0 of the byte at `--offset` (so
+        /// 9 is bit 1 of the next byte); comma-separated
+        #[arg(long, value_delimiter = ',', default_value = "0")]
+        bits: Vec<usize>,
+        /// Directory the image was written to with `--pflash-out`
+        #[arg(long, value_name = "DIR")]
+        pflash_out: Option<PathBuf>,
+    },
+    /// List the regions of `pflash-<ARCH>.img`
+    Inspect {
+        /// Target architecture (aliases such as rv64, arm64, amd64 and la64
+        /// are accepted too)
+        #[arg(long, default_value = "riscv64", ignore_case = true)]
+        arch: Arch,
+        /// Also decode the crash records in the panics region
+        /// (`--features panic-record`)
+        #[arg(long)]
+        panics: bool,
+        /// Also list the benchmark results in the bench region
+        /// (`--features bench-record`)
+        #[arg(long)]
+        bench: bool,
+        /// Directory the image was written to with `--pflash-out`
+        #[arg(long, value_name = "DIR")]
+        pflash_out: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RunsCmd {
+    /// List the archived runs, oldest first, with their exit status
+    List,
+    /// Print the status, QEMU command line and serial log of a run
+    Show {
+        /// Run directory name, or a prefix of it such as a date (the
+        /// latest matching run); the latest run if omitted
+        run: Option<String>,
     },
 }
 
+/// How a run gets from QEMU's reset to the kernel (`run --boot`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BootFlow {
+    /// QEMU loads the kernel itself
+    #[default]
+    Direct,
+    /// U-Boot loads the kernel from a boot disk
+    Uboot,
+    /// No `-kernel`; firmware boots the kernel stored in flash
+    Flash,
+}
+
+/// What the app does with the watchdog of `run --watchdog`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WatchdogMode {
+    /// Keep it from firing during the flash scan
+    Pet,
+    /// Let it fire, which powers the machine off
+    Starve,
+}
+
+impl WatchdogMode {
+    /// The guest feature that does it.
+    fn feature(self) -> &'static str {
+        match self {
+            Self::Pet => "watchdog",
+            Self::Starve => "watchdog-starve",
+        }
+    }
+}
+
+/// How `run --print-cmdline` prints the QEMU command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CmdlineFormat {
+    /// One shell-quoted line
+    Text,
+    /// A `{"qemu": ..., "args": [...]}` object
+    Json,
+}
+
+/// The form of the kernel a run hands to QEMU's `-kernel`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BootArtifact {
+    /// The ELF cargo builds, loaded at the addresses in its program headers
+    Elf,
+    /// A raw binary made from the ELF with rust-objcopy, loaded at the
+    /// machine's kernel address
+    Bin,
+}
+
+impl BootArtifact {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Elf => "elf",
+            Self::Bin => "bin",
+        }
+    }
+}
+
 #[allow(dead_code)]
 struct ArchInfo {
     target: &'static str,
     platform: &'static str,
     objcopy_arch: &'static str,
+    /// What `-kernel` gets unless `--boot-artifact` says otherwise.
+    boot_artifact: BootArtifact,
+    /// Default QEMU `-machine` argument.
+    machine: &'static str,
+    /// Oldest QEMU release (major, minor) whose machine has the flash bank
+    /// the app reads, where distributions still ship older ones.
+    min_qemu: Option<(u32, u32)>,
 }
 
-fn arch_info(arch: &str) -> ArchInfo {
+fn arch_info(arch: Arch) -> ArchInfo {
     match arch {
-        "riscv64" => ArchInfo {
+        Arch::Riscv64 => ArchInfo {
             target: "riscv64gc-unknown-none-elf",
             platform: "riscv64-qemu-virt",
             objcopy_arch: "riscv64",
+            boot_artifact: BootArtifact::Bin,
+            machine: "virt",
+            // The first with CFI flash on virt.
+            min_qemu: Some((4, 0)),
         },
-        "aarch64" => ArchInfo {
+        Arch::Aarch64 => ArchInfo {
             target: "aarch64-unknown-none-softfloat",
             platform: "aarch64-qemu-virt",
             objcopy_arch: "aarch64",
+            boot_artifact: BootArtifact::Bin,
+            machine: "virt",
+            min_qemu: None,
         },
-        "x86_64" => ArchInfo {
+        Arch::X86_64 => ArchInfo {
             target: "x86_64-unknown-none",
             platform: "x86-pc",
             objcopy_arch: "x86_64",
+            // QEMU boots it through multiboot from the ELF.
+            boot_artifact: BootArtifact::Elf,
+            machine: "q35",
+            min_qemu: None,
         },
-        "loongarch64" => ArchInfo {
+        Arch::Loongarch64 => ArchInfo {
             target: "loongarch64-unknown-none",
             platform: "loongarch64-qemu-virt",
             objcopy_arch: "loongarch64",
+            boot_artifact: BootArtifact::Bin,
+            machine: "virt",
+            // The first with both pflash0 and pflash1 on virt; older ones
+            // have a single bank, or no LoongArch at all before 7.1.
+            min_qemu: Some((9, 1)),
         },
-        _ => {
-            eprintln!(
-                "Error: unsupported architecture '{}'. \
-                 Supported: riscv64, aarch64, x86_64, loongarch64",
-                arch
-            );
-            process::exit(1);
-        }
     }
 }
 
+/// Whether `dir` is the root of this project (rather than some other
+/// cargo workspace the tool happens to run in).
+fn is_project_root(dir: &Path) -> bool {
+    dir.join("Cargo.toml").is_file()
+        && dir.join("configs").join("riscv64.toml").is_file()
+        && dir.join("xtask").is_dir()
+}
+
+/// The workspace `cargo locate-project --workspace` finds from the current
+/// directory.
+fn locate_workspace() -> Option<PathBuf> {
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let out = Command::new(cargo)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let manifest = PathBuf::from(String::from_utf8(out.stdout).ok()?.trim());
+    out.status
+        .success()
+        .then(|| manifest.parent().map(Path::to_path_buf))
+        .flatten()
+}
+
 /// Locate the project root.
+///
+/// In order: the workspace cargo finds from the current directory, the
+/// directory xtask was built from, and the ancestors of the xtask binary
+/// (`<root>/target/<profile>/xtask`), so every subcommand works from any
+/// directory and from a vendored or relocated checkout.
 fn project_root() -> PathBuf {
-    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    let candidates = locate_workspace()
+        .into_iter()
+        .chain([PathBuf::from(env!("CARGO_MANIFEST_DIR"))])
+        .chain(std::env::current_exe().ok().into_iter().flat_map(|exe| {
+            exe.ancestors()
+                .skip(1)
+                .map(Path::to_path_buf)
+                .collect::<Vec<_>>()
+        }));
+    for dir in candidates {
+        if is_project_root(&dir) {
+            return dir;
+        }
+    }
+    eprintln!(
+        "Error: cannot find the app-readpflash project root; run xtask from inside the checkout"
+    );
+    process::exit(1);
+}
+
+/// Per-arch build output directory (`target/<triple>`).
+fn target_dir(root: &Path, info: &ArchInfo) -> PathBuf {
+    root.join("target").join(info.target)
+}
+
+/// Exclusive lock on a per-arch build directory, released on drop.
+///
+/// Guards the generated axconfig and build artifacts of one architecture so
+/// that two xtask invocations for the same arch don't mutate them at once.
+/// Builds for different arches use different directories and never contend.
+struct BuildLock {
+    path: PathBuf,
+}
+
+impl BuildLock {
+    fn acquire(dir: &Path) -> Self {
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| {
+            eprintln!("Error: failed to create {}: {}", dir.display(), e);
+            process::exit(1);
+        });
+        let path = dir.join("xtask.lock");
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    use std::io::Write;
+                    let _ = write!(file, "{}", process::id());
+                    return Self { path };
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let owner = std::fs::read_to_string(&path).unwrap_or_default();
+                    let owner = owner.trim();
+                    // A lock left behind by a process that no longer exists
+                    // (e.g. one that exited early on an error) is stale.
+                    let stale = cfg!(target_os = "linux")
+                        && !owner.is_empty()
+                        && !Path::new("/proc").join(owner).exists();
+                    if stale {
+                        let _ = std::fs::remove_file(&path);
+                        continue;
+                    }
+                    eprintln!(
+                        "Error: {} is locked by another xtask process (pid {}).",
+                        dir.display(),
+                        if owner.is_empty() { "unknown" } else { owner }
+                    );
+                    eprintln!(
+                        "Wait for it to finish, or remove {} if it is stale.",
+                        path.display()
+                    );
+                    process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: failed to create {}: {}", path.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for BuildLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Paths of the built kernel ELF and its raw binary for `arch`.
+fn kernel_artifacts(root: &Path, info: &ArchInfo, arch: Arch) -> (PathBuf, PathBuf) {
+    let elf = target_dir(root, info)
+        .join("release")
+        .join("arceos-readpflash");
+    // Name the raw binary per arch so images of different targets
+    // can be told apart once copied out of the target directory.
+    let bin = elf.with_file_name(format!("arceos-readpflash-{arch}.bin"));
+    (elf, bin)
 }
 
-/// Copy the architecture-specific axconfig to .axconfig.toml
-fn install_config(root: &Path, arch: &str) {
-    let src = root.join("configs").join(format!("{arch}.toml"));
-    let dst = root.join(".axconfig.toml");
+/// The form of the kernel a run of `arch` boots: `requested`
+/// (`--boot-artifact`), or the arch's default. `uboot` (`--boot uboot`) and
+/// `firmware_flash` (riscv64 `--bios flash`) each need one form; asking
+/// for the other is an error.
+fn resolve_boot_artifact(
+    arch: Arch,
+    requested: Option<BootArtifact>,
+    uboot: bool,
+    firmware_flash: bool,
+) -> BootArtifact {
+    let needed = if uboot {
+        Some((
+            BootArtifact::Bin,
+            "--boot uboot",
+            "U-Boot loads the raw binary from its boot disk",
+        ))
+    } else if firmware_flash {
+        // Without QEMU's firmware a raw binary would be loaded at the DRAM
+        // base, where OpenSBI is copied; the ELF loads at its link address
+        // above it.
+        Some((
+            BootArtifact::Elf,
+            "--bios flash",
+            "a raw binary would be loaded over OpenSBI at the DRAM base",
+        ))
+    } else {
+        None
+    };
+    match (requested, needed) {
+        (Some(asked), Some((form, flow, why))) if asked != form => {
+            eprintln!(
+                "Error: --boot-artifact {} cannot be used with {flow}: {why}",
+                asked.name()
+            );
+            process::exit(1);
+        }
+        (_, Some((form, ..))) => form,
+        (Some(asked), None) => asked,
+        (None, None) => arch_info(arch).boot_artifact,
+    }
+}
+
+/// Install the architecture-specific axconfig into the per-arch target directory.
+///
+/// Each arch gets its own copy (`target/<triple>/axconfig.toml`) which is
+/// handed to the build via `AX_CONFIG_PATH`, so interleaved builds for
+/// different arches never overwrite each other's configuration. The
+/// checked-in `.axconfig.toml` is left alone for editors and plain `cargo`.
+/// `board` replaces `configs/<arch>.toml` for a board other than QEMU's.
+fn install_config(root: &Path, arch: Arch, info: &ArchInfo, board: Option<&Path>) -> PathBuf {
+    let src = board.map_or_else(
+        || root.join("configs").join(format!("{arch}.toml")),
+        Path::to_path_buf,
+    );
+    let dst = target_dir(root, info).join("axconfig.toml");
     if !src.exists() {
         eprintln!("Error: config file not found: {}", src.display());
         process::exit(1);
@@ -91,24 +972,84 @@ fn install_config(root: &Path, arch: &str) {
         );
         process::exit(1);
     });
-    println!("Installed config: {} -> .axconfig.toml", src.display());
+    println!("Installed config: {} -> {}", src.display(), dst.display());
+    dst
 }
 
-/// Run cargo build for the target architecture.
-fn do_build(root: &Path, info: &ArchInfo) {
+/// Kernel features that change the kernel page table and so need `paging`.
+const PAGING_FEATURES: [&str; 8] = [
+    "xip",
+    "device-map",
+    "write-combining",
+    "bench-record",
+    "cached-alias",
+    "map-info",
+    "huge-pages",
+    "lazy-map",
+];
+
+/// Run cargo build for the target architecture, without the default
+/// `paging` feature unless `paging` is set. `layout` is the `--layout` file
+/// of the image, whose regions `build.rs` compiles in for the
+/// `layout-regions` feature.
+fn do_build(
+    root: &Path,
+    info: &ArchInfo,
+    ax_config: &Path,
+    features: Option<&str>,
+    paging: bool,
+    layout: Option<&Path>,
+    link_script: Option<&Path>,
+) {
+    if !paging
+        && let Some(name) = PAGING_FEATURES
+            .into_iter()
+            .find(|&name| has_feature(features, name))
+    {
+        eprintln!("Error: --features {name} needs paging; drop --no-paging");
+        process::exit(1);
+    }
     let manifest = root.join("Cargo.toml");
-    let ax_config = root.join(".axconfig.toml");
-    let status = Command::new("cargo")
-        .args([
-            "build",
-            "--release",
-            "--target",
-            info.target,
-            "--manifest-path",
-            manifest.to_str().unwrap(),
-        ])
-        // Ensure dependencies read the intended config regardless of subprocess cwd.
+    let mut cmd = Command::new("cargo");
+    cmd.args([
+        "build",
+        "--release",
+        "--target",
+        info.target,
+        "--manifest-path",
+        manifest.to_str().unwrap(),
+    ]);
+    if !paging {
+        let features = features.map_or("axstd".into(), |f| format!("axstd,{f}"));
+        cmd.args(["--no-default-features", "--features", &features]);
+    } else if let Some(features) = features {
+        cmd.args(["--features", features]);
+    }
+    match layout {
+        Some(layout) => cmd.env(
+            "READPFLASH_LAYOUT",
+            std::path::absolute(layout).unwrap_or_else(|_| layout.to_path_buf()),
+        ),
+        None => cmd.env_remove("READPFLASH_LAYOUT"),
+    };
+    match link_script {
+        Some(script) if !script.is_file() => {
+            eprintln!("Error: linker script not found: {}", script.display());
+            process::exit(1);
+        }
+        Some(script) => cmd.env(
+            "READPFLASH_LINK_SCRIPT",
+            std::path::absolute(script).unwrap_or_else(|_| script.to_path_buf()),
+        ),
+        None => cmd.env_remove("READPFLASH_LINK_SCRIPT"),
+    };
+    let status = cmd
+        // Point dependencies at this arch's config; an explicit env var takes
+        // precedence over the default in .cargo/config.toml.
         .env("AX_CONFIG_PATH", ax_config.to_str().unwrap())
+        // From the root, so rust-toolchain.toml and .cargo/config.toml apply
+        // wherever xtask was started.
+        .current_dir(root)
         .status()
         .expect("failed to execute cargo build");
     if !status.success() {
@@ -117,6 +1058,20 @@ fn do_build(root: &Path, info: &ArchInfo) {
     }
 }
 
+/// Whether `name` is listed in a `--features` value (comma or space separated).
+fn has_feature(features: Option<&str>, name: &str) -> bool {
+    features.is_some_and(|f| f.split([',', ' ']).any(|feature| feature.trim() == name))
+}
+
+/// Add `name` to a `--features` value unless it is already listed.
+fn add_feature(features: &mut Option<String>, name: &str) {
+    match features {
+        Some(f) if has_feature(Some(f), name) => {}
+        Some(f) => *f = format!("{f},{name}"),
+        None => *features = Some(name.into()),
+    }
+}
+
 /// Convert ELF to raw binary using rust-objcopy.
 fn do_objcopy(elf: &Path, bin: &Path, objcopy_arch: &str) {
     let status = Command::new("rust-objcopy")
@@ -128,6 +1083,9 @@ fn do_objcopy(elf: &Path, bin: &Path, objcopy_arch: &str) {
             "binary",
             bin.to_str().unwrap(),
         ])
+        // Inside the project, so rustup picks the toolchain (and its
+        // llvm-tools) from rust-toolchain.toml.
+        .current_dir(elf.parent().unwrap())
         .status()
         .expect("failed to execute rust-objcopy (install with: cargo install cargo-binutils)");
     if !status.success() {
@@ -136,221 +1094,2421 @@ fn do_objcopy(elf: &Path, bin: &Path, objcopy_arch: &str) {
     }
 }
 
-/// Find SeaBIOS binary on the system (needed for x86_64 pflash).
-fn find_seabios() -> PathBuf {
-    let candidates = [
-        "/usr/share/qemu/bios-256k.bin",
-        "/usr/share/seabios/bios-256k.bin",
-        "/usr/local/share/qemu/bios-256k.bin",
-        "/usr/share/qemu/bios.bin",
-        "/usr/share/seabios/bios.bin",
-    ];
+/// How the kernel image is handed to the guest.
+enum KernelBoot {
+    /// QEMU loads the kernel directly via `-kernel`.
+    Direct,
+    /// U-Boot runs first and loads the kernel from a FAT boot directory.
+    Uboot { firmware: PathBuf, bootdir: PathBuf },
+    /// No `-kernel`: the kernel only exists inside the pflash image
+    /// (`--kernel-in-flash`) and firmware is expected to start it from there.
+    Flash,
+}
+
+/// Read an unsigned integer value (e.g. `kernel-base-paddr`) from an axconfig file.
+fn read_config_uint(config: &Path, key: &str) -> Option<u64> {
+    let text = std::fs::read_to_string(config).ok()?;
+    text.lines().find_map(|line| {
+        let (k, v) = line.split_once('=')?;
+        if k.trim() != key {
+            return None;
+        }
+        let v = v
+            .split('#')
+            .next()?
+            .trim()
+            .trim_matches('"')
+            .replace('_', "");
+        match v.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => v.parse().ok(),
+        }
+    })
+}
+
+/// Find a U-Boot binary suitable for the QEMU virt machine of `arch`.
+fn find_uboot(arch: Arch, uboot: Option<&Path>) -> PathBuf {
+    if let Some(path) = uboot {
+        if !path.is_file() {
+            eprintln!("Error: U-Boot binary not found: {}", path.display());
+            process::exit(1);
+        }
+        return path.to_path_buf();
+    }
+    let candidates: &[&str] = match arch {
+        // S-mode U-Boot, started by OpenSBI as its payload
+        Arch::Riscv64 => &[
+            "/usr/lib/u-boot/qemu-riscv64_smode/u-boot.bin",
+            "/usr/share/u-boot/qemu-riscv64_smode/u-boot.bin",
+        ],
+        Arch::Aarch64 => &[
+            "/usr/lib/u-boot/qemu_arm64/u-boot.bin",
+            "/usr/share/u-boot/qemu_arm64/u-boot.bin",
+        ],
+        Arch::X86_64 | Arch::Loongarch64 => {
+            eprintln!("Error: --boot uboot is only supported for riscv64 and aarch64");
+            process::exit(1);
+        }
+    };
     for path in candidates {
         let p = PathBuf::from(path);
         if p.exists() {
             return p;
         }
     }
-    eprintln!("Error: Could not find SeaBIOS binary for x86_64 pflash.");
+    eprintln!("Error: Could not find a U-Boot binary for {arch}.");
     eprintln!("Looked in:");
-    for p in &candidates {
+    for p in candidates {
         eprintln!("  - {p}");
     }
-    eprintln!("Install with: sudo apt install seabios  (or equivalent)");
+    eprintln!("Install with: sudo apt install u-boot-qemu  (or pass --uboot <path>)");
     process::exit(1);
 }
 
-/// Returns the required PFlash image size for each architecture.
-///
-/// QEMU virt machines have fixed pflash bank sizes that must be matched exactly:
-/// - riscv64 virt: pflash0/1 each 32MB
-/// - aarch64 virt: pflash0/1 each 64MB
-/// - x86_64 q35:   pflash0 size is flexible (we use 4MB)
-/// - loongarch64:  pflash0 size is flexible (we use 4MB)
-fn pflash_size(arch: &str) -> usize {
-    match arch {
-        "riscv64" => 32 * 1024 * 1024,    // 32MB - fixed by QEMU virt machine
-        "aarch64" => 64 * 1024 * 1024,    // 64MB - fixed by QEMU virt machine
-        "x86_64" => 4 * 1024 * 1024,      // 4MB
-        "loongarch64" => 4 * 1024 * 1024, // 4MB
-        _ => 4 * 1024 * 1024,
+/// Where OpenSBI's `fw_dynamic` is looked for, in order.
+const OPENSBI_PATHS: [&str; 4] = [
+    "/usr/lib/riscv64-linux-gnu/opensbi/generic/fw_dynamic.bin",
+    "/usr/share/opensbi/lp64/generic/firmware/fw_dynamic.bin",
+    "/usr/share/qemu/opensbi-riscv64-generic-fw_dynamic.bin",
+    "/usr/local/share/qemu/opensbi-riscv64-generic-fw_dynamic.bin",
+];
+
+/// Find an OpenSBI `fw_dynamic` build for `--bios flash`.
+fn find_opensbi(opensbi: Option<&Path>) -> PathBuf {
+    if let Some(path) = opensbi {
+        if !path.is_file() {
+            eprintln!("Error: OpenSBI binary not found: {}", path.display());
+            process::exit(1);
+        }
+        return path.to_path_buf();
+    }
+    if let Some(path) = OPENSBI_PATHS.iter().map(PathBuf::from).find(|p| p.exists()) {
+        return path;
     }
+    eprintln!("Error: Could not find an OpenSBI fw_dynamic binary.");
+    eprintln!("Looked in:");
+    for p in &OPENSBI_PATHS {
+        eprintln!("  - {p}");
+    }
+    eprintln!("Install with: sudo apt install opensbi  (or pass --opensbi <path>)");
+    process::exit(1);
 }
 
-/// Create a PFlash image with magic string "PFLA" at offset 0.
+/// Prepare a boot directory with the kernel and a compiled `boot.scr`.
 ///
-/// For x86_64, the image also includes SeaBIOS at the end so that
-/// pflash0 can serve as both data storage and boot ROM.
-fn create_pflash_image(root: &Path, arch: &str) -> PathBuf {
-    let size = pflash_size(arch);
-    let pflash_path = root.join("pflash.img");
-    let mut image = vec![0xFFu8; size]; // CFI flash erased state is 0xFF
-
-    // Write magic "PFLA" at offset 0
-    image[0..4].copy_from_slice(b"PFLA");
-
-    if arch == "x86_64" {
-        // For x86_64 Q35: pflash0 replaces the BIOS ROM.
-        // We embed SeaBIOS at the end of the image so the CPU reset
-        // vector (0xFFFFFFF0) lands inside SeaBIOS code.
-        let bios_path = find_seabios();
-        let bios_data = std::fs::read(&bios_path).unwrap_or_else(|e| {
-            eprintln!("Error: failed to read SeaBIOS binary: {}", e);
+/// QEMU exposes the directory to the guest as a virtual FAT disk; U-Boot's
+/// default boot command finds `boot.scr` on it, which loads the raw kernel
+/// binary to its link address (`kernel-base-paddr` in the installed
+/// `config`) and jumps to it.
+fn prepare_uboot_dir(arch: Arch, config: &Path, bin: &Path) -> PathBuf {
+    let load_addr = read_config_uint(config, "kernel-base-paddr").unwrap_or_else(|| {
+        eprintln!("Error: kernel-base-paddr not found in {}", config.display());
+        process::exit(1);
+    });
+    let bootdir = bin.with_file_name("uboot");
+    std::fs::create_dir_all(&bootdir).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create {}: {}", bootdir.display(), e);
+        process::exit(1);
+    });
+    std::fs::copy(bin, bootdir.join("kernel.bin")).unwrap_or_else(|e| {
+        eprintln!("Error: failed to copy kernel into boot directory: {}", e);
+        process::exit(1);
+    });
+
+    // U-Boot on arm64 runs with the MMU and caches on; turn them off
+    // before handing over so ArceOS starts from a clean state.
+    let cache_off = if arch == Arch::Aarch64 {
+        "dcache flush\ndcache off\nicache off\n"
+    } else {
+        ""
+    };
+    let script = format!(
+        "# Generated by cargo xtask: load the ArceOS kernel from the boot disk\n\
+         virtio scan\n\
+         fatload virtio 0:1 {load_addr:#x} kernel.bin\n\
+         {cache_off}\
+         go {load_addr:#x}\n"
+    );
+    let cmd_path = bootdir.join("boot.cmd");
+    std::fs::write(&cmd_path, script).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", cmd_path.display(), e);
+        process::exit(1);
+    });
+
+    let uboot_arch = if arch == Arch::Aarch64 {
+        "arm64"
+    } else {
+        "riscv"
+    };
+    let status = Command::new("mkimage")
+        .args([
+            "-A", uboot_arch, "-O", "linux", "-T", "script", "-C", "none",
+        ])
+        .args(["-n", "arceos-readpflash"])
+        .arg("-d")
+        .arg(&cmd_path)
+        .arg(bootdir.join("boot.scr"))
+        .stdout(process::Stdio::null())
+        .status()
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "Error: failed to run mkimage ({e}). Install with: sudo apt install u-boot-tools"
+            );
             process::exit(1);
         });
-        let bios_size = bios_data.len();
-        assert!(
-            bios_size <= size - 4,
-            "SeaBIOS binary ({bios_size} bytes) too large for {size}-byte pflash image"
-        );
-        println!(
-            "Embedding SeaBIOS ({} bytes) from {}",
-            bios_size,
-            bios_path.display()
-        );
-        image[size - bios_size..].copy_from_slice(&bios_data);
+    if !status.success() {
+        eprintln!("Error: mkimage failed to compile {}", cmd_path.display());
+        process::exit(status.code().unwrap_or(1));
     }
+    println!("Prepared U-Boot boot directory: {}", bootdir.display());
+    bootdir
+}
 
-    std::fs::write(&pflash_path, &image).unwrap_or_else(|e| {
-        eprintln!("Error: failed to write pflash image: {}", e);
+/// Resolve the `-bios` argument for riscv64.
+///
+/// `default` and `none` are passed through to QEMU as-is, and `flash` selects
+/// firmware in pflash0; anything else is treated as a firmware file and must
+/// exist.
+fn resolve_bios(arch: Arch, bios: Option<&str>) -> String {
+    let Some(bios) = bios else {
+        return "default".into();
+    };
+    if arch != Arch::Riscv64 {
+        eprintln!("Error: --bios is only supported for riscv64 (got --arch {arch})");
         process::exit(1);
-    });
-    println!(
-        "Created pflash image: {} ({} bytes)",
-        pflash_path.display(),
-        size
-    );
-    pflash_path
+    }
+    if bios == "default" || bios == "none" || bios == "flash" {
+        return bios.into();
+    }
+    let path = Path::new(bios);
+    if !path.is_file() {
+        eprintln!("Error: firmware file not found: {}", path.display());
+        process::exit(1);
+    }
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    println!("Using custom firmware: {}", path.display());
+    path.display().to_string()
 }
 
-/// Run the kernel image in QEMU with PFlash attached.
-fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, pflash: &Path) {
-    let mem = "128M";
-    let smp = "1";
-
-    let qemu = format!("qemu-system-{arch}");
+/// QEMU settings for a run, resolved from the command line.
+struct QemuOpts {
+    /// riscv64 `-bios` argument.
+    bios: String,
+    /// riscv64 pflash0 image holding the firmware (`--bios flash`).
+    firmware_flash: Option<PathBuf>,
+    boot: KernelBoot,
+    /// `-machine` argument (the per-arch default unless overridden).
+    machine: String,
+    /// Extra `key=value` properties for the pflash `-drive` spec.
+    pflash_opts: Vec<(String, String)>,
+    /// Geometry of the `cfi.pflash01` banks (`--flash-geometry`).
+    flash_geometry: Option<FlashGeometry>,
+    /// Guest RAM size in MiB (`--mem`).
+    mem_mib: usize,
+    /// File backing guest RAM (`--mem-backend file:<PATH>`).
+    mem_file: Option<PathBuf>,
+    /// CPU topology for `-smp`.
+    smp: Topology,
+    /// Number of NUMA nodes, if any.
+    numa: Option<usize>,
+    /// Attach a watchdog device (`--watchdog`).
+    watchdog: bool,
+    /// Kernel command line (`-append`), for a direct boot.
+    append: Option<String>,
+    /// Local TCP port to serve the serial port on (`--serial-tcp`).
+    serial_tcp: Option<u16>,
+    /// File to log the `pflash_*` trace events to (`--trace-pflash`).
+    trace_log: Option<PathBuf>,
+    /// Enable semihosting (`--semihost`).
+    semihosting: bool,
+    /// Accelerator (`--accel`), already checked to be usable.
+    accel: accel::Accel,
+}
 
-    let mut args: Vec<String> = vec![
-        "-m".into(),
-        mem.into(),
-        "-smp".into(),
-        smp.into(),
-        "-nographic".into(),
-    ];
+/// Parse a RAM size (`512M`, `1G`, or MiB without a suffix) into MiB.
+fn parse_mem(spec: &str) -> Result<usize, String> {
+    let upper = spec.trim().to_ascii_uppercase();
+    let (number, scale) = match upper.strip_suffix('G') {
+        Some(number) => (number, 1024),
+        None => (upper.strip_suffix('M').unwrap_or(&upper), 1),
+    };
+    match number.parse::<usize>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(scale)
+            .ok_or_else(|| format!("invalid memory size '{spec}': too large")),
+        _ => Err(format!(
+            "invalid memory size '{spec}', expected e.g. 128M or 1G"
+        )),
+    }
+}
 
+/// RAM the kernel can map from `phys-memory-base` without a hole, in MiB,
+/// where the machine splits guest RAM.
+fn linear_ram_limit(arch: Arch) -> Option<usize> {
     match arch {
-        "riscv64" => {
-            // pflash1 at 0x22000000 (pflash0 is for firmware)
-            args.extend([
-                "-machine".into(),
-                "virt".into(),
-                "-bios".into(),
-                "default".into(),
-                "-kernel".into(),
-                bin.to_str().unwrap().into(),
-                "-drive".into(),
-                format!(
-                    "if=pflash,format=raw,unit=1,file={},readonly=on",
-                    pflash.display()
-                ),
-            ]);
+        // q35 keeps at most 2G below the PCI hole; the rest goes above 4G.
+        Arch::X86_64 => Some(2048),
+        // The virt machine's low memory (`low-memory-size`).
+        Arch::Loongarch64 => Some(256),
+        Arch::Riscv64 | Arch::Aarch64 => None,
+    }
+}
+
+/// CPU topology from `--smp`.
+struct Topology {
+    /// Total number of CPUs.
+    cpus: usize,
+    /// `-smp` argument.
+    arg: String,
+}
+
+/// Parse `--smp`: a CPU count or `sockets=S,cores=C,threads=T[,cpus=N]`
+/// (omitted factors default to 1).
+fn parse_smp(spec: &str) -> Topology {
+    let fail = |msg: &str| -> ! {
+        eprintln!("Error: invalid --smp '{spec}': {msg}");
+        process::exit(1);
+    };
+    if let Ok(cpus) = spec.parse::<usize>() {
+        if cpus == 0 {
+            fail("need at least one CPU");
         }
-        "aarch64" => {
-            // pflash1 at 0x04000000 (pflash0 is for firmware)
-            args.extend([
-                "-cpu".into(),
-                "cortex-a72".into(),
-                "-machine".into(),
-                "virt".into(),
-                "-kernel".into(),
-                bin.to_str().unwrap().into(),
-                "-drive".into(),
-                format!(
-                    "if=pflash,format=raw,unit=1,file={},readonly=on",
-                    pflash.display()
-                ),
-            ]);
+        return Topology {
+            cpus,
+            arg: cpus.to_string(),
+        };
+    }
+    let (mut sockets, mut cores, mut threads, mut cpus) = (1, 1, 1, None);
+    for item in spec.split(',') {
+        let Some((key, value)) = item.split_once('=') else {
+            fail("expected a count or KEY=VALUE pairs");
+        };
+        let Ok(value) = value.parse::<usize>() else {
+            fail(&format!("'{value}' is not a number"));
+        };
+        if value == 0 {
+            fail(&format!("{key} must be at least 1"));
         }
-        "x86_64" => {
-            // pflash0 at 4GB-4MB = 0xFFC00000 (combined SeaBIOS + data)
-            args.extend([
-                "-machine".into(),
-                "q35".into(),
-                "-drive".into(),
-                format!(
-                    "if=pflash,format=raw,unit=0,file={},readonly=on",
-                    pflash.display()
-                ),
-                "-kernel".into(),
-                elf.to_str().unwrap().into(),
-            ]);
+        match key {
+            "sockets" => sockets = value,
+            "cores" => cores = value,
+            "threads" => threads = value,
+            "cpus" => cpus = Some(value),
+            _ => fail(&format!(
+                "unknown key '{key}' (supported: sockets, cores, threads, cpus)"
+            )),
         }
-        "loongarch64" => {
-            // pflash1 at 0x1d000000 (VIRT_FLASH region, pflash0 absent)
-            // pflash0 is used for firmware, so we use pflash1 for data.
-            // When pflash0 is not provided, pflash1 maps at the start of
-            // the VIRT_FLASH region (0x1d000000).
-            args.extend([
-                "-machine".into(),
-                "virt".into(),
-                "-drive".into(),
-                format!(
-                    "if=pflash,format=raw,unit=1,file={},readonly=on",
-                    pflash.display()
-                ),
-                "-kernel".into(),
-                bin.to_str().unwrap().into(),
-            ]);
+    }
+    let total = sockets * cores * threads;
+    if cpus.is_some_and(|cpus| cpus != total) {
+        fail(&format!(
+            "cpus must equal sockets * cores * threads ({total})"
+        ));
+    }
+    Topology {
+        cpus: total,
+        arg: format!("cpus={total},sockets={sockets},cores={cores},threads={threads}"),
+    }
+}
+
+/// Check that `nodes` NUMA nodes evenly divide the CPUs and guest RAM.
+fn check_numa(nodes: usize, smp: &Topology, mem_mib: usize) {
+    if nodes == 0 || !smp.cpus.is_multiple_of(nodes) || !mem_mib.is_multiple_of(nodes) {
+        eprintln!(
+            "Error: --numa {nodes} must evenly divide the {} CPUs and {mem_mib} MiB of RAM",
+            smp.cpus
+        );
+        process::exit(1);
+    }
+}
+
+/// Mirror the run's topology into the installed axconfig.
+///
+/// `max-cpu-num` is the number of CPUs ArceOS boots on platforms without
+/// runtime detection; `numa-nodes` is informational.
+fn mirror_topology(config: &Path, cpus: usize, numa: Option<usize>) {
+    let text = std::fs::read_to_string(config).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {}", config.display(), e);
+        process::exit(1);
+    });
+    let mut out = String::with_capacity(text.len() + 32);
+    for line in text.lines() {
+        if line
+            .split_once('=')
+            .is_some_and(|(k, _)| k.trim() == "max-cpu-num")
+        {
+            out.push_str(&format!("max-cpu-num = {cpus} # uint\n"));
+            if let Some(nodes) = numa {
+                out.push_str("# Number of NUMA nodes (set by `cargo xtask run --numa`).\n");
+                out.push_str(&format!("numa-nodes = {nodes} # uint\n"));
+            }
+        } else {
+            out.push_str(line);
+            out.push('\n');
         }
-        _ => unreachable!(),
     }
+    std::fs::write(config, out).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", config.display(), e);
+        process::exit(1);
+    });
+    match numa {
+        Some(nodes) => println!("Config topology: {cpus} CPUs, {nodes} NUMA nodes"),
+        None => println!("Config topology: {cpus} CPUs"),
+    }
+}
 
-    println!("Running: {} {}", qemu, args.join(" "));
-    let status = Command::new(&qemu)
-        .args(&args)
-        .status()
-        .unwrap_or_else(|e| {
-            eprintln!("Error: failed to run {}: {}", qemu, e);
-            process::exit(1);
-        });
-    if !status.success() {
-        process::exit(status.code().unwrap_or(1));
+/// Mirror the guest RAM size into the installed axconfig as
+/// `phys-memory-size`, capped at what `arch` can map linearly.
+fn mirror_memory(config: &Path, arch: Arch, mem_mib: usize) {
+    let mapped = linear_ram_limit(arch).map_or(mem_mib, |limit| mem_mib.min(limit));
+    let text = std::fs::read_to_string(config).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {}", config.display(), e);
+        process::exit(1);
+    });
+    let mut out = String::with_capacity(text.len());
+    for line in text.lines() {
+        if line
+            .split_once('=')
+            .is_some_and(|(k, _)| k.trim() == "phys-memory-size")
+        {
+            out.push_str(&format!("phys-memory-size = {:#x} # uint\n", mapped << 20));
+        } else {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    std::fs::write(config, out).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", config.display(), e);
+        process::exit(1);
+    });
+    if mapped < mem_mib {
+        println!(
+            "Config memory: {mapped} MiB mapped of {mem_mib} MiB (the rest is not contiguous)"
+        );
+    } else {
+        println!("Config memory: {mem_mib} MiB");
     }
 }
 
-fn main() {
-    let cli = Cli::parse();
+/// Whether `path` starts with a newc cpio magic (`070701` or `070702`).
+fn is_cpio(path: &Path) -> bool {
+    let mut magic = [0; 6];
+    std::fs::File::open(path)
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut magic))
+        .is_ok_and(|()| magic == *b"070701" || magic == *b"070702")
+}
 
-    let root = project_root();
+/// Whether `path` is a tar archive (ustar magic at offset 257).
+fn is_ustar(path: &Path) -> bool {
+    let mut header = [0; 262];
+    std::fs::File::open(path)
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut header))
+        .is_ok_and(|()| &header[257..] == b"ustar")
+}
 
-    match cli.command {
-        Cmd::Build { ref arch } => {
-            let info = arch_info(arch);
-            install_config(&root, arch);
-            do_build(&root, &info);
-            println!("Build complete for {arch} ({})", info.target);
-        }
-        Cmd::Run { ref arch } => {
-            let info = arch_info(arch);
-            install_config(&root, arch);
-            do_build(&root, &info);
-
-            let elf = root
-                .join("target")
-                .join(info.target)
-                .join("release")
-                .join("arceos-readpflash");
-            let bin = elf.with_extension("bin");
-
-            // objcopy for non-x86_64 architectures
-            if arch != "x86_64" {
-                do_objcopy(&elf, &bin, info.objcopy_arch);
+/// Parse `--pflash-opt` values into key/value pairs.
+fn parse_pflash_opts(opts: &[String]) -> Vec<(String, String)> {
+    opts.iter()
+        .map(|opt| match opt.split_once('=') {
+            Some((k, v)) if !k.is_empty() => (k.to_string(), v.to_string()),
+            _ => {
+                eprintln!("Error: invalid --pflash-opt '{opt}', expected KEY=VALUE");
+                process::exit(1);
             }
+        })
+        .collect()
+}
 
-            // Create pflash image with magic data
-            let pflash = create_pflash_image(&root, arch);
-
-            do_run_qemu(arch, &elf, &bin, &pflash);
+/// Parse `--mem-backend`; only `file:<PATH>` is supported.
+fn parse_mem_backend(backend: &str) -> PathBuf {
+    let Some(path) = backend.strip_prefix("file:").filter(|p| !p.is_empty()) else {
+        eprintln!("Error: unsupported --mem-backend '{backend}'. Supported: file:<PATH>");
+        process::exit(1);
+    };
+    let path = Path::new(path);
+    if path.is_dir() {
+        // QEMU would create (and delete) a temporary file inside it.
+        eprintln!(
+            "Error: --mem-backend file: needs a file path, not a directory: {}",
+            path.display()
+        );
+        process::exit(1);
+    }
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Build the `-drive` spec attaching `pflash` to the given flash unit.
+///
+/// `extra` properties replace generated ones with the same key and are
+/// appended otherwise. `run` builds the app for the bank a `unit` moves the
+/// image to (see `select_bank`).
+fn pflash_drive(unit: u8, pflash: &Path, extra: &[(String, String)]) -> String {
+    let mut props: Vec<(String, String)> = vec![
+        ("if".into(), "pflash".into()),
+        ("format".into(), "raw".into()),
+        ("unit".into(), unit.to_string()),
+        ("file".into(), pflash.display().to_string()),
+        ("readonly".into(), "on".into()),
+    ];
+    for (key, value) in extra {
+        match props.iter_mut().find(|(k, _)| k == key) {
+            Some(prop) => prop.1 = value.clone(),
+            None => props.push((key.clone(), value.clone())),
+        }
+    }
+    props
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Guest features that send CFI commands to the bank or write to it.
+const CFI_FEATURES: [&str; 13] = [
+    "identify",
+    "journal",
+    "fs-write",
+    "erase-suspend",
+    "write-queue",
+    "replicas",
+    "flash-log",
+    "panic-record",
+    "selftest",
+    "device-map",
+    "bench-record",
+    "shell",
+    "flash-script",
+];
+
+/// Whether `machine` (a `-machine` argument) is the x86_64 microvm machine.
+fn is_microvm(machine: &str) -> bool {
+    machine.split(',').next() == Some("microvm")
+}
+
+/// Check that a run on the microvm machine can work.
+///
+/// microvm has no pflash device, so the image is loaded as its firmware
+/// (`-bios`): QEMU maps that ROM to end at 4 GiB, the address of the q35
+/// bank, with the SeaBIOS at the end of the image where it expects it. The
+/// app reads the image there, but the ROM ignores writes and flash
+/// commands, so the features that send them, and the options that
+/// configure a pflash device, are refused.
+fn check_microvm(
+    arch: Arch,
+    features: Option<&str>,
+    pflash_opts: &[(String, String)],
+    trace: bool,
+    geometry: bool,
+) {
+    if arch != Arch::X86_64 {
+        eprintln!("Error: microvm is an x86_64 machine (got --arch {arch})");
+        process::exit(1);
+    }
+    if geometry {
+        eprintln!("Error: --flash-geometry configures pflash devices, which microvm has not");
+        process::exit(1);
+    }
+    if let Some(name) = CFI_FEATURES
+        .into_iter()
+        .find(|&name| has_feature(features, name))
+    {
+        eprintln!(
+            "Error: --features {name} sends flash commands, but microvm has no pflash: \
+             the image is read-only firmware ROM there; use the default q35 machine"
+        );
+        process::exit(1);
+    }
+    if !pflash_opts.is_empty() || trace {
+        eprintln!(
+            "Error: --pflash-opt and --trace-pflash need a pflash device, which microvm has not"
+        );
+        process::exit(1);
+    }
+}
+
+/// Whether `machine` (a `-machine` argument) turns off the virt machine's
+/// memory map above 4 GiB (`highmem=off`).
+fn is_highmem_off(machine: &str) -> bool {
+    machine
+        .split(',')
+        .skip(1)
+        .any(|prop| matches!(prop, "highmem=off" | "highmem=no" | "highmem=false"))
+}
+
+/// `machine` (a `-machine` argument) with its type pinned to QEMU release
+/// `version` (`8.2`, or the full type name): `virt-<V>` on aarch64 and
+/// `pc-q35-<V>` on x86_64. The riscv64 and loongarch64 virt machines and
+/// microvm are not versioned.
+fn versioned_machine(arch: Arch, machine: &str, version: &str) -> Result<String, String> {
+    let (ty, props) = machine.split_once(',').unwrap_or((machine, ""));
+    let prefix = match (arch, ty) {
+        (Arch::Aarch64, "virt") => "virt-",
+        (Arch::X86_64, "q35") => "pc-q35-",
+        (Arch::Riscv64 | Arch::Loongarch64, _) => {
+            return Err(format!(
+                "QEMU has no versioned machine types for {arch}; its virt machine may change between releases"
+            ));
+        }
+        (_, ty) => return Err(format!("machine type '{ty}' has no versioned variants")),
+    };
+    let number = version.strip_prefix(prefix).unwrap_or(version);
+    let valid = number.split('.').count() == 2
+        && number
+            .split('.')
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()));
+    if !valid {
+        return Err(format!("expected a QEMU release like 8.2 (or {prefix}8.2)"));
+    }
+    Ok(match props {
+        "" => format!("{prefix}{number}"),
+        props => format!("{prefix}{number},{props}"),
+    })
+}
+
+/// Check that the QEMU of `arch` knows the machine type of `machine`,
+/// listing the versions it has if not. Nothing is checked without QEMU.
+fn check_machine_type(arch: Arch, machine: &str) {
+    let ty = machine.split(',').next().unwrap_or(machine);
+    let qemu = format!("qemu-system-{arch}");
+    let Ok(out) = Command::new(&qemu).args(["-machine", "help"]).output() else {
+        return;
+    };
+    let listing = String::from_utf8_lossy(&out.stdout);
+    let types: Vec<&str> = listing
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+    if types.contains(&ty) {
+        return;
+    }
+    let family = ty.rsplit_once('-').map_or(ty, |(family, _)| family);
+    let versions: Vec<&str> = types
+        .iter()
+        .copied()
+        .filter(|t| t.rsplit_once('-').is_some_and(|(f, _)| f == family))
+        .collect();
+    eprintln!(
+        "Error: {qemu} has no machine type {ty}; it has: {}",
+        if versions.is_empty() {
+            "none of that family".to_string()
+        } else {
+            versions.join(" ")
+        }
+    );
+    process::exit(1);
+}
+
+/// The release (major, minor) in the first line of `qemu --version`, e.g.
+/// `QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)`.
+fn parse_qemu_version(line: &str) -> Option<(u32, u32)> {
+    let (_, rest) = line.split_once("version ")?;
+    let release = rest.split_whitespace().next()?;
+    let mut parts = release.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// The release of the QEMU of `arch` that is installed, with its version
+/// line, or `None` without that QEMU (or with one whose version does not
+/// parse).
+fn qemu_version(arch: Arch) -> Option<((u32, u32), String)> {
+    let out = Command::new(format!("qemu-system-{arch}"))
+        .arg("--version")
+        .output()
+        .ok()?;
+    let text = String::from_utf8_lossy(&out.stdout);
+    let line = text.lines().next()?.trim();
+    Some((parse_qemu_version(line)?, line.to_string()))
+}
+
+/// Why the installed QEMU of `arch` is too old for the app, if it is.
+/// Nothing is checked without QEMU; running it says what to install.
+fn qemu_too_old(arch: Arch) -> Option<String> {
+    let (major, minor) = arch_info(arch).min_qemu?;
+    let (installed, line) = qemu_version(arch)?;
+    (installed < (major, minor)).then(|| {
+        format!(
+            "qemu-system-{arch} is {}.{} ({line}), but {arch} needs QEMU {major}.{minor} or later \
+             for its flash bank",
+            installed.0, installed.1
+        )
+    })
+}
+
+/// Guest RAM the aarch64 virt machine can hold below 4 GiB, in MiB.
+const HIGHMEM_OFF_RAM_LIMIT: usize = 3072;
+
+/// Replace the value of `key` in axconfig `text` with `value`, keeping the
+/// type comment. A value spanning several lines (an array) is replaced as
+/// a whole.
+fn set_config_value(text: &str, key: &str, value: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let Some((k, v)) = line.split_once('=').filter(|(k, _)| k.trim() == key) else {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        let mut last = v;
+        if v.trim().starts_with('[') && !v.split('#').next().unwrap_or("").contains(']') {
+            for next in lines.by_ref() {
+                last = next;
+                if next.trim().starts_with(']') {
+                    break;
+                }
+            }
+        }
+        out.push_str(k.trim_end());
+        out.push_str(" = ");
+        out.push_str(value);
+        if let Some((_, ty)) = last.rsplit_once('#') {
+            out.push_str(" #");
+            out.push_str(ty);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Move the PCIe devices in the installed aarch64 axconfig to where the
+/// virt machine puts them with `highmem=off`: the ECAM window drops from
+/// `0x40_1000_0000` to 16 buses at `0x3f00_0000`, and the 64-bit PCI
+/// memory window at 512 GiB goes away. The flash banks, UART, GIC and the
+/// 32-bit window stay where they are.
+fn mirror_highmem_off(config: &Path) {
+    let mut text = std::fs::read_to_string(config).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {}", config.display(), e);
+        process::exit(1);
+    });
+    for (key, value) in [
+        ("pci-ecam-base", "0x3f00_0000"),
+        ("pci-bus-end", "0xf"),
+        (
+            "pci-ranges",
+            "[\n    [0x3ef_f0000, 0x1_0000],\n    [0x1000_0000, 0x2eff_0000]\n]",
+        ),
+    ] {
+        text = set_config_value(&text, key, value);
+    }
+    // The ECAM window is mapped through `mmio-ranges`.
+    text = text.replace("[0x40_1000_0000, 0x1000_0000]", "[0x3f00_0000, 0x100_0000]");
+    std::fs::write(config, text).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", config.display(), e);
+        process::exit(1);
+    });
+    println!("Config memory map: highmem=off (PCIe ECAM at 0x3f000000, no 64-bit PCI window)");
+}
+
+/// Link and load the kernel at physical address `addr` (`--load-addr`):
+/// set `kernel-base-paddr` in `config`, and `kernel-base-vaddr` at the
+/// same distance from it as the linear map (`phys-virt-offset`). axhal's
+/// linker script and boot code take both from there.
+fn mirror_load_addr(config: &Path, addr: usize) {
+    let addr = addr as u64;
+    if !addr.is_multiple_of(0x1000) {
+        eprintln!("Error: --load-addr {addr:#x} is not 4K-aligned");
+        process::exit(1);
+    }
+    let key = |key: &str| {
+        read_config_uint(config, key).unwrap_or_else(|| {
+            eprintln!("Error: {key} not found in {}", config.display());
+            process::exit(1);
+        })
+    };
+    let (base, size) = (key("phys-memory-base"), key("phys-memory-size"));
+    if addr < base || addr >= base + size {
+        eprintln!(
+            "Warning: --load-addr {addr:#x} is outside the RAM in the config \
+             ({base:#x}..{:#x}); the kernel only runs there if the memory is writable \
+             and mapped at boot",
+            base + size
+        );
+    }
+    let vaddr = key("phys-virt-offset").wrapping_add(addr);
+    let mut text = std::fs::read_to_string(config).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {}", config.display(), e);
+        process::exit(1);
+    });
+    text = set_config_value(&text, "kernel-base-paddr", &format!("{addr:#x}"));
+    text = set_config_value(&text, "kernel-base-vaddr", &format!("\"{vaddr:#x}\""));
+    std::fs::write(config, text).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", config.display(), e);
+        process::exit(1);
+    });
+    println!("Config kernel base: {addr:#x} (virtual {vaddr:#x})");
+}
+
+/// Match the flash unit the image is attached to with the bank the app
+/// reads: `--features bank0`/`bank1` attaches it to that unit, and
+/// `--pflash-opt unit=N` builds the app for unit N.
+///
+/// On the virt machines pflash0 is the firmware slot: QEMU starts the guest
+/// from whatever image is there instead of the kernel, so `bank0` is only
+/// for boards (`flash-device`). q35 has pflash0 alone.
+fn select_bank(arch: Arch, features: &mut Option<String>, pflash_opts: &mut Vec<(String, String)>) {
+    let feature = ["bank0", "bank1"]
+        .into_iter()
+        .find(|&bank| has_feature(features.as_deref(), bank));
+    let unit = pflash_opts
+        .iter()
+        .find(|(k, _)| k == "unit")
+        .map(|(_, v)| v.clone());
+    let unit = match (feature, unit) {
+        (Some(bank), Some(unit)) if bank[4..] != unit => {
+            eprintln!(
+                "Error: --features {bank} reads pflash{}, but --pflash-opt unit={unit} attaches the image to pflash{unit}",
+                &bank[4..]
+            );
+            process::exit(1);
+        }
+        (Some(bank), None) => {
+            pflash_opts.push(("unit".into(), bank[4..].into()));
+            bank[4..].to_string()
+        }
+        (None, Some(unit)) if unit == "0" || unit == "1" => {
+            add_feature(features, &format!("bank{unit}"));
+            unit
+        }
+        (None, Some(unit)) => {
+            eprintln!(
+                "Error: --pflash-opt unit={unit}: the machines have pflash0 and pflash1 only"
+            );
+            process::exit(1);
+        }
+        (Some(_), Some(unit)) => unit,
+        (None, None) => return,
+    };
+    match (arch, unit.as_str()) {
+        (Arch::X86_64, "1") => {
+            eprintln!("Error: q35 has one flash bank, pflash0 (holding SeaBIOS and the image)");
+            process::exit(1);
+        }
+        (Arch::X86_64, _) | (_, "1") => {}
+        (arch, _) => {
+            eprintln!(
+                "Error: pflash0 is the firmware slot of the {arch} virt machine, and QEMU would start \
+                 the guest from an image there; bank0 is for boards (`cargo xtask flash-device`)"
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Compose the QEMU binary and arguments to run the kernel with PFlash attached.
+fn qemu_command(
+    arch: Arch,
+    kernel: &Path,
+    pflash: &Path,
+    opts: &QemuOpts,
+) -> (String, Vec<String>) {
+    let mem = format!("{}M", opts.mem_mib);
+
+    let qemu = format!("qemu-system-{arch}");
+
+    let mut machine = opts.machine.clone();
+    if opts.mem_file.is_some() {
+        machine.push_str(",memory-backend=ram");
+    }
+    let mut args: Vec<String> = vec![
+        "-m".into(),
+        mem.clone(),
+        "-smp".into(),
+        opts.smp.arg.clone(),
+        "-nographic".into(),
+        "-machine".into(),
+        machine,
+    ];
+    if let Some(path) = &opts.mem_file {
+        // share=on writes guest stores through to the file, so it holds
+        // the final memory contents once QEMU exits. Commas in QEMU
+        // option values are escaped by doubling them.
+        args.extend([
+            "-object".into(),
+            format!(
+                "memory-backend-file,id=ram,size={mem},mem-path={},share=on",
+                path.display().to_string().replace(',', ",,")
+            ),
+        ]);
+    }
+    if let Some(port) = opts.serial_tcp {
+        // wait=on holds the guest until the client is there, so it sees the
+        // whole boot.
+        args.extend([
+            "-chardev".into(),
+            format!("socket,id=serial0,host=127.0.0.1,port={port},server=on,wait=on"),
+            "-serial".into(),
+            "chardev:serial0".into(),
+            "-monitor".into(),
+            "none".into(),
+        ]);
+    }
+    if let Some(log) = &opts.trace_log {
+        args.extend([
+            "-trace".into(),
+            format!(
+                "enable=pflash_*,file={}",
+                log.display().to_string().replace(',', ",,")
+            ),
+        ]);
+    }
+    if let Some(geometry) = opts.flash_geometry {
+        // On the device type, so every bank the machine creates gets it.
+        // The type name holds a dot, so the long form is needed.
+        for (property, value) in [
+            ("sector-length", geometry.sector_size as usize),
+            ("width", geometry.bank_width()),
+            ("device-width", usize::from(geometry.device_width)),
+        ] {
+            args.extend([
+                "-global".into(),
+                format!("driver=cfi.pflash01,property={property},value={value}"),
+            ]);
+        }
+    }
+    if opts.accel.is_hardware() {
+        args.extend(["-accel".into(), opts.accel.name().into()]);
+        // The guest runs on the host CPU, which no other model matches.
+        // loongarch64 takes its default CPU model.
+        if matches!(arch, Arch::Riscv64 | Arch::X86_64) {
+            args.extend(["-cpu".into(), "host".into()]);
+        }
+    }
+    if opts.semihosting {
+        // `target=native`: the guest's file calls go to the host's files
+        // rather than a gdbstub.
+        args.extend([
+            "-semihosting-config".into(),
+            "enable=on,target=native".into(),
+        ]);
+    }
+    if let (Some(append), KernelBoot::Direct) = (&opts.append, &opts.boot) {
+        args.extend(["-append".into(), append.clone()]);
+    }
+    if opts.watchdog {
+        // The virt machines have no built-in watchdog; the 6300ESB goes on
+        // PCI bus 0. Powering off makes a starved watchdog end the run.
+        let device = if arch == Arch::X86_64 {
+            "ib700"
+        } else {
+            "i6300esb"
+        };
+        args.extend([
+            "-device".into(),
+            device.into(),
+            "-action".into(),
+            "watchdog=poweroff".into(),
+        ]);
+    }
+    if let Some(nodes) = opts.numa {
+        // Equal shares of RAM and consecutive CPU ranges per node.
+        let per_cpu = opts.smp.cpus / nodes;
+        for node in 0..nodes {
+            let first = node * per_cpu;
+            args.extend([
+                "-object".into(),
+                format!(
+                    "memory-backend-ram,id=node{node},size={}M",
+                    opts.mem_mib / nodes
+                ),
+                "-numa".into(),
+                format!(
+                    "node,nodeid={node},cpus={first}-{},memdev=node{node}",
+                    first + per_cpu - 1
+                ),
+            ]);
+        }
+    }
+
+    // Disk holding the kernel and boot script for U-Boot to load.
+    let uboot_disk = |bootdir: &Path| -> [String; 4] {
+        [
+            "-drive".into(),
+            format!(
+                "file=fat:{},format=raw,if=none,id=bootdisk",
+                bootdir.display()
+            ),
+            "-device".into(),
+            "virtio-blk-device,drive=bootdisk".into(),
+        ]
+    };
+
+    match arch {
+        Arch::Riscv64 => {
+            // The image on pflash1 (`banks::RISCV64`); pflash0 is for firmware
+            match &opts.firmware_flash {
+                // With a pflash0 drive and no QEMU firmware, the reset
+                // vector jumps to the pflash0 base.
+                Some(firmware) => args.extend([
+                    "-bios".into(),
+                    "none".into(),
+                    "-drive".into(),
+                    pflash_drive(0, firmware, &[]),
+                ]),
+                None => args.extend(["-bios".into(), opts.bios.clone()]),
+            }
+            match &opts.boot {
+                KernelBoot::Direct => {
+                    args.extend(["-kernel".into(), kernel.to_str().unwrap().into()]);
+                }
+                // OpenSBI starts S-mode U-Boot as its payload
+                KernelBoot::Uboot { firmware, bootdir } => {
+                    args.extend(["-kernel".into(), firmware.to_str().unwrap().into()]);
+                    args.extend(uboot_disk(bootdir));
+                }
+                KernelBoot::Flash => {}
+            }
+            args.extend([
+                "-drive".into(),
+                pflash_drive(arch.bank().unit, pflash, &opts.pflash_opts),
+            ]);
+        }
+        Arch::Aarch64 => {
+            // The image on pflash1 (`banks::AARCH64`); pflash0 is for firmware
+            let cpu = if opts.accel.is_hardware() {
+                "host"
+            } else {
+                "cortex-a72"
+            };
+            args.extend(["-cpu".into(), cpu.into()]);
+            match &opts.boot {
+                KernelBoot::Direct => {
+                    args.extend(["-kernel".into(), kernel.to_str().unwrap().into()]);
+                }
+                // U-Boot runs from pflash0 as the machine firmware
+                KernelBoot::Uboot { firmware, bootdir } => {
+                    args.extend(["-bios".into(), firmware.to_str().unwrap().into()]);
+                    args.extend(uboot_disk(bootdir));
+                }
+                KernelBoot::Flash => {}
+            }
+            args.extend([
+                "-drive".into(),
+                pflash_drive(arch.bank().unit, pflash, &opts.pflash_opts),
+            ]);
+        }
+        // No pflash on microvm: the image is its firmware ROM, mapped at
+        // 4GB-4MB as well (see `check_microvm`).
+        Arch::X86_64 if is_microvm(&opts.machine) => {
+            args.extend(["-bios".into(), pflash.to_str().unwrap().into()]);
+            if !matches!(opts.boot, KernelBoot::Flash) {
+                args.extend(["-kernel".into(), kernel.to_str().unwrap().into()]);
+            }
+        }
+        Arch::X86_64 => {
+            // The image on pflash0, ending at 4 GiB (`banks::X86_64`), combined
+            // with SeaBIOS
+            args.extend([
+                "-drive".into(),
+                pflash_drive(arch.bank().unit, pflash, &opts.pflash_opts),
+            ]);
+            if !matches!(opts.boot, KernelBoot::Flash) {
+                args.extend(["-kernel".into(), kernel.to_str().unwrap().into()]);
+            }
+        }
+        Arch::Loongarch64 => {
+            // The image on pflash1 (`banks::LOONGARCH64`), pflash0 absent.
+            // pflash0 is used for firmware, so we use pflash1 for data.
+            // When pflash0 is not provided, pflash1 maps at the start of
+            // the VIRT_FLASH region.
+            args.extend([
+                "-drive".into(),
+                pflash_drive(arch.bank().unit, pflash, &opts.pflash_opts),
+            ]);
+            if !matches!(opts.boot, KernelBoot::Flash) {
+                args.extend(["-kernel".into(), kernel.to_str().unwrap().into()]);
+            }
+        }
+    }
+
+    (qemu, args)
+}
+
+/// Summarize the pflash trace events QEMU logged to `log`: how many there
+/// are of each.
+fn report_trace(log: &Path) {
+    let text = match std::fs::read_to_string(log) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!(
+                "Warning: no pflash trace in {} ({e}); QEMU may lack a trace backend that logs to a file",
+                log.display()
+            );
+            return;
+        }
+    };
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for event in text.lines().filter_map(|line| {
+        // The log backend prefixes the event name with `<pid>@<time>:`.
+        let name = line
+            .split_whitespace()
+            .find(|word| word.contains("pflash_"))?;
+        Some(name[name.find("pflash_")?..].trim_end_matches(':'))
+    }) {
+        match counts.iter_mut().find(|(name, _)| *name == event) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((event, 1)),
+        }
+    }
+    let total: usize = counts.iter().map(|(_, count)| count).sum();
+    println!("pflash trace: {total} events in {}", log.display());
+    for (name, count) in counts {
+        println!("  {name:<28} {count}");
+    }
+}
+
+/// Start of the line the app prints when it stops on an error, followed by
+/// the exit code (`PflashError::exit_code` in `src/error.rs`) and a colon.
+const GUEST_ERROR: &str = "PFlash error ";
+
+/// The exit code of the error the app stopped on, from its `output`.
+fn guest_exit_code(output: &str) -> Option<i32> {
+    output.lines().find_map(|line| {
+        let (code, _) = line.strip_prefix(GUEST_ERROR)?.split_once(':')?;
+        code.parse().ok()
+    })
+}
+
+/// Run QEMU with the composed arguments under QMP supervision, stopping it
+/// at `deadline`, summarize the pflash trace in `trace_log` if one was
+/// asked for, record the run in `archive`, and exit with QEMU's status on
+/// failure, or with the app's exit code if it stopped on an error.
+fn do_run_qemu(
+    qemu: &str,
+    args: &[String],
+    deadline: Option<Instant>,
+    trace_log: Option<&Path>,
+    archive: Option<&runs::Archive>,
+) {
+    println!("Running: {} {}", qemu, args.join(" "));
+    let started = Instant::now();
+    let (code, output) = qmp::supervise(
+        qemu,
+        args,
+        deadline,
+        archive.and_then(runs::Archive::serial_log),
+    );
+    if let Some(log) = trace_log {
+        report_trace(log);
+    }
+    // QEMU exits 0 however the guest stopped.
+    let code = match guest_exit_code(&output) {
+        Some(guest) if code == 0 => {
+            eprintln!("Error: the app stopped on an error (exit code {guest})");
+            guest
+        }
+        _ => code,
+    };
+    if let Some(archive) = archive {
+        archive.record_exit(code, started.elapsed());
+    }
+    if code != 0 {
+        process::exit(code);
+    }
+}
+
+/// How a child xtask invocation ended.
+struct Finished {
+    /// Exit status, or `None` if it was killed on the timeout.
+    status: Option<ExitStatus>,
+    stdout: String,
+    /// Standard error, if captured.
+    stderr: String,
+}
+
+/// Run this xtask binary with `args`, killing it (and the QEMU it started)
+/// if it does not finish within `timeout`. Standard output is captured;
+/// standard error too if `capture_stderr` is set, else it is passed through.
+fn run_self(args: &[&str], timeout: Duration, capture_stderr: bool) -> Finished {
+    let exe = std::env::current_exe().unwrap_or_else(|e| {
+        eprintln!("Error: cannot locate the xtask binary: {e}");
+        process::exit(1);
+    });
+    let mut command = Command::new(exe);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+    if capture_stderr {
+        command.stderr(Stdio::piped());
+    }
+    // Its own process group, so QEMU can be killed with it on a timeout.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command.spawn().unwrap_or_else(|e| {
+        eprintln!("Error: failed to run xtask: {e}");
+        process::exit(1);
+    });
+    let collect = |pipe: Option<Box<dyn std::io::Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut output = String::new();
+            if let Some(mut pipe) = pipe {
+                let _ = std::io::Read::read_to_string(&mut pipe, &mut output);
+            }
+            output
+        })
+    };
+    let stdout = collect(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = collect(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            _ => {
+                #[cfg(unix)]
+                let _ = Command::new("kill")
+                    .args(["-KILL", &format!("-{}", child.id())])
+                    .status();
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+        }
+    };
+    Finished {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    }
+}
+
+/// Arguments of this invocation with the value of `--arch` replaced by
+/// `arch`.
+fn args_for(arch: Arch) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut given = std::env::args().skip(1);
+    while let Some(arg) = given.next() {
+        if arg == "--arch" {
+            given.next();
+            args.extend(["--arch".into(), arch.name().into()]);
+        } else if arg.starts_with("--arch=") {
+            args.push(format!("--arch={arch}"));
+        } else {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+/// Repeat this invocation for each architecture in turn (`--arch all`),
+/// going on after a failure, then print a summary and exit, with status 1
+/// if any of them failed.
+fn run_each_arch() -> ! {
+    let exe = std::env::current_exe().unwrap_or_else(|e| {
+        eprintln!("Error: cannot locate the xtask binary: {e}");
+        process::exit(1);
+    });
+    let mut results = Vec::new();
+    for arch in Arch::ALL {
+        println!("=== {arch} ===");
+        let result = Command::new(&exe).args(args_for(arch)).status();
+        results.push((arch, result));
+    }
+    println!("Summary:");
+    let mut failed = 0;
+    for (arch, result) in &results {
+        match result {
+            Ok(status) if status.success() => println!("  {arch:<12} ok"),
+            Ok(status) => {
+                failed += 1;
+                println!("  {arch:<12} FAILED ({status})");
+            }
+            Err(e) => {
+                failed += 1;
+                println!("  {arch:<12} FAILED (could not start: {e})");
+            }
+        }
+    }
+    if failed > 0 {
+        eprintln!(
+            "Error: {failed} of {} architecture(s) failed",
+            results.len()
+        );
+        process::exit(1);
+    }
+    process::exit(0);
+}
+
+/// Time a run given `--timeout` has to stop QEMU and exit before it is
+/// killed.
+const RUN_GRACE: Duration = Duration::from_secs(30);
+
+/// How `xtask test` went for one architecture.
+enum Verdict {
+    Pass,
+    /// Passed after this many attempts timed out or crashed.
+    Flaky(u32),
+    /// Failed a check, or timed out or crashed on every attempt; the reason
+    /// of the last one.
+    Fail(String),
+}
+
+/// Run `xtask run --arch <arch> --features <features> <run_args>` and check
+/// its output against the snapshot of `arch` and `features` and the JSON
+/// report against `expect`. A run that times out or crashes is retried up
+/// to `retries` more times; one that finishes is judged on its checks
+/// alone. Build progress on stderr is passed through.
+///
+/// Every attempt starts from a freshly created image, so state the guest
+/// wrote to flash in one cannot affect the next, and its serial output is
+/// saved to `target/test/<case>/attempt-<N>.log`.
+#[allow(clippy::too_many_arguments)]
+fn test_arch(
+    root: &Path,
+    arch: Arch,
+    case: &str,
+    features: &str,
+    run_args: &[&str],
+    timeout: Duration,
+    retries: u32,
+    update: bool,
+    expect: &[json::Assertion],
+) -> Verdict {
+    let logs = root.join("target").join("test").join(case);
+    let _ = std::fs::remove_dir_all(&logs);
+    std::fs::create_dir_all(&logs).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create {}: {}", logs.display(), e);
+        process::exit(1);
+    });
+    let mut reason = String::new();
+    for attempt in 1..=retries + 1 {
+        if attempt > 1 {
+            println!(
+                "{case}: retrying ({reason}), attempt {attempt} of {}",
+                retries + 1
+            );
+        }
+        let pflash = root.join(format!("pflash-{arch}.img"));
+        let _ = std::fs::remove_file(&pflash);
+        // The run stops QEMU itself at the timeout, recording its state
+        // over QMP; the kill after a grace period is only a backstop.
+        let secs = timeout.as_secs().to_string();
+        let args: Vec<&str> = ["run", "--arch", arch.name(), "--timeout", &secs]
+            .into_iter()
+            .chain(["--features", features])
+            .chain(run_args.iter().copied())
+            .collect();
+        let run = run_self(&args, timeout + RUN_GRACE, false);
+        let log = logs.join(format!("attempt-{attempt}.log"));
+        if let Err(e) = std::fs::write(&log, &run.stdout) {
+            eprintln!("Warning: failed to write {}: {}", log.display(), e);
+        }
+        reason = match run.status {
+            None => format!("no exit within {} s", timeout.as_secs()),
+            Some(status) if status.code() == Some(qmp::TIMEOUT_EXIT) => {
+                format!(
+                    "timed out after {} s (QEMU stopped over QMP)",
+                    timeout.as_secs()
+                )
+            }
+            Some(status) if !status.success() => format!("run failed ({status})"),
+            // The guest ran to the end, so what it printed is its answer: a
+            // rerun would only print the same.
+            Some(_) => {
+                let checked = measure::check(&pflash, &run.stdout)
+                    .and_then(|()| json::check(&run.stdout, expect))
+                    .and_then(|()| {
+                        snapshot::check(root, arch.name(), features, &run.stdout, update)
+                            .then_some(())
+                            .ok_or_else(|| "output differs from the snapshot".to_string())
+                    });
+                return match (checked, attempt) {
+                    (Err(reason), _) => {
+                        println!(
+                            "{case}: attempt {attempt} failed: {reason} (log: {})",
+                            log.display()
+                        );
+                        Verdict::Fail(reason)
+                    }
+                    (Ok(()), 1) => Verdict::Pass,
+                    (Ok(()), n) => Verdict::Flaky(n - 1),
+                };
+            }
+        };
+        println!(
+            "{case}: attempt {attempt} failed: {reason} (log: {})",
+            log.display()
+        );
+    }
+    Verdict::Fail(reason)
+}
+
+/// Quote a string for POSIX sh.
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=,:@+".contains(c))
+    {
+        return s.into();
+    }
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Encode a string as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Print the composed QEMU command on a single line for external tools.
+fn print_qemu_cmdline(format: CmdlineFormat, qemu: &str, args: &[String]) {
+    match format {
+        CmdlineFormat::Text => {
+            let quoted: Vec<String> = args.iter().map(|a| shell_quote(a)).collect();
+            println!("{} {}", shell_quote(qemu), quoted.join(" "));
+        }
+        CmdlineFormat::Json => {
+            let args: Vec<String> = args.iter().map(|a| json_string(a)).collect();
+            println!(
+                "{{\"qemu\":{},\"args\":[{}]}}",
+                json_string(qemu),
+                args.join(",")
+            );
+        }
+    }
+}
+
+/// Relative path from directory `from` to `to` (both absolute).
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut rel = PathBuf::new();
+    for _ in common..from.len() {
+        rel.push("..");
+    }
+    for c in &to[common..] {
+        rel.push(c);
+    }
+    if rel.as_os_str().is_empty() {
+        rel.push(".");
+    }
+    rel
+}
+
+/// Write a standalone shell script that reproduces the QEMU invocation.
+///
+/// Artifacts under the project root are referenced relative to the script's
+/// own location, so the script keeps working when the project (or just the
+/// script with `target/` and the pflash image) is moved or copied elsewhere.
+/// Paths outside the project (system firmware, `--uboot`) stay absolute.
+fn emit_run_script(root: &Path, script: &Path, qemu: &str, args: &[String]) {
+    let script_dir = script
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let script_dir = std::fs::canonicalize(script_dir).unwrap_or_else(|e| {
+        eprintln!(
+            "Error: script directory {} is not accessible: {}",
+            script_dir.display(),
+            e
+        );
+        process::exit(1);
+    });
+    let rel_root = relative_path(&script_dir, root);
+    let root_prefix = format!("{}/", root.display());
+
+    let mut text = String::from("#!/bin/sh\n");
+    text.push_str("# Generated by `cargo xtask run --emit-script`.\n");
+    text.push_str("set -e\n");
+    text.push_str(&format!(
+        "ROOT=\"$(cd \"$(dirname \"$0\")\"/{} && pwd)\"\n",
+        shell_quote(&rel_root.display().to_string())
+    ));
+    text.push_str(&format!("exec {}", shell_quote(qemu)));
+    for arg in args {
+        // Splice "$ROOT"/ in place of the absolute project root.
+        let quoted: Vec<String> = arg.split(&root_prefix).map(shell_quote).collect();
+        text.push_str(" \\\n    ");
+        text.push_str(&quoted.join("\"$ROOT\"/"));
+    }
+    text.push_str(" \"$@\"\n");
+
+    std::fs::write(script, text).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", script.display(), e);
+        process::exit(1);
+    });
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(script, std::fs::Permissions::from_mode(0o755));
+    }
+    println!("Wrote run script: {}", script.display());
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let root = project_root();
+
+    match cli.command {
+        Cmd::Build {
+            arch,
+            ref features,
+            no_paging,
+            ref link,
+        } => {
+            let ArchSet::One(arch) = arch else {
+                run_each_arch();
+            };
+            let info = arch_info(arch);
+            let _lock = BuildLock::acquire(&target_dir(&root, &info));
+            let config = install_config(&root, arch, &info, None);
+            if let Some(addr) = link.load_addr {
+                mirror_load_addr(&config, addr);
+            }
+            do_build(
+                &root,
+                &info,
+                &config,
+                features.as_deref(),
+                !no_paging,
+                None,
+                link.link_script.as_deref(),
+            );
+            println!("Build complete for {arch} ({})", info.target);
+        }
+        Cmd::Size {
+            arch,
+            ref features,
+            no_paging,
+            check,
+        } => {
+            let ArchSet::One(arch) = arch else {
+                run_each_arch();
+            };
+            let info = arch_info(arch);
+            let _lock = BuildLock::acquire(&target_dir(&root, &info));
+            let config = install_config(&root, arch, &info, None);
+            do_build(
+                &root,
+                &info,
+                &config,
+                features.as_deref(),
+                !no_paging,
+                None,
+                None,
+            );
+            let (elf, bin) = kernel_artifacts(&root, &info, arch);
+            size::run(&root, arch, &elf, &bin, info.objcopy_arch, check);
+        }
+        Cmd::Mkimage {
+            arch,
+            ref image,
+            boot_artifact,
+        } => {
+            let image = &layout::apply(image);
+            let info = arch_info(arch);
+            // Embedding the kernel uses whatever `build`/`run` produced last.
+            let (elf, bin) = kernel_artifacts(&root, &info, arch);
+            let kernel = match boot_artifact.unwrap_or(info.boot_artifact) {
+                BootArtifact::Elf => elf,
+                BootArtifact::Bin => bin,
+            };
+            if image.kernel_in_flash.is_some() && !kernel.exists() {
+                eprintln!(
+                    "Error: {} not found; run `cargo xtask run --arch {arch}` first",
+                    kernel.display()
+                );
+                process::exit(1);
+            }
+            create_pflash_image(&root, arch, image, &kernel);
+        }
+        Cmd::FlashDevice {
+            arch,
+            ref probe,
+            ref config,
+            ref features,
+            dry_run,
+            ref image,
+        } => {
+            if arch == Arch::X86_64 {
+                eprintln!(
+                    "Error: flash-device does not support x86_64, whose flash holds the BIOS \
+                     and is written with a SPI programmer"
+                );
+                process::exit(1);
+            }
+            if let Some(path) = config.as_deref().filter(|path| !path.exists()) {
+                eprintln!("Error: config file not found: {}", path.display());
+                process::exit(1);
+            }
+            let image = &layout::apply(image);
+            let info = arch_info(arch);
+            let _lock = BuildLock::acquire(&target_dir(&root, &info));
+            // The app is built with the board's config, so it reads the
+            // bank at the address the image is written to.
+            let config = install_config(&root, arch, &info, config.as_deref());
+            let key = |key: &str| {
+                read_config_uint(&config, key).unwrap_or_else(|| {
+                    eprintln!("Error: {} has no {key}", config.display());
+                    process::exit(1);
+                })
+            };
+            // bank0 builds read pflash0, so the image goes there.
+            let paddr = match has_feature(features.as_deref(), "bank0") {
+                true => "pflash0-paddr",
+                false => "pflash-paddr",
+            };
+            let (flash, bank, entry) = (key(paddr), key("pflash-size"), key("kernel-base-paddr"));
+            if pflash_size(arch) as u64 > bank {
+                eprintln!(
+                    "Error: the {} MiB image does not fit the {bank:#x}-byte bank (pflash-size)",
+                    pflash_size(arch) >> 20
+                );
+                process::exit(1);
+            }
+            do_build(
+                &root,
+                &info,
+                &config,
+                features.as_deref(),
+                true,
+                image.layout.as_deref(),
+                None,
+            );
+            let (elf, bin) = kernel_artifacts(&root, &info, arch);
+            do_objcopy(&elf, &bin, info.objcopy_arch);
+            let pflash = create_pflash_image(&root, arch, image, &bin);
+            let target = device::Target {
+                kernel: &elf,
+                entry,
+                image: &pflash,
+                flash,
+            };
+            println!(
+                "Writing {} to {flash:#x} and the kernel to {entry:#x}",
+                pflash.display()
+            );
+            device::flash(probe, &target, dry_run);
+        }
+        Cmd::Test {
+            arch,
+            ref features,
+            update_snapshots,
+            timeout,
+            retries,
+            ref mem,
+            ref smp,
+            ref expect,
+        } => {
+            let archs = arch.archs();
+            let features = if expect.is_empty() || features.split(',').any(|f| f == "json-report") {
+                features.clone()
+            } else if features.is_empty() {
+                "json-report".to_string()
+            } else {
+                format!("{features},json-report")
+            };
+            let features = features.as_str();
+            for size in mem {
+                if let Err(e) = parse_mem(size) {
+                    eprintln!("Error: {e}");
+                    process::exit(1);
+                }
+            }
+            let matrix: Vec<(&str, usize)> = mem
+                .iter()
+                .flat_map(|size| smp.iter().map(move |&cpus| (size.as_str(), cpus)))
+                .collect();
+            let timeout = Duration::from_secs(timeout);
+            let mut verdicts = Vec::new();
+            for arch in archs {
+                for (i, &(size, cpus)) in matrix.iter().enumerate() {
+                    let case = match matrix.len() {
+                        1 => arch.to_string(),
+                        _ => format!("{arch}-{size}-smp{cpus}"),
+                    };
+                    println!("Testing {arch} (features {features}, mem {size}, smp {cpus})...");
+                    let cpus = cpus.to_string();
+                    let args = ["--mem", size, "--smp", &cpus];
+                    // The app must print the same whatever the RAM size and
+                    // CPU count, so every combination is held to the one
+                    // snapshot of the architecture and features, written by
+                    // the first.
+                    let update = update_snapshots && i == 0;
+                    let verdict = test_arch(
+                        &root, arch, &case, features, &args, timeout, retries, update, expect,
+                    );
+                    verdicts.push((case, verdict));
+                }
+            }
+            println!("Summary:");
+            let mut failed = 0;
+            for (case, verdict) in &verdicts {
+                match verdict {
+                    Verdict::Pass => println!("  {case:<24} PASS"),
+                    Verdict::Flaky(n) => {
+                        println!("  {case:<24} FLAKY (passed after {n} failed attempt(s))")
+                    }
+                    Verdict::Fail(reason) => {
+                        failed += 1;
+                        println!("  {case:<24} FAIL ({reason})");
+                    }
+                }
+            }
+            if failed > 0 {
+                eprintln!("Error: {failed} test run(s) failed");
+                process::exit(1);
+            }
+        }
+        Cmd::Shell {
+            arch,
+            ref features,
+            ref script,
+            port,
+            timeout,
+        } => {
+            let opts = shell::Options {
+                arch,
+                features: features.as_deref(),
+                port,
+                timeout: Duration::from_secs(timeout),
+            };
+            shell::run(&opts, script.as_deref());
+        }
+        Cmd::CaptureDump {
+            arch,
+            ref features,
+            offset,
+            len,
+            ref out,
+            port,
+            timeout,
+        } => {
+            let opts = shell::Options {
+                arch,
+                features: features.as_deref(),
+                port,
+                timeout: Duration::from_secs(timeout),
+            };
+            shell::capture(&opts, offset, len, out);
+        }
+        Cmd::Status => daemon::status(&root),
+        Cmd::Stop { arch } => daemon::stop(&root, &arch.archs()),
+        Cmd::Runs { action } => match action {
+            RunsCmd::List => runs::list(&root),
+            RunsCmd::Show { run } => runs::show(&root, run.as_deref()),
+        },
+        Cmd::VerifyRun { run } => verify::run(&root, run.as_deref()),
+        Cmd::List => {
+            println!(
+                "{:<12} {:<32} {:<22} {:<8} {:<7} {:<12} IMAGE",
+                "ARCH", "TARGET", "PLATFORM", "MACHINE", "BANK", "BASE"
+            );
+            for arch in Arch::ALL {
+                let info = arch_info(arch);
+                println!(
+                    "{arch:<12} {:<32} {:<22} {:<8} {:<7} {:<12} {} MiB",
+                    info.target,
+                    info.platform,
+                    info.machine,
+                    format!("pflash{}", arch.bank().unit),
+                    format!("{:#x}", arch.bank().paddr),
+                    pflash_size(arch) >> 20
+                );
+            }
+        }
+        Cmd::GenConfigs { check } => genconfig::run(&root, check),
+        Cmd::Env {
+            arch,
+            ref features,
+            ref machine,
+            ref bios,
+            ref mem,
+            ref smp,
+            boot_artifact,
+        } => {
+            let flags = settings::Flags {
+                features: features.as_deref(),
+                machine: machine.as_deref(),
+                bios: bios.as_deref(),
+                mem,
+                smp,
+                boot_artifact,
+            };
+            settings::print(&root, arch, &flags);
+        }
+        Cmd::Compare {
+            ref archs,
+            ref features,
+            ref ignore,
+            timeout,
+            ref run_args,
+        } => {
+            let mut unique: Vec<Arch> = Vec::new();
+            for &arch in archs {
+                if !unique.contains(&arch) {
+                    unique.push(arch);
+                }
+            }
+            if unique.len() < 2 {
+                eprintln!("Error: --archs needs at least two different architectures");
+                process::exit(1);
+            }
+            let opts = compare::Options {
+                archs: unique,
+                features,
+                ignore,
+                run_args,
+                timeout: Duration::from_secs(timeout),
+            };
+            compare::run(&root, &opts);
+        }
+        Cmd::Bench {
+            arch,
+            ref features,
+            runs,
+            warmup,
+            ref export,
+            timeout,
+            ref run_args,
+        } => {
+            if runs == 0 {
+                eprintln!("Error: --runs must be at least 1");
+                process::exit(1);
+            }
+            let export = export.as_deref().map(|path| match bench::Export::of(path) {
+                Ok(format) => (path, format),
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    process::exit(1);
+                }
+            });
+            let opts = bench::Options {
+                arch,
+                features,
+                runs,
+                warmup,
+                run_args,
+                timeout: Duration::from_secs(timeout),
+                export,
+            };
+            bench::run(&root, &opts);
+        }
+        Cmd::Ci {
+            arch,
+            ref features,
+            ref expect,
+            timeout,
+            ref report_dir,
+        } => {
+            let archs = arch.archs();
+            let opts = ci::Options {
+                archs,
+                features,
+                expect,
+                timeout: Duration::from_secs(timeout),
+            };
+            ci::run(&opts, &root.join(report_dir));
+        }
+        Cmd::Image {
+            action:
+                ImageCmd::Corrupt {
+                    arch,
+                    offset,
+                    ref bits,
+                    ref pflash_out,
+                },
+        } => {
+            let path =
+                image::image_dir(&root, pflash_out.as_deref()).join(format!("pflash-{arch}.img"));
+            if !path.exists() {
+                eprintln!(
+                    "Error: {} not found; run `cargo xtask mkimage --arch {arch}` first",
+                    path.display()
+                );
+                process::exit(1);
+            }
+            image::corrupt(&path, offset, bits);
+        }
+        Cmd::Image {
+            action:
+                ImageCmd::Inspect {
+                    arch,
+                    panics,
+                    bench,
+                    ref pflash_out,
+                },
+        } => {
+            let path =
+                image::image_dir(&root, pflash_out.as_deref()).join(format!("pflash-{arch}.img"));
+            if !path.exists() {
+                eprintln!(
+                    "Error: {} not found; run `cargo xtask mkimage --arch {arch}` first",
+                    path.display()
+                );
+                process::exit(1);
+            }
+            image::inspect(&path, panics, bench);
+        }
+        Cmd::Run {
+            arch,
+            ref image,
+            ref features,
+            mode,
+            ref bios,
+            ref opensbi,
+            boot,
+            ref uboot,
+            boot_artifact,
+            ref machine,
+            ref machine_version,
+            accel,
+            strict_accel,
+            secure,
+            el2,
+            semihost,
+            selftest,
+            ref access_width,
+            heap_hash,
+            no_paging,
+            ref link,
+            ref smp,
+            ref mem,
+            numa,
+            watchdog,
+            ref mem_backend,
+            ref emit_script,
+            ref print_cmdline,
+            ref pflash_opts,
+            serial_tcp,
+            timeout,
+            ref trace_pflash,
+            daemon,
+        } => {
+            let deadline = timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+            if daemon && timeout.is_some() {
+                eprintln!("Error: --timeout only applies to runs in the foreground, not --daemon");
+                process::exit(1);
+            }
+            // A daemon has no terminal, so its serial port goes on a socket.
+            let serial_tcp = match serial_tcp {
+                None if daemon => Some(qmp::free_port().unwrap_or_else(|| {
+                    eprintln!("Error: cannot find a free local port for the serial socket");
+                    process::exit(1);
+                })),
+                port => port,
+            };
+            let arch = match arch {
+                ArchSet::One(arch) => arch,
+                // Each run would overwrite the same script.
+                ArchSet::All if emit_script.is_some() => {
+                    eprintln!("Error: --emit-script needs a single --arch");
+                    process::exit(1);
+                }
+                ArchSet::All => run_each_arch(),
+            };
+            let info = arch_info(arch);
+            // Before the build, so a QEMU too old for the bank stops the run
+            // at once rather than with an obscure machine error.
+            if emit_script.is_none()
+                && print_cmdline.is_none()
+                && let Some(e) = qemu_too_old(arch)
+            {
+                eprintln!("Error: {e}");
+                process::exit(1);
+            }
+            let accel = match accel::unavailable(accel, arch, secure) {
+                Some(why) if strict_accel => {
+                    eprintln!("Error: --accel {}: {why}", accel.name());
+                    process::exit(1);
+                }
+                Some(why) => {
+                    eprintln!(
+                        "Warning: --accel {} cannot be used here ({why}); falling back to tcg",
+                        accel.name()
+                    );
+                    accel::Accel::Tcg
+                }
+                None => accel,
+            };
+            let image = &layout::apply(image);
+            let mut pflash_opts = parse_pflash_opts(pflash_opts);
+            let mem_file = mem_backend.as_deref().map(parse_mem_backend);
+            let smp = parse_smp(smp);
+            let mem_mib = parse_mem(mem).unwrap_or_else(|e| {
+                eprintln!("Error: {e}");
+                process::exit(1);
+            });
+            if let Some(nodes) = numa {
+                check_numa(nodes, &smp, mem_mib);
+                if mem_file.is_some() {
+                    eprintln!("Error: --numa cannot be combined with --mem-backend");
+                    process::exit(1);
+                }
+            }
+            let mut features = features.clone();
+            if let Some(mode) = mode {
+                add_feature(&mut features, &mode.feature());
+                for feature in mode.features() {
+                    add_feature(&mut features, feature);
+                }
+            }
+            // More than one CPU needs ArceOS's SMP support to bring them up.
+            if smp.cpus > 1 {
+                add_feature(&mut features, "smp");
+            }
+            if let Some(mode) = watchdog {
+                add_feature(&mut features, mode.feature());
+            }
+            // --romfs is only read by the guest's romfs feature, which in turn
+            // needs something in the fs region.
+            if image.romfs.is_some() {
+                add_feature(&mut features, "romfs");
+            } else if has_feature(features.as_deref(), "romfs") && image.fs.is_none() {
+                eprintln!(
+                    "Error: --features romfs needs --romfs <DIR> (or --fs with a romfs image)"
+                );
+                process::exit(1);
+            }
+            // A script is run by the guest's flash-script feature, and may
+            // program and erase.
+            let flash_script = image.flash_script.is_some();
+            if flash_script {
+                add_feature(&mut features, "flash-script");
+            } else if has_feature(features.as_deref(), "flash-script") {
+                eprintln!("Error: --features flash-script needs --flash-script <FILE>");
+                process::exit(1);
+            }
+            // --meta pairs are printed by the guest's meta feature.
+            if !image.meta.is_empty() {
+                add_feature(&mut features, "meta");
+            } else if has_feature(features.as_deref(), "meta") {
+                eprintln!("Error: --features meta needs at least one --meta KEY=VALUE");
+                process::exit(1);
+            }
+            // So is the banner, by the guest's banner feature.
+            if image.banner.is_some() {
+                add_feature(&mut features, "banner");
+            } else if has_feature(features.as_deref(), "banner") {
+                eprintln!("Error: --features banner needs --banner <FILE|TEXT>");
+                process::exit(1);
+            }
+            // And the catalog, by the guest's messages feature.
+            if image.messages.is_some() {
+                add_feature(&mut features, "messages");
+            } else if has_feature(features.as_deref(), "messages") {
+                eprintln!("Error: --features messages needs --messages <FILE>");
+                process::exit(1);
+            }
+            // The guest checks a --flash-geometry against what the chips
+            // report.
+            if image.flash_geometry.is_some() {
+                add_feature(&mut features, "identify");
+            }
+            // A --pattern payload is checked by the guest's pattern feature.
+            if image.pattern.is_some() {
+                add_feature(&mut features, "pattern");
+            } else if has_feature(features.as_deref(), "pattern") {
+                eprintln!("Error: --features pattern needs --pattern prng:<SEED>:<LEN>");
+                process::exit(1);
+            }
+            // An --encrypt payload is decrypted by the guest's decrypt
+            // feature, which otherwise gets one sealed with the development
+            // key it has built in.
+            let dev_key = image.encrypt.is_none() && has_feature(features.as_deref(), "decrypt");
+            if image.encrypt.is_some() {
+                add_feature(&mut features, "decrypt");
+            }
+            // A cpio payload is listed by the guest's cpio feature.
+            if image.payload.as_deref().is_some_and(is_cpio) {
+                add_feature(&mut features, "cpio");
+            }
+            // So is a tar archive, as the payload or filesystem image.
+            if [&image.payload, &image.fs]
+                .into_iter()
+                .flatten()
+                .any(|path| is_ustar(path))
+            {
+                add_feature(&mut features, "tar");
+            }
+            // The fs mode reads whichever filesystem it is given, and writes
+            // back into the fs region if there is one.
+            if mode == Some(mode::Mode::Fs) {
+                if image.ext2.is_some() {
+                    add_feature(&mut features, "ext2");
+                }
+                let fs_region = image.fs.is_some() || image.romfs.is_some() || image.ext2.is_some();
+                if fs_region {
+                    add_feature(&mut features, "fs-write");
+                } else if !["cpio", "tar"]
+                    .into_iter()
+                    .any(|feature| has_feature(features.as_deref(), feature))
+                {
+                    eprintln!(
+                        "Error: --mode fs needs a filesystem: --romfs <DIR>, --ext2 <DIR>, --fs <FILE> \
+                         or a cpio or tar --payload"
+                    );
+                    process::exit(1);
+                }
+            }
+            // A partition table is read by the guest's partitions feature,
+            // which finds the image through it.
+            if image.partition_table.is_some() {
+                add_feature(&mut features, "partitions");
+            }
+            // The journal, write-back, erase suspend, write queue, replica,
+            // flash log and crash record demos need their regions and a
+            // writable flash bank.
+            if image.journal {
+                add_feature(&mut features, "journal");
+            }
+            let journal = ["journal", "erase-suspend", "write-queue"]
+                .into_iter()
+                .any(|feature| has_feature(features.as_deref(), feature));
+            let fs_writable = image.fs_writable || has_feature(features.as_deref(), "fs-write");
+            if fs_writable {
+                add_feature(&mut features, "fs-write");
+            }
+            let replicas = image.replicas || has_feature(features.as_deref(), "replicas");
+            if replicas {
+                add_feature(&mut features, "replicas");
+            }
+            let log_ring = image.log_ring || has_feature(features.as_deref(), "flash-log");
+            if log_ring {
+                add_feature(&mut features, "flash-log");
+            }
+            let panic_region = image.panic_region
+                || ["panic-record", "panic-test"]
+                    .into_iter()
+                    .any(|feature| has_feature(features.as_deref(), feature));
+            if panic_region {
+                add_feature(&mut features, "panic-record");
+            }
+            let bench_region =
+                image.bench_region || has_feature(features.as_deref(), "bench-record");
+            if bench_region {
+                add_feature(&mut features, "bench-record");
+            }
+            if (journal
+                || fs_writable
+                || replicas
+                || log_ring
+                || panic_region
+                || bench_region
+                || flash_script)
+                && !pflash_opts.iter().any(|(k, _)| k == "readonly")
+            {
+                pflash_opts.push(("readonly".into(), "off".into()));
+            }
+            let bios = resolve_bios(arch, bios.as_deref());
+            let use_uboot = boot == BootFlow::Uboot;
+            let flash_boot = boot == BootFlow::Flash;
+            if flash_boot && image.kernel_in_flash.is_none() {
+                eprintln!("Error: --boot flash requires --kernel-in-flash <OFFSET>");
+                process::exit(1);
+            }
+            if flash_boot && image.partition_table.is_some() {
+                // The boot flow jumps to the offset in the header, which a
+                // partition table moves the kernel away from.
+                eprintln!("Error: --boot flash cannot be combined with --partition-table");
+                process::exit(1);
+            }
+            // Kernel command line, for the features that read it.
+            let mut bootargs = Vec::new();
+            if selftest || mode == Some(mode::Mode::Selftest) {
+                add_feature(&mut features, "selftest");
+                bootargs.push("selftest".to_string());
+            }
+            if let Some(bits) = access_width {
+                add_feature(&mut features, "access-width");
+                bootargs.push(format!("width={bits}"));
+            }
+            if let Some(mib) = heap_hash {
+                add_feature(&mut features, "heap-hash");
+                bootargs.push(format!("hash-mib={mib}"));
+                if mib as usize >= mem_mib {
+                    eprintln!(
+                        "Warning: a {mib} MiB buffer does not fit in {mem_mib} MiB of guest \
+                         RAM; raise --mem"
+                    );
+                }
+                if !matches!(arch, Arch::Riscv64 | Arch::Aarch64) {
+                    eprintln!(
+                        "Warning: the guest cannot read its command line on {arch}, so it \
+                         hashes the default span"
+                    );
+                }
+            }
+            if let Some(path) = &image.encrypt {
+                // The guest learns the key from its command line only.
+                let key = image::read_key(path);
+                bootargs.push(format!("key={}", image::hex(&key)));
+                if !matches!(arch, Arch::Riscv64 | Arch::Aarch64) {
+                    eprintln!(
+                        "Warning: the guest cannot read its command line on {arch}, so it only \
+                         tries the development key"
+                    );
+                }
+            }
+            if semihost {
+                if !matches!(arch, Arch::Riscv64 | Arch::Aarch64) {
+                    eprintln!(
+                        "Error: --semihost is only supported for riscv64 and aarch64 (got --arch {arch})"
+                    );
+                    process::exit(1);
+                }
+                add_feature(&mut features, "semihosting");
+            }
+            if (!bootargs.is_empty() || semihost) && boot != BootFlow::Direct {
+                eprintln!(
+                    "Error: --selftest, --access-width, --encrypt and --semihost need --boot direct, where QEMU passes the command line"
+                );
+                process::exit(1);
+            }
+            if secure && arch != Arch::Aarch64 {
+                eprintln!("Error: --secure is only supported for aarch64 (got --arch {arch})");
+                process::exit(1);
+            }
+            if el2 {
+                if arch != Arch::Aarch64 {
+                    eprintln!("Error: --el2 is only supported for aarch64 (got --arch {arch})");
+                    process::exit(1);
+                }
+                add_feature(&mut features, "el2");
+            }
+            if machine.as_deref().is_some_and(is_microvm) {
+                check_microvm(
+                    arch,
+                    features.as_deref(),
+                    &pflash_opts,
+                    trace_pflash.is_some(),
+                    image.flash_geometry.is_some(),
+                );
+            }
+            let highmem_off = machine.as_deref().is_some_and(is_highmem_off);
+            if highmem_off && arch != Arch::Aarch64 {
+                eprintln!("Error: highmem=off is an aarch64 virt option (got --arch {arch})");
+                process::exit(1);
+            }
+            if highmem_off && mem_mib > HIGHMEM_OFF_RAM_LIMIT {
+                eprintln!(
+                    "Error: --mem {mem} does not fit below 4 GiB, which is all the RAM \
+                     highmem=off leaves (at most {HIGHMEM_OFF_RAM_LIMIT}M)"
+                );
+                process::exit(1);
+            }
+            select_bank(arch, &mut features, &mut pflash_opts);
+            if image.layout.is_some() {
+                add_feature(&mut features, "layout-regions");
+            }
+            let uboot_firmware = use_uboot.then(|| find_uboot(arch, uboot.as_deref()));
+            if opensbi.is_some() && bios != "flash" {
+                eprintln!("Error: --opensbi is only used with --bios flash");
+                process::exit(1);
+            }
+            if bios == "flash" && use_uboot {
+                eprintln!("Error: --bios flash cannot be combined with --boot uboot");
+                process::exit(1);
+            }
+            let opensbi = (bios == "flash").then(|| find_opensbi(opensbi.as_deref()));
+            // QEMU loads a raw binary at the machine's kernel address, and
+            // the ELF where it is linked. U-Boot loads the raw binary at
+            // kernel-base-paddr itself.
+            let moved = link.load_addr.is_some() && !use_uboot;
+            if moved && boot_artifact == Some(BootArtifact::Bin) {
+                eprintln!(
+                    "Warning: QEMU loads a raw binary at the machine's kernel address, not at \
+                     --load-addr; use --boot-artifact elf"
+                );
+            }
+            let requested = boot_artifact.or(moved.then_some(BootArtifact::Elf));
+            let artifact = resolve_boot_artifact(arch, requested, use_uboot, opensbi.is_some());
+            let lock = BuildLock::acquire(&target_dir(&root, &info));
+            let config = install_config(&root, arch, &info, None);
+            mirror_topology(&config, smp.cpus, numa);
+            mirror_memory(&config, arch, mem_mib);
+            if highmem_off {
+                mirror_highmem_off(&config);
+            }
+            if let Some(addr) = link.load_addr {
+                mirror_load_addr(&config, addr);
+            }
+            do_build(
+                &root,
+                &info,
+                &config,
+                features.as_deref(),
+                !no_paging,
+                image.layout.as_deref(),
+                link.link_script.as_deref(),
+            );
+
+            let (elf, bin) = kernel_artifacts(&root, &info, arch);
+            if artifact == BootArtifact::Bin {
+                do_objcopy(&elf, &bin, info.objcopy_arch);
+            }
+
+            // Create pflash image with header, manifest and payload.
+            // The guest's xip, integrity, fs-write, journal, replicas,
+            // flash-log, panic-record and bench-record features need their
+            // regions, so add them implicitly.
+            let image = ImageArgs {
+                xip: image.xip || has_feature(features.as_deref(), "xip"),
+                crc: image.crc || has_feature(features.as_deref(), "integrity"),
+                journal,
+                replicas,
+                log_ring,
+                panic_region,
+                bench_region,
+                fs_writable,
+                dev_key,
+                ..image.clone()
+            };
+            let kernel = match artifact {
+                BootArtifact::Elf => &elf,
+                BootArtifact::Bin => &bin,
+            };
+            let pflash = create_pflash_image(&root, arch, &image, kernel);
+            if semihost {
+                // The guest opens it on the host by this path, so it must
+                // not depend on QEMU's working directory or split the
+                // command line.
+                let path = pflash.canonicalize().unwrap_or_else(|_| pflash.clone());
+                let path = path.display().to_string();
+                if path.contains(char::is_whitespace) {
+                    eprintln!(
+                        "Error: --semihost passes the image path on the kernel command line, \
+                         which cannot hold the spaces in {path}"
+                    );
+                    process::exit(1);
+                }
+                bootargs.push(format!("semihost={path}"));
+            }
+
+            let boot = match uboot_firmware {
+                Some(firmware) => KernelBoot::Uboot {
+                    firmware,
+                    bootdir: prepare_uboot_dir(arch, &config, &bin),
+                },
+                None if flash_boot => KernelBoot::Flash,
+                None => KernelBoot::Direct,
+            };
+
+            let firmware_flash = opensbi.map(|opensbi| {
+                let out = image::image_dir(&root, image.pflash_out.as_deref());
+                create_firmware_image(&out, arch, &opensbi)
+            });
+
+            let mut machine = machine.clone().unwrap_or_else(|| info.machine.into());
+            if let Some(version) = machine_version {
+                machine = versioned_machine(arch, &machine, version).unwrap_or_else(|e| {
+                    eprintln!("Error: --machine-version {version}: {e}");
+                    process::exit(1);
+                });
+                check_machine_type(arch, &machine);
+            }
+            if secure {
+                // A pflash0 drive would count as firmware and QEMU would
+                // start from it instead of the kernel, so bank 0 is left
+                // without one.
+                machine.push_str(",secure=on");
+            }
+            if el2 {
+                machine.push_str(",virtualization=on");
+            }
+            let opts = QemuOpts {
+                bios,
+                firmware_flash,
+                boot,
+                machine,
+                pflash_opts,
+                flash_geometry: image.flash_geometry,
+                mem_mib,
+                mem_file,
+                smp,
+                numa,
+                watchdog: watchdog.is_some(),
+                append: (!bootargs.is_empty()).then(|| bootargs.join(" ")),
+                serial_tcp,
+                trace_log: trace_pflash.as_ref().map(|path| {
+                    if path.as_os_str().is_empty() {
+                        image::image_dir(&root, image.pflash_out.as_deref())
+                            .join(format!("pflash-{arch}.trace"))
+                    } else {
+                        path.clone()
+                    }
+                }),
+                semihosting: semihost,
+                accel,
+            };
+            if opts.machine != info.machine {
+                println!("Using machine override: {}", opts.machine);
+            }
+            if let Some(geometry) = opts.flash_geometry {
+                println!(
+                    "Flash geometry: {geometry} on a {}-bit bus, checked by the guest's \
+                     identify feature",
+                    geometry.bank_width() * 8
+                );
+            }
+            if let Some(path) = &opts.mem_file {
+                println!(
+                    "Guest RAM backed by {} (kept after QEMU exits)",
+                    path.display()
+                );
+            }
+
+            // QEMU reads the image and kernel at startup, so the artifacts
+            // can be rebuilt by another invocation while this guest runs.
+            drop(lock);
+
+            let (qemu, args) = qemu_command(arch, kernel, &pflash, &opts);
+            if let Some(script) = emit_script {
+                emit_run_script(&root, script, &qemu, &args);
+            }
+            if let Some(format) = print_cmdline {
+                print_qemu_cmdline(*format, &qemu, &args);
+            }
+            if emit_script.is_none() && print_cmdline.is_none() {
+                if let Some(log) = &opts.trace_log {
+                    // QEMU appends to an existing log.
+                    let _ = std::fs::remove_file(log);
+                }
+                match opts.serial_tcp {
+                    Some(port) if daemon => daemon::start(&root, arch, &qemu, &args, port),
+                    _ => {
+                        let archive = runs::Archive::create(&root, arch);
+                        if let Some(archive) = &archive {
+                            archive.record_start(&qemu, &args, &pflash);
+                            // For `verify-run`.
+                            if opts
+                                .pflash_opts
+                                .iter()
+                                .any(|(k, v)| k == "readonly" && v == "off")
+                            {
+                                archive.record_image(&pflash);
+                            }
+                        }
+                        do_run_qemu(
+                            &qemu,
+                            &args,
+                            deadline,
+                            opts.trace_log.as_deref(),
+                            archive.as_ref(),
+                        );
+                    }
+                }
+            }
         }
     }
 }
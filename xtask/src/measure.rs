@@ -0,0 +1,87 @@
+//! Expected measured-boot log of an image (see the guest's `measure.rs`).
+//!
+//! `mkimage` writes the event log and PCR values the guest's `measure`
+//! feature prints for an image to `<image>.measurements`, one line each as
+//! the guest prints it after `Measure: `. `xtask test` compares the log of
+//! a run with the file of its image, so a guest that hashes something other
+//! than what was written is caught even when the snapshot is updated.
+
+use crate::image::hex;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// PCRs extended.
+const PCRS: usize = 3;
+
+/// Prefix of the guest's measurement lines.
+const PREFIX: &str = "Measure: ";
+
+/// The PCR and TCG event type a part of the image is measured into.
+fn classify(name: &str) -> (usize, &'static str) {
+    match name {
+        "kernel" | "firmware" => (0, "EV_POST_CODE"),
+        "header" | "manifest" => (1, "EV_PLATFORM_CONFIG_FLAGS"),
+        _ => (2, "EV_IPL"),
+    }
+}
+
+/// The lines of the log of measuring `parts` (name and SHA-256 digest, in
+/// order), followed by the final PCR values.
+pub fn log<'a>(parts: impl IntoIterator<Item = (&'a str, [u8; 32])>) -> Vec<String> {
+    let mut pcrs = [[0u8; 32]; PCRS];
+    let mut lines = Vec::new();
+    for (i, (name, digest)) in parts.into_iter().enumerate() {
+        let (pcr, kind) = classify(name);
+        let mut hasher = Sha256::new();
+        hasher.update(pcrs[pcr]);
+        hasher.update(digest);
+        pcrs[pcr] = hasher.finalize().into();
+        lines.push(format!(
+            "#{i:<2} PCR{pcr} {kind:<24} {} {name}",
+            hex(&digest)
+        ));
+    }
+    for (i, pcr) in pcrs.iter().enumerate() {
+        lines.push(format!("PCR{i} {}", hex(pcr)));
+    }
+    lines
+}
+
+/// Compare the measurement log in the serial `output` of a run with the one
+/// expected for its image, `<image>.measurements` next to `pflash`.
+///
+/// A run without the `measure` feature prints no log and passes.
+pub fn check(pflash: &Path, output: &str) -> Result<(), String> {
+    let printed: Vec<&str> = output
+        .lines()
+        .filter_map(|line| line.trim_end().strip_prefix(PREFIX))
+        .collect();
+    if printed.is_empty() {
+        return Ok(());
+    }
+    let path = pflash.with_extension("measurements");
+    let expected = std::fs::read_to_string(&path)
+        .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+    let expected: Vec<&str> = expected.lines().collect();
+    if let Some((i, (want, got))) = expected
+        .iter()
+        .zip(&printed)
+        .enumerate()
+        .find(|(_, (want, got))| want != got)
+    {
+        return Err(format!(
+            "measurement {} differs from {}: expected '{want}', got '{got}'",
+            i + 1,
+            path.display()
+        ));
+    }
+    if expected.len() != printed.len() {
+        return Err(format!(
+            "the guest printed {} measurement line(s), {} expects {}",
+            printed.len(),
+            path.display(),
+            expected.len()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Demo modes of the app (`run --mode`).
+//!
+//! Each guest demo is a cargo feature, which lets a build hold only what it
+//! needs but leaves a lesson to know which features go together and which
+//! image options and kernel arguments they need. A mode names such a set.
+//! `--mode <MODE>` builds the guest's `mode-<MODE>` feature, which pulls in
+//! the demos of the mode and makes the app print the mode at start-up, and
+//! adds the same demos to `--features` so that `run` sets up their regions,
+//! bank access and command line as it does for each one on its own.
+//!
+//! The feature lists here and the `mode-*` features in `Cargo.toml` must
+//! match.
+
+use clap::ValueEnum;
+
+/// A set of demos for one lesson.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Check the image and read the bank; sends the chip no commands
+    Read,
+    /// Program and erase flash: the journal, replicas and the flash log
+    Write,
+    /// Read the filesystem given with --romfs, --ext2, --fs or a cpio or tar
+    /// --payload, and write back into the fs region
+    Fs,
+    /// Time reads of the bank through different mappings
+    Bench,
+    /// Run the self-test instead of the demos
+    Selftest,
+    /// Serve flash commands on the console after the boot checks
+    Shell,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Read => "read",
+            Mode::Write => "write",
+            Mode::Fs => "fs",
+            Mode::Bench => "bench",
+            Mode::Selftest => "selftest",
+            Mode::Shell => "shell",
+        }
+    }
+
+    /// The guest feature that selects the mode.
+    pub fn feature(self) -> String {
+        format!("mode-{}", self.name())
+    }
+
+    /// The demos of the mode. Those of the fs mode depend on the image:
+    /// `run` adds the reader of the filesystem it is given, and `fs-write`
+    /// if it has an fs region.
+    pub fn features(self) -> &'static [&'static str] {
+        match self {
+            Mode::Read => &["verify", "integrity", "report"],
+            Mode::Write => &["journal", "replicas", "flash-log"],
+            Mode::Fs => &[],
+            Mode::Bench => &["write-combining", "map-info"],
+            Mode::Selftest => &["selftest"],
+            Mode::Shell => &["shell"],
+        }
+    }
+}
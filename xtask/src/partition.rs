@@ -0,0 +1,160 @@
+//! Partition tables around the image (`--partition-table`).
+//!
+//! Flash on real boards often carries a partition table, as a disk does.
+//! With `--partition-table mbr` or `gpt` the bank starts with one instead of
+//! the image header, and the image sits in a data partition at
+//! [`DATA_OFFSET`]. The guest's `partitions` feature lists the table and
+//! reads the image through it.
+//!
+//! - `mbr`: a classic MBR with one primary partition of type
+//!   [`MBR_DATA_TYPE`] running to the end of the bank.
+//! - `gpt`: a protective MBR, the GPT header at LBA 1 and its entry array
+//!   from LBA 2, with one partition of type [`GPT_DATA_TYPE`] named
+//!   `readpflash`. The backup header and entries take the last
+//!   [`GPT_BACKUP`] bytes of the bank, so the partition ends before them.
+//!
+//! Region offsets in the manifest, `<image>.manifest.json` and
+//! `<image>.measurements` stay relative to the start of the image, that is
+//! of the data partition.
+
+use clap::ValueEnum;
+pub use readpflash_layout::{GPT_DATA_TYPE, MBR_DATA_TYPE, MBR_PROTECTIVE, SECTOR};
+
+/// Offset of the data partition in the bank: 1 MiB, as partitioning tools
+/// align the first partition, which is also a whole number of erase blocks.
+pub const DATA_OFFSET: usize = 0x10_0000;
+/// Bytes kept at the end of the bank for the backup GPT: its header and
+/// 32 sectors of entries, rounded up to 4 KiB.
+pub const GPT_BACKUP: usize = 0x8000;
+/// Disk and partition GUIDs, fixed so that the same inputs build the same
+/// image.
+const DISK_GUID: [u8; 16] = guid(0x5046_4C41, 0x0001, 0x0001, *b"readpfls");
+const PART_GUID: [u8; 16] = guid(0x5046_4C41, 0x0002, 0x0001, *b"readpfls");
+/// Entries in the GPT entry array, and bytes each.
+const GPT_ENTRIES: usize = 128;
+const GPT_ENTRY_SIZE: usize = 128;
+/// Size of the GPT header.
+const GPT_HEADER_SIZE: usize = 92;
+
+/// Partition table written with `--partition-table`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PartitionTable {
+    Mbr,
+    Gpt,
+}
+
+/// A GUID in its on-disk byte order: the first three fields little-endian.
+const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let (a, b, c) = (a.to_le_bytes(), b.to_le_bytes(), c.to_le_bytes());
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6],
+        d[7],
+    ]
+}
+
+/// Bytes of a bank of `size` bytes left for the image in the data
+/// partition.
+pub fn data_len(table: PartitionTable, size: usize) -> usize {
+    match table {
+        PartitionTable::Mbr => size - DATA_OFFSET,
+        PartitionTable::Gpt => size - DATA_OFFSET - GPT_BACKUP,
+    }
+}
+
+/// An MBR with `(type, first LBA, sectors)` as its first entry.
+fn mbr(kind: u8, first: usize, sectors: usize) -> Vec<u8> {
+    let mut mbr = vec![0; SECTOR];
+    let entry = &mut mbr[446..462];
+    // Start and end CHS set to "beyond CHS range"; the LBA fields count.
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = kind;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&(first as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors.min(u32::MAX as usize) as u32).to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+    mbr
+}
+
+/// A GPT header at `lba` with its backup at `alternate` and entries from
+/// `entries_lba`, describing a disk of `sectors` sectors.
+fn gpt_header(
+    lba: usize,
+    alternate: usize,
+    entries_lba: usize,
+    sectors: usize,
+    entries_crc: u32,
+) -> Vec<u8> {
+    let entry_sectors = GPT_ENTRIES * GPT_ENTRY_SIZE / SECTOR;
+    let mut header = vec![0; SECTOR];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+    header[24..32].copy_from_slice(&(lba as u64).to_le_bytes());
+    header[32..40].copy_from_slice(&(alternate as u64).to_le_bytes());
+    header[40..48].copy_from_slice(&(2 + entry_sectors as u64).to_le_bytes());
+    header[48..56].copy_from_slice(&((sectors - 2 - entry_sectors) as u64).to_le_bytes());
+    header[56..72].copy_from_slice(&DISK_GUID);
+    header[72..80].copy_from_slice(&(entries_lba as u64).to_le_bytes());
+    header[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+    header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let crc = crate::image::crc32(&header[..GPT_HEADER_SIZE]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+/// The `(offset, bytes)` extents of `table` in a bank of `size` bytes.
+pub fn extents(table: PartitionTable, size: usize) -> Vec<(usize, Vec<u8>)> {
+    let sectors = size / SECTOR;
+    let first = DATA_OFFSET / SECTOR;
+    let len = data_len(table, size) / SECTOR;
+    match table {
+        PartitionTable::Mbr => vec![(0, mbr(MBR_DATA_TYPE, first, len))],
+        PartitionTable::Gpt => {
+            let mut entries = vec![0; GPT_ENTRIES * GPT_ENTRY_SIZE];
+            entries[0..16].copy_from_slice(&GPT_DATA_TYPE);
+            entries[16..32].copy_from_slice(&PART_GUID);
+            entries[32..40].copy_from_slice(&(first as u64).to_le_bytes());
+            entries[40..48].copy_from_slice(&((first + len - 1) as u64).to_le_bytes());
+            for (i, unit) in "readpflash".encode_utf16().enumerate() {
+                entries[56 + 2 * i..58 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+            }
+            let entries_crc = crate::image::crc32(&entries);
+            let backup_entries = sectors - 1 - entries.len() / SECTOR;
+            vec![
+                (0, mbr(MBR_PROTECTIVE, 1, sectors - 1)),
+                (SECTOR, gpt_header(1, sectors - 1, 2, sectors, entries_crc)),
+                (2 * SECTOR, entries.clone()),
+                (backup_entries * SECTOR, entries),
+                (
+                    (sectors - 1) * SECTOR,
+                    gpt_header(sectors - 1, 1, backup_entries, sectors, entries_crc),
+                ),
+            ]
+        }
+    }
+}
+
+/// Offset of the image in `bank`: the start of its data partition if it
+/// begins with a table written by [`extents`], else 0.
+pub fn image_offset(bank: &[u8]) -> usize {
+    if bank.len() < 2 * SECTOR || bank[510..512] != [0x55, 0xAA] {
+        return 0;
+    }
+    let u32_at = |at: usize| u32::from_le_bytes(bank[at..at + 4].try_into().unwrap()) as usize;
+    let u64_at = |at: usize| u64::from_le_bytes(bank[at..at + 8].try_into().unwrap()) as usize;
+    match bank[446 + 4] {
+        MBR_DATA_TYPE => u32_at(446 + 8) * SECTOR,
+        MBR_PROTECTIVE if &bank[SECTOR..SECTOR + 8] == b"EFI PART" => {
+            let entries = u64_at(SECTOR + 72) * SECTOR;
+            let count = u32_at(SECTOR + 80);
+            let entry_size = u32_at(SECTOR + 84);
+            (0..count)
+                .map(|i| entries + i * entry_size)
+                .take_while(|&entry| entry + GPT_ENTRY_SIZE.min(entry_size) <= bank.len())
+                .find(|&entry| bank[entry..entry + 16] == GPT_DATA_TYPE)
+                .map_or(0, |entry| u64_at(entry + 32) * SECTOR)
+        }
+        _ => 0,
+    }
+}
@@ -0,0 +1,301 @@
+//! Supervising QEMU over QMP, the QEMU Machine Protocol.
+//!
+//! `xtask run` starts QEMU with a QMP server on a local TCP port and
+//! connects to it instead of only waiting for the process to exit:
+//!
+//! - a `SHUTDOWN` event says the guest powered off (and why: `guest-shutdown`,
+//!   `guest-panic`, a watchdog, ...); if QEMU does not exit on its own soon
+//!   after, it is told to `quit`;
+//! - `WATCHDOG` and `GUEST_PANICKED` events are reported as they arrive;
+//! - when `--timeout` expires, `query-status` records what the machine was
+//!   doing (running, paused on a panic, ...) before it is told to `quit`.
+//!
+//! QMP speaks one JSON object per line. The few fields read here are
+//! picked out of the text, so no JSON parser is needed.
+//!
+//! QEMU's standard error is passed on to the terminal and kept, so that a
+//! QEMU that fails can be explained (see [`crate::diagnose`]).
+
+use crate::diagnose;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{self, Child, Command, ExitStatus, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Exit status of `xtask run` when `--timeout` stopped QEMU, as for
+/// coreutils `timeout`.
+pub const TIMEOUT_EXIT: i32 = 124;
+/// How long QEMU may take to exit after the guest shut down before it is
+/// told to `quit`.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// How long to wait for the QMP server to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Read timeout on the socket, so the child is polled between events.
+const POLL: Duration = Duration::from_millis(100);
+
+/// A local TCP port nobody listens on right now.
+pub fn free_port() -> Option<u16> {
+    TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .ok()
+}
+
+/// QEMU arguments for a QMP server on the local TCP `port`.
+pub fn server_args(port: u16) -> [String; 2] {
+    [
+        "-qmp".into(),
+        format!("tcp:127.0.0.1:{port},server=on,wait=off"),
+    ]
+}
+
+/// The string value of `"key"` in the JSON object `line`, if it has one.
+fn string_field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let rest = &line[line.find(&format!("\"{key}\""))? + key.len() + 2..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let rest = rest.strip_prefix('"')?;
+    Some(&rest[..rest.find('"')?])
+}
+
+/// An open QMP session.
+struct Qmp {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// A line read up to a timeout, completed by the next read.
+    pending: String,
+}
+
+impl Qmp {
+    /// Connect to the QMP server on `port` and leave capabilities
+    /// negotiation, giving up when `alive` says QEMU exited or
+    /// [`CONNECT_TIMEOUT`] passes.
+    fn connect(port: u16, mut alive: impl FnMut() -> bool) -> Option<Self> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let stream = loop {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)) {
+                break stream;
+            }
+            if Instant::now() > deadline || !alive() {
+                return None;
+            }
+            std::thread::sleep(POLL);
+        };
+        stream.set_read_timeout(Some(POLL)).ok()?;
+        let mut qmp = Self {
+            reader: BufReader::new(stream.try_clone().ok()?),
+            writer: stream,
+            pending: String::new(),
+        };
+        // The greeting, then the reply to qmp_capabilities.
+        qmp.wait_for("\"QMP\"", deadline)?;
+        qmp.execute("qmp_capabilities", deadline)?;
+        Some(qmp)
+    }
+
+    /// The next complete line, `Some("")` if none arrived within [`POLL`],
+    /// or `None` once the connection is closed.
+    fn line(&mut self) -> Option<String> {
+        match self.reader.read_line(&mut self.pending) {
+            Ok(0) => None,
+            Ok(_) => Some(std::mem::take(&mut self.pending)),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Some(String::new())
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Read until a line containing `needle`, reporting the events read on
+    /// the way.
+    fn wait_for(&mut self, needle: &str, deadline: Instant) -> Option<String> {
+        while Instant::now() < deadline {
+            let line = self.line()?;
+            if line.contains(needle) {
+                return Some(line);
+            }
+            report_event(&line);
+        }
+        None
+    }
+
+    /// Send the QMP command `name` without waiting for the reply.
+    fn send(&mut self, name: &str) -> Option<()> {
+        writeln!(self.writer, "{{\"execute\": \"{name}\"}}").ok()
+    }
+
+    /// Run the QMP command `name` and return its reply line.
+    fn execute(&mut self, name: &str, deadline: Instant) -> Option<String> {
+        self.send(name)?;
+        let reply = self.wait_for("\"return\"", deadline);
+        if reply.is_none() {
+            eprintln!("Warning: QMP: no reply to {name}");
+        }
+        reply
+    }
+
+    /// The machine's run state, as `query-status` reports it.
+    fn status(&mut self) -> String {
+        let reply = self.execute("query-status", Instant::now() + CONNECT_TIMEOUT);
+        reply
+            .as_deref()
+            .and_then(|reply| string_field(reply, "status"))
+            .unwrap_or("unknown")
+            .to_string()
+    }
+}
+
+/// Print what an event line says about the guest. Returns the reason if it
+/// reports a shutdown.
+fn report_event(line: &str) -> Option<String> {
+    match string_field(line, "event")? {
+        "SHUTDOWN" => {
+            let reason = string_field(line, "reason").unwrap_or("unknown");
+            eprintln!("QMP: guest shut down ({reason})");
+            Some(reason.to_string())
+        }
+        "WATCHDOG" => {
+            let action = string_field(line, "action").unwrap_or("unknown");
+            eprintln!("QMP: watchdog fired (action: {action})");
+            None
+        }
+        "GUEST_PANICKED" => {
+            let action = string_field(line, "action").unwrap_or("unknown");
+            eprintln!("QMP: guest panicked (action: {action})");
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Ask the QEMU whose QMP server listens on `port` to `quit`. Returns
+/// whether the request was sent.
+pub fn request_quit(port: u16) -> bool {
+    Qmp::connect(port, || true).is_some_and(|mut qmp| qmp.send("quit").is_some())
+}
+
+/// Tell QEMU to `quit` through `qmp` (if connected) and reap `child`,
+/// killing it if it has not exited within [`SHUTDOWN_GRACE`].
+fn quit(child: &mut Child, qmp: Option<&mut Qmp>) -> Option<ExitStatus> {
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    // QEMU may close the socket before it replies.
+    if let Some(qmp) = qmp {
+        let _ = qmp.send("quit");
+    }
+    while Instant::now() < deadline {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Some(status);
+        }
+        std::thread::sleep(POLL);
+    }
+    let _ = child.kill();
+    child.wait().ok()
+}
+
+/// Copy everything `from` yields to standard output and `log`, if any, as
+/// it arrives, and return it all at the end.
+fn tee(mut from: impl Read + Send + 'static, mut log: Option<File>) -> JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut kept = Vec::new();
+        let mut buf = [0u8; 4096];
+        let mut stdout = std::io::stdout();
+        while let Ok(n @ 1..) = from.read(&mut buf) {
+            let _ = stdout.write_all(&buf[..n]);
+            let _ = stdout.flush();
+            if let Some(log) = log.as_mut() {
+                let _ = log.write_all(&buf[..n]);
+            }
+            kept.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8_lossy(&kept).into_owned()
+    })
+}
+
+/// Copy everything `from` yields to standard error as it arrives, and
+/// return it all at the end.
+fn keep_stderr(mut from: impl Read + Send + 'static) -> JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut kept = Vec::new();
+        let mut buf = [0u8; 4096];
+        let mut stderr = std::io::stderr();
+        while let Ok(n @ 1..) = from.read(&mut buf) {
+            let _ = stderr.write_all(&buf[..n]);
+            kept.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8_lossy(&kept).into_owned()
+    })
+}
+
+/// Run `qemu` with `args` to the end and return the exit status `xtask run`
+/// should have: QEMU's own, or [`TIMEOUT_EXIT`] if `deadline` passed first,
+/// along with everything it printed. QEMU's output is copied into
+/// `serial_log` too, if given. If QEMU fails, known failures in its standard
+/// error get a hint.
+pub fn supervise(
+    qemu: &str,
+    args: &[String],
+    deadline: Option<Instant>,
+    serial_log: Option<File>,
+) -> (i32, String) {
+    let port = free_port();
+    let mut command = Command::new(qemu);
+    command.args(args);
+    if let Some(port) = port {
+        command.args(server_args(port));
+    }
+    command.stdout(Stdio::piped());
+    command.stderr(Stdio::piped());
+    let mut child = command.spawn().unwrap_or_else(|e| {
+        eprintln!("Error: {}", diagnose::spawn_error(qemu, &e));
+        process::exit(1);
+    });
+    let copier = child.stdout.take().map(|out| tee(out, serial_log));
+    let errors = child.stderr.take().map(keep_stderr);
+    let code = wait(&mut child, port, deadline);
+    // The copiers end when QEMU's ends of the pipes close.
+    let output = copier
+        .and_then(|copier| copier.join().ok())
+        .unwrap_or_default();
+    let errors = errors.and_then(|errors| errors.join().ok());
+    if let Some(errors) = errors.filter(|_| code != 0 && code != TIMEOUT_EXIT) {
+        diagnose::report(&errors);
+    }
+    (code, output)
+}
+
+/// Wait for `child`, supervising it over the QMP server on `port`.
+fn wait(child: &mut Child, port: Option<u16>, deadline: Option<Instant>) -> i32 {
+    let mut qmp = port.and_then(|port| Qmp::connect(port, || matches!(child.try_wait(), Ok(None))));
+    if qmp.is_none() {
+        eprintln!("Warning: QMP unavailable; waiting for QEMU to exit");
+    }
+
+    let mut shut_down: Option<Instant> = None;
+    loop {
+        if let Ok(Some(status)) = child.try_wait() {
+            return status.code().unwrap_or(1);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            let state = qmp.as_mut().map_or("unknown".into(), Qmp::status);
+            eprintln!("QMP: timed out; the machine was {state}, stopping QEMU");
+            quit(child, qmp.as_mut());
+            return TIMEOUT_EXIT;
+        }
+        if shut_down.is_some_and(|at| at.elapsed() > SHUTDOWN_GRACE) {
+            eprintln!("QMP: QEMU still running after the guest shut down; quitting it");
+            let status = quit(child, qmp.as_mut());
+            return status.and_then(|status| status.code()).unwrap_or(1);
+        }
+        match qmp.as_mut().map(Qmp::line) {
+            Some(Some(line)) => {
+                if report_event(&line).is_some() {
+                    shut_down.get_or_insert_with(Instant::now);
+                }
+            }
+            // QEMU closed the socket on its way out.
+            Some(None) => qmp = None,
+            None => std::thread::sleep(POLL),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! romfs image builder.
+//!
+//! Packs a host directory tree into a Linux romfs image (see
+//! `Documentation/filesystems/romfs.rst`) for the fs region. The format is
+//! a chain of 16-byte aligned file headers with the data right behind each
+//! one, all integers big-endian:
+//!
+//! ```text
+//! volume   "-rom1fs-"  full size  checksum  volume name (NUL, padded to 16)
+//! file     next|type   spec.info  size      checksum  name (padded)  data (padded)
+//! ```
+//!
+//! Like `genromfs`, the root directory header is named `.` and lists
+//! itself first; every other directory starts with `.` and `..` hard links.
+//! The checksums make the 32-bit big-endian word sum of the first 512
+//! bytes, and of every header plus its name, zero.
+
+use std::path::Path;
+use std::process;
+
+const MAGIC: &[u8; 8] = b"-rom1fs-";
+const ALIGN: usize = 16;
+/// The image size is padded to this, as `genromfs` does.
+const IMAGE_ALIGN: usize = 1024;
+/// Bytes covered by the volume checksum.
+const VOLUME_CHECKSUM_LEN: usize = 512;
+
+const TYPE_HARDLINK: u32 = 0;
+const TYPE_DIR: u32 = 1;
+const TYPE_FILE: u32 = 2;
+const TYPE_SYMLINK: u32 = 3;
+const FLAG_EXEC: u32 = 8;
+
+fn fail(msg: String) -> ! {
+    eprintln!("Error: {msg}");
+    process::exit(1);
+}
+
+/// Sum of the big-endian words of `bytes` (a multiple of 4 long).
+fn word_sum(bytes: &[u8]) -> u32 {
+    bytes.chunks_exact(4).fold(0u32, |sum, w| {
+        sum.wrapping_add(u32::from_be_bytes(w.try_into().unwrap()))
+    })
+}
+
+struct Writer {
+    buf: Vec<u8>,
+    /// Offset and checksummed length (header plus padded name) of every
+    /// file header, for the final checksum pass.
+    headers: Vec<(usize, usize)>,
+}
+
+impl Writer {
+    fn pad(&mut self) {
+        self.buf.resize(self.buf.len().next_multiple_of(ALIGN), 0);
+    }
+
+    fn put_u32(&mut self, off: usize, value: u32) {
+        self.buf[off..off + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Append a file header with its name and data; returns its offset.
+    fn header(&mut self, kind: u32, spec: usize, name: &str, data: &[u8]) -> usize {
+        let off = self.buf.len();
+        for word in [kind, spec as u32, data.len() as u32, 0] {
+            self.buf.extend_from_slice(&word.to_be_bytes());
+        }
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.push(0);
+        self.pad();
+        self.headers.push((off, self.buf.len() - off));
+        self.buf.extend_from_slice(data);
+        self.pad();
+        off
+    }
+
+    /// Point the header at `prev` to the next entry of its directory.
+    fn set_next(&mut self, prev: usize, next: usize) {
+        let kind = u32::from_be_bytes(self.buf[prev..prev + 4].try_into().unwrap()) & 0xF;
+        self.put_u32(prev, next as u32 | kind);
+    }
+
+    /// Append the entries of `dir`, whose header is at `this`. `listed` is
+    /// the header already standing in for `.` (the root's own), if any.
+    /// Returns the offset of the first entry.
+    fn list(&mut self, dir: &Path, this: usize, parent: usize, listed: Option<usize>) -> usize {
+        let mut entries: Vec<_> = std::fs::read_dir(dir)
+            .unwrap_or_else(|e| fail(format!("failed to read {}: {e}", dir.display())))
+            .map(|entry| {
+                entry
+                    .unwrap_or_else(|e| fail(format!("failed to read {}: {e}", dir.display())))
+                    .path()
+            })
+            .collect();
+        entries.sort();
+
+        let mut first = listed;
+        let mut prev = listed;
+        let mut chain = |w: &mut Self, off: usize| {
+            match prev {
+                Some(prev) => w.set_next(prev, off),
+                None => first = Some(off),
+            }
+            prev = Some(off);
+        };
+        if listed.is_none() {
+            let dot = self.header(TYPE_HARDLINK, this, ".", &[]);
+            chain(self, dot);
+        }
+        let dotdot = self.header(TYPE_HARDLINK, parent, "..", &[]);
+        chain(self, dotdot);
+
+        for path in entries {
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_else(|| fail(format!("non-UTF-8 file name: {}", path.display())));
+            let meta = std::fs::symlink_metadata(&path)
+                .unwrap_or_else(|e| fail(format!("failed to stat {}: {e}", path.display())));
+            let off = if meta.is_dir() {
+                let off = self.header(TYPE_DIR, 0, name, &[]);
+                let entries = self.list(&path, off, this, None);
+                self.put_u32(off + 4, entries as u32);
+                off
+            } else if meta.is_symlink() {
+                let target = std::fs::read_link(&path)
+                    .unwrap_or_else(|e| fail(format!("failed to read {}: {e}", path.display())));
+                let target = target.to_str().unwrap_or_else(|| {
+                    fail(format!("non-UTF-8 symlink target: {}", path.display()))
+                });
+                self.header(TYPE_SYMLINK, 0, name, target.as_bytes())
+            } else if meta.is_file() {
+                let data = std::fs::read(&path)
+                    .unwrap_or_else(|e| fail(format!("failed to read {}: {e}", path.display())));
+                self.header(TYPE_FILE | exec_flag(&meta), 0, name, &data)
+            } else {
+                println!("Skipping special file {}", path.display());
+                continue;
+            };
+            chain(self, off);
+        }
+        first.unwrap()
+    }
+}
+
+#[cfg(unix)]
+fn exec_flag(meta: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    if meta.permissions().mode() & 0o111 != 0 {
+        FLAG_EXEC
+    } else {
+        0
+    }
+}
+
+#[cfg(not(unix))]
+fn exec_flag(_meta: &std::fs::Metadata) -> u32 {
+    0
+}
+
+/// Pack the tree under `dir` into a romfs image named after the directory.
+pub fn build(dir: &Path) -> Vec<u8> {
+    if !dir.is_dir() {
+        fail(format!("--romfs needs a directory: {}", dir.display()));
+    }
+    let volume = std::path::absolute(dir)
+        .ok()
+        .and_then(|d| d.file_name()?.to_str().map(String::from))
+        .unwrap_or_else(|| "romfs".into());
+
+    let mut w = Writer {
+        buf: Vec::new(),
+        headers: Vec::new(),
+    };
+    w.buf.extend_from_slice(MAGIC);
+    w.buf.extend_from_slice(&[0; 8]);
+    w.buf.extend_from_slice(volume.as_bytes());
+    w.buf.push(0);
+    w.pad();
+
+    let root = w.header(TYPE_DIR, 0, ".", &[]);
+    let first = w.list(dir, root, root, Some(root));
+    w.put_u32(root + 4, first as u32);
+
+    let size = w.buf.len().next_multiple_of(IMAGE_ALIGN);
+    w.buf.resize(size, 0);
+    w.put_u32(8, size as u32);
+    for (off, len) in std::mem::take(&mut w.headers) {
+        let sum = word_sum(&w.buf[off..off + len]);
+        w.put_u32(off + 12, sum.wrapping_neg());
+    }
+    let sum = word_sum(&w.buf[..VOLUME_CHECKSUM_LEN.min(size)]);
+    w.put_u32(12, sum.wrapping_neg());
+    println!(
+        "Packed {} into a {size}-byte romfs image (volume \"{volume}\")",
+        dir.display()
+    );
+    w.buf
+}
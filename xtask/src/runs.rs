@@ -0,0 +1,234 @@
+//! Archive of past runs (`runs/<timestamp>-<arch>/`, `xtask runs`).
+//!
+//! Every `xtask run` that launches QEMU in the foreground keeps a directory
+//! named after its start time (UTC) and architecture, e.g.
+//! `runs/20261015-093012-aarch64/`, holding:
+//!
+//! ```text
+//! command.txt    the QEMU command line, shell-quoted
+//! manifest.json  the manifest of the image the run used
+//! serial.log     everything QEMU printed (the guest's serial console)
+//! status.txt     exit=<code>, seconds=<duration>, and timeout=true if
+//!                `--timeout` stopped QEMU
+//! pflash.img     the image as the run started, if the guest may write to
+//!                it (for `xtask verify-run`)
+//! image.txt      the path of that image
+//! ```
+//!
+//! Runs started by `test` and `ci` are archived too, so an intermittent
+//! failure can be looked at after the fact with `xtask runs list` and
+//! `xtask runs show`.
+
+use crate::{Arch, qmp, shell_quote};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Directory of archived runs, relative to the project root.
+const RUNS_DIR: &str = "runs";
+/// Copy of a writable run's image as it started.
+pub const IMAGE_BEFORE: &str = "pflash.img";
+/// Path of the image a writable run used.
+pub const IMAGE_PATH: &str = "image.txt";
+
+/// `YYYYMMDD-HHMMSS` (UTC) of `secs` since the Unix epoch.
+pub fn timestamp(secs: u64) -> String {
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+/// The archive directory of one run.
+pub struct Archive {
+    dir: PathBuf,
+}
+
+impl Archive {
+    /// Create the directory for a run of `arch` starting now. Returns
+    /// `None` (with a warning) if it cannot be created, since the run
+    /// itself does not need it.
+    pub fn create(root: &Path, arch: Arch) -> Option<Self> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let base = format!("{}-{arch}", timestamp(secs));
+        let runs = root.join(RUNS_DIR);
+        // Two runs in the same second get a suffix.
+        for n in 1.. {
+            let name = match n {
+                1 => base.clone(),
+                n => format!("{base}-{n}"),
+            };
+            let dir = runs.join(name);
+            match std::fs::create_dir_all(&runs).and_then(|()| std::fs::create_dir(&dir)) {
+                Ok(()) => return Some(Self { dir }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    eprintln!(
+                        "Warning: failed to create {}: {e}; the run is not archived",
+                        dir.display()
+                    );
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    fn write(&self, name: &str, contents: &str) {
+        let path = self.dir.join(name);
+        if let Err(e) = std::fs::write(&path, contents) {
+            eprintln!("Warning: failed to write {}: {e}", path.display());
+        }
+    }
+
+    /// Record the QEMU command line and the manifest of `pflash`.
+    pub fn record_start(&self, qemu: &str, args: &[String], pflash: &Path) {
+        // Said up front: the guest's output must stay last for snapshots.
+        println!("Archiving the run in {}", self.dir.display());
+        let command: Vec<String> = std::iter::once(qemu)
+            .chain(args.iter().map(String::as_str))
+            .map(shell_quote)
+            .collect();
+        self.write("command.txt", &format!("{}\n", command.join(" ")));
+        let manifest = pflash.with_extension("manifest.json");
+        if let Err(e) = std::fs::copy(&manifest, self.dir.join("manifest.json")) {
+            eprintln!("Warning: failed to archive {}: {e}", manifest.display());
+        }
+    }
+
+    /// Keep a copy of `pflash` as it is before a run that writes to it,
+    /// and its path.
+    pub fn record_image(&self, pflash: &Path) {
+        if let Err(e) = std::fs::copy(pflash, self.dir.join(IMAGE_BEFORE)) {
+            eprintln!("Warning: failed to archive {}: {e}", pflash.display());
+            return;
+        }
+        let path = pflash.canonicalize().unwrap_or_else(|_| pflash.into());
+        self.write(IMAGE_PATH, &format!("{}\n", path.display()));
+    }
+
+    /// The file to copy QEMU's output into.
+    pub fn serial_log(&self) -> Option<File> {
+        let path = self.dir.join("serial.log");
+        File::create(&path)
+            .map_err(|e| eprintln!("Warning: failed to create {}: {e}", path.display()))
+            .ok()
+    }
+
+    /// Record how the run ended.
+    pub fn record_exit(&self, code: i32, elapsed: Duration) {
+        let mut status = format!("exit={code}\nseconds={:.1}\n", elapsed.as_secs_f64());
+        if code == qmp::TIMEOUT_EXIT {
+            status.push_str("timeout=true\n");
+        }
+        self.write("status.txt", &status);
+    }
+}
+
+/// Archived run directories, oldest first.
+fn archived(root: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = std::fs::read_dir(root.join(RUNS_DIR))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+                .collect()
+        })
+        .unwrap_or_default();
+    dirs.sort();
+    dirs
+}
+
+/// A `key=value` field of the `status.txt` in `dir`.
+fn status_field(dir: &Path, key: &str) -> Option<String> {
+    let text = std::fs::read_to_string(dir.join("status.txt")).ok()?;
+    text.lines()
+        .find_map(|line| Some(line.strip_prefix(key)?.strip_prefix('=')?.to_string()))
+}
+
+/// List the archived runs with their exit status.
+pub fn list(root: &Path) {
+    let dirs = archived(root);
+    if dirs.is_empty() {
+        println!("No archived runs in {}", root.join(RUNS_DIR).display());
+        return;
+    }
+    println!("{:<36} {:>7} {:>9}", "RUN", "EXIT", "SECONDS");
+    for dir in dirs {
+        let name = dir.file_name().unwrap_or_default().to_string_lossy();
+        let exit = match (status_field(&dir, "exit"), status_field(&dir, "timeout")) {
+            (Some(_), Some(_)) => "timeout".into(),
+            (Some(code), None) => code,
+            // Killed, or still running.
+            (None, _) => "-".into(),
+        };
+        let seconds = status_field(&dir, "seconds").unwrap_or_else(|| "-".into());
+        println!("{name:<36} {exit:>7} {seconds:>9}");
+    }
+}
+
+/// The archived run named `run`, or the latest one whose name starts with
+/// it (so a date picks the latest run of that day), or the latest run if
+/// `run` is `None`. Only runs for which `keep` holds are considered.
+pub fn find(root: &Path, run: Option<&str>, keep: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+    let dirs: Vec<PathBuf> = archived(root).into_iter().filter(|dir| keep(dir)).collect();
+    let name_of = |dir: &PathBuf| {
+        dir.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+    match run {
+        Some(run) => dirs
+            .iter()
+            .find(|dir| name_of(dir) == run)
+            .or_else(|| dirs.iter().rev().find(|dir| name_of(dir).starts_with(run)))
+            .cloned(),
+        None => dirs.last().cloned(),
+    }
+}
+
+/// Print the archived run named `run` (or whose name starts with it), or
+/// the latest one: its status, command line and serial log.
+pub fn show(root: &Path, run: Option<&str>) {
+    let Some(dir) = find(root, run, |_| true) else {
+        eprintln!(
+            "Error: no archived run{} (see `cargo xtask runs list`)",
+            run.map(|run| format!(" matches '{run}'"))
+                .unwrap_or_default()
+        );
+        process::exit(1);
+    };
+    println!("Run: {}", dir.display());
+    for (name, title) in [
+        ("status.txt", "Status"),
+        ("command.txt", "Command"),
+        ("serial.log", "Serial log"),
+    ] {
+        println!("--- {title} ({name}) ---");
+        match std::fs::read_to_string(dir.join(name)) {
+            Ok(text) => print!("{text}"),
+            Err(e) => println!("(unavailable: {e})"),
+        }
+    }
+    if dir.join("manifest.json").exists() {
+        println!("--- Manifest: {} ---", dir.join("manifest.json").display());
+    }
+}
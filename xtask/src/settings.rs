@@ -0,0 +1,188 @@
+//! `cargo xtask env`: every setting a run would use, fully resolved.
+//!
+//! Prints what `cargo xtask run` with the same flags would pick: the
+//! target and platform, the config file and the values mirrored into it,
+//! the pflash bank, the build artifacts, the QEMU binary and the firmware,
+//! plus the environment variables that change any of them. Nothing is
+//! built or written.
+
+use crate::image::{pflash_size, seabios_path};
+use crate::{
+    Arch, BootArtifact, OPENSBI_PATHS, add_feature, arch_info, is_microvm, kernel_artifacts,
+    linear_ram_limit, parse_mem, parse_smp, read_config_uint, resolve_boot_artifact, target_dir,
+};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+/// Flags of `cargo xtask env`, as given to `run`.
+pub struct Flags<'a> {
+    pub features: Option<&'a str>,
+    pub machine: Option<&'a str>,
+    pub bios: Option<&'a str>,
+    pub mem: &'a str,
+    pub smp: &'a str,
+    pub boot_artifact: Option<BootArtifact>,
+}
+
+/// Environment variables that affect a build or run.
+const ENV_VARS: [&str; 5] = [
+    "AX_CONFIG_PATH",
+    "CARGO_TARGET_DIR",
+    "RUSTFLAGS",
+    "RUSTUP_TOOLCHAIN",
+    "PATH",
+];
+
+fn row(key: &str, value: impl std::fmt::Display) {
+    println!("  {key:<20} {value}");
+}
+
+/// `path`, followed by `(missing)` if it does not exist.
+fn path_state(path: &Path) -> String {
+    if path.exists() {
+        path.display().to_string()
+    } else {
+        format!("{} (missing)", path.display())
+    }
+}
+
+/// Full path of `program` on `PATH`.
+fn which(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|p| p.is_file())
+}
+
+/// First line `program --version` prints, run from `dir`.
+fn version(program: &Path, dir: &Path) -> String {
+    Command::new(program)
+        .arg("--version")
+        .current_dir(dir)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| {
+            let text = String::from_utf8_lossy(&out.stdout);
+            text.lines().next().map(|line| line.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Print the resolved settings for `arch`.
+pub fn print(root: &Path, arch: Arch, flags: &Flags) {
+    let info = arch_info(arch);
+    let mem_mib = parse_mem(flags.mem).unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        process::exit(1);
+    });
+    let smp = parse_smp(flags.smp);
+
+    println!("Build");
+    row("arch", arch);
+    row("target", info.target);
+    row("platform", info.platform);
+    row("project root", root.display());
+    let rustc = which("rustc").unwrap_or_else(|| "rustc".into());
+    row("rustc", version(&rustc, root));
+    let mut features = flags.features.map(String::from);
+    // As in `run`: more than one CPU needs ArceOS's SMP support.
+    if smp.cpus > 1 {
+        add_feature(&mut features, "smp");
+    }
+    row("features", features.as_deref().unwrap_or("(none)"));
+
+    println!("Config");
+    let source = root.join("configs").join(format!("{arch}.toml"));
+    let installed = target_dir(root, &info).join("axconfig.toml");
+    row("source", path_state(&source));
+    row("installed as", installed.display());
+    // `run` always points AX_CONFIG_PATH at the installed copy.
+    row(
+        "AX_CONFIG_PATH",
+        format!("{} (set by run)", installed.display()),
+    );
+    row("max-cpu-num", smp.cpus);
+    let mapped = linear_ram_limit(arch).map_or(mem_mib, |limit| mem_mib.min(limit));
+    row(
+        "phys-memory-size",
+        format!(
+            "{:#x} ({mapped} MiB; configs/ has {})",
+            mapped << 20,
+            read_config_uint(&source, "phys-memory-size")
+                .map_or("none".into(), |size| format!("{size:#x}"))
+        ),
+    );
+
+    println!("PFlash");
+    let size = pflash_size(arch);
+    row("base", format!("{:#x}", arch.bank().paddr));
+    row("size", format!("{size:#x} ({} MiB)", size >> 20));
+    if flags.machine.is_some_and(is_microvm) {
+        row("qemu unit", "none (read-only firmware ROM on microvm)");
+    } else {
+        row("qemu unit", format!("pflash{}", arch.bank().unit));
+    }
+    row(
+        "image",
+        path_state(&root.join(format!("pflash-{arch}.img"))),
+    );
+
+    println!("Artifacts");
+    let (elf, bin) = kernel_artifacts(root, &info, arch);
+    let artifact = resolve_boot_artifact(
+        arch,
+        flags.boot_artifact,
+        false,
+        arch == Arch::Riscv64 && flags.bios == Some("flash"),
+    );
+    row("elf", path_state(&elf));
+    if artifact == BootArtifact::Bin {
+        row("binary", path_state(&bin));
+    }
+    row("-kernel", artifact.name());
+
+    println!("QEMU");
+    let qemu = format!("qemu-system-{arch}");
+    match which(&qemu) {
+        Some(path) => {
+            row("binary", path.display());
+            row("version", version(&path, root));
+        }
+        None => row("binary", format!("{qemu} (not found in PATH)")),
+    }
+    row("machine", flags.machine.unwrap_or(info.machine));
+    row("memory", format!("{mem_mib} MiB"));
+    row("smp", &smp.arg);
+
+    println!("Firmware");
+    let firmware = match (arch, flags.bios.unwrap_or("default")) {
+        (Arch::Riscv64, "default") => "QEMU's bundled OpenSBI".to_string(),
+        (Arch::Riscv64, "none") => "none (-bios none)".to_string(),
+        (Arch::Riscv64, "flash") => {
+            match OPENSBI_PATHS.iter().map(Path::new).find(|p| p.exists()) {
+                Some(path) => format!("OpenSBI in pflash0 from {}", path.display()),
+                None => "OpenSBI in pflash0 (no fw_dynamic.bin found)".to_string(),
+            }
+        }
+        (Arch::Riscv64, file) => path_state(Path::new(file)),
+        (Arch::X86_64, "default") => match seabios_path() {
+            Some(path) => format!("SeaBIOS embedded in the image from {}", path.display()),
+            None => "SeaBIOS (not found)".to_string(),
+        },
+        (_, "default") => "QEMU default".to_string(),
+        (_, _) => {
+            eprintln!("Error: --bios is only supported for riscv64 (got --arch {arch})");
+            process::exit(1);
+        }
+    };
+    row("firmware", firmware);
+
+    println!("Environment");
+    for var in ENV_VARS {
+        match std::env::var(var) {
+            Ok(value) => row(var, value),
+            Err(_) => row(var, "(unset)"),
+        }
+    }
+}
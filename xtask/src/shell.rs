@@ -0,0 +1,344 @@
+//! Host side of the guest's flash command shell (`cargo xtask shell`).
+//!
+//! Starts `xtask run --features shell` with the serial port on a local TCP
+//! socket, waits for the guest to print its ready line, then sends the
+//! commands of a script (or standard input) one line at a time and prints
+//! each reply. The guest's `shell.rs` describes the commands. Boot output
+//! and the build go to standard error, so standard output holds only the
+//! commands and their replies:
+//!
+//! ```text
+//! > I
+//! OK 0x0089 0x0018 size=0x2000000 erase=0x40000
+//! > R 0 4
+//! OK 50464c41
+//! ```
+//!
+//! The command exits non-zero if any command was answered with `ERR`.
+//!
+//! `cargo xtask capture-dump` uses the same session for one `dumpb64`
+//! command: it decodes the base64 lines between `BEGIN-B64` and `END-B64`,
+//! checks their length and CRC-32, and writes the bytes to a host file.
+
+use crate::Arch;
+use crate::image::crc32;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{self, Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Line the guest prints when it starts reading commands.
+const READY: &str = "PFLASH SHELL READY";
+
+/// Options of `cargo xtask shell`.
+pub struct Options<'a> {
+    pub arch: Arch,
+    pub features: Option<&'a str>,
+    pub port: Option<u16>,
+    /// Allowed for the build and boot, and for each reply.
+    pub timeout: Duration,
+}
+
+/// A local TCP port nobody listens on right now.
+fn free_port() -> u16 {
+    TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .unwrap_or_else(|e| {
+            eprintln!("Error: cannot find a free local port: {e}");
+            process::exit(1);
+        })
+}
+
+/// Kill `child` and the QEMU it started.
+fn stop(child: &mut Child) {
+    #[cfg(unix)]
+    let _ = Command::new("kill")
+        .args(["-KILL", &format!("-{}", child.id())])
+        .status();
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Connect to QEMU's serial socket once it listens, giving up when `child`
+/// exits or `timeout` passes.
+fn connect(child: &mut Child, port: u16, timeout: Duration) -> TcpStream {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)) {
+            return stream;
+        }
+        if let Ok(Some(status)) = child.try_wait() {
+            eprintln!("Error: xtask run exited ({status}) before the serial socket opened");
+            process::exit(1);
+        }
+        if Instant::now() > deadline {
+            stop(child);
+            eprintln!("Error: the serial socket did not open within {timeout:?}");
+            process::exit(1);
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Read serial lines until one satisfies `done`, passing the others to
+/// standard error. `None` if the guest closed the port first.
+fn read_until(serial: &mut impl BufRead, done: impl Fn(&str) -> bool) -> Option<String> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match serial.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Error: reading the serial socket: {e}");
+                return None;
+            }
+        }
+        let text = line.trim_end();
+        if done(text) {
+            return Some(text.to_string());
+        }
+        eprintln!("{text}");
+    }
+}
+
+/// Start `xtask run` with the shell and wait for its ready line. Returns
+/// the child, the socket to send commands on and the one to read from.
+fn start(opts: &Options) -> (Child, TcpStream, BufReader<TcpStream>) {
+    let features = match opts.features {
+        Some(extra) => format!("shell,{extra}"),
+        None => "shell".into(),
+    };
+    let port = opts.port.unwrap_or_else(free_port);
+    let exe = std::env::current_exe().unwrap_or_else(|e| {
+        eprintln!("Error: cannot locate the xtask binary: {e}");
+        process::exit(1);
+    });
+    let mut command = Command::new(exe);
+    command
+        .args(["run", "--arch", opts.arch.name(), "--features", &features])
+        .args(["--serial-tcp", &port.to_string()])
+        // W and E change the bank.
+        .args(["--pflash-opt", "readonly=off"])
+        .stdin(Stdio::null())
+        .stdout(io::stderr());
+    // Its own process group, so QEMU can be killed with it.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command.spawn().unwrap_or_else(|e| {
+        eprintln!("Error: failed to run xtask: {e}");
+        process::exit(1);
+    });
+
+    let stream = connect(&mut child, port, opts.timeout);
+    // A reply that never comes ends the session instead of hanging it.
+    let _ = stream.set_read_timeout(Some(opts.timeout));
+    let writer = stream.try_clone().unwrap_or_else(|e| {
+        eprintln!("Error: cannot use the serial socket: {e}");
+        process::exit(1);
+    });
+    let mut serial = BufReader::new(stream);
+    if read_until(&mut serial, |line| line == READY).is_none() {
+        stop(&mut child);
+        eprintln!(
+            "Error: the guest stopped before starting its shell (build it with the shell feature)"
+        );
+        process::exit(1);
+    }
+    (child, writer, serial)
+}
+
+/// Leave the shell unless `quit` says a `Q` was sent already, then pass the
+/// rest of the run to standard error until QEMU closes the socket.
+fn finish(mut child: Child, mut writer: TcpStream, mut serial: impl BufRead, quit: bool) {
+    if !quit {
+        // Let the app finish and power off.
+        let _ = writeln!(writer, "Q");
+        let _ = read_until(&mut serial, |text| text.starts_with("OK bye"));
+    }
+    let _ = read_until(&mut serial, |_| false);
+    let _ = child.wait();
+}
+
+/// Run `cargo xtask shell`, sending the commands in `script` (or standard
+/// input).
+pub fn run(opts: &Options, script: Option<&Path>) {
+    let commands: Box<dyn BufRead> = match script {
+        Some(path) => match std::fs::File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("Error: cannot open {}: {e}", path.display());
+                process::exit(1);
+            }
+        },
+        None => Box::new(io::stdin().lock()),
+    };
+    let (child, mut writer, mut serial) = start(opts);
+
+    let mut failed = 0;
+    let mut quit = false;
+    for line in commands.lines() {
+        let line = line.unwrap_or_else(|e| {
+            eprintln!("Error: reading commands: {e}");
+            process::exit(1);
+        });
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        println!("> {line}");
+        if writeln!(writer, "{line}").is_err() {
+            eprintln!("Error: the guest closed the serial port");
+            failed += 1;
+            break;
+        }
+        let Some(reply) = read_until(&mut serial, |text| {
+            text.starts_with("OK") || text.starts_with("ERR")
+        }) else {
+            eprintln!("Error: no reply to '{line}'");
+            failed += 1;
+            break;
+        };
+        println!("{reply}");
+        if reply.starts_with("ERR") {
+            failed += 1;
+        }
+        if line == "Q" {
+            quit = true;
+            break;
+        }
+    }
+    finish(child, writer, serial, quit);
+
+    if failed > 0 {
+        eprintln!("Error: {failed} command(s) failed");
+        process::exit(1);
+    }
+}
+
+/// Decode one line of standard base64 with `=` padding.
+fn decode_base64(line: &str) -> Result<Vec<u8>, String> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    if !line.len().is_multiple_of(4) {
+        return Err(format!("{} characters, not a multiple of 4", line.len()));
+    }
+    let mut out = Vec::with_capacity(line.len() / 4 * 3);
+    let quads = line.as_bytes().chunks(4);
+    let last = quads.len().saturating_sub(1);
+    for (i, quad) in quads.enumerate() {
+        let pad = quad.iter().rev().take_while(|&&c| c == b'=').count();
+        if pad > 2 || (pad > 0 && i != last) {
+            return Err("misplaced '=' padding".into());
+        }
+        let mut bits = 0u32;
+        for &c in &quad[..4 - pad] {
+            let v = value(c).ok_or_else(|| format!("bad character '{}'", c as char))?;
+            bits = bits << 6 | u32::from(v);
+        }
+        bits <<= 6 * pad;
+        out.extend_from_slice(&bits.to_be_bytes()[1..4 - pad]);
+    }
+    Ok(out)
+}
+
+/// Kill the run and exit with `message`.
+fn fail(child: &mut Child, message: String) -> ! {
+    stop(child);
+    eprintln!("Error: {message}");
+    process::exit(1);
+}
+
+/// Run `cargo xtask capture-dump`: have the guest stream `len` bytes of
+/// the bank at `offset` (the rest of the bank if `None`) with `dumpb64`,
+/// and write them to `out` once their length and CRC check out.
+pub fn capture(opts: &Options, offset: usize, len: Option<usize>, out: &Path) {
+    let size = opts.arch.bank().size;
+    let Some(len) = len.or_else(|| size.checked_sub(offset)) else {
+        eprintln!("Error: offset {offset:#x} is past the {size:#x}-byte bank");
+        process::exit(1);
+    };
+    let (mut child, mut writer, mut serial) = start(opts);
+    let command = format!("dumpb64 {offset:#x} {len:#x}");
+    if writeln!(writer, "{command}").is_err() {
+        fail(&mut child, "the guest closed the serial port".into());
+    }
+    let begin = read_until(&mut serial, |text| {
+        text.starts_with("BEGIN-B64") || text.starts_with("ERR")
+    });
+    match begin {
+        Some(line) if line.starts_with("ERR") => {
+            fail(&mut child, format!("'{command}' failed: {line}"))
+        }
+        Some(_) => {}
+        None => fail(&mut child, format!("no reply to '{command}'")),
+    }
+
+    let mut data = Vec::with_capacity(len);
+    let mut line = String::new();
+    let sent_crc = loop {
+        line.clear();
+        match serial.read_line(&mut line) {
+            Ok(0) => fail(&mut child, "the guest closed the port mid-dump".into()),
+            Ok(_) => {}
+            Err(e) => fail(&mut child, format!("reading the dump: {e}")),
+        }
+        let text = line.trim_end();
+        if let Some(encoded) = text.strip_prefix(':') {
+            match decode_base64(encoded) {
+                Ok(bytes) => data.extend_from_slice(&bytes),
+                Err(e) => fail(&mut child, format!("dump line {}: {e}", data.len())),
+            }
+        } else if let Some(crc) = text.strip_prefix("END-B64 ") {
+            match u32::from_str_radix(crc.trim(), 16) {
+                Ok(crc) => break crc,
+                Err(_) => fail(&mut child, format!("bad checksum line '{text}'")),
+            }
+        } else {
+            // Console output of another CPU or the kernel; not dump data.
+            eprintln!("{text}");
+        }
+        if data.len() > len {
+            fail(
+                &mut child,
+                format!("more than the {len:#x} bytes asked for"),
+            );
+        }
+    };
+    if read_until(&mut serial, |text| {
+        text.starts_with("OK") || text.starts_with("ERR")
+    })
+    .is_none()
+    {
+        fail(&mut child, format!("no reply to '{command}'"));
+    }
+    if data.len() != len {
+        fail(
+            &mut child,
+            format!("received {:#x} bytes, expected {len:#x}", data.len()),
+        );
+    }
+    let crc = crc32(&data);
+    if crc != sent_crc {
+        fail(
+            &mut child,
+            format!("checksum mismatch: the guest sent {sent_crc:08x}, the bytes give {crc:08x}"),
+        );
+    }
+    if let Err(e) = std::fs::write(out, &data) {
+        fail(&mut child, format!("cannot write {}: {e}", out.display()));
+    }
+    finish(child, writer, serial, false);
+    println!(
+        "Captured {len:#x} bytes at {offset:#x} (crc32 {crc:08x}) to {}",
+        out.display()
+    );
+}
@@ -0,0 +1,253 @@
+//! Kernel size and its budget (`cargo xtask size`).
+//!
+//! The kernel links in whatever ArceOS modules its features pull in, so a
+//! new demo or a dependency update can grow it without anyone noticing.
+//! `size` builds the kernel and reports two figures: the ELF with its
+//! symbols and debug info stripped, and the raw binary QEMU and U-Boot
+//! load. `xtask.toml` in the project root holds a budget for each, per
+//! architecture:
+//!
+//! ```toml
+//! [size.riscv64]
+//! stripped = 0x30_0000   # bytes
+//! bin = 0x20_0000
+//! ```
+//!
+//! With `--check` a figure over its budget is an error, and `cargo xtask
+//! ci` runs that check for every architecture it builds. An architecture
+//! without a table, or a figure without a key, is not checked.
+
+use crate::layout::{self, Line, Value};
+use crate::{Arch, do_objcopy};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::{self, Command};
+
+/// The budget file, in the project root.
+const BUDGET_FILE: &str = "xtask.toml";
+
+/// The measured figures: key in the budget table, and description.
+const FIGURES: [(&str, &str); 2] = [("stripped", "stripped ELF"), ("bin", "raw binary")];
+
+/// Budgets of one architecture, by figure key.
+type Budget = BTreeMap<String, u64>;
+
+/// Parse the budget file: `[size.<ARCH>]` tables of `<FIGURE> = <BYTES>`.
+fn parse_budgets(text: &str) -> Result<BTreeMap<String, Budget>, String> {
+    let mut budgets: BTreeMap<String, Budget> = BTreeMap::new();
+    let mut table: Option<String> = None;
+    for line in layout::lines(text) {
+        let (n, line) = line?;
+        let at = |e: String| format!("line {n}: {e}");
+        let (key, value) = match line {
+            Line::Table { name, array: false } => {
+                let Some(arch) = name.strip_prefix("size.") else {
+                    return Err(at(format!(
+                        "unknown table [{name}] (expected [size.<ARCH>])"
+                    )));
+                };
+                if Arch::ALL.iter().all(|a| a.name() != arch) {
+                    return Err(at(format!("unknown architecture [{name}]")));
+                }
+                if budgets.insert(arch.to_string(), Budget::new()).is_some() {
+                    return Err(at(format!("[{name}] appears twice")));
+                }
+                table = Some(arch.to_string());
+                continue;
+            }
+            Line::Table { name, array: true } => {
+                return Err(at(format!(
+                    "unknown table [[{name}]] (expected [size.<ARCH>])"
+                )));
+            }
+            Line::Key(key, value) => (key, value),
+        };
+        let Some(arch) = &table else {
+            return Err(at("budgets must be in a [size.<ARCH>] table".into()));
+        };
+        if FIGURES.iter().all(|(figure, _)| *figure != key) {
+            return Err(at(format!(
+                "unknown key '{key}' (expected stripped or bin)"
+            )));
+        }
+        let Value::Int(bytes) = value else {
+            return Err(at(format!("{key} must be an integer")));
+        };
+        if budgets
+            .get_mut(arch)
+            .unwrap()
+            .insert(key.to_string(), bytes as u64)
+            .is_some()
+        {
+            return Err(at(format!("{key} is set twice in [size.{arch}]")));
+        }
+    }
+    Ok(budgets)
+}
+
+/// The budget of `arch` in the budget file under `root`, empty if the file
+/// or its table is missing.
+fn budget(root: &Path, arch: Arch) -> Budget {
+    let path = root.join(BUDGET_FILE);
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        return Budget::new();
+    };
+    let mut budgets = parse_budgets(&text).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {e}", path.display());
+        process::exit(1);
+    });
+    budgets.remove(arch.name()).unwrap_or_default()
+}
+
+/// The figures `sizes`, in [`FIGURES`] order, that are over `budget`, as
+/// `<figure> <size> > <limit>`.
+fn over_budget(budget: &Budget, sizes: [u64; FIGURES.len()]) -> Vec<String> {
+    FIGURES
+        .into_iter()
+        .zip(sizes)
+        .filter_map(|((key, what), size)| {
+            let limit = *budget.get(key)?;
+            (size > limit).then(|| format!("{what} {size} > {limit}"))
+        })
+        .collect()
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path)
+        .map(|meta| meta.len())
+        .unwrap_or_else(|e| {
+            eprintln!("Error: cannot read {}: {e}", path.display());
+            process::exit(1);
+        })
+}
+
+/// Strip `elf` into `out` with rust-objcopy.
+fn strip(elf: &Path, out: &Path) {
+    let status = Command::new("rust-objcopy")
+        .args(["--strip-all", elf.to_str().unwrap(), out.to_str().unwrap()])
+        .current_dir(elf.parent().unwrap())
+        .status()
+        .expect("failed to execute rust-objcopy (install with: cargo install cargo-binutils)");
+    if !status.success() {
+        eprintln!("Error: rust-objcopy failed");
+        process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// Measure the kernel `elf` built for `arch` (writing the stripped ELF and
+/// the raw binary `bin` next to it) and compare it with its budget. With
+/// `check`, exit with status 1 if a figure is over budget.
+pub fn run(root: &Path, arch: Arch, elf: &Path, bin: &Path, objcopy_arch: &str, check: bool) {
+    let stripped = elf.with_file_name(format!("arceos-readpflash-{arch}.stripped"));
+    strip(elf, &stripped);
+    do_objcopy(elf, bin, objcopy_arch);
+    let budget = budget(root, arch);
+    let sizes = [file_size(&stripped), file_size(bin)];
+
+    println!("Kernel size ({arch}):");
+    for ((key, what), size) in FIGURES.into_iter().zip(sizes) {
+        let verdict = match budget.get(key) {
+            Some(&limit) if size > limit => format!("budget {limit}, OVER by {}", size - limit),
+            Some(&limit) => format!(
+                "budget {limit}, {}% used, {} left",
+                size * 100 / limit.max(1),
+                limit - size
+            ),
+            None => "no budget".into(),
+        };
+        println!("  {what:<13} {size:>10} bytes  {verdict}");
+    }
+    if budget.is_empty() {
+        println!("No [size.{arch}] table in {BUDGET_FILE}; nothing to check");
+    }
+    let over = over_budget(&budget, sizes);
+    if !over.is_empty() {
+        let message = format!(
+            "the {arch} kernel is over its size budget in {BUDGET_FILE}: {}",
+            over.join(", ")
+        );
+        if check {
+            eprintln!("Error: {message}");
+            process::exit(1);
+        }
+        eprintln!("Warning: {message}");
+    } else if check && !budget.is_empty() {
+        println!("Size check passed for {arch}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budgets_parse_per_arch() {
+        let text = "# budgets\n\
+                    [size.riscv64]\n\
+                    stripped = 0x30_0000   # bytes\n\
+                    bin = 2097152\n\
+                    \n\
+                    [size.x86_64]\n\
+                    bin = 0x10\n";
+        let budgets = parse_budgets(text).unwrap();
+        assert_eq!(budgets.len(), 2);
+        assert_eq!(budgets["riscv64"]["stripped"], 0x30_0000);
+        assert_eq!(budgets["riscv64"]["bin"], 0x20_0000);
+        assert_eq!(budgets["x86_64"].get("stripped"), None);
+        assert_eq!(budgets["x86_64"]["bin"], 0x10);
+    }
+
+    #[test]
+    fn checked_in_budgets_parse() {
+        let budgets = parse_budgets(include_str!("../../xtask.toml")).unwrap();
+        assert!(
+            Arch::ALL
+                .iter()
+                .all(|arch| budgets.contains_key(arch.name()))
+        );
+    }
+
+    #[test]
+    fn bad_budget_files_name_the_line() {
+        for (text, error) in [
+            (
+                "bin = 1",
+                "line 1: budgets must be in a [size.<ARCH>] table",
+            ),
+            ("[size]", "line 1: unknown table [size]"),
+            ("[size.mips]", "line 1: unknown architecture [size.mips]"),
+            ("[[size.riscv64]]", "line 1: unknown table [[size.riscv64]]"),
+            (
+                "[size.riscv64]\n[size.riscv64]",
+                "line 2: [size.riscv64] appears twice",
+            ),
+            ("[size.riscv64]\nheap = 1", "line 2: unknown key 'heap'"),
+            (
+                "[size.riscv64]\nbin = \"1\"",
+                "line 2: bin must be an integer",
+            ),
+            (
+                "[size.riscv64]\nbin = 1\nbin = 2",
+                "line 3: bin is set twice",
+            ),
+            ("[size.riscv64]\nbin", "line 2: expected key = value"),
+        ] {
+            let e = parse_budgets(text).unwrap_err();
+            assert!(e.starts_with(error), "{text:?}: {e}");
+        }
+    }
+
+    #[test]
+    fn only_figures_over_their_budget_are_reported() {
+        let budget = Budget::from([("stripped".to_string(), 100), ("bin".to_string(), 50)]);
+        assert!(over_budget(&budget, [100, 50]).is_empty());
+        assert_eq!(over_budget(&budget, [101, 50]), ["stripped ELF 101 > 100"]);
+        assert_eq!(
+            over_budget(&budget, [101, 51]),
+            ["stripped ELF 101 > 100", "raw binary 51 > 50"]
+        );
+        let bin_only = Budget::from([("bin".to_string(), 50)]);
+        assert!(over_budget(&bin_only, [u64::MAX, 0]).is_empty());
+        assert!(over_budget(&Budget::new(), [u64::MAX, u64::MAX]).is_empty());
+    }
+}
@@ -0,0 +1,260 @@
+//! Golden-output snapshots.
+//!
+//! `cargo xtask test` runs the app and compares its serial output with
+//! `tests/snapshots/<ARCH>-<FEATURES>.snap`, one golden file per
+//! architecture and feature set (the features sorted and joined with `+`,
+//! e.g. `riscv64-identify+verify.snap`). Only the output from the app's
+//! first line on is kept, and it is normalized first so that runs on
+//! different hosts and QEMU versions match:
+//!
+//! - ANSI colour sequences are removed;
+//! - every hexadecimal number (`0x...`) becomes `0x_`, which masks
+//!   addresses and anything derived from them;
+//! - the timestamp of axlog lines (`[  0.123456 ...`) becomes `[T ...`;
+//! - trailing whitespace and carriage returns are dropped.
+//!
+//! A missing snapshot fails the test like a differing one, so that a
+//! checkout without its goldens cannot pass; `--update-snapshots` writes
+//! them.
+
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// First line the app prints; output before it belongs to the firmware and
+/// the kernel's boot banner.
+pub const APP_START: &str = "Reading PFlash at physical address";
+
+/// Differing lines shown for a mismatch.
+const DIFF_CONTEXT: usize = 12;
+
+/// Path of the snapshot for `arch` built with the comma-separated
+/// `features`, whose order does not matter.
+pub fn path(root: &Path, arch: &str, features: &str) -> PathBuf {
+    let mut features: Vec<&str> = features
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .collect();
+    features.sort_unstable();
+    features.dedup();
+    let name = if features.is_empty() {
+        format!("{arch}.snap")
+    } else {
+        format!("{arch}-{}.snap", features.join("+"))
+    };
+    root.join("tests").join("snapshots").join(name)
+}
+
+/// Remove ANSI escape sequences (`ESC [ ... letter`).
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.peek() == Some(&'[') {
+            chars.next();
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Replace every `0x` hexadecimal number in `line` with `0x_`.
+fn mask_hex(line: &str) -> String {
+    let bytes = line.as_bytes();
+    let mut out = String::with_capacity(line.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let is_hex = bytes[i] == b'0'
+            && matches!(bytes.get(i + 1), Some(b'x' | b'X'))
+            && bytes.get(i + 2).is_some_and(u8::is_ascii_hexdigit)
+            && (i == 0 || !bytes[i - 1].is_ascii_alphanumeric());
+        if is_hex {
+            out.push_str("0x_");
+            i += 2;
+            while i < bytes.len() && (bytes[i].is_ascii_hexdigit() || bytes[i] == b'_') {
+                i += 1;
+            }
+        } else {
+            let c = line[i..].chars().next().unwrap();
+            out.push(c);
+            i += c.len_utf8();
+        }
+    }
+    out
+}
+
+/// Replace the timestamp of an axlog line (`[  0.123456 0 module] ...`).
+fn mask_timestamp(line: &str) -> String {
+    let Some(rest) = line.strip_prefix('[') else {
+        return line.into();
+    };
+    let trimmed = rest.trim_start();
+    let stamp_len = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    if stamp_len == 0 || !trimmed[..stamp_len].contains('.') {
+        return line.into();
+    }
+    format!("[T{}", &trimmed[stamp_len..])
+}
+
+/// The normalized app output in a run's console output.
+///
+/// The JSON report line is left out: `--expect` checks it field by field,
+/// and it carries benchmark figures that differ from run to run.
+pub fn normalize(output: &str) -> String {
+    let mut lines = output.lines().map(strip_ansi);
+    let Some(first) = lines.by_ref().find(|line| line.contains(APP_START)) else {
+        return String::new();
+    };
+    let mut out = String::new();
+    for line in std::iter::once(first).chain(lines) {
+        if line.starts_with(crate::json::PREFIX) {
+            continue;
+        }
+        let line = mask_timestamp(&mask_hex(&line));
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Print where `actual` departs from `expected`.
+fn print_diff(expected: &str, actual: &str) {
+    let expected: Vec<_> = expected.lines().collect();
+    let actual: Vec<_> = actual.lines().collect();
+    let first = expected
+        .iter()
+        .zip(&actual)
+        .position(|(e, a)| e != a)
+        .unwrap_or(expected.len().min(actual.len()));
+    println!("  first difference at line {}:", first + 1);
+    for line in expected.iter().skip(first).take(DIFF_CONTEXT) {
+        println!("  - {line}");
+    }
+    for line in actual.iter().skip(first).take(DIFF_CONTEXT) {
+        println!("  + {line}");
+    }
+}
+
+/// Compare the normalized `output` of a run on `arch` with `features` with
+/// its snapshot, writing the snapshot instead if `update` is set.
+///
+/// Returns `true` if the output matches (or the snapshot was written).
+pub fn check(root: &Path, arch: &str, features: &str, output: &str, update: bool) -> bool {
+    let actual = normalize(output);
+    if actual.is_empty() {
+        println!("{arch}: FAIL (the app printed nothing; no {APP_START:?} line)");
+        return false;
+    }
+    let path = path(root, arch, features);
+    let expected = std::fs::read_to_string(&path).ok();
+    if !update {
+        let Some(expected) = &expected else {
+            println!("{arch}: FAIL (no snapshot at {})", path.display());
+            println!("  (rerun with --update-snapshots to create it)");
+            return false;
+        };
+        if *expected == actual {
+            println!("{arch}: PASS (output matches {})", path.display());
+            return true;
+        }
+        println!("{arch}: FAIL (output differs from {})", path.display());
+        print_diff(expected, &actual);
+        println!("  (rerun with --update-snapshots if the change is intended)");
+        return false;
+    }
+    std::fs::create_dir_all(path.parent().unwrap())
+        .and_then(|()| std::fs::write(&path, &actual))
+        .unwrap_or_else(|e| {
+            eprintln!("Error: failed to write {}: {}", path.display(), e);
+            process::exit(1);
+        });
+    let verb = if expected.is_some() {
+        "updated"
+    } else {
+        "created"
+    };
+    println!(
+        "{arch}: {verb} {} ({} lines)",
+        path.display(),
+        actual.lines().count()
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_are_keyed_by_the_feature_set() {
+        let root = Path::new("/p");
+        let snap = |features| path(root, "riscv64", features);
+        assert_eq!(
+            snap("verify,identify"),
+            Path::new("/p/tests/snapshots/riscv64-identify+verify.snap")
+        );
+        // Order, repeats and blanks do not make a different set.
+        assert_eq!(snap("identify, verify,,identify"), snap("verify,identify"));
+        assert_ne!(snap("verify,identify,json-report"), snap("verify,identify"));
+        assert_eq!(snap(""), Path::new("/p/tests/snapshots/riscv64.snap"));
+    }
+
+    #[test]
+    fn ansi_sequences_are_stripped() {
+        assert_eq!(strip_ansi("\x1b[37mplain\x1b[m"), "plain");
+        assert_eq!(strip_ansi("a\x1b[1;31mb\x1b[0mc"), "abc");
+        // A lone escape is not a sequence.
+        assert_eq!(strip_ansi("a\x1bb"), "a\x1bb");
+    }
+
+    #[test]
+    fn hex_numbers_are_masked() {
+        assert_eq!(
+            mask_hex("at 0x22000000, size 0X2_000_000"),
+            "at 0x_, size 0x_"
+        );
+        assert_eq!(mask_hex("[0xffff_ffc0]"), "[0x_]");
+        // Not a number on its own, or no digits after the prefix.
+        assert_eq!(mask_hex("id ab0x12 0x 0xg"), "id ab0x12 0x 0xg");
+        assert_eq!(mask_hex("décalage 0x10 → ok"), "décalage 0x_ → ok");
+    }
+
+    #[test]
+    fn axlog_timestamps_are_masked() {
+        assert_eq!(
+            mask_timestamp("[  0.123456 0 axruntime:130] Initialize"),
+            "[T 0 axruntime:130] Initialize"
+        );
+        assert_eq!(mask_timestamp("[12.5 1 axtask] x"), "[T 1 axtask] x");
+        // Brackets that do not start with a timestamp are kept.
+        assert_eq!(mask_timestamp("[ 42 ] count"), "[ 42 ] count");
+        assert_eq!(mask_timestamp("[report] ok"), "[report] ok");
+        assert_eq!(mask_timestamp("no bracket 0.5"), "no bracket 0.5");
+    }
+
+    #[test]
+    fn output_before_the_app_is_dropped() {
+        let output = format!(
+            "OpenSBI v1.5\r\n\
+             [  0.000100 0 axruntime:130] Logging is enabled.\n\
+             {APP_START} 0x22000000...  \r\n\
+             [  1.250000 0 readpflash] \x1b[32mPASS\x1b[m\n\
+             {}{{\"crc_ok\":true}}\n\
+             done\n",
+            crate::json::PREFIX
+        );
+        assert_eq!(
+            normalize(&output),
+            format!("{APP_START} 0x_...\n[T 0 readpflash] PASS\ndone\n")
+        );
+        assert_eq!(normalize("OpenSBI v1.5\npanicked\n"), "");
+    }
+}
@@ -0,0 +1,296 @@
+//! What a writable run did to its image (`xtask verify-run`).
+//!
+//! A run whose guest may write to the bank keeps a copy of the image as it
+//! started in its archive directory (see [`crate::runs`]). `verify-run`
+//! reads the image file again once the run is over and
+//!
+//! - recomputes the SHA-256 of every region in the manifest: a read-only
+//!   region must still match its digest, while a writable one is only
+//!   reported, since its digest describes the image as created;
+//! - compares the image with the copy one erase block at a time and lists
+//!   the blocks that changed, with the regions they lie in; a byte changed
+//!   outside every writable region is an error;
+//! - cross-checks the changed blocks with the `Flash writes:` line a guest
+//!   built with the `write-trace` feature prints at the end: a block that
+//!   changed although the guest did not say it wrote it is an error, while
+//!   one it wrote that did not change (rewritten with the data it held, or
+//!   erased while erased) is only listed.
+//!
+//! The image must not have been rebuilt or run again in between.
+
+use crate::image::{JOURNAL_ALIGN, REGION_ALIGN};
+use crate::{partition, runs};
+use readpflash_layout::{Header, Manifest};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// What the guest's `write-trace` feature prints before the blocks.
+const TRACE_PREFIX: &str = "Flash writes: ";
+
+/// The erase blocks the guest said it wrote.
+struct Trace {
+    /// Erase block size, 0 if nothing was written.
+    block_size: usize,
+    /// Bank offsets of the blocks.
+    blocks: Vec<usize>,
+    /// Offset past which the guest wrote blocks it did not list.
+    beyond: Option<usize>,
+}
+
+fn parse_offset(s: &str) -> Option<usize> {
+    usize::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+/// Parse the text after [`TRACE_PREFIX`], e.g. `2 block(s) of 0x40000
+/// bytes at 0x80000 0xc0000`.
+fn parse_trace(text: &str) -> Option<Trace> {
+    if text == "none" {
+        return Some(Trace {
+            block_size: 0,
+            blocks: Vec::new(),
+            beyond: None,
+        });
+    }
+    let (count, rest) = text.split_once(" block(s) of ")?;
+    let (size, rest) = rest.split_once(" bytes at")?;
+    let (list, beyond) = match rest.split_once(" and more past ") {
+        Some((list, beyond)) => (list, Some(parse_offset(beyond)?)),
+        None => (rest, None),
+    };
+    let blocks = list
+        .split_whitespace()
+        .map(parse_offset)
+        .collect::<Option<Vec<_>>>()?;
+    let block_size = parse_offset(size)?;
+    (count.parse() == Ok(blocks.len()) && block_size > 0).then_some(Trace {
+        block_size,
+        blocks,
+        beyond,
+    })
+}
+
+/// A manifest region, at its bank offset.
+struct Region {
+    name: String,
+    start: usize,
+    len: usize,
+    writable: bool,
+    sha256: [u8; 32],
+}
+
+impl Region {
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.start + self.len
+    }
+}
+
+fn read(what: &str, path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {what} {}: {e}", path.display());
+        process::exit(1);
+    })
+}
+
+/// The regions of the image in `bank`, with the first 4K, which holds the
+/// header and manifest, as a read-only region of its own.
+fn regions(bank: &[u8]) -> Result<Vec<Region>, String> {
+    let base = partition::image_offset(bank);
+    let image = &bank[base..];
+    let header = Header::parse(image).map_err(|e| e.to_string())?;
+    let manifest = Manifest::parse(image, &header).map_err(|e| e.to_string())?;
+    let mut regions = vec![Region {
+        name: "(manifest)".into(),
+        start: base,
+        len: REGION_ALIGN,
+        writable: false,
+        sha256: Sha256::digest(&image[..REGION_ALIGN]).into(),
+    }];
+    for region in manifest.regions() {
+        let region = region.map_err(|e| e.to_string())?;
+        if region.data(image).is_none() {
+            return Err(format!("region {} extends past the bank", region.name));
+        }
+        regions.push(Region {
+            name: region.name.into(),
+            start: base + region.offset as usize,
+            len: region.len as usize,
+            writable: region.writable(),
+            sha256: *region.sha256,
+        });
+    }
+    Ok(regions)
+}
+
+/// Check the image of the archived run named `run` (or whose name starts
+/// with it), or of the latest run that kept one, and exit with status 1 if
+/// it changed where it should not have.
+pub fn run(root: &Path, run: Option<&str>) {
+    let Some(dir) = runs::find(root, run, |dir| dir.join(runs::IMAGE_BEFORE).exists()) else {
+        eprintln!(
+            "Error: no archived run{} kept its image; only runs whose bank is writable \
+             do (see `cargo xtask runs list`)",
+            run.map(|run| format!(" matching '{run}'"))
+                .unwrap_or_default()
+        );
+        process::exit(1);
+    };
+    let path = PathBuf::from(
+        String::from_utf8_lossy(&read("image path", &dir.join(runs::IMAGE_PATH))).trim(),
+    );
+    let before = read("image copy", &dir.join(runs::IMAGE_BEFORE));
+    let after = read("image", &path);
+    println!("Run: {}", dir.display());
+    println!(
+        "Image: {}, against its copy from the start of the run",
+        path.display()
+    );
+    if before.len() != after.len() {
+        eprintln!(
+            "Error: the image is {} bytes, its copy {}; it was rebuilt after the run",
+            after.len(),
+            before.len()
+        );
+        process::exit(1);
+    }
+    let regions = regions(&before).unwrap_or_else(|e| {
+        eprintln!("Error: cannot read the manifest of the image copy: {e}");
+        process::exit(1);
+    });
+    let mut problems = 0;
+
+    println!("Regions:");
+    for region in &regions {
+        let range = region.start..region.start + region.len;
+        let digest: [u8; 32] = Sha256::digest(&after[range.clone()]).into();
+        let changed = before[range.clone()] != after[range];
+        let status = if region.name == "(manifest)" {
+            if changed {
+                problems += 1;
+                "CHANGED"
+            } else {
+                "unchanged"
+            }
+        } else if digest == region.sha256 {
+            "matches the manifest"
+        } else if region.writable {
+            "written (writable; the manifest has its initial digest)"
+        } else if changed {
+            problems += 1;
+            "DIFFERS from the manifest (read-only)"
+        } else {
+            "differs from the manifest, as it did before the run"
+        };
+        println!(
+            "  {:<10} {:#010x} +{:<9} {status}",
+            region.name, region.start, region.len
+        );
+    }
+
+    let serial = std::fs::read_to_string(dir.join("serial.log")).unwrap_or_default();
+    let trace = serial.lines().rev().find_map(|line| {
+        let (_, text) = line.split_once(TRACE_PREFIX)?;
+        Some(parse_trace(text.trim()))
+    });
+    let trace = match trace {
+        Some(Some(trace)) => Some(trace),
+        Some(None) => {
+            eprintln!(
+                "Warning: cannot parse the guest's `{}` line; not cross-checked",
+                TRACE_PREFIX.trim_end()
+            );
+            None
+        }
+        None => None,
+    };
+    let block_size = match &trace {
+        Some(trace) if trace.block_size > 0 => trace.block_size,
+        // The largest erase block of the emulated devices.
+        _ => JOURNAL_ALIGN,
+    };
+
+    let writable = |at: usize| {
+        regions
+            .iter()
+            .any(|region| region.writable && region.overlaps(at, at + 1))
+    };
+    println!("Erase blocks ({block_size:#x} bytes) that changed:");
+    let mut changed = Vec::new();
+    for start in (0..after.len()).step_by(block_size) {
+        let end = (start + block_size).min(after.len());
+        let differ: Vec<usize> = (start..end).filter(|&at| before[at] != after[at]).collect();
+        if differ.is_empty() {
+            continue;
+        }
+        let names: Vec<&str> = regions
+            .iter()
+            .filter(|region| region.overlaps(start, end))
+            .map(|region| region.name.as_str())
+            .collect();
+        let outside = differ.iter().filter(|&&at| !writable(at)).count();
+        let mut notes = Vec::new();
+        if outside > 0 {
+            problems += 1;
+            notes.push(format!("{outside} OUTSIDE writable regions"));
+        }
+        if let Some(trace) = &trace {
+            if trace.blocks.contains(&start) {
+                notes.push("written by the guest".into());
+            } else if trace.beyond.is_some_and(|beyond| start >= beyond) {
+                notes.push("past the blocks the guest listed".into());
+            } else {
+                problems += 1;
+                notes.push("NOT REPORTED by the guest".into());
+            }
+        }
+        let line = format!(
+            "  {start:#010x} {:<20} {:>8} byte(s) differ  {}",
+            if names.is_empty() {
+                "(no region)".into()
+            } else {
+                names.join(",")
+            },
+            differ.len(),
+            notes.join(", ")
+        );
+        println!("{}", line.trim_end());
+        changed.push(start);
+    }
+    if changed.is_empty() {
+        println!("  none");
+    }
+
+    match &trace {
+        Some(trace) => {
+            let unchanged: Vec<String> = trace
+                .blocks
+                .iter()
+                .filter(|block| !changed.contains(block))
+                .map(|block| format!("{block:#x}"))
+                .collect();
+            if !unchanged.is_empty() {
+                println!(
+                    "Written by the guest but unchanged (same data, or erased while erased): {}",
+                    unchanged.join(" ")
+                );
+            }
+        }
+        None => println!(
+            "The guest printed no `{}` line, so its writes are not cross-checked; \
+             build it with --features write-trace",
+            TRACE_PREFIX.trim_end()
+        ),
+    }
+    if problems > 0 {
+        eprintln!("Error: {problems} problem(s) with the image after the run (marked above)");
+        process::exit(1);
+    }
+    println!(
+        "Image OK: changed only in writable regions{}",
+        if trace.is_some() {
+            ", where the guest said it wrote"
+        } else {
+            ""
+        }
+    );
+}